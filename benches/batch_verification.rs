@@ -0,0 +1,61 @@
+//! Compares checking encrypted signatures one by one with `encverify` against checking them all at
+//! once with `encverify_batch`, as a tumbler would for the signatures of many sessions.
+
+use a2l_poc::secp256k1::{self, EncryptedSignature, KeyPair, PublicKey, ToMessage};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+struct Digest([u8; 32]);
+
+impl ToMessage for Digest {
+    fn to_message(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+fn encrypted_signatures(n: usize) -> Vec<(PublicKey, PublicKey, [u8; 32], EncryptedSignature)> {
+    let mut rng = rand::thread_rng();
+
+    (0..n)
+        .map(|i| {
+            let x = KeyPair::random(&mut rng);
+            let y = KeyPair::random(&mut rng);
+            let message = [i as u8 + 1; 32];
+
+            let encsig = secp256k1::encsign(Digest(message), &x, &y.to_pk(), &mut rng);
+
+            (x.to_pk(), y.to_pk(), message, encsig)
+        })
+        .collect()
+}
+
+fn encverify(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encverify");
+
+    for n in [1, 8, 32, 128].iter() {
+        let encsigs = encrypted_signatures(*n);
+        let items = encsigs
+            .iter()
+            .map(|(signing_key, encryption_key, message, encsig)| {
+                (signing_key, encryption_key, message, encsig)
+            })
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_with_input(BenchmarkId::new("loop", n), &items, |b, items| {
+            b.iter(|| {
+                for (signing_key, encryption_key, message, encsig) in items {
+                    secp256k1::encverify(signing_key, encryption_key, message, encsig).unwrap();
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batch", n), &items, |b, items| {
+            let mut rng = rand::thread_rng();
+            b.iter(|| secp256k1::encverify_batch(&mut rng, items).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encverify);
+criterion_main!(benches);
@@ -0,0 +1,255 @@
+use a2l_poc::bitcoin::{self, OutputType};
+use a2l_poc::chain::{self, Broadcaster, ChainSource};
+use a2l_poc::client::ReceiverClient;
+use a2l_poc::config::{self, Backend, ClientConfig};
+use a2l_poc::joint_output::JointOutputScheme;
+use a2l_poc::secp256k1::{self, Ecdsa, Schnorr};
+use a2l_poc::storage::{FileStorage, Snapshot, Storage};
+use a2l_poc::transport::TcpTransport;
+use a2l_poc::tumbler_service::SessionId;
+use a2l_poc::wire::{self, Decode, Encode, WireMessage};
+use a2l_poc::{dummy_hsm_cl, hsm_cl, puzzle_promise, puzzle_solver};
+use anyhow::{bail, Context};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+const STATE: &str = "receiver";
+const SESSION: &str = "session";
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "a2l-receiver",
+    about = "Receive a payment through an A2L tumbler"
+)]
+struct Opts {
+    #[structopt(long, parse(from_os_str))]
+    config: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Performs the next protocol step.
+    Step {
+        /// Hex-encoded message from the sender, if the next step needs one.
+        #[structopt(long)]
+        input: Option<String>,
+    },
+    /// Performs protocol steps until a message from the sender is needed or the protocol is
+    /// complete.
+    Run {
+        /// Hex-encoded message from the sender, if a step needs one.
+        #[structopt(long)]
+        input: Option<String>,
+    },
+}
+
+enum Progress {
+    Continue,
+    NeedsInput(&'static str),
+    Done,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let config = config::load::<ClientConfig>(&opts.config)?;
+
+    match config.backend {
+        Backend::Dummy => {
+            let publickey =
+                config::read_key::<dummy_hsm_cl::PublicKey>(&config.tumbler_public_key)?;
+
+            run::<_, dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof>(
+                &config,
+                publickey,
+                opts.command,
+            )
+        }
+        Backend::HsmCl => {
+            let publickey =
+                config::read_hsm_cl_public_key(&config.tumbler_public_key, &config.public_setup)?;
+
+            run::<_, hsm_cl::Ciphertext, hsm_cl::Proof>(&config, publickey, opts.command)
+        }
+    }
+}
+
+fn run<PK, C, P>(config: &ClientConfig, publickey: PK, command: Command) -> anyhow::Result<()>
+where
+    PK: hsm_cl::Verify<C, P> + hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+{
+    match config.params.output_type {
+        OutputType::P2wsh => run_steps::<PK, C, P, Ecdsa>(config, publickey, command),
+        OutputType::P2tr => run_steps::<PK, C, P, Schnorr>(config, publickey, command),
+    }
+}
+
+fn run_steps<PK, C, P, S>(
+    config: &ClientConfig,
+    publickey: PK,
+    command: Command,
+) -> anyhow::Result<()>
+where
+    PK: hsm_cl::Verify<C, P> + hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+    S: JointOutputScheme,
+{
+    let client = ReceiverClient::new(
+        TcpTransport::new(config.tumbler, config.timeout()),
+        publickey,
+    );
+    let mut storage = FileStorage::open(&config.state_dir)?;
+
+    let (single_step, input) = match command {
+        Command::Step { input } => (true, input),
+        Command::Run { input } => (false, input),
+    };
+
+    loop {
+        match step::<PK, C, P, S>(config, &client, &mut storage, input.as_deref())? {
+            Progress::Continue if !single_step => {}
+            Progress::Continue | Progress::Done => return Ok(()),
+            Progress::NeedsInput(what) => {
+                println!("waiting for {} from the sender, pass it with --input", what);
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn step<PK, C, P, S>(
+    config: &ClientConfig,
+    client: &ReceiverClient<TcpTransport, PK>,
+    storage: &mut FileStorage,
+    input: Option<&str>,
+) -> anyhow::Result<Progress>
+where
+    PK: hsm_cl::Verify<C, P> + hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+    S: JointOutputScheme,
+{
+    let tag = match storage.get(STATE)? {
+        Some(state) => *state.get(1).context("corrupt state")?,
+        None => {
+            if let Some(chain) = config.bitcoind(0) {
+                chain::check_fee_rate(
+                    &chain,
+                    config.params.spend_transaction_fee_per_vbyte,
+                    config.fee_estimate_target_blocks,
+                )?;
+            }
+
+            let session = SessionId::random(&mut rand::thread_rng());
+
+            save_session(storage, session)?;
+            let receiver = client.start_promise::<C, P, S>(
+                session,
+                config.params.redeem_identity()?,
+                &config.params.terms(),
+            )?;
+            storage.save(STATE, &receiver)?;
+
+            return Ok(Progress::Continue);
+        }
+    };
+
+    if tag == puzzle_promise::Receiver1::<C, S>::TAG {
+        let receiver = load::<puzzle_promise::Receiver1<C, S>>(storage)?;
+
+        let receiver = client.finish_promise::<C, P, S>(load_session(storage)?, receiver)?;
+        storage.save(STATE, &receiver)?;
+
+        println!(
+            "lock for the sender: {}",
+            hex::encode(receiver.next_message().to_bytes())
+        );
+
+        Ok(Progress::Continue)
+    } else if tag == puzzle_promise::Receiver2::<C, S>::TAG {
+        let input = match input {
+            Some(input) => input,
+            None => return Ok(Progress::NeedsInput("the solution")),
+        };
+        let message = puzzle_solver::Message4::from_bytes(
+            &hex::decode(input.trim()).context("input is not valid hex")?,
+        )?;
+        let receiver = load::<puzzle_promise::Receiver2<C, S>>(storage)?;
+
+        let receiver = puzzle_solver::Receiver0::new(
+            receiver.redeem_session().clone(),
+            receiver.unsigned_redeem_transaction().clone(),
+            receiver.sig_redeem_t().clone(),
+            receiver.sig_redeem_r().clone(),
+            receiver.beta().clone(),
+        );
+        let receiver = receiver.receive(message)?;
+        storage.save(STATE, &receiver)?;
+
+        publish_redeem_transaction(config, &receiver)?;
+
+        Ok(Progress::Done)
+    } else if tag == puzzle_solver::Receiver1::TAG {
+        publish_redeem_transaction(config, &load::<puzzle_solver::Receiver1>(storage)?)?;
+
+        Ok(Progress::Done)
+    } else {
+        bail!("unknown state with tag {}", tag)
+    }
+}
+
+/// Prints the redeem transaction and broadcasts it if bitcoind is configured and the joint output
+/// is unspent.
+fn publish_redeem_transaction(
+    config: &ClientConfig,
+    receiver: &puzzle_solver::Receiver1,
+) -> anyhow::Result<()> {
+    let redeem_transaction = receiver.signed_redeem_transaction();
+
+    println!(
+        "redeem transaction: {}",
+        bitcoin::to_hex(redeem_transaction)
+    );
+
+    let chain = match config.bitcoind(0) {
+        Some(chain) => chain,
+        None => return Ok(()),
+    };
+
+    if chain
+        .utxo(&redeem_transaction.input[0].previous_output)?
+        .is_none()
+    {
+        // either the tumbler has not funded it yet, or it is spent by this transaction or the
+        // tumbler's refund
+        println!("the joint output is not unspent, not broadcasting the redeem transaction");
+
+        return Ok(());
+    }
+
+    let txid = chain.broadcast(redeem_transaction)?;
+    println!("redeem transaction {} broadcast", txid);
+
+    Ok(())
+}
+
+fn load<S: Snapshot>(storage: &FileStorage) -> anyhow::Result<S> {
+    storage.load(STATE)?.context("no state")
+}
+
+fn save_session(storage: &mut FileStorage, session: SessionId) -> anyhow::Result<()> {
+    let mut buffer = Vec::new();
+    session.encode(&mut buffer);
+
+    storage.put(SESSION, &buffer)
+}
+
+fn load_session(storage: &FileStorage) -> anyhow::Result<SessionId> {
+    let bytes = storage.get(SESSION)?.context("no session in progress")?;
+
+    Ok(SessionId::decode(&mut wire::Reader::new(&bytes))?)
+}
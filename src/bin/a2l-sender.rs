@@ -0,0 +1,339 @@
+use a2l_poc::bitcoin::{self, OutputType};
+use a2l_poc::chain::{self, BitcoindRpc, Broadcaster, ChainSource};
+use a2l_poc::client::SenderClient;
+use a2l_poc::config::{self, Backend, ClientConfig};
+use a2l_poc::joint_output::JointOutputScheme;
+use a2l_poc::secp256k1::{self, Ecdsa, Schnorr};
+use a2l_poc::storage::{FileStorage, Snapshot, Storage};
+use a2l_poc::transport::TcpTransport;
+use a2l_poc::tumbler_service::SessionId;
+use a2l_poc::watcher::{Outcome, Poll, SenderWatcher};
+use a2l_poc::wire::{self, Decode, Encode, WireMessage};
+use a2l_poc::{dummy_hsm_cl, hsm_cl, puzzle_promise, puzzle_solver};
+use anyhow::{bail, Context};
+use std::path::PathBuf;
+use std::thread;
+use structopt::StructOpt;
+
+const STATE: &str = "sender";
+/// The session with the tumbler, until the tumbler has the sender's redeem signature.
+const SESSION: &str = "session";
+/// The height from which on the chain is searched for the tumbler's redeem transaction.
+const WATCH_FROM: &str = "watch_from";
+
+#[derive(StructOpt)]
+#[structopt(name = "a2l-sender", about = "Pay a receiver through an A2L tumbler")]
+struct Opts {
+    #[structopt(long, parse(from_os_str))]
+    config: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Performs the next protocol step.
+    Step {
+        /// The hex-encoded lock from the receiver or the redeem transaction published by the
+        /// tumbler, if the next step needs one.
+        #[structopt(long)]
+        input: Option<String>,
+    },
+    /// Performs protocol steps until input is needed or the protocol is complete. With bitcoind
+    /// configured, this waits for the tumbler to redeem or the refund to confirm.
+    Run {
+        /// The hex-encoded lock from the receiver or the redeem transaction published by the
+        /// tumbler, if a step needs one.
+        #[structopt(long)]
+        input: Option<String>,
+    },
+}
+
+enum Progress {
+    Continue,
+    NeedsInput(&'static str),
+    Waiting(&'static str),
+    Done,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let config = config::load::<ClientConfig>(&opts.config)?;
+
+    match config.backend {
+        Backend::Dummy => {
+            let publickey =
+                config::read_key::<dummy_hsm_cl::PublicKey>(&config.tumbler_public_key)?;
+
+            run::<_, dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof>(
+                &config,
+                publickey,
+                opts.command,
+            )
+        }
+        Backend::HsmCl => {
+            let publickey =
+                config::read_hsm_cl_public_key(&config.tumbler_public_key, &config.public_setup)?;
+
+            run::<_, hsm_cl::Ciphertext, hsm_cl::Proof>(&config, publickey, opts.command)
+        }
+    }
+}
+
+fn run<PK, C, P>(config: &ClientConfig, publickey: PK, command: Command) -> anyhow::Result<()>
+where
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+{
+    match config.params.output_type {
+        OutputType::P2wsh => run_steps::<PK, C, P, Ecdsa>(config, publickey, command),
+        OutputType::P2tr => run_steps::<PK, C, P, Schnorr>(config, publickey, command),
+    }
+}
+
+fn run_steps<PK, C, P, S>(
+    config: &ClientConfig,
+    publickey: PK,
+    command: Command,
+) -> anyhow::Result<()>
+where
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+    S: JointOutputScheme,
+{
+    let client = SenderClient::new(
+        TcpTransport::new(config.tumbler, config.timeout()),
+        publickey,
+    );
+    let mut storage = FileStorage::open(&config.state_dir)?;
+
+    let (single_step, mut input) = match command {
+        Command::Step { input } => (true, input),
+        Command::Run { input } => (false, input),
+    };
+
+    loop {
+        // the lock and the redeem transaction are needed at different steps, so `run` can only
+        // consume one of them
+        match step::<PK, C, P, S>(config, &client, &mut storage, &mut input)? {
+            Progress::Continue if !single_step => {}
+            Progress::Waiting(_) if !single_step => thread::sleep(config.poll_interval()),
+            Progress::Continue | Progress::Done => return Ok(()),
+            Progress::NeedsInput(what) => {
+                println!("waiting for {}, pass it with --input", what);
+                return Ok(());
+            }
+            Progress::Waiting(what) => {
+                println!("waiting for {}", what);
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn step<PK, C, P, S>(
+    config: &ClientConfig,
+    client: &SenderClient<TcpTransport, PK>,
+    storage: &mut FileStorage,
+    input: &mut Option<String>,
+) -> anyhow::Result<Progress>
+where
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+    C: wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+    S: JointOutputScheme,
+{
+    let tag = match storage.get(STATE)? {
+        Some(state) => *state.get(1).context("corrupt state")?,
+        None => {
+            let input = match input.take() {
+                Some(input) => input,
+                None => return Ok(Progress::NeedsInput("the lock from the receiver")),
+            };
+            let message = puzzle_promise::Message3::<C>::from_bytes(
+                &hex::decode(input.trim()).context("input is not valid hex")?,
+            )?;
+            let lock = puzzle_promise::Sender0::new()
+                .receive(message)
+                .lock()
+                .clone();
+
+            let mut contribution = config.params.sender_contribution()?;
+            if let Some(wallet) = config.bitcoind(0) {
+                chain::check_fee_rate(
+                    &wallet,
+                    config.params.spend_transaction_fee_per_vbyte,
+                    config.fee_estimate_target_blocks,
+                )?;
+
+                if contribution.partial_fund_spent_outputs.is_empty() {
+                    contribution.partial_fund_spent_outputs =
+                        chain::spent_outputs(&wallet, &contribution.partial_fund_transaction)?;
+                }
+            }
+
+            let session = SessionId::random(&mut rand::thread_rng());
+
+            save_session(storage, session)?;
+            let sender = client.start_solver::<C, P, S>(
+                session,
+                contribution,
+                &config.params.terms(),
+                lock,
+            )?;
+            storage.save(STATE, &sender)?;
+
+            return Ok(Progress::Continue);
+        }
+    };
+
+    if tag == puzzle_solver::Sender1::<C, S>::TAG {
+        let sender = load::<puzzle_solver::Sender1<C, S>>(storage)?;
+
+        // the redeem transaction cannot be in a block that is older than the fund transaction
+        if let Some(chain) = config.bitcoind(0) {
+            let mut buffer = Vec::new();
+            chain.height()?.encode(&mut buffer);
+
+            storage.put(WATCH_FROM, &buffer)?;
+        }
+
+        // a rerun must not sign again, so the signature is in storage before it leaves
+        let sender = client.sign_solver::<C, P, S>(load_session(storage)?, sender)?;
+        storage.save(STATE, &sender)?;
+
+        Ok(Progress::Continue)
+    } else if tag == puzzle_solver::Sender2::<S>::TAG {
+        let sender = load::<puzzle_solver::Sender2<S>>(storage)?;
+
+        // the session is forgotten once the tumbler has the signature
+        if storage.get(SESSION)?.is_some() {
+            client.finish_solver::<C, P, S>(load_session(storage)?, &sender)?;
+            storage.delete(SESSION)?;
+
+            println!(
+                "fund transaction (psbt): {}",
+                bitcoin::psbt_to_base64(&sender.unsigned_fund_psbt()?)
+            );
+            println!(
+                "refund transaction: {}",
+                bitcoin::to_hex(&sender.signed_refund_transaction())
+            );
+
+            if let Some(wallet) = config.bitcoind(0) {
+                publish_fund_transaction(&wallet, &sender)?;
+            }
+
+            return Ok(Progress::Continue);
+        }
+
+        if let Some(input) = input.take() {
+            let sender = sender.receive(bitcoin::from_hex(&input)?)?;
+            storage.save(STATE, &sender)?;
+
+            print_solution(&sender);
+
+            return Ok(Progress::Done);
+        }
+
+        let chain = match &config.bitcoind {
+            Some(url) => BitcoindRpc::new(url.as_str(), load_watch_from(storage)?),
+            None => {
+                return Ok(Progress::NeedsInput(
+                    "the redeem transaction of the tumbler",
+                ))
+            }
+        };
+
+        // broadcasting it right after the protocol may have failed
+        publish_fund_transaction(&chain, &sender)?;
+
+        match SenderWatcher::new(sender).poll(&chain)? {
+            Poll::Ready(Outcome::Solved(sender)) => {
+                storage.save(STATE, &sender)?;
+
+                print_solution(&sender);
+
+                Ok(Progress::Done)
+            }
+            Poll::Ready(Outcome::Refunded(txid)) => {
+                println!("refund transaction {} confirmed", txid);
+
+                Ok(Progress::Done)
+            }
+            Poll::Pending(_) => Ok(Progress::Waiting(
+                "the tumbler to redeem or the refund to confirm",
+            )),
+            Poll::Retry(_, e) => {
+                println!("failed to look at the chain: {:#}", e);
+
+                Ok(Progress::Waiting(
+                    "the tumbler to redeem or the refund to confirm",
+                ))
+            }
+        }
+    } else if tag == puzzle_solver::Sender3::TAG {
+        print_solution(&load::<puzzle_solver::Sender3>(storage)?);
+
+        Ok(Progress::Done)
+    } else {
+        bail!("unknown state with tag {}", tag)
+    }
+}
+
+/// Signs the fund transaction with the wallet and broadcasts it, unless that happened already.
+fn publish_fund_transaction<S: JointOutputScheme>(
+    wallet: &BitcoindRpc,
+    sender: &puzzle_solver::Sender2<S>,
+) -> anyhow::Result<()> {
+    let fund_transaction = sender.unsigned_fund_transaction();
+
+    // once its inputs are spent, the fund transaction is in the mempool or in the chain
+    if wallet
+        .utxo(&fund_transaction.input[0].previous_output)?
+        .is_none()
+    {
+        return Ok(());
+    }
+
+    let psbt = wallet.sign_psbt(&sender.unsigned_fund_psbt()?)?;
+    let txid = wallet.broadcast(&sender.signed_fund_transaction(psbt)?)?;
+
+    println!("fund transaction {} broadcast", txid);
+
+    Ok(())
+}
+
+fn print_solution(sender: &puzzle_solver::Sender3) {
+    println!(
+        "solution for the receiver: {}",
+        hex::encode(sender.next_message().to_bytes())
+    );
+}
+
+fn load<S: Snapshot>(storage: &FileStorage) -> anyhow::Result<S> {
+    storage.load(STATE)?.context("no state")
+}
+
+fn save_session(storage: &mut FileStorage, session: SessionId) -> anyhow::Result<()> {
+    let mut buffer = Vec::new();
+    session.encode(&mut buffer);
+
+    storage.put(SESSION, &buffer)
+}
+
+fn load_session(storage: &FileStorage) -> anyhow::Result<SessionId> {
+    let bytes = storage.get(SESSION)?.context("no session in progress")?;
+
+    Ok(SessionId::decode(&mut wire::Reader::new(&bytes))?)
+}
+
+fn load_watch_from(storage: &FileStorage) -> anyhow::Result<u32> {
+    let bytes = storage.get(WATCH_FROM)?.context(
+        "the fund transaction was made without bitcoind, pass the redeem transaction with --input",
+    )?;
+
+    Ok(u32::decode(&mut wire::Reader::new(&bytes))?)
+}
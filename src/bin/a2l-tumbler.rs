@@ -0,0 +1,163 @@
+use a2l_poc::bitcoin::{self, OutputType};
+use a2l_poc::chain::{BitcoindRpc, Broadcaster};
+use a2l_poc::config::{self, Backend, TumblerConfig};
+use a2l_poc::joint_output::JointOutputScheme;
+use a2l_poc::secp256k1::{Ecdsa, Schnorr};
+use a2l_poc::storage::FileStorage;
+use a2l_poc::transport;
+use a2l_poc::tumbler_service::TumblerService;
+use a2l_poc::{dummy_hsm_cl, hsm_cl, puzzle_promise, wire};
+use anyhow::Context;
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+const SECRET_KEY_FILE: &str = "tumbler.key";
+const PUBLIC_KEY_FILE: &str = "tumbler.pub";
+
+#[derive(StructOpt)]
+#[structopt(name = "a2l-tumbler", about = "Run an A2L tumbler")]
+struct Opts {
+    #[structopt(long, parse(from_os_str))]
+    config: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Generates the tumbler's encryption key. The public key has to be handed to every sender
+    /// and receiver.
+    Keygen,
+    /// Serves puzzle promise and puzzle solver sessions until interrupted.
+    Serve,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::from_args();
+    let config = config::load::<TumblerConfig>(&opts.config)?;
+    fs::create_dir_all(&config.state_dir)
+        .with_context(|| format!("failed to create {}", config.state_dir.display()))?;
+
+    match (opts.command, config.backend, config.output_type) {
+        (Command::Keygen, Backend::Dummy, _) => keygen(&config, dummy_hsm_cl::keygen()),
+        (Command::Keygen, Backend::HsmCl, _) => {
+            keygen(&config, hsm_cl::keygen(config.public_setup.as_bytes()))
+        }
+        (Command::Serve, Backend::Dummy, OutputType::P2wsh) => {
+            serve::<dummy_hsm_cl::SecretKey, _, Ecdsa>(&config)
+        }
+        (Command::Serve, Backend::Dummy, OutputType::P2tr) => {
+            serve::<dummy_hsm_cl::SecretKey, _, Schnorr>(&config)
+        }
+        (Command::Serve, Backend::HsmCl, OutputType::P2wsh) => {
+            serve::<hsm_cl::KeyPair, _, Ecdsa>(&config)
+        }
+        (Command::Serve, Backend::HsmCl, OutputType::P2tr) => {
+            serve::<hsm_cl::KeyPair, _, Schnorr>(&config)
+        }
+    }
+}
+
+fn keygen<SK, PK>(config: &TumblerConfig, (secretkey, publickey): (SK, PK)) -> anyhow::Result<()>
+where
+    SK: wire::Encode,
+    PK: wire::Encode,
+{
+    let secret_key_path = config.state_dir.join(SECRET_KEY_FILE);
+    let public_key_path = config.state_dir.join(PUBLIC_KEY_FILE);
+
+    config::write_key(&secret_key_path, &secretkey)?;
+    config::write_key(&public_key_path, &publickey)?;
+
+    println!("public key written to {}", public_key_path.display());
+
+    Ok(())
+}
+
+fn serve<SK, C, S>(config: &TumblerConfig) -> anyhow::Result<()>
+where
+    SK: hsm_cl::Encrypt<Ciphertext = C>
+        + hsm_cl::Decrypt<Ciphertext = C>
+        + wire::Decode
+        + Send
+        + Sync
+        + 'static,
+    SK::Proof: wire::Encode + wire::Decode,
+    C: wire::Encode + wire::Decode,
+    S: JointOutputScheme + Send + Sync + 'static,
+{
+    let secretkey = config::read_key::<SK>(&config.state_dir.join(SECRET_KEY_FILE))?;
+    let storage = FileStorage::open(&config.state_dir)?;
+
+    // the wallet is only asked for its coins and the height and to sign and broadcast, so nothing
+    // needs to be scanned
+    let wallet = BitcoindRpc::new(config.bitcoind.as_str(), 0);
+    let service = Arc::new(TumblerService::<_, _, _, S>::new(
+        secretkey,
+        BitcoindRpc::new(config.bitcoind.as_str(), 0),
+        storage,
+        config.service_config()?,
+    )?);
+    let listener = TcpListener::bind(config.listen)
+        .with_context(|| format!("failed to listen on {}", config.listen))?;
+    let _server = transport::serve_tcp(service.clone(), listener);
+
+    println!("listening on {}", config.listen);
+
+    loop {
+        for id in service.expire_idle_sessions() {
+            println!("session {} expired", id);
+        }
+
+        for id in service.completed_sessions() {
+            if let Some(tumbler) = service.take_completed_promise(&id)? {
+                println!("puzzle promise session {} completed", id);
+                println!(
+                    "  fund transaction (psbt): {}",
+                    bitcoin::psbt_to_base64(&tumbler.unsigned_fund_psbt()?)
+                );
+                println!(
+                    "  refund transaction: {}",
+                    bitcoin::to_hex(tumbler.signed_refund_transaction())
+                );
+
+                // the state is stored, so a failure here only needs the printed PSBT to be signed
+                // and broadcast by hand
+                match fund(&wallet, &tumbler) {
+                    Ok(txid) => println!("  fund transaction {} broadcast", txid),
+                    Err(e) => println!("  failed to fund: {:#}", e),
+                }
+            }
+
+            if let Some(tumbler) = service.take_completed_solver(&id)? {
+                println!("puzzle solver session {} completed", id);
+                println!(
+                    "  redeem transaction: {}",
+                    bitcoin::to_hex(tumbler.signed_redeem_transaction())
+                );
+
+                match wallet.broadcast(tumbler.signed_redeem_transaction()) {
+                    Ok(txid) => println!("  redeem transaction {} broadcast", txid),
+                    Err(e) => println!("  failed to broadcast redeem transaction: {:#}", e),
+                }
+            }
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Signs the fund transaction of a puzzle promise session with the wallet and broadcasts it.
+fn fund<S: JointOutputScheme>(
+    wallet: &BitcoindRpc,
+    tumbler: &puzzle_promise::Tumbler1<S>,
+) -> anyhow::Result<bitcoin::Txid> {
+    let psbt = wallet.sign_psbt(&tumbler.unsigned_fund_psbt()?)?;
+
+    wallet.broadcast(&tumbler.signed_fund_transaction(psbt)?)
+}
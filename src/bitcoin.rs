@@ -1,17 +1,42 @@
+use crate::fee;
 use crate::secp256k1;
 use crate::secp256k1::ToMessage;
+use crate::taproot;
+use crate::wire;
 use anyhow::{bail, Context};
-pub use bitcoin::hash_types::SigHash;
+use bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1;
+use bitcoin::blockdata::script::Builder;
+pub use bitcoin::hash_types::{SigHash, Txid};
 use bitcoin::hashes::Hash;
 use bitcoin::util::bip143::SighashComponents;
+pub use bitcoin::util::psbt::PartiallySignedTransaction;
 pub use bitcoin::Transaction;
 pub use bitcoin::TxIn;
-pub use bitcoin::{Address, OutPoint, SigHashType, TxOut};
-use fehler::throws;
-use std::{collections::HashMap, str::FromStr};
+pub use bitcoin::{Address, OutPoint, Script, SigHashType, TxOut};
+use fehler::{throw, throws};
+use std::str::FromStr;
 
-pub const MAX_SATISFACTION_WEIGHT: u64 = 222;
-const MINISCRIPT_TEMPLATE: &str = "and_v(vc:pk(X_from),c:pk(X_to))";
+/// The fund output can be spent with the signatures of both parties, either right away through the
+/// first (redeem) branch or through the second (refund) branch once `LOCKTIME` has passed.
+const MINISCRIPT_TEMPLATE: &str =
+    "or_i(and_v(vc:pk(X_from),c:pk(X_to)),and_v(vc:pk(X_from),and_v(vc:pk(X_to),after(LOCKTIME))))";
+
+/// Sequence number of the redeem input, which does not opt into nLockTime.
+pub(crate) const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+/// Sequence number of the refund input. Anything below `SEQUENCE_FINAL` enables nLockTime, which
+/// `OP_CHECKLOCKTIMEVERIFY` requires.
+pub(crate) const SEQUENCE_ENABLE_LOCKTIME: u32 = 0xFFFF_FFFE;
+
+/// Value of an anchor output, the dust threshold of its P2WSH output like in Lightning.
+pub const ANCHOR_VALUE: u64 = 330;
+/// Minimum relay fee of Bitcoin Core, in satoshi per virtual byte.
+const MIN_RELAY_FEE_PER_VBYTE: u64 = 1;
+/// Fee rate Bitcoin Core uses to decide whether an output is dust, in satoshi per virtual byte.
+const DUST_RELAY_FEE_PER_VBYTE: u64 = 3;
+/// Serialized size of the joint output: value, script length and a P2WSH or P2TR script.
+const JOINT_OUTPUT_SIZE: u64 = 8 + 1 + 34;
+/// Index of the joint output in the fund transaction.
+pub(crate) const JOINT_OUTPUT_INDEX: u32 = 0;
 
 #[derive(Debug)]
 pub struct Transactions {
@@ -22,62 +47,93 @@ pub struct Transactions {
     pub refund_tx_digest: SigHash,
 }
 
+/// The kind of joint output a session locks its funds in.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputType {
+    /// A P2WSH output with a redeem and a refund branch, both spent with two ECDSA signatures.
+    P2wsh,
+    /// A P2TR output, redeemed through the key path with a MuSig2 signature and refunded through
+    /// a script leaf. See `taproot`.
+    P2tr,
+}
+
+/// The joint output of a fund transaction and what the redeem and refund transactions spending it
+/// pay.
+#[derive(Clone, Debug)]
+pub struct JointOutput {
+    pub output_type: OutputType,
+    /// Value of the joint output.
+    pub value: u64,
+    /// Value the redeem and refund transactions pay to their identity. The rest of the joint output
+    /// goes to fees and anchor outputs.
+    pub spend_value: u64,
+    pub refund_locktime: u32,
+    pub redeem_identity: Address,
+    pub refund_identity: Address,
+    pub anchor_outputs: bool,
+}
+
 pub fn make_transactions(
     partial_fund_transaction: Transaction,
-    fund_amount: u64,
-    spend_amount: u64,
     X_fund_from: &secp256k1::PublicKey,
     X_fund_to: &secp256k1::PublicKey,
-    refund_locktime: u32,
-    X_redeem: &bitcoin::Address,
-    X_refund: &bitcoin::Address,
+    joint_output: &JointOutput,
 ) -> Transactions {
-    let descriptor = descriptor(&X_fund_from, &X_fund_to);
+    if joint_output.output_type == OutputType::P2tr {
+        return taproot::make_transactions(
+            partial_fund_transaction,
+            X_fund_from,
+            X_fund_to,
+            joint_output,
+        );
+    }
+
+    let JointOutput {
+        output_type: _,
+        value: fund_amount,
+        spend_value: spend_amount,
+        refund_locktime,
+        redeem_identity: X_redeem,
+        refund_identity: X_refund,
+        anchor_outputs,
+    } = joint_output.clone();
+    let descriptor = descriptor(&X_fund_from, &X_fund_to, refund_locktime);
 
     let fund_output = bitcoin::TxOut {
         value: fund_amount,
         script_pubkey: descriptor.script_pubkey(),
     };
 
-    let Transaction {
-        input,
-        output: existing_outputs,
-        lock_time,
-        version,
-    } = partial_fund_transaction;
-
-    let joint_output_index = 0;
-
-    let mut outputs = Vec::with_capacity(existing_outputs.len() + 1);
-
-    outputs.insert(joint_output_index, fund_output);
-    outputs.extend(existing_outputs);
-
-    let fund_transaction = bitcoin::Transaction {
-        input,
-        lock_time,
-        version,
-        output: outputs,
-    };
+    let fund_transaction = insert_joint_output(partial_fund_transaction, fund_output);
 
     let input = TxIn {
         previous_output: bitcoin::OutPoint {
             txid: fund_transaction.txid(),
-            vout: joint_output_index as u32,
+            vout: JOINT_OUTPUT_INDEX,
         },
         script_sig: descriptor.unsigned_script_sig(),
-        sequence: 0xFFFF_FFFF,
-        witness: Vec::new(),
+        sequence: SEQUENCE_FINAL,
+        // the signatures are added in front of the witness script when completing the spend
+        witness: vec![descriptor.witness_script().into_bytes()],
+    };
+
+    // either party can bump the fee of the pre-signed spend transactions by spending the anchor
+    let anchors = if anchor_outputs {
+        vec![anchor_output()]
+    } else {
+        Vec::new()
     };
 
     let (redeem_transaction, redeem_tx_digest) = {
-        let output = make_spend_output(spend_amount, &X_redeem);
+        let mut output = vec![make_spend_output(spend_amount, &X_redeem)];
+        output.extend(anchors.iter().cloned());
 
         let transaction = bitcoin::Transaction {
             version: 2,
             lock_time: 0,
             input: vec![input.clone()],
-            output: vec![output.clone()],
+            output,
         };
 
         let digest = SighashComponents::new(&transaction).sighash_all(
@@ -90,13 +146,18 @@ pub fn make_transactions(
     };
 
     let (refund_transaction, refund_tx_digest) = {
-        let output = make_spend_output(spend_amount, &X_refund);
+        let mut output = vec![make_spend_output(spend_amount, &X_refund)];
+        output.extend(anchors);
+        let input = TxIn {
+            sequence: SEQUENCE_ENABLE_LOCKTIME,
+            ..input
+        };
 
         let transaction = bitcoin::Transaction {
             version: 2,
             lock_time: refund_locktime,
             input: vec![input.clone()],
-            output: vec![output.clone()],
+            output,
         };
 
         let digest = SighashComponents::new(&transaction).sighash_all(
@@ -111,39 +172,231 @@ pub fn make_transactions(
     Transactions {
         fund: fund_transaction,
         redeem: redeem_transaction,
-        redeem_tx_digest: dbg!(redeem_tx_digest),
+        redeem_tx_digest,
         refund: refund_transaction,
-        refund_tx_digest: dbg!(refund_tx_digest),
+        refund_tx_digest,
+    }
+}
+
+/// Completes the partial fund transaction with the joint output, placed at `JOINT_OUTPUT_INDEX`.
+pub(crate) fn insert_joint_output(
+    partial_fund_transaction: Transaction,
+    joint_output: TxOut,
+) -> Transaction {
+    let Transaction {
+        input,
+        output: existing_outputs,
+        lock_time,
+        version,
+    } = partial_fund_transaction;
+
+    let mut outputs = Vec::with_capacity(existing_outputs.len() + 1);
+
+    outputs.insert(JOINT_OUTPUT_INDEX as usize, joint_output);
+    outputs.extend(existing_outputs);
+
+    bitcoin::Transaction {
+        input,
+        lock_time,
+        version,
+        output: outputs,
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum InvalidPartialFundTransaction {
+    #[error("partial fund transaction does not spend anything")]
+    NoInputs,
+    #[error("expected the {expected} outputs spent by the partial fund transaction, got {actual}")]
+    SpentOutputsMismatch { expected: usize, actual: usize },
+    #[error("input {0} of the partial fund transaction does not spend a P2WPKH output")]
+    NonP2wpkhInput(usize),
+    #[error("change output {index} of the partial fund transaction is dust ({value} satoshi)")]
+    DustOutput { index: usize, value: u64 },
+    #[error("partial fund transaction spends {available} satoshi, but needs at least {required}")]
+    InsufficientFunds { available: u64, required: u64 },
+}
+
+/// Checks that `partial_fund_transaction` can be completed into a fund transaction paying
+/// `joint_output_value` into the joint output.
+///
+/// `spent_outputs` are the outputs the inputs spend, in order. They all have to be native segwit,
+/// otherwise the txid of the fund transaction could change while it is signed or relayed,
+/// invalidating the redeem and refund transactions spending it. They also have to be P2WPKH, the
+/// only kind of output whose witness size is known up front. Every output of the partial fund
+/// transaction is change of the funding party and must not be dust. The fee is whatever the inputs
+/// leave after the change and the joint output, and has to meet the minimum relay fee for the
+/// fund transaction once signed.
+#[throws(InvalidPartialFundTransaction)]
+pub fn validate_partial_fund_transaction(
+    partial_fund_transaction: &Transaction,
+    spent_outputs: &[TxOut],
+    joint_output_value: u64,
+) {
+    if partial_fund_transaction.input.is_empty() {
+        throw!(InvalidPartialFundTransaction::NoInputs)
+    }
+    if spent_outputs.len() != partial_fund_transaction.input.len() {
+        throw!(InvalidPartialFundTransaction::SpentOutputsMismatch {
+            expected: partial_fund_transaction.input.len(),
+            actual: spent_outputs.len(),
+        })
+    }
+
+    for (index, (input, spent_output)) in partial_fund_transaction
+        .input
+        .iter()
+        .zip(spent_outputs)
+        .enumerate()
+    {
+        if !spent_output.script_pubkey.is_v0_p2wpkh() || !input.script_sig.is_empty() {
+            throw!(InvalidPartialFundTransaction::NonP2wpkhInput(index))
+        }
+    }
+
+    for (index, output) in partial_fund_transaction.output.iter().enumerate() {
+        if output.value < dust_threshold(output) {
+            throw!(InvalidPartialFundTransaction::DustOutput {
+                index,
+                value: output.value,
+            })
+        }
+    }
+
+    let available = spent_outputs
+        .iter()
+        .fold(0u64, |sum, output| sum.saturating_add(output.value));
+    let weight = fee::p2wpkh_spend_weight(partial_fund_transaction) + 4 * JOINT_OUTPUT_SIZE;
+    let fee = fee::vsize(weight) * MIN_RELAY_FEE_PER_VBYTE;
+    let required = partial_fund_transaction
+        .output
+        .iter()
+        .fold(joint_output_value.saturating_add(fee), |sum, output| {
+            sum.saturating_add(output.value)
+        });
+
+    if available < required {
+        throw!(InvalidPartialFundTransaction::InsufficientFunds {
+            available,
+            required,
+        })
+    }
+}
+
+fn is_native_segwit(script_pubkey: &Script) -> bool {
+    script_pubkey.is_v0_p2wpkh() || script_pubkey.is_v0_p2wsh()
+}
+
+/// The value below which Bitcoin Core refuses to relay `output`, because spending it would cost
+/// more than a third of its value.
+pub(crate) fn dust_threshold(output: &TxOut) -> u64 {
+    if output.script_pubkey.is_provably_unspendable() {
+        return 0;
+    }
+
+    // the size of the input spending the output, counting witness data at a quarter
+    let input_size = if is_native_segwit(&output.script_pubkey) {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    let output_size = bitcoin::consensus::encode::serialize(output).len() as u64;
+
+    (output_size + input_size) * DUST_RELAY_FEE_PER_VBYTE
+}
+
+/// Adds the witness spending the fund output through the redeem branch.
+pub fn complete_redeem_transaction(
+    transaction: Transaction,
+    from: (secp256k1::PublicKey, secp256k1::Signature),
+    to: (secp256k1::PublicKey, secp256k1::Signature),
+) -> anyhow::Result<Transaction> {
+    complete_spend_transaction(transaction, Branch::Redeem, from, to)
+}
+
+/// Adds the witness spending the fund output through the time-locked refund branch.
+pub fn complete_refund_transaction(
+    transaction: Transaction,
+    from: (secp256k1::PublicKey, secp256k1::Signature),
+    to: (secp256k1::PublicKey, secp256k1::Signature),
+) -> anyhow::Result<Transaction> {
+    complete_spend_transaction(transaction, Branch::Refund, from, to)
+}
+
+/// The two ways of spending the fund output.
+#[derive(Clone, Copy, Debug)]
+pub enum Branch {
+    Redeem,
+    Refund,
+}
+
+impl Branch {
+    /// The witness element making `OP_IF` take this branch.
+    pub fn selector(self) -> Vec<u8> {
+        match self {
+            Branch::Redeem => vec![1u8],
+            Branch::Refund => Vec::new(),
+        }
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("spend transaction does not carry the witness script of the fund output")]
+pub struct MissingWitnessScript;
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness script is not locked to the given keys")]
+pub struct KeysNotInWitnessScript;
+
 #[throws(anyhow::Error)]
-pub fn complete_spend_transaction(
+fn complete_spend_transaction(
     mut transaction: Transaction,
+    branch: Branch,
     (X_from, mut sig_from): (secp256k1::PublicKey, secp256k1::Signature),
     (X_to, mut sig_to): (secp256k1::PublicKey, secp256k1::Signature),
 ) -> Transaction {
     sig_from.normalize_s();
     sig_to.normalize_s();
 
-    let satisfier = {
-        let mut satisfier = HashMap::with_capacity(2);
-
-        let X_from = ::bitcoin::PublicKey::from_slice(&X_from.serialize_compressed())?;
-        let sig_from = ::bitcoin::secp256k1::Signature::from_compact(&sig_from.serialize())?;
+    let input = match transaction.input.as_mut_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        inputs => bail!(TooManyInputs(inputs.len())),
+    };
+    let witness_script = match input.witness.as_slice() {
+        [witness_script] => witness_script.clone(),
+        _ => bail!(MissingWitnessScript),
+    };
+    match (
+        find(&witness_script, &X_from.serialize_compressed()),
+        find(&witness_script, &X_to.serialize_compressed()),
+    ) {
+        (Some(from), Some(to)) if from < to => {}
+        _ => bail!(KeysNotInWitnessScript),
+    }
 
-        let X_to = ::bitcoin::PublicKey::from_slice(&X_to.serialize_compressed())?;
-        let sig_to = ::bitcoin::secp256k1::Signature::from_compact(&sig_to.serialize())?;
+    // `OP_IF` consumes the selector first, then `X_from`'s `OP_CHECKSIGVERIFY` its signature
+    input.witness = vec![
+        to_der_with_sighash_all(&sig_to),
+        to_der_with_sighash_all(&sig_from),
+        branch.selector(),
+        witness_script,
+    ];
 
-        satisfier.insert(X_from, (sig_from, ::bitcoin::SigHashType::All));
-        satisfier.insert(X_to, (sig_to, ::bitcoin::SigHashType::All));
+    transaction
+}
 
-        satisfier
-    };
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
 
-    descriptor(&X_from, &X_to).satisfy(&mut transaction.input[0], satisfier)?;
+pub(crate) fn to_der_with_sighash_all(signature: &secp256k1::Signature) -> Vec<u8> {
+    let mut bytes = signature.serialize_der().as_ref().to_vec();
+    bytes.push(SigHashType::All as u8);
 
-    transaction
+    bytes
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -159,8 +412,12 @@ pub struct TooManyInputs(usize);
 pub struct EmptyWitnessStack;
 
 #[derive(thiserror::Error, Debug)]
-#[error("input has {0} witnesses, expected 3")]
-pub struct NotThreeWitnesses(usize);
+#[error("input has {0} witnesses, expected 4")]
+pub struct NotFourWitnesses(usize);
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness element of the signature is empty")]
+pub struct EmptySignature;
 
 pub fn extract_signature_by_key(
     spend_transaction: Transaction,
@@ -180,12 +437,13 @@ pub fn extract_signature_by_key(
         .collect::<Vec<_>>()
         .as_slice()
     {
-        [_sig_to @ [..], sig_from @ [..], _script @ [..]] => {
-            secp256k1::Signature::parse_der(&sig_from[..sig_from.len() - 1])
-                .context("unknown witness layout")?
+        [_sig_to @ [..], sig_from @ [..], _selector @ [..], _script @ [..]] => {
+            let (_sighash_type, sig_from) = sig_from.split_last().ok_or(EmptySignature)?;
+
+            secp256k1::Signature::parse_der(sig_from).context("unknown witness layout")?
         }
         [] => bail!(EmptyWitnessStack),
-        [witnesses @ ..] => bail!(NotThreeWitnesses(witnesses.len())),
+        [witnesses @ ..] => bail!(NotFourWitnesses(witnesses.len())),
     };
 
     secp256k1::verify(digest, &sig_from, X_from)
@@ -194,40 +452,355 @@ pub fn extract_signature_by_key(
     Ok(sig_from)
 }
 
-pub fn tumbler_redeem_amount(tumble_amount: u64, tumbler_fee: u64, redeem_fee_per_wu: u64) -> u64 {
-    tumble_amount + tumbler_fee + MAX_SATISFACTION_WEIGHT * redeem_fee_per_wu
+#[derive(thiserror::Error, Debug)]
+#[error("spend transaction does not spend an output of the given fund transaction")]
+pub struct NotSpendingFundTransaction;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("spend transaction is not consensus-valid: {0}")]
+pub struct InvalidSpendTransaction(String);
+
+/// Runs the script interpreter of Bitcoin Core (libbitcoinconsensus) on the only input of
+/// `spend_transaction`, against the output of `fund_transaction` it spends. A Taproot joint output
+/// is checked by `taproot::verify_input` instead.
+///
+/// Only the script is checked. Whether the transaction is final at the current height, e.g. for a
+/// time-locked refund, is up to the chain.
+#[throws(anyhow::Error)]
+pub fn verify_spend_transaction(spend_transaction: &Transaction, fund_transaction: &Transaction) {
+    let input = match spend_transaction.input.as_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        inputs => bail!(TooManyInputs(inputs.len())),
+    };
+
+    if input.previous_output.txid != fund_transaction.txid() {
+        bail!(NotSpendingFundTransaction)
+    }
+    let fund_output = fund_transaction
+        .output
+        .get(input.previous_output.vout as usize)
+        .ok_or(NotSpendingFundTransaction)?;
+
+    verify_input(spend_transaction, 0, &[fund_output.clone()])?;
+}
+
+/// Runs the script interpreter of Bitcoin Core on input `index` of `transaction`. `spent_outputs`
+/// are the outputs spent by all inputs, in order.
+///
+/// The libbitcoinconsensus we link against predates Taproot, so the spend of a version 1 witness
+/// program is checked by `taproot::verify_input` instead.
+#[throws(InvalidSpendTransaction)]
+pub fn verify_input(transaction: &Transaction, index: usize, spent_outputs: &[TxOut]) {
+    let previous_output = spent_outputs
+        .get(index)
+        .ok_or_else(|| InvalidSpendTransaction(format!("no spent output for input {}", index)))?;
+
+    if taproot::is_v1_witness_program(&previous_output.script_pubkey) {
+        taproot::verify_input(transaction, index, spent_outputs)
+            .map_err(|e| InvalidSpendTransaction(e.to_string()))?;
+    } else {
+        previous_output
+            .script_pubkey
+            .verify(
+                index,
+                previous_output.value,
+                &bitcoin::consensus::encode::serialize(transaction),
+            )
+            .map_err(|e| InvalidSpendTransaction(format!("{:?}", e)))?;
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("expected the {expected} outputs spent by the fund transaction, got {actual}")]
+pub struct SpentOutputsMismatch {
+    expected: usize,
+    actual: usize,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("refund transaction does not spend the fund transaction")]
+pub struct RefundNotSpendingFundTransaction;
+
+/// Wraps an unsigned fund transaction in a PSBT (BIP174), so that any wallet or hardware signer can
+/// sign its inputs.
+///
+/// A P2WSH joint output is annotated with its witness script, which is taken from the signed
+/// refund transaction spending it. A Taproot joint output has no witness script. `spent_outputs`
+/// are the outputs the inputs of the fund transaction spend, in order. Each input is annotated with
+/// the output it spends, which signers need to sign segwit inputs and to check the fee.
+#[throws(anyhow::Error)]
+pub fn fund_psbt(
+    fund_transaction: &Transaction,
+    signed_refund_transaction: &Transaction,
+    spent_outputs: &[TxOut],
+) -> PartiallySignedTransaction {
+    if spent_outputs.len() != fund_transaction.input.len() {
+        bail!(SpentOutputsMismatch {
+            expected: fund_transaction.input.len(),
+            actual: spent_outputs.len(),
+        })
+    }
+
+    let refund_input = match signed_refund_transaction.input.as_slice() {
+        [input] if input.previous_output.txid == fund_transaction.txid() => input,
+        _ => bail!(RefundNotSpendingFundTransaction),
+    };
+    let joint_output = fund_transaction
+        .output
+        .get(refund_input.previous_output.vout as usize)
+        .ok_or(RefundNotSpendingFundTransaction)?;
+    let witness_script = if taproot::is_v1_witness_program(&joint_output.script_pubkey) {
+        None
+    } else {
+        Some(
+            refund_input
+                .witness
+                .last()
+                .cloned()
+                .ok_or(MissingWitnessScript)?,
+        )
+    };
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(fund_transaction.clone())
+        .context("fund transaction is already signed")?;
+
+    for (input, spent_output) in psbt.inputs.iter_mut().zip(spent_outputs) {
+        input.witness_utxo = Some(spent_output.clone());
+    }
+    psbt.outputs
+        .get_mut(refund_input.previous_output.vout as usize)
+        .ok_or(RefundNotSpendingFundTransaction)?
+        .witness_script = witness_script.map(Script::from);
+
+    psbt
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("input {0} of the fund transaction is not finalized")]
+pub struct InputNotFinalized(usize);
+
+#[derive(thiserror::Error, Debug)]
+#[error("signed fund transaction has txid {actual}, expected {expected}")]
+pub struct FundTxidMismatch {
+    expected: Txid,
+    actual: Txid,
+}
+
+/// Extracts the signed fund transaction from a finalized PSBT.
+///
+/// The redeem and refund transactions spend the joint output by txid, so the signed transaction
+/// must have exactly the txid they were built against. This is the case as long as the signer
+/// did not change the transaction and all inputs are segwit.
+#[throws(anyhow::Error)]
+pub fn extract_fund_transaction(
+    psbt: PartiallySignedTransaction,
+    expected_txid: Txid,
+) -> Transaction {
+    if let Some(index) = psbt
+        .inputs
+        .iter()
+        .position(|input| input.final_script_sig.is_none() && input.final_script_witness.is_none())
+    {
+        bail!(InputNotFinalized(index))
+    }
+
+    let transaction = psbt.extract_tx();
+    if transaction.txid() != expected_txid {
+        bail!(FundTxidMismatch {
+            expected: expected_txid,
+            actual: transaction.txid(),
+        })
+    }
+
+    transaction
+}
+
+pub fn psbt_to_base64(psbt: &PartiallySignedTransaction) -> String {
+    base64::encode(bitcoin::consensus::encode::serialize(psbt))
+}
+
+pub fn psbt_from_base64(psbt: &str) -> anyhow::Result<PartiallySignedTransaction> {
+    let bytes = base64::decode(psbt.trim()).context("psbt is not valid base64")?;
+
+    Ok(bitcoin::consensus::encode::deserialize(&bytes).context("invalid psbt")?)
 }
 
-pub fn receiver_redeem_amount(tumble_amount: u64, redeem_fee_per_wu: u64) -> u64 {
-    tumble_amount + MAX_SATISFACTION_WEIGHT * redeem_fee_per_wu
+pub fn to_hex(transaction: &Transaction) -> String {
+    bitcoin::consensus::encode::serialize_hex(transaction)
+}
+
+pub fn from_hex(hex: &str) -> anyhow::Result<Transaction> {
+    let bytes = hex::decode(hex.trim()).context("transaction is not valid hex")?;
+
+    Ok(bitcoin::consensus::encode::deserialize(&bytes).context("invalid transaction")?)
 }
 
 fn descriptor(
     X_from: &secp256k1::PublicKey,
     X_to: &secp256k1::PublicKey,
+    refund_locktime: u32,
 ) -> miniscript::Descriptor<bitcoin::PublicKey> {
     let X_from = hex::encode(X_from.serialize_compressed().to_vec());
     let X_to = hex::encode(X_to.serialize_compressed().to_vec());
 
     let miniscript = MINISCRIPT_TEMPLATE
         .replace("X_from", &X_from)
-        .replace("X_to", &X_to);
+        .replace("X_to", &X_to)
+        .replace("LOCKTIME", &refund_locktime.to_string());
 
     let miniscript = miniscript::Miniscript::<bitcoin::PublicKey>::from_str(&miniscript)
         .expect("a valid miniscript");
 
-    println!("{}", miniscript);
-
     miniscript::Descriptor::Wsh(miniscript)
 }
 
-fn make_spend_output(amount: u64, X_to: &bitcoin::Address) -> TxOut {
+/// A witness script of the fund output with the given refund locktime, locked to placeholder keys.
+///
+/// All keys are serialized compressed, so its length is that of every fund output with the same
+/// refund locktime.
+pub fn placeholder_witness_script(refund_locktime: u32) -> Script {
+    let placeholder_key = |byte| {
+        secp256k1::PublicKey::from_secret_key(
+            &secp256k1::SecretKey::parse(&[byte; 32]).expect("a valid secret key"),
+        )
+    };
+
+    descriptor(&placeholder_key(1), &placeholder_key(2), refund_locktime).witness_script()
+}
+
+pub(crate) fn make_spend_output(amount: u64, X_to: &bitcoin::Address) -> TxOut {
     TxOut {
         value: amount,
         script_pubkey: X_to.script_pubkey(),
     }
 }
 
+/// An output of `ANCHOR_VALUE` that anyone can spend, through P2WSH of `OP_TRUE`.
+///
+/// No key is needed to spend it, so the anchor, and a child spending it, cannot be tied to the
+/// keys of the joint output.
+pub fn anchor_output() -> TxOut {
+    TxOut {
+        value: ANCHOR_VALUE,
+        script_pubkey: Address::p2wsh(&anchor_witness_script(), bitcoin::Network::Bitcoin)
+            .script_pubkey(),
+    }
+}
+
+/// The witness spending an [`anchor_output`].
+pub fn anchor_witness() -> Vec<Vec<u8>> {
+    vec![anchor_witness_script().into_bytes()]
+}
+
+fn anchor_witness_script() -> Script {
+    Builder::new().push_opcode(OP_PUSHNUM_1).into_script()
+}
+
+#[cfg(test)]
+pub(crate) fn to_bitcoin_public_key(X: &secp256k1::PublicKey) -> bitcoin::PublicKey {
+    bitcoin::PublicKey::from_slice(&X.serialize_compressed()).expect("a valid public key")
+}
+
+impl wire::Encode for Transaction {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        wire::write_var_bytes(buffer, &bitcoin::consensus::encode::serialize(self));
+    }
+}
+
+impl wire::Decode for Transaction {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+
+        bitcoin::consensus::encode::deserialize(bytes)
+            .map_err(|_| wire::DecodeError::InvalidTransaction)
+    }
+}
+
+impl wire::Encode for TxOut {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        wire::write_var_bytes(buffer, &bitcoin::consensus::encode::serialize(self));
+    }
+}
+
+impl wire::Decode for TxOut {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+
+        bitcoin::consensus::encode::deserialize(bytes).map_err(|_| wire::DecodeError::InvalidOutput)
+    }
+}
+
+impl wire::Encode for Address {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        wire::write_var_bytes(buffer, self.to_string().as_bytes());
+    }
+}
+
+impl wire::Decode for Address {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+
+        std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|address| Address::from_str(address).ok())
+            .ok_or(wire::DecodeError::InvalidAddress)
+    }
+}
+
+impl wire::Encode for SigHash {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.into_inner());
+    }
+}
+
+impl wire::Decode for SigHash {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(SigHash::from_inner(reader.read_array_32()?))
+    }
+}
+
+impl wire::Encode for Transactions {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.fund.encode(buffer);
+        self.redeem.encode(buffer);
+        self.redeem_tx_digest.encode(buffer);
+        self.refund.encode(buffer);
+        self.refund_tx_digest.encode(buffer);
+    }
+}
+
+impl wire::Decode for Transactions {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Transactions {
+            fund: Transaction::decode(reader)?,
+            redeem: Transaction::decode(reader)?,
+            redeem_tx_digest: SigHash::decode(reader)?,
+            refund: Transaction::decode(reader)?,
+            refund_tx_digest: SigHash::decode(reader)?,
+        })
+    }
+}
+
+impl wire::Encode for OutputType {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            OutputType::P2wsh => buffer.push(0),
+            OutputType::P2tr => buffer.push(1),
+        }
+    }
+}
+
+impl wire::Decode for OutputType {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        match reader.read_u8()? {
+            0 => Ok(OutputType::P2wsh),
+            1 => Ok(OutputType::P2tr),
+            variant => Err(wire::DecodeError::UnknownVariant(variant)),
+        }
+    }
+}
+
 impl ToMessage for SigHash {
     fn to_message(&self) -> [u8; 32] {
         self.into_inner()
@@ -237,6 +810,7 @@ impl ToMessage for SigHash {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_chain::{MockChain, Rejected};
     use rand::thread_rng;
 
     #[test]
@@ -255,19 +829,318 @@ mod tests {
         println!("{}", descriptor);
     }
 
-    #[test]
-    fn max_satisfaction_weight() {
-        let descriptor = descriptor(
-            &secp256k1::PublicKey::from_secret_key(
-                &secp256k1::SecretKey::random(&mut thread_rng()),
+    const EXPIRY: u32 = 500;
+
+    /// The fund transaction and the signed redeem and refund transactions of a joint output locked
+    /// to `x_from` and `x_to`.
+    fn signed_transactions(
+        x_from: &secp256k1::KeyPair,
+        x_to: &secp256k1::KeyPair,
+    ) -> (Transaction, Transaction, Transaction) {
+        let address = Address::p2wpkh(
+            &::bitcoin::PublicKey::from_slice(&x_to.to_pk().serialize_compressed()).unwrap(),
+            ::bitcoin::Network::Regtest,
+        );
+
+        let transactions = make_transactions(
+            partial_fund_transaction().0,
+            &x_from.to_pk(),
+            &x_to.to_pk(),
+            &JointOutput {
+                output_type: OutputType::P2wsh,
+                value: 10_000,
+                spend_value: 9_000,
+                refund_locktime: EXPIRY,
+                redeem_identity: address.clone(),
+                refund_identity: address,
+                anchor_outputs: false,
+            },
+        );
+
+        let redeem = complete_redeem_transaction(
+            transactions.redeem,
+            (
+                x_from.to_pk(),
+                secp256k1::sign(transactions.redeem_tx_digest, x_from),
+            ),
+            (
+                x_to.to_pk(),
+                secp256k1::sign(transactions.redeem_tx_digest, x_to),
             ),
-            &secp256k1::PublicKey::from_secret_key(
-                &secp256k1::SecretKey::random(&mut thread_rng()),
+        )
+        .unwrap();
+        let refund = complete_refund_transaction(
+            transactions.refund,
+            (
+                x_from.to_pk(),
+                secp256k1::sign(transactions.refund_tx_digest, x_from),
             ),
+            (
+                x_to.to_pk(),
+                secp256k1::sign(transactions.refund_tx_digest, x_to),
+            ),
+        )
+        .unwrap();
+
+        (transactions.fund, redeem, refund)
+    }
+
+    fn random_keys() -> (secp256k1::KeyPair, secp256k1::KeyPair) {
+        (
+            secp256k1::KeyPair::random(&mut thread_rng()),
+            secp256k1::KeyPair::random(&mut thread_rng()),
+        )
+    }
+
+    #[test]
+    fn refund_is_invalid_before_expiry() {
+        let (x_from, x_to) = random_keys();
+        let (fund, _, refund) = signed_transactions(&x_from, &x_to);
+        let mut chain = MockChain::new();
+        chain.mine_external(fund);
+
+        assert_eq!(
+            chain.broadcast(refund.clone()),
+            Err(Rejected::NonFinal(EXPIRY))
+        );
+
+        chain.mine_until(EXPIRY - 1);
+        assert_eq!(
+            chain.broadcast(refund.clone()),
+            Err(Rejected::NonFinal(EXPIRY))
+        );
+
+        chain.mine_until(EXPIRY);
+        chain.broadcast(refund).unwrap();
+    }
+
+    #[test]
+    fn redeem_is_valid_right_away() {
+        let (x_from, x_to) = random_keys();
+        let (fund, redeem, _) = signed_transactions(&x_from, &x_to);
+        let mut chain = MockChain::new();
+        chain.mine_external(fund);
+
+        chain.broadcast(redeem).unwrap();
+    }
+
+    #[test]
+    fn refund_spends_the_time_locked_branch() {
+        let (x_from, x_to) = random_keys();
+        let (fund, redeem, refund) = signed_transactions(&x_from, &x_to);
+        let joint_output = &fund.output[JOINT_OUTPUT_INDEX as usize];
+
+        assert!(refund.input[0].witness[2].is_empty());
+        verify_input(&refund, 0, &[joint_output.clone()]).unwrap();
+        assert_eq!(redeem.input[0].witness[2], vec![1u8]);
+        verify_input(&redeem, 0, &[joint_output.clone()]).unwrap();
+
+        // even signed by both parties, a refund with an earlier locktime fails the script
+        let mut early_refund = Transaction {
+            lock_time: EXPIRY - 1,
+            ..refund
+        };
+        let witness_script = Script::from(early_refund.input[0].witness.pop().unwrap());
+        early_refund.input[0].witness = vec![witness_script.to_bytes()];
+        let digest = SighashComponents::new(&early_refund).sighash_all(
+            &early_refund.input[0],
+            &witness_script,
+            joint_output.value,
+        );
+        let early_refund = complete_refund_transaction(
+            early_refund,
+            (x_from.to_pk(), secp256k1::sign(digest, &x_from)),
+            (x_to.to_pk(), secp256k1::sign(digest, &x_to)),
+        )
+        .unwrap();
+
+        assert!(verify_input(&early_refund, 0, &[joint_output.clone()]).is_err());
+    }
+
+    #[test]
+    fn anchor_output_is_not_derived_from_the_fund_keys() {
+        let (x_from, x_to) = random_keys();
+        let address = Address::p2wpkh(
+            &to_bitcoin_public_key(&x_to.to_pk()),
+            ::bitcoin::Network::Regtest,
+        );
+        // whatever of a key can end up in an output script: the key, its x coordinate and hash
+        let derived = [&x_from, &x_to]
+            .iter()
+            .flat_map(|x| {
+                let X = x.to_pk().serialize_compressed();
+                let hash = bitcoin::hashes::hash160::Hash::hash(&X);
+
+                vec![X.to_vec(), X[1..].to_vec(), hash.into_inner().to_vec()]
+            })
+            .collect::<Vec<_>>();
+
+        for output_type in &[OutputType::P2wsh, OutputType::P2tr] {
+            let transactions = make_transactions(
+                partial_fund_transaction().0,
+                &x_from.to_pk(),
+                &x_to.to_pk(),
+                &JointOutput {
+                    output_type: *output_type,
+                    value: 10_000,
+                    spend_value: 9_000,
+                    refund_locktime: EXPIRY,
+                    redeem_identity: address.clone(),
+                    refund_identity: address.clone(),
+                    anchor_outputs: true,
+                },
+            );
+
+            for spend in &[transactions.redeem, transactions.refund] {
+                let anchor = &spend.output[1].script_pubkey;
+
+                assert_eq!(spend.output[1], anchor_output());
+                for bytes in derived.iter() {
+                    assert!(!anchor
+                        .as_bytes()
+                        .windows(bytes.len())
+                        .any(|window| window == bytes.as_slice()));
+                }
+            }
+        }
+    }
+
+    fn p2wpkh_output(value: u64) -> TxOut {
+        let x = secp256k1::KeyPair::random(&mut thread_rng());
+        let address = Address::p2wpkh(
+            &::bitcoin::PublicKey::from_slice(&x.to_pk().serialize_compressed()).unwrap(),
+            ::bitcoin::Network::Regtest,
+        );
+
+        TxOut {
+            value,
+            script_pubkey: address.script_pubkey(),
+        }
+    }
+
+    /// A partial fund transaction spending one coin of 1 BTC, with a change output of 0.1 BTC.
+    fn partial_fund_transaction() -> (Transaction, Vec<TxOut>) {
+        let transaction = Transaction {
+            lock_time: 0,
+            version: 2,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Default::default(),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+                witness: Vec::new(),
+            }],
+            output: vec![p2wpkh_output(10_000_000)],
+        };
+
+        (transaction, vec![p2wpkh_output(100_000_000)])
+    }
+
+    #[test]
+    fn accepts_funded_partial_fund_transaction() {
+        let (transaction, spent_outputs) = partial_fund_transaction();
+
+        validate_partial_fund_transaction(&transaction, &spent_outputs, 80_000_000).unwrap();
+    }
+
+    #[test]
+    fn rejects_partial_fund_transaction_without_inputs() {
+        let (mut transaction, _) = partial_fund_transaction();
+        transaction.input.clear();
+
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &[], 0),
+            Err(InvalidPartialFundTransaction::NoInputs)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_spent_outputs() {
+        let (transaction, _) = partial_fund_transaction();
+
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &[], 0),
+            Err(InvalidPartialFundTransaction::SpentOutputsMismatch {
+                expected: 1,
+                actual: 0
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_inputs_not_spending_p2wpkh() {
+        let (mut transaction, mut spent_outputs) = partial_fund_transaction();
+        let p2pkh = Address::p2pkh(
+            &::bitcoin::PublicKey::from_slice(
+                &secp256k1::KeyPair::random(&mut thread_rng())
+                    .to_pk()
+                    .serialize_compressed(),
+            )
+            .unwrap(),
+            ::bitcoin::Network::Regtest,
+        );
+
+        spent_outputs[0].script_pubkey = p2pkh.script_pubkey();
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &spent_outputs, 0),
+            Err(InvalidPartialFundTransaction::NonP2wpkhInput(0))
+        );
+
+        // the witness of a P2WSH output is not known until it is signed
+        spent_outputs[0].script_pubkey = Script::new().to_v0_p2wsh();
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &spent_outputs, 0),
+            Err(InvalidPartialFundTransaction::NonP2wpkhInput(0))
         );
 
-        let max_weight = descriptor.max_satisfaction_weight() as u64;
+        // a segwit output spent with a script_sig, e.g. nested in P2SH, is malleable all the same
+        let (_, spent_outputs) = partial_fund_transaction();
+        transaction.input[0].script_sig = Script::from(vec![0x00]);
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &spent_outputs, 0),
+            Err(InvalidPartialFundTransaction::NonP2wpkhInput(0))
+        );
+    }
+
+    #[test]
+    fn rejects_dust_change() {
+        let (mut transaction, spent_outputs) = partial_fund_transaction();
 
-        assert_eq!(max_weight, MAX_SATISFACTION_WEIGHT)
+        transaction.output[0].value = 294;
+        validate_partial_fund_transaction(&transaction, &spent_outputs, 0).unwrap();
+
+        transaction.output[0].value = 293;
+        assert_eq!(
+            validate_partial_fund_transaction(&transaction, &spent_outputs, 0),
+            Err(InvalidPartialFundTransaction::DustOutput {
+                index: 0,
+                value: 293
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_partial_fund_transaction_not_covering_joint_output_and_fee() {
+        let (transaction, spent_outputs) = partial_fund_transaction();
+        // version, counts and locktime, one input, the change and the joint output, plus the segwit
+        // marker and the witness of the P2WPKH input with a signature of maximum size, at a quarter
+        // rounded up
+        let min_relay_fee = 10 + 41 + 31 + 43 + (2 + 1 + 74 + 34 + 3) / 4;
+
+        validate_partial_fund_transaction(&transaction, &spent_outputs, 90_000_000 - min_relay_fee)
+            .unwrap();
+        assert_eq!(
+            validate_partial_fund_transaction(
+                &transaction,
+                &spent_outputs,
+                90_000_000 - min_relay_fee + 1
+            ),
+            Err(InvalidPartialFundTransaction::InsufficientFunds {
+                available: 100_000_000,
+                required: 100_000_001,
+            })
+        );
     }
 }
@@ -0,0 +1,363 @@
+//! Clients for the sender and the receiver which run their side of the protocols against a remote
+//! [`TumblerService`](crate::tumbler_service::TumblerService).
+//!
+//! Requests that fail in transit are retried. The tumbler answers a retransmitted request with the
+//! response it already sent, so retrying never advances a session twice.
+//!
+//! The tumbler completes the params of every session with its own part. The clients check that it
+//! left theirs alone and that the terms are the ones they expect.
+
+use crate::bitcoin::Address;
+use crate::joint_output::JointOutputScheme;
+use crate::transport::Transport;
+use crate::tumbler_service::{Request, Response, SenderContribution, SessionId};
+use crate::wire::{self, WireMessage};
+use crate::{hsm_cl, puzzle_promise, puzzle_solver, secp256k1, Lock, Params, Protocol, Terms};
+use anyhow::{bail, Context};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How often a request is sent before giving up, including the first attempt.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The delay doubles after every attempt.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("tumbler rejected request: {0}")]
+pub struct Rejected(pub String);
+
+#[derive(thiserror::Error, Debug)]
+#[error("unexpected response from tumbler, expected {0}")]
+pub struct UnexpectedResponse(&'static str);
+
+#[derive(thiserror::Error, Debug)]
+#[error("tumbler did not respond after {0} attempts")]
+pub struct RetriesExhausted(u32);
+
+#[derive(thiserror::Error, Debug)]
+#[error("tumbler completed the params with unexpected {0}")]
+pub struct UnexpectedParams(&'static str);
+
+/// Checks the terms the tumbler chose for a session of `protocol` against the `expected` ones.
+///
+/// The expiry depends on when the session is started, so `expected.expiry` is a bound: the
+/// receiver needs at least until then to redeem, and the sender wants its coins back by then at
+/// the latest.
+fn check_terms(protocol: Protocol, offered: &Terms, expected: &Terms) -> anyhow::Result<()> {
+    let expiry_acceptable = match protocol {
+        Protocol::PuzzlePromise => offered.expiry >= expected.expiry,
+        Protocol::PuzzleSolver => offered.expiry <= expected.expiry,
+    };
+    if !expiry_acceptable {
+        bail!(UnexpectedParams("expiry"))
+    }
+
+    let expected = Terms {
+        expiry: offered.expiry,
+        ..*expected
+    };
+    if *offered != expected {
+        bail!(UnexpectedParams("terms"))
+    }
+
+    Ok(())
+}
+
+/// Checks that the tumbler left the sender's part of the params alone.
+fn check_contribution(params: &Params, contribution: &SenderContribution) -> anyhow::Result<()> {
+    if params.refund_identity != contribution.refund_identity {
+        bail!(UnexpectedParams("refund identity"))
+    }
+    if params.partial_fund_transaction != contribution.partial_fund_transaction
+        || params.partial_fund_spent_outputs != contribution.partial_fund_spent_outputs
+    {
+        bail!(UnexpectedParams("partial fund transaction"))
+    }
+
+    Ok(())
+}
+
+struct Connection<T> {
+    transport: T,
+    retry_policy: RetryPolicy,
+}
+
+impl<T: Transport> Connection<T> {
+    fn request<C, P, S>(&self, request: &Request<C, S>) -> anyhow::Result<Response<C, P, S>>
+    where
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let request = request.to_bytes();
+        let mut backoff = self.retry_policy.initial_backoff;
+        let mut last_error = None;
+
+        for attempt in 1..=self.retry_policy.max_attempts {
+            if attempt > 1 {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+
+            let response = match self.transport.send(&request) {
+                Ok(response) => response,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            match Response::from_bytes(&response).context("failed to decode tumbler response")? {
+                Response::Busy => continue,
+                Response::Rejected(reason) => bail!(Rejected(reason)),
+                response => return Ok(response),
+            }
+        }
+
+        let exhausted = RetriesExhausted(self.retry_policy.max_attempts);
+        match last_error {
+            Some(e) => Err(e.context(exhausted)),
+            None => bail!(exhausted),
+        }
+    }
+}
+
+/// Runs the puzzle promise protocol with the tumbler on behalf of the receiver.
+pub struct ReceiverClient<T, HE> {
+    connection: Connection<T>,
+    HE: HE,
+}
+
+impl<T: Transport, HE> ReceiverClient<T, HE> {
+    pub fn new(transport: T, HE: HE) -> Self {
+        Self {
+            connection: Connection {
+                transport,
+                retry_policy: RetryPolicy::default(),
+            },
+            HE,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.connection.retry_policy = retry_policy;
+
+        self
+    }
+
+    /// Obtains a puzzle from the tumbler, to be paid to `redeem_identity` on the `expected` terms.
+    ///
+    /// The returned state's `next_message` is the [`Lock`] to be handed to the sender.
+    pub fn request_promise<C, P, S>(
+        &self,
+        redeem_identity: Address,
+        expected: &Terms,
+    ) -> anyhow::Result<puzzle_promise::Receiver2<C, S>>
+    where
+        HE: hsm_cl::Verify<C, P> + hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let session = SessionId::random(&mut rand::thread_rng());
+
+        let receiver = self.start_promise::<C, P, S>(session, redeem_identity, expected)?;
+        self.finish_promise::<C, P, S>(session, receiver)
+    }
+
+    pub fn start_promise<C, P, S>(
+        &self,
+        session: SessionId,
+        redeem_identity: Address,
+        expected: &Terms,
+    ) -> anyhow::Result<puzzle_promise::Receiver1<C, S>>
+    where
+        HE: hsm_cl::Verify<C, P>,
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let (params, message) =
+            match self.connection.request::<C, P, S>(&Request::StartPromise {
+                session,
+                redeem_identity: redeem_identity.clone(),
+            })? {
+                Response::PromiseMessage0(params, message) => (params, message),
+                _ => bail!(UnexpectedResponse("puzzle promise message 0")),
+            };
+
+        if params.redeem_identity != redeem_identity {
+            bail!(UnexpectedParams("redeem identity"))
+        }
+        check_terms(Protocol::PuzzlePromise, params.terms(), expected)?;
+
+        let mut rng = rand::thread_rng();
+        puzzle_promise::Receiver0::new(params, &mut rng).receive(message, &mut rng, &self.HE)
+    }
+
+    pub fn finish_promise<C, P, S>(
+        &self,
+        session: SessionId,
+        receiver: puzzle_promise::Receiver1<C, S>,
+    ) -> anyhow::Result<puzzle_promise::Receiver2<C, S>>
+    where
+        HE: hsm_cl::Verify<C, P> + hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let message = match self
+            .connection
+            .request::<C, P, S>(&Request::PromiseMessage1 {
+                session,
+                message: receiver.next_message(),
+            })? {
+            Response::PromiseMessage2(message) => message,
+            _ => bail!(UnexpectedResponse("puzzle promise message 2")),
+        };
+
+        receiver.receive(message, &mut rand::thread_rng(), &self.HE)
+    }
+}
+
+/// Runs the puzzle solver protocol with the tumbler on behalf of the sender.
+pub struct SenderClient<T, HE> {
+    connection: Connection<T>,
+    HE: HE,
+}
+
+impl<T: Transport, HE> SenderClient<T, HE> {
+    pub fn new(transport: T, HE: HE) -> Self {
+        Self {
+            connection: Connection {
+                transport,
+                retry_policy: RetryPolicy::default(),
+            },
+            HE,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.connection.retry_policy = retry_policy;
+
+        self
+    }
+
+    /// Pays the tumbler to solve the puzzle in `lock`, funding the joint output as described by
+    /// `contribution` on the `expected` terms.
+    ///
+    /// Once the tumbler publishes the redeem transaction, the returned state extracts the
+    /// solution from it.
+    pub fn solve<C, P, S>(
+        &self,
+        contribution: SenderContribution,
+        expected: &Terms,
+        lock: Lock<C>,
+    ) -> anyhow::Result<puzzle_solver::Sender2<S>>
+    where
+        // `Verify` is only required to determine the proof type of the tumbler's responses.
+        HE: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Verify<C, P>,
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let session = SessionId::random(&mut rand::thread_rng());
+
+        let sender = self.start_solver::<C, P, S>(session, contribution, expected, lock)?;
+        let sender = self.sign_solver::<C, P, S>(session, sender)?;
+        self.finish_solver::<C, P, S>(session, &sender)?;
+
+        Ok(sender)
+    }
+
+    pub fn start_solver<C, P, S>(
+        &self,
+        session: SessionId,
+        contribution: SenderContribution,
+        expected: &Terms,
+        lock: Lock<C>,
+    ) -> anyhow::Result<puzzle_solver::Sender1<C, S>>
+    where
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let (params, message) = match self.connection.request::<C, P, S>(&Request::StartSolver {
+            session,
+            sender: contribution.clone(),
+        })? {
+            Response::SolverMessage0(params, message) => (params, message),
+            _ => bail!(UnexpectedResponse("puzzle solver message 0")),
+        };
+
+        check_contribution(&params, &contribution)?;
+        check_terms(Protocol::PuzzleSolver, params.terms(), expected)?;
+
+        let mut rng = rand::thread_rng();
+        puzzle_solver::Sender0::new(params, lock, &mut rng).receive(message, &mut rng)
+    }
+
+    /// Exchanges the sender's first message for the tumbler's answer and signs the redeem
+    /// transaction with it.
+    ///
+    /// The returned state has to be persisted before [`SenderClient::finish_solver`] hands the
+    /// signature to the tumbler: a sender restored from before this step refuses to sign again.
+    pub fn sign_solver<C, P, S>(
+        &self,
+        session: SessionId,
+        sender: puzzle_solver::Sender1<C, S>,
+    ) -> anyhow::Result<puzzle_solver::Sender2<S>>
+    where
+        HE: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey>,
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        let message = match self
+            .connection
+            .request::<C, P, S>(&Request::SolverMessage1 {
+                session,
+                message: sender.next_message(&self.HE),
+            })? {
+            Response::SolverMessage2(message) => message,
+            _ => bail!(UnexpectedResponse("puzzle solver message 2")),
+        };
+
+        sender.receive(message, &mut rand::thread_rng(), &self.HE)
+    }
+
+    /// Hands the sender's encrypted redeem signature to the tumbler, after which the sender can
+    /// fund the joint output.
+    pub fn finish_solver<C, P, S>(
+        &self,
+        session: SessionId,
+        sender: &puzzle_solver::Sender2<S>,
+    ) -> anyhow::Result<()>
+    where
+        C: wire::Encode + wire::Decode,
+        P: wire::Encode + wire::Decode,
+        S: JointOutputScheme,
+    {
+        match self
+            .connection
+            .request::<C, P, S>(&Request::SolverMessage3 {
+                session,
+                message: sender.next_message(),
+            })? {
+            Response::SolverDone => Ok(()),
+            _ => bail!(UnexpectedResponse("puzzle solver completion")),
+        }
+    }
+}
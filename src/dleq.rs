@@ -1,19 +1,41 @@
 use crate::secp256k1;
-use sha2::Digest;
-use sha2::Sha256;
-use std::convert::TryInto;
+use crate::secp256k1::{group, schnorr};
+use crate::transcript::Transcript;
+use crate::wire;
 
+/// A proof in commitment form, so that the challenge is recomputed by the verifier and many proofs
+/// can be checked at once with [`verify_batch`].
 #[derive(Debug, Clone)]
 pub struct Proof {
+    Gr: secp256k1::PublicKey,
+    Hr: secp256k1::PublicKey,
     s: secp256k1::Scalar,
-    c: secp256k1::Scalar,
 }
 
-#[derive(Debug, thiserror::Error)]
+impl wire::Encode for Proof {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.Gr.encode(buffer);
+        self.Hr.encode(buffer);
+        self.s.encode(buffer);
+    }
+}
+
+impl wire::Decode for Proof {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Proof {
+            Gr: secp256k1::PublicKey::decode(reader)?,
+            Hr: secp256k1::PublicKey::decode(reader)?,
+            s: secp256k1::Scalar::decode(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
 #[error("discrete-log not equal")]
 pub struct DiscreteLogNotEqual;
 
 pub fn prove<R: rand::Rng>(
+    transcript: &mut Transcript,
     rng: &mut R,
     G: &secp256k1::PublicKey,
     Gx: &secp256k1::PublicKey,
@@ -21,102 +43,223 @@ pub fn prove<R: rand::Rng>(
     Hx: &secp256k1::PublicKey,
     x: secp256k1::Scalar,
 ) -> Proof {
-    // NOTE: using rng for PoC and even early stage production but there
-    // are more robust ways of doing this which include hashing secret
-    // information along with randomness (see https://github.com/bitcoin/bips/pull/893/).
-    let r = secp256k1::KeyPair::random(rng);
-    let r = r.as_ref();
+    let mut aux = [0u8; 32];
+    rng.fill_bytes(&mut aux);
+
+    prove_with_aux(transcript, G, Gx, H, Hx, x, &aux)
+}
+
+/// Proves that `Gx` and `Hx` have the same discrete log `x` to the bases `G` and `H`, using `aux`
+/// as auxiliary randomness.
+///
+/// The proof is bound to everything absorbed into `transcript` beforehand, and only verifies
+/// against a transcript with the same contents. The nonce is derived from `x`, the transcript and
+/// `aux` like a BIP340 nonce, so that a weak or repeated `aux` cannot leak `x` through `s`.
+pub fn prove_with_aux(
+    transcript: &mut Transcript,
+    G: &secp256k1::PublicKey,
+    Gx: &secp256k1::PublicKey,
+    H: &secp256k1::PublicKey,
+    Hx: &secp256k1::PublicKey,
+    x: secp256k1::Scalar,
+    aux: &[u8; 32],
+) -> Proof {
+    append_statement(transcript, G, Gx, H, Hx);
+
+    let r = {
+        let aux_hash = schnorr::tagged_hash("DLEQ/aux", &[aux]);
+        let mut t = x.b32();
+        for (t, aux_hash) in t.iter_mut().zip(aux_hash.iter()) {
+            *t ^= aux_hash;
+        }
+
+        let r = schnorr::scalar_from_hash(schnorr::tagged_hash(
+            "DLEQ/nonce",
+            &[&t, &transcript.challenge_bytes("nonce")],
+        ));
+        assert!(!r.is_zero(), "nonce is zero with negligible probability");
+
+        r
+    };
 
     // Gr
-    let mut Gr = secp256k1::G.clone();
-    Gr.tweak_mul_assign(r).unwrap();
+    let Gr = group::mul(G, &r).expect("G and H are not the point at infinity");
 
     // Hr
-    let mut Hr = H.clone();
-    Hr.tweak_mul_assign(r).unwrap();
+    let Hr = group::mul(H, &r).expect("G and H are not the point at infinity");
 
-    // c = H(G | Gx | H | Hx | Gr | Hr)
-    let mut hasher = Sha256::default();
-    hasher.input(&G.serialize_compressed() as &[u8]);
-    hasher.input(&Gx.serialize_compressed() as &[u8]);
-    hasher.input(&H.serialize_compressed() as &[u8]);
-    hasher.input(&Hx.serialize_compressed() as &[u8]);
-    hasher.input(&Gr.serialize_compressed() as &[u8]);
-    hasher.input(&Hr.serialize_compressed() as &[u8]);
+    let c = challenge(transcript, &Gr, &Hr);
 
-    let r: secp256k1::Scalar = r.clone().into();
-
-    let c: secp256k1::Scalar = secp256k1::SecretKey::parse_slice(&hasher.result()[..])
-        .unwrap()
-        .into();
-    let s = r + c.clone() * x;
+    let s = r + c * x;
 
-    Proof { s, c }
+    Proof { Gr, Hr, s }
 }
 
 pub fn verify(
+    transcript: &mut Transcript,
     G: &secp256k1::PublicKey,
     Gx: &secp256k1::PublicKey,
     H: &secp256k1::PublicKey,
     Hx: &secp256k1::PublicKey,
-    proof: &Proof, // (s = r + cx, c)
+    Proof { Gr, Hr, s }: &Proof, // s = r + cx
 ) -> Result<(), DiscreteLogNotEqual> {
-    let c_neg = -proof.c.clone();
+    append_statement(transcript, G, Gx, H, Hx);
 
-    // Gr = Gs + (Gx * -c) = Gr + Gcx - Gcx
-    let Gr = {
-        let mut Gxc_neg = Gx.clone();
-        // TODO: Don't panic on things controlled by adversary
-        Gxc_neg
-            .tweak_mul_assign(&c_neg.clone().try_into().unwrap())
-            .unwrap();
+    let c = challenge(transcript, Gr, Hr);
+    let c_neg = -c;
 
-        let mut Gs = G.clone();
-        Gs.tweak_mul_assign(&proof.s.clone().try_into().unwrap())
-            .unwrap();
-        secp256k1::PublicKey::combine(&[Gxc_neg, Gs]).unwrap()
-    };
+    // every part of the proof and of the statement can be chosen by an adversary, so the
+    // candidates may be the point at infinity, which never equals a commitment
 
-    // Hr = Hs + (Hx * -c) = Hr + Hcx - Hcx
-    let Hr = {
-        let mut Hxc_neg = Hx.clone();
-        Hxc_neg
-            .tweak_mul_assign(&c_neg.try_into().unwrap())
-            .unwrap();
+    let zero = secp256k1::Scalar::from_int(0);
 
-        let mut Hs = H.clone();
-        Hs.tweak_mul_assign(&proof.s.clone().try_into().unwrap())
-            .unwrap();
-        secp256k1::PublicKey::combine(&[Hxc_neg, Hs]).unwrap()
-    };
+    // Gr = Gs + (Gx * -c) = Gr + Gcx - Gcx
+    let Gr_candidate = group::multi_mul(
+        &[(s.clone(), G.clone()), (c_neg.clone(), Gx.clone())],
+        &zero,
+    );
 
-    // c = H(G | Gx | H | Hx | Gr | Hr)
-    let mut hasher = Sha256::default();
-    hasher.input(&G.serialize_compressed() as &[u8]);
-    hasher.input(&Gx.serialize_compressed() as &[u8]);
-    hasher.input(&H.serialize_compressed() as &[u8]);
-    hasher.input(&Hx.serialize_compressed() as &[u8]);
-    hasher.input(&Gr.serialize_compressed() as &[u8]);
-    hasher.input(&Hr.serialize_compressed() as &[u8]);
-    let c = secp256k1::SecretKey::parse_slice(&hasher.result()[..])
-        .unwrap()
-        .into();
+    // Hr = Hs + (Hx * -c) = Hr + Hcx - Hcx
+    let Hr_candidate = group::multi_mul(&[(s.clone(), H.clone()), (c_neg, Hx.clone())], &zero);
 
-    // c == c'
-    if proof.c != c {
+    if Gr_candidate.as_ref() != Some(Gr) || Hr_candidate.as_ref() != Some(Hr) {
         return Err(DiscreteLogNotEqual);
     }
 
     Ok(())
 }
 
+/// A proof together with the statement it proves and the transcript it was made in, to be checked
+/// with [`verify_batch`].
+pub struct BatchItem<'a> {
+    pub transcript: Transcript,
+    pub G: &'a secp256k1::PublicKey,
+    pub Gx: &'a secp256k1::PublicKey,
+    pub H: &'a secp256k1::PublicKey,
+    pub Hx: &'a secp256k1::PublicKey,
+    pub proof: &'a Proof,
+}
+
+/// Checks all proofs at once, which is faster than checking them one by one.
+///
+/// The equations `sG = Gr + cGx` and `sH = Hr + cHx` of every proof are combined with random
+/// weights drawn from `rng` into one multi-scalar multiplication, which is the point at infinity
+/// if all proofs are valid and otherwise only with negligible probability. The error does not say
+/// which proof is invalid; [`verify`] does.
+pub fn verify_batch<R: rand::Rng>(
+    rng: &mut R,
+    items: Vec<BatchItem<'_>>,
+) -> Result<(), DiscreteLogNotEqual> {
+    let mut terms = Vec::with_capacity(6 * items.len());
+
+    for BatchItem {
+        mut transcript,
+        G,
+        Gx,
+        H,
+        Hx,
+        proof: Proof { Gr, Hr, s },
+    } in items
+    {
+        append_statement(&mut transcript, G, Gx, H, Hx);
+        let c = challenge(&mut transcript, Gr, Hr);
+
+        // z(sG - cGx - Gr) + w(sH - cHx - Hr)
+        let z = schnorr::scalar_from_hash(rng.gen());
+        let w = schnorr::scalar_from_hash(rng.gen());
+
+        terms.push((z.clone() * s.clone(), G.clone()));
+        terms.push((-(z.clone() * c.clone()), Gx.clone()));
+        terms.push((-z, Gr.clone()));
+        terms.push((w.clone() * s.clone(), H.clone()));
+        terms.push((-(w.clone() * c), Hx.clone()));
+        terms.push((-w, Hr.clone()));
+    }
+
+    match group::multi_mul(&terms, &secp256k1::Scalar::from_int(0)) {
+        None => Ok(()),
+        Some(_) => Err(DiscreteLogNotEqual),
+    }
+}
+
+fn append_statement(
+    transcript: &mut Transcript,
+    G: &secp256k1::PublicKey,
+    Gx: &secp256k1::PublicKey,
+    H: &secp256k1::PublicKey,
+    Hx: &secp256k1::PublicKey,
+) {
+    transcript.append("proof", b"DLEQ");
+    transcript.append_point("G", G);
+    transcript.append_point("Gx", Gx);
+    transcript.append_point("H", H);
+    transcript.append_point("Hx", Hx);
+}
+
+/// The challenge `c = H(transcript | Gr | Hr)`, where the transcript already holds the statement.
+fn challenge(
+    transcript: &mut Transcript,
+    Gr: &secp256k1::PublicKey,
+    Hr: &secp256k1::PublicKey,
+) -> secp256k1::Scalar {
+    transcript.append_point("Gr", Gr);
+    transcript.append_point("Hr", Hr);
+
+    transcript.challenge_scalar("c")
+}
+
+/// Arbitrary, mostly invalid, values of the types that an adversary controls.
+#[cfg(test)]
+pub mod strategy {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Scalars with zero and one overrepresented, since those are the edge cases.
+    pub fn scalar() -> impl Strategy<Value = secp256k1::Scalar> {
+        prop_oneof![
+            Just(secp256k1::Scalar::from_int(0)),
+            Just(secp256k1::Scalar::from_int(1)),
+            any::<[u8; 32]>().prop_map(schnorr::scalar_from_hash),
+        ]
+    }
+
+    pub fn secret_key() -> impl Strategy<Value = secp256k1::SecretKey> {
+        any::<[u8; 32]>().prop_filter_map("not a secret key", |bytes| {
+            secp256k1::SecretKey::parse(&bytes).ok()
+        })
+    }
+
+    pub fn point() -> impl Strategy<Value = secp256k1::PublicKey> {
+        prop_oneof![
+            Just(secp256k1::G.clone()),
+            secret_key().prop_map(|x| secp256k1::PublicKey::from_secret_key(&x)),
+        ]
+    }
+
+    pub fn proof() -> impl Strategy<Value = Proof> {
+        (point(), point(), scalar()).prop_map(|(Gr, Hr, s)| Proof { Gr, Hr, s })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::secp256k1;
 
-    #[test]
-    fn prove_and_verify() {
+    fn session_transcript(session: &[u8], step: &str) -> Transcript {
+        let mut transcript = Transcript::new("A2L/test");
+        transcript.append("session", session);
+        transcript.append("step", step.as_bytes());
+
+        transcript
+    }
+
+    fn random_statement() -> (
+        secp256k1::KeyPair,
+        secp256k1::PublicKey,
+        secp256k1::PublicKey,
+        secp256k1::PublicKey,
+    ) {
         let x_1 = secp256k1::KeyPair::random_from_thread_rng();
         let x_2 = secp256k1::KeyPair::random_from_thread_rng();
 
@@ -129,15 +272,241 @@ mod test {
         let mut Hx = H.clone();
         Hx.tweak_mul_assign(x_1.as_ref()).unwrap();
 
+        (x_1, Gx, H, Hx)
+    }
+
+    #[test]
+    fn prove_and_verify() {
+        let (x, Gx, H, Hx) = random_statement();
+
         let proof = prove(
+            &mut session_transcript(b"session-1", "step-1"),
             &mut rand::thread_rng(),
             &secp256k1::G,
             &Gx,
             &H,
             &Hx,
-            x_1.as_ref().clone().into(),
+            x.as_ref().clone().into(),
         );
 
-        verify(&secp256k1::G, &Gx, &H, &Hx, &proof).unwrap()
+        verify(
+            &mut session_transcript(b"session-1", "step-1"),
+            &secp256k1::G,
+            &Gx,
+            &H,
+            &Hx,
+            &proof,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn proof_does_not_verify_in_other_session_or_step() {
+        let (x, Gx, H, Hx) = random_statement();
+
+        let proof = prove(
+            &mut session_transcript(b"session-1", "step-1"),
+            &mut rand::thread_rng(),
+            &secp256k1::G,
+            &Gx,
+            &H,
+            &Hx,
+            x.as_ref().clone().into(),
+        );
+
+        for transcript in &mut [
+            session_transcript(b"session-2", "step-1"),
+            session_transcript(b"session-1", "step-2"),
+        ] {
+            assert_eq!(
+                verify(transcript, &secp256k1::G, &Gx, &H, &Hx, &proof),
+                Err(DiscreteLogNotEqual)
+            );
+        }
+    }
+
+    /// Fixed vectors: the secret `x`, the discrete log of `H`, auxiliary randomness and the encoded
+    /// proof `Gr | Hr | s` that `xG` and `xH` have the same discrete log, in a transcript with
+    /// domain `"A2L/test"` and nothing else absorbed.
+    #[test]
+    fn test_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "03990B5B4FCC4FD823E7A1D1C0CB2B4587439C1B589EAA7DB28D7BD7F7E792E4E1029ED8B564926738B1D686E6859FAFFFE3994F124AEE8DEB1FE3EE7D902624147C1A2F5E0E1E7658A0626554ED2A9EDA3599CFE9EE6CFE27758BCC4B5999CB9668",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "03D6DA9E7E06F302E54549C211802003AE2DE0A16B5A5CE5369BDBDEC4B7F6046B03417CE3EAEF3E1B459A2F9D1E501584B25234D43F2B579A9C0156B57F0DA54D0E93954827B115935858A8B9A2C02852E3815C4A82B14493C72E30861A5B3A8A73",
+            ),
+            (
+                "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+                "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "03015C17C5F6983E7D804FA05A9907DEB096C4498948F16820C26EC951AB65D9FE03FDD396689E0617CE34DF14849D1FA9E3D67D54EDA7C9359097FE893413803FC7A0DCD77FC78ECFFA3A8385F1B2FCA217B428BA6E05490EDFB1BFD9DCFAE546C3",
+            ),
+        ];
+
+        for (x, h, aux, expected_proof) in vectors.iter() {
+            let x = secp256k1::SecretKey::parse(&bytes32(x)).unwrap();
+            let h = secp256k1::SecretKey::parse(&bytes32(h)).unwrap();
+
+            let Gx = secp256k1::PublicKey::from_secret_key(&x);
+            let H = secp256k1::PublicKey::from_secret_key(&h);
+            let mut Hx = H.clone();
+            Hx.tweak_mul_assign(&x).unwrap();
+
+            let proof = prove_with_aux(
+                &mut Transcript::new("A2L/test"),
+                &secp256k1::G,
+                &Gx,
+                &H,
+                &Hx,
+                x.into(),
+                &bytes32(aux),
+            );
+            let mut encoded = Vec::new();
+            wire::Encode::encode(&proof, &mut encoded);
+            assert_eq!(hex::encode_upper(encoded), *expected_proof);
+
+            verify(
+                &mut Transcript::new("A2L/test"),
+                &secp256k1::G,
+                &Gx,
+                &H,
+                &Hx,
+                &proof,
+            )
+            .unwrap();
+        }
+    }
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn verify_rejects_proof_whose_recomputed_commitment_is_infinity() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let Gx = x.to_pk();
+        let (Gr, Hr) = (secp256k1::G.clone(), secp256k1::G.clone());
+
+        let c = {
+            let mut transcript = Transcript::new("A2L/test");
+            append_statement(&mut transcript, &secp256k1::G, &Gx, &secp256k1::G, &Gx);
+            challenge(&mut transcript, &Gr, &Hr)
+        };
+
+        // s = cx makes Gs - cGx the point at infinity
+        let s = c * x.as_ref().clone().into();
+
+        let result = verify(
+            &mut Transcript::new("A2L/test"),
+            &secp256k1::G,
+            &Gx,
+            &secp256k1::G,
+            &Gx,
+            &Proof { Gr, Hr, s },
+        );
+
+        assert_eq!(result, Err(DiscreteLogNotEqual))
+    }
+
+    fn prove_random_statements(
+        n: usize,
+    ) -> Vec<(
+        secp256k1::PublicKey,
+        secp256k1::PublicKey,
+        secp256k1::PublicKey,
+        secp256k1::PublicKey,
+        Proof,
+    )> {
+        (0..n)
+            .map(|i| {
+                let (x, Gx, H, Hx) = random_statement();
+
+                // alternate between the generator and another base for G
+                let (G, Gx) = if i % 2 == 0 {
+                    (secp256k1::G.clone(), Gx)
+                } else {
+                    (H.clone(), Hx.clone())
+                };
+
+                let proof = prove(
+                    &mut session_transcript(&[i as u8], "step"),
+                    &mut rand::thread_rng(),
+                    &G,
+                    &Gx,
+                    &H,
+                    &Hx,
+                    x.as_ref().clone().into(),
+                );
+
+                (G, Gx, H, Hx, proof)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verify_batch_accepts_valid_proofs() {
+        let proofs = prove_random_statements(5);
+
+        let items = proofs
+            .iter()
+            .enumerate()
+            .map(|(i, (G, Gx, H, Hx, proof))| BatchItem {
+                transcript: session_transcript(&[i as u8], "step"),
+                G,
+                Gx,
+                H,
+                Hx,
+                proof,
+            })
+            .collect();
+
+        verify_batch(&mut rand::thread_rng(), items).unwrap()
+    }
+
+    #[test]
+    fn verify_batch_rejects_batch_with_one_invalid_proof() {
+        let proofs = prove_random_statements(5);
+
+        let items = proofs
+            .iter()
+            .enumerate()
+            .map(|(i, (G, Gx, H, Hx, proof))| BatchItem {
+                // the last proof is checked in the wrong session
+                transcript: session_transcript(&[(i as u8).min(3)], "step"),
+                G,
+                Gx,
+                H,
+                Hx,
+                proof,
+            })
+            .collect();
+
+        assert_eq!(
+            verify_batch(&mut rand::thread_rng(), items),
+            Err(DiscreteLogNotEqual)
+        )
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn verify_does_not_panic(
+            G in strategy::point(),
+            Gx in strategy::point(),
+            H in strategy::point(),
+            Hx in strategy::point(),
+            proof in strategy::proof(),
+        ) {
+            let _ = verify(&mut Transcript::new("A2L/test"), &G, &Gx, &H, &Hx, &proof);
+        }
     }
 }
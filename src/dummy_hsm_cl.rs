@@ -1,54 +1,123 @@
+use crate::hsm_cl::{Decrypt, Encrypt, InvalidProof, Pow, Verify};
 use crate::secp256k1;
+use crate::wire;
 
 #[derive(Clone, Debug)]
 pub struct Ciphertext {
     sk: secp256k1::SecretKey,
 }
 
+#[derive(Clone, Debug)]
+pub struct Proof;
+
+impl wire::Encode for Ciphertext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.sk.encode(buffer);
+    }
+}
+
+impl wire::Decode for Ciphertext {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Ciphertext {
+            sk: secp256k1::SecretKey::decode(reader)?,
+        })
+    }
+}
+
+impl wire::Encode for Proof {
+    fn encode(&self, _buffer: &mut Vec<u8>) {}
+}
+
+impl wire::Decode for Proof {
+    fn decode(_reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Proof)
+    }
+}
+
 #[derive(Default)]
 pub struct SecretKey;
 
 #[derive(Default)]
 pub struct PublicKey;
 
-pub fn keygen() -> (SecretKey, PublicKey) {
-    (SecretKey, PublicKey)
+impl wire::Encode for SecretKey {
+    fn encode(&self, _buffer: &mut Vec<u8>) {}
 }
 
-pub trait Encrypt {
-    fn encrypt(&self, x: &secp256k1::KeyPair, witness: &secp256k1::SecretKey) -> Ciphertext;
+impl wire::Decode for SecretKey {
+    fn decode(_reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(SecretKey)
+    }
+}
+
+impl wire::Encode for PublicKey {
+    fn encode(&self, _buffer: &mut Vec<u8>) {}
+}
+
+impl wire::Decode for PublicKey {
+    fn decode(_reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(PublicKey)
+    }
+}
+
+pub fn keygen() -> (SecretKey, PublicKey) {
+    (SecretKey, PublicKey)
 }
 
 impl Encrypt for SecretKey {
-    fn encrypt(&self, _x: &secp256k1::KeyPair, witness: &secp256k1::SecretKey) -> Ciphertext {
-        Ciphertext {
-            sk: witness.clone(),
-        }
+    type Ciphertext = Ciphertext;
+    type Proof = Proof;
+
+    fn encrypt(
+        &self,
+        _x: &secp256k1::KeyPair,
+        witness: &secp256k1::KeyPair,
+    ) -> (Ciphertext, Proof) {
+        let ciphertext = Ciphertext {
+            sk: witness.to_sk(),
+        };
+
+        (ciphertext, Proof)
     }
 }
 
-pub trait Pow<T> {
-    fn pow(&self, t: &T, x: &secp256k1::KeyPair) -> T;
+impl Verify<Ciphertext, Proof> for PublicKey {
+    fn verify(
+        &self,
+        ciphertext: &Ciphertext,
+        encrypts: &secp256k1::PublicKey,
+        _proof: &Proof,
+    ) -> Result<(), InvalidProof> {
+        if &secp256k1::PublicKey::from_secret_key(&ciphertext.sk) != encrypts {
+            return Err(InvalidProof);
+        }
+
+        Ok(())
+    }
 }
 
 impl Pow<Ciphertext> for PublicKey {
-    fn pow(&self, t: &Ciphertext, _x: &secp256k1::KeyPair) -> Ciphertext {
-        t.clone()
+    fn pow(&self, t: &Ciphertext, x: &secp256k1::KeyPair) -> Ciphertext {
+        Ciphertext {
+            sk: secp256k1::randomize(&t.sk, x),
+        }
     }
 }
 
 impl Pow<secp256k1::PublicKey> for PublicKey {
-    fn pow(&self, t: &secp256k1::PublicKey, _x: &secp256k1::KeyPair) -> secp256k1::PublicKey {
-        t.clone()
+    fn pow(&self, t: &secp256k1::PublicKey, x: &secp256k1::KeyPair) -> secp256k1::PublicKey {
+        secp256k1::randomize_point(t, x)
     }
 }
 
-pub trait Decrypt {
-    fn decrypt(&self, x: &secp256k1::KeyPair, c: &Ciphertext) -> secp256k1::SecretKey;
-}
-
 impl Decrypt for SecretKey {
-    fn decrypt(&self, _x: &secp256k1::KeyPair, c: &Ciphertext) -> secp256k1::SecretKey {
-        c.sk.clone()
+    type Ciphertext = Ciphertext;
+
+    fn decrypt(
+        &self,
+        _x: &secp256k1::KeyPair,
+        c: &Ciphertext,
+    ) -> anyhow::Result<secp256k1::SecretKey> {
+        Ok(c.sk.clone())
     }
 }
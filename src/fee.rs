@@ -0,0 +1,612 @@
+//! Fees of the redeem and refund transactions.
+//!
+//! Both transactions are pre-signed, so their fee has to be set aside in the joint output when it
+//! is funded. It is computed from the weight the transactions will have once signed, which depends
+//! on the type of the joint output, the branch they spend, the refund locktime in the witness
+//! script or refund leaf and the output script of the identity they pay to.
+//!
+//! If fees rise after signing, a spend transaction with an anchor output can still be confirmed in
+//! time by spending its anchor in a child transaction paying for both (CPFP).
+
+use crate::bitcoin::{
+    self, Address, Branch, OutPoint, OutputType, PartiallySignedTransaction, Script, Transaction,
+    TxIn, TxOut,
+};
+use crate::taproot;
+use anyhow::bail;
+use fehler::throws;
+
+/// A DER-encoded signature is at most 72 bytes long, followed by the sighash type.
+const MAX_SIGNATURE_SIZE: usize = 73;
+/// A BIP340 signature with `SIGHASH_DEFAULT` is always 64 bytes long.
+const TAPROOT_SIGNATURE_SIZE: usize = 64;
+/// Sequence number of the inputs of a CPFP child, which signals replaceability (BIP125) so that
+/// the child can be bumped again.
+const SEQUENCE_REPLACEABLE: u32 = 0xFFFF_FFFD;
+
+/// Returns the weight of a transaction spending a fund output locked with `witness_script` through
+/// `branch` to `destination`, with or without anchor outputs.
+///
+/// Signatures are assumed to be of maximum size, so the signed transaction can only be lighter, by
+/// at most a few weight units.
+pub fn spend_transaction_weight(
+    witness_script: &Script,
+    destination: &Address,
+    branch: Branch,
+    anchor_outputs: bool,
+) -> u64 {
+    weight_with_witness(
+        vec![
+            vec![0u8; MAX_SIGNATURE_SIZE],
+            vec![0u8; MAX_SIGNATURE_SIZE],
+            branch.selector(),
+            witness_script.to_bytes(),
+        ],
+        destination,
+        anchor_outputs,
+    )
+}
+
+/// Returns the weight of a transaction spending a Taproot joint output with `refund_locktime`
+/// through `branch` to `destination`, with or without anchor outputs.
+///
+/// The redeem transaction spends the key path with one signature, the refund transaction the refund
+/// leaf with two. Signatures have a fixed size, so this is the exact weight of the signed
+/// transaction.
+pub fn taproot_spend_transaction_weight(
+    refund_locktime: u32,
+    destination: &Address,
+    branch: Branch,
+    anchor_outputs: bool,
+) -> u64 {
+    let witness = match branch {
+        Branch::Redeem => vec![vec![0u8; TAPROOT_SIGNATURE_SIZE]],
+        Branch::Refund => vec![
+            vec![0u8; TAPROOT_SIGNATURE_SIZE],
+            vec![0u8; TAPROOT_SIGNATURE_SIZE],
+            taproot::placeholder_refund_script(refund_locktime).to_bytes(),
+            vec![0u8; taproot::CONTROL_BLOCK_SIZE],
+        ],
+    };
+
+    weight_with_witness(witness, destination, anchor_outputs)
+}
+
+/// The weight of a transaction with a single input carrying `witness`, paying to `destination`.
+fn weight_with_witness(witness: Vec<Vec<u8>>, destination: &Address, anchor_outputs: bool) -> u64 {
+    let mut output = vec![TxOut {
+        value: 0,
+        script_pubkey: destination.script_pubkey(),
+    }];
+    if anchor_outputs {
+        output.push(bitcoin::anchor_output());
+    }
+
+    let transaction = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Default::default(),
+                vout: 0,
+            },
+            script_sig: Script::new(),
+            sequence: 0,
+            witness,
+        }],
+        output,
+    };
+
+    transaction.get_weight() as u64
+}
+
+/// Returns the fee both the redeem and the refund transaction of a fund output of `output_type`
+/// pay at a fee rate of at least `fee_per_vbyte`.
+///
+/// They spend the same joint output and pay the same amount, so the heavier of the two decides.
+pub fn spend_transaction_fee(
+    output_type: OutputType,
+    refund_locktime: u32,
+    redeem_identity: &Address,
+    refund_identity: &Address,
+    fee_per_vbyte: u64,
+    anchor_outputs: bool,
+) -> u64 {
+    let weight = |destination: &Address, branch: Branch| match output_type {
+        OutputType::P2wsh => spend_transaction_weight(
+            &bitcoin::placeholder_witness_script(refund_locktime),
+            destination,
+            branch,
+            anchor_outputs,
+        ),
+        OutputType::P2tr => {
+            taproot_spend_transaction_weight(refund_locktime, destination, branch, anchor_outputs)
+        }
+    };
+
+    let weight = std::cmp::max(
+        weight(redeem_identity, Branch::Redeem),
+        weight(refund_identity, Branch::Refund),
+    );
+
+    vsize(weight) * fee_per_vbyte
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("transaction has no anchor output")]
+pub struct NoAnchorOutput;
+
+#[derive(thiserror::Error, Debug)]
+#[error("wallet input {0} does not spend a P2WPKH output")]
+pub struct NotP2wpkhWalletInput(usize);
+
+#[derive(thiserror::Error, Debug)]
+#[error("child transaction can spend {available} satoshi, but needs {required}")]
+pub struct InsufficientFeeBumpFunds {
+    available: u64,
+    required: u64,
+}
+
+/// Builds a transaction spending the anchor output of `parent`, so that `parent` and the child
+/// together pay `fee_per_vbyte`.
+///
+/// The anchor alone is not worth enough to pay for the package, so the child also spends
+/// `wallet_inputs`, which have to be P2WPKH outputs, and pays what is left to `change`.
+/// `fund_output_value` is the value of the joint output `parent` spends, from which the fee
+/// `parent` already pays is derived.
+///
+/// The anchor input needs no signature. The returned PSBT only needs to be signed by the wallet
+/// owning the other inputs before it can be broadcast alongside `parent`.
+#[throws(anyhow::Error)]
+pub fn cpfp_child(
+    parent: &Transaction,
+    fund_output_value: u64,
+    wallet_inputs: &[(OutPoint, TxOut)],
+    change: &Address,
+    fee_per_vbyte: u64,
+) -> PartiallySignedTransaction {
+    let anchor = bitcoin::anchor_output();
+    let anchor_vout = parent
+        .output
+        .iter()
+        .position(|output| *output == anchor)
+        .ok_or(NoAnchorOutput)?;
+    if let Some(index) = wallet_inputs
+        .iter()
+        .position(|(_, output)| !output.script_pubkey.is_v0_p2wpkh())
+    {
+        bail!(NotP2wpkhWalletInput(index))
+    }
+
+    let spent_outputs = std::iter::once(anchor.clone())
+        .chain(wallet_inputs.iter().map(|(_, output)| output.clone()))
+        .collect::<Vec<_>>();
+    let outpoints = std::iter::once(OutPoint {
+        txid: parent.txid(),
+        vout: anchor_vout as u32,
+    })
+    .chain(wallet_inputs.iter().map(|(outpoint, _)| *outpoint));
+
+    let mut child = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: outpoints
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_REPLACEABLE,
+                witness: Vec::new(),
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: change.script_pubkey(),
+        }],
+    };
+
+    let parent_fee =
+        fund_output_value.saturating_sub(parent.output.iter().map(|output| output.value).sum());
+    let package_vsize = vsize(parent.get_weight() as u64) + vsize(cpfp_child_weight(&child));
+    let fee = (package_vsize * fee_per_vbyte).saturating_sub(parent_fee);
+    let available = spent_outputs.iter().map(|output| output.value).sum::<u64>();
+
+    // the change has to be worth spending on its own
+    let required = fee + bitcoin::dust_threshold(&child.output[0]);
+    if available < required {
+        bail!(InsufficientFeeBumpFunds {
+            available,
+            required,
+        })
+    }
+    child.output[0].value = available - fee;
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(child)?;
+    for (input, spent_output) in psbt.inputs.iter_mut().zip(spent_outputs) {
+        input.witness_utxo = Some(spent_output);
+    }
+    psbt.inputs[0].final_script_witness = Some(bitcoin::anchor_witness());
+
+    psbt
+}
+
+/// Returns the weight of a child built by [`cpfp_child`] once the wallet has signed its other
+/// inputs.
+fn cpfp_child_weight(child: &Transaction) -> u64 {
+    let mut child = child.clone();
+    for input in child.input.iter_mut().skip(1) {
+        input.witness = p2wpkh_placeholder_witness();
+    }
+    child.input[0].witness = bitcoin::anchor_witness();
+
+    child.get_weight() as u64
+}
+
+/// Returns the weight of `transaction` once all its inputs are signed, assuming they spend
+/// P2WPKH outputs.
+pub(crate) fn p2wpkh_spend_weight(transaction: &Transaction) -> u64 {
+    let mut transaction = transaction.clone();
+    for input in transaction.input.iter_mut() {
+        input.witness = p2wpkh_placeholder_witness();
+    }
+
+    transaction.get_weight() as u64
+}
+
+/// A witness as large as the one of a signed P2WPKH input can be.
+fn p2wpkh_placeholder_witness() -> Vec<Vec<u8>> {
+    vec![vec![0u8; MAX_SIGNATURE_SIZE], vec![0u8; 33]]
+}
+
+/// Converts `weight` to virtual bytes, rounding up like Bitcoin Core.
+pub fn vsize(weight: u64) -> u64 {
+    (weight + 3) / 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secp256k1::{self, schnorr};
+    use rand::thread_rng;
+
+    const EXPIRY: u32 = 1_000;
+
+    fn p2wpkh_address(x: &secp256k1::KeyPair) -> Address {
+        Address::p2wpkh(
+            &bitcoin::to_bitcoin_public_key(&x.to_pk()),
+            ::bitcoin::Network::Regtest,
+        )
+    }
+
+    fn partial_fund_transaction() -> Transaction {
+        Transaction {
+            lock_time: 0,
+            version: 2,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Default::default(),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            }],
+            output: Vec::new(),
+        }
+    }
+
+    /// The fund transaction and the signed redeem and refund transactions of a joint output locked
+    /// to `x_from` and `x_to`.
+    fn signed_transactions(
+        x_from: &secp256k1::KeyPair,
+        x_to: &secp256k1::KeyPair,
+        destination: &Address,
+        refund_locktime: u32,
+        anchor_outputs: bool,
+    ) -> (Transaction, Transaction, Transaction) {
+        let transactions = bitcoin::make_transactions(
+            partial_fund_transaction(),
+            &x_from.to_pk(),
+            &x_to.to_pk(),
+            &bitcoin::JointOutput {
+                output_type: OutputType::P2wsh,
+                value: 10_000,
+                spend_value: 9_000,
+                refund_locktime,
+                redeem_identity: destination.clone(),
+                refund_identity: destination.clone(),
+                anchor_outputs,
+            },
+        );
+
+        let redeem = bitcoin::complete_redeem_transaction(
+            transactions.redeem,
+            (
+                x_from.to_pk(),
+                secp256k1::sign(transactions.redeem_tx_digest, x_from),
+            ),
+            (
+                x_to.to_pk(),
+                secp256k1::sign(transactions.redeem_tx_digest, x_to),
+            ),
+        )
+        .unwrap();
+        let refund = bitcoin::complete_refund_transaction(
+            transactions.refund,
+            (
+                x_from.to_pk(),
+                secp256k1::sign(transactions.refund_tx_digest, x_from),
+            ),
+            (
+                x_to.to_pk(),
+                secp256k1::sign(transactions.refund_tx_digest, x_to),
+            ),
+        )
+        .unwrap();
+
+        (transactions.fund, redeem, refund)
+    }
+
+    fn random_signed_transactions(
+        destination: &Address,
+        refund_locktime: u32,
+        anchor_outputs: bool,
+    ) -> (Transaction, Transaction) {
+        let (_, redeem, refund) = signed_transactions(
+            &secp256k1::KeyPair::random(&mut thread_rng()),
+            &secp256k1::KeyPair::random(&mut thread_rng()),
+            destination,
+            refund_locktime,
+            anchor_outputs,
+        );
+
+        (redeem, refund)
+    }
+
+    fn assert_weight_matches(
+        transaction: &Transaction,
+        destination: &Address,
+        branch: Branch,
+        anchor_outputs: bool,
+    ) {
+        let witness_script = Script::from(transaction.input[0].witness[3].clone());
+        let estimated =
+            spend_transaction_weight(&witness_script, destination, branch, anchor_outputs);
+        let actual = transaction.get_weight() as u64;
+
+        // signatures with a low S are one byte shorter than the maximum, and a few more bytes only
+        // if R or S happen to have leading zeros
+        assert!(
+            actual <= estimated && estimated - actual <= 8,
+            "estimated weight {}, actual weight {}",
+            estimated,
+            actual
+        );
+    }
+
+    #[test]
+    fn weight_matches_signed_transactions() {
+        let x = secp256k1::KeyPair::random(&mut thread_rng());
+        let p2wpkh = p2wpkh_address(&x);
+        let p2wsh = Address::p2wsh(
+            &bitcoin::placeholder_witness_script(EXPIRY),
+            ::bitcoin::Network::Regtest,
+        );
+        let p2pkh = Address::p2pkh(
+            &bitcoin::to_bitcoin_public_key(&x.to_pk()),
+            ::bitcoin::Network::Regtest,
+        );
+        let p2tr = Address {
+            payload: ::bitcoin::util::address::Payload::WitnessProgram {
+                version: ::bitcoin::bech32::u5::try_from_u8(1).unwrap(),
+                program: vec![0x79; 32],
+            },
+            network: ::bitcoin::Network::Regtest,
+        };
+
+        for destination in &[p2wpkh, p2wsh, p2pkh, p2tr] {
+            for refund_locktime in &[1, EXPIRY, 500_000, u32::MAX] {
+                for anchor_outputs in &[false, true] {
+                    let (redeem, refund) =
+                        random_signed_transactions(destination, *refund_locktime, *anchor_outputs);
+
+                    assert_weight_matches(&redeem, destination, Branch::Redeem, *anchor_outputs);
+                    assert_weight_matches(&refund, destination, Branch::Refund, *anchor_outputs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn weight_matches_transaction_with_maximum_size_signatures() {
+        let destination = p2wpkh_address(&secp256k1::KeyPair::random(&mut thread_rng()));
+        let (mut redeem, _) = random_signed_transactions(&destination, EXPIRY, false);
+        let witness_script = Script::from(redeem.input[0].witness[3].clone());
+
+        redeem.input[0].witness[0] = vec![0u8; MAX_SIGNATURE_SIZE];
+        redeem.input[0].witness[1] = vec![0u8; MAX_SIGNATURE_SIZE];
+
+        assert_eq!(
+            spend_transaction_weight(&witness_script, &destination, Branch::Redeem, false),
+            redeem.get_weight() as u64
+        );
+    }
+
+    #[test]
+    fn fee_depends_on_destination_locktime_and_anchors() {
+        let x = secp256k1::KeyPair::random(&mut thread_rng());
+        let p2wpkh = p2wpkh_address(&x);
+        let p2wsh = Address::p2wsh(
+            &bitcoin::placeholder_witness_script(EXPIRY),
+            ::bitcoin::Network::Regtest,
+        );
+
+        let fee = spend_transaction_fee(OutputType::P2wsh, EXPIRY, &p2wpkh, &p2wpkh, 1, false);
+
+        // a P2WSH output script is 12 bytes longer than a P2WPKH one
+        assert_eq!(
+            spend_transaction_fee(OutputType::P2wsh, EXPIRY, &p2wsh, &p2wpkh, 1, false),
+            fee + 12
+        );
+        assert_eq!(
+            spend_transaction_fee(OutputType::P2wsh, EXPIRY, &p2wpkh, &p2wpkh, 10, false),
+            fee * 10
+        );
+        // a locktime needing a 5-byte script number instead of a 2-byte one
+        assert!(
+            spend_transaction_fee(OutputType::P2wsh, u32::MAX, &p2wpkh, &p2wpkh, 1, false) > fee
+        );
+        // two P2WPKH outputs of 31 bytes each
+        assert_eq!(
+            spend_transaction_fee(OutputType::P2wsh, EXPIRY, &p2wpkh, &p2wpkh, 1, true),
+            fee + 62
+        );
+    }
+
+    #[test]
+    fn taproot_weight_matches_signed_transactions() {
+        let mut rng = thread_rng();
+        let x_from = secp256k1::KeyPair::random(&mut rng);
+        let x_to = secp256k1::KeyPair::random(&mut rng);
+        let destination = p2wpkh_address(&x_to);
+
+        for refund_locktime in &[1, EXPIRY, 500_000, u32::MAX] {
+            for anchor_outputs in &[false, true] {
+                let transactions = bitcoin::make_transactions(
+                    partial_fund_transaction(),
+                    &x_from.to_pk(),
+                    &x_to.to_pk(),
+                    &bitcoin::JointOutput {
+                        output_type: OutputType::P2tr,
+                        value: 10_000,
+                        spend_value: 9_000,
+                        refund_locktime: *refund_locktime,
+                        redeem_identity: destination.clone(),
+                        refund_identity: destination.clone(),
+                        anchor_outputs: *anchor_outputs,
+                    },
+                );
+                // only the size of the signatures matters here
+                let signature = schnorr::sign(transactions.redeem_tx_digest, &x_from, &mut rng);
+                let redeem =
+                    taproot::complete_redeem_transaction(transactions.redeem, &signature).unwrap();
+                let refund = taproot::complete_refund_transaction(
+                    transactions.refund,
+                    &signature,
+                    &signature,
+                )
+                .unwrap();
+
+                assert_eq!(
+                    taproot_spend_transaction_weight(
+                        *refund_locktime,
+                        &destination,
+                        Branch::Redeem,
+                        *anchor_outputs
+                    ),
+                    redeem.get_weight() as u64
+                );
+                assert_eq!(
+                    taproot_spend_transaction_weight(
+                        *refund_locktime,
+                        &destination,
+                        Branch::Refund,
+                        *anchor_outputs
+                    ),
+                    refund.get_weight() as u64
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn taproot_spend_transactions_pay_less_fee() {
+        let p2wpkh = p2wpkh_address(&secp256k1::KeyPair::random(&mut thread_rng()));
+
+        for anchor_outputs in &[false, true] {
+            assert!(
+                spend_transaction_fee(
+                    OutputType::P2tr,
+                    EXPIRY,
+                    &p2wpkh,
+                    &p2wpkh,
+                    1,
+                    *anchor_outputs
+                ) < spend_transaction_fee(
+                    OutputType::P2wsh,
+                    EXPIRY,
+                    &p2wpkh,
+                    &p2wpkh,
+                    1,
+                    *anchor_outputs
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn vsize_rounds_up() {
+        assert_eq!(vsize(400), 100);
+        assert_eq!(vsize(401), 101);
+        assert_eq!(vsize(403), 101);
+    }
+
+    #[test]
+    fn cpfp_child_bumps_package_fee_rate() {
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+        let destination = p2wpkh_address(&x_to);
+        let (fund, redeem, _) = signed_transactions(&x_from, &x_to, &destination, EXPIRY, true);
+        let wallet_input = (
+            OutPoint {
+                txid: Default::default(),
+                vout: 1,
+            },
+            TxOut {
+                value: 100_000,
+                script_pubkey: p2wpkh_address(&x_to).script_pubkey(),
+            },
+        );
+
+        let psbt = cpfp_child(
+            &redeem,
+            fund.output[0].value,
+            &[wallet_input.clone()],
+            &destination,
+            50,
+        )
+        .unwrap();
+        let child = psbt.clone().extract_tx();
+
+        let anchor_vout = child.input[0].previous_output.vout as usize;
+        assert_eq!(child.input[0].previous_output.txid, redeem.txid());
+        assert_eq!(redeem.output[anchor_vout], bitcoin::anchor_output());
+        bitcoin::verify_input(
+            &child,
+            0,
+            &[redeem.output[anchor_vout].clone(), wallet_input.1.clone()],
+        )
+        .unwrap();
+
+        assert_eq!(child.input[1].previous_output, wallet_input.0);
+        assert!(psbt.inputs[1].final_script_witness.is_none());
+
+        // the wallet input is not signed yet, but will make up for its estimated weight
+        let parent_fee =
+            fund.output[0].value - redeem.output.iter().map(|output| output.value).sum::<u64>();
+        let child_fee = bitcoin::ANCHOR_VALUE + 100_000 - child.output[0].value;
+        let package_vsize = vsize(redeem.get_weight() as u64) + vsize(cpfp_child_weight(&child));
+        assert_eq!(parent_fee + child_fee, package_vsize * 50);
+    }
+
+    #[test]
+    fn cpfp_child_needs_an_anchor() {
+        let x_from = secp256k1::KeyPair::random(&mut thread_rng());
+        let x_to = secp256k1::KeyPair::random(&mut thread_rng());
+        let destination = p2wpkh_address(&x_to);
+        let (fund, redeem, _) = signed_transactions(&x_from, &x_to, &destination, EXPIRY, false);
+
+        let error = cpfp_child(&redeem, fund.output[0].value, &[], &destination, 50).unwrap_err();
+
+        assert!(error.downcast_ref::<NoAnchorOutput>().is_some());
+    }
+}
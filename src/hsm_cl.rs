@@ -1,4 +1,6 @@
+use crate::wire;
 use class_group::primitives::cl_dl_lcm::{CLDLProofPublicSetup, Witness, HSMCL, PK};
+use class_group::BinaryQF;
 use curv::arithmetic::traits::Converter;
 use curv::arithmetic::traits::Samplable;
 use curv::elliptic::curves::traits::ECPoint;
@@ -6,31 +8,192 @@ use curv::elliptic::curves::traits::ECScalar;
 use curv::BigInt;
 use curv::FE;
 use curv::GE;
+use std::convert::TryFrom;
 
 pub use class_group::primitives::cl_dl_lcm::Ciphertext;
-pub type PublicKey = PK;
 
 pub type Proof = CLDLProofPublicSetup;
 
 #[derive(Clone, Debug)]
-pub struct KeyPair(HSMCL);
+pub struct KeyPair {
+    hsmcl: HSMCL,
+    public_key: PublicKey,
+}
+
+/// An HSM-CL public key together with the public setup it was generated from.
+///
+/// The public setup is needed to verify proofs produced under this key. It is not part of the
+/// encoding of the key: whoever verifies proofs has to bring their own, otherwise the tumbler could
+/// pick one it knows a trapdoor for.
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+    pk: PK,
+    public_setup: BigInt,
+}
+
+impl PublicKey {
+    /// Decodes a public key written by [`wire::Encode`], to be used with `public_setup`.
+    pub fn decode(
+        reader: &mut wire::Reader<'_>,
+        public_setup: &[u8],
+    ) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+        let pk = bincode::deserialize::<PK>(bytes)
+            .map_err(|_| wire::DecodeError::InvalidEncryptionKey)?;
+
+        if pk.q != FE::q()
+            || pk.delta_k >= BigInt::zero()
+            || pk.delta_q != &pk.delta_k * &pk.q * &pk.q
+            || !is_group_element(&pk.gq, &pk.delta_q)
+            || !is_group_element(&pk.h, &pk.delta_q)
+        {
+            return Err(wire::DecodeError::InvalidEncryptionKey);
+        }
+
+        Ok(PublicKey {
+            pk,
+            public_setup: BigInt::from(public_setup),
+        })
+    }
+}
 
 impl KeyPair {
     pub fn public_key(&self) -> &PublicKey {
-        &self.0.pk
+        &self.public_key
     }
 
     pub fn gen(public_setup: &BigInt) -> Self {
-        Self(HSMCL::keygen_with_setup(&FE::q(), &1348, &public_setup))
+        let hsmcl = HSMCL::keygen_with_setup(&FE::q(), &1348, &public_setup);
+        let public_key = PublicKey {
+            pk: hsmcl.pk.clone(),
+            public_setup: public_setup.clone(),
+        };
+
+        Self { hsmcl, public_key }
+    }
+
+    pub fn to_pk(&self) -> PublicKey {
+        self.public_key.clone()
+    }
+}
+
+pub fn keygen(public_setup: &[u8]) -> (KeyPair, PublicKey) {
+    let keypair = KeyPair::gen(&BigInt::from(public_setup));
+    let public_key = keypair.to_pk();
+
+    (keypair, public_key)
+}
+
+/// Homomorphically encrypts the discrete log of `witness` and proves that it did so (lines 3-4,
+/// Figure 6).
+pub trait Encrypt {
+    type Ciphertext;
+    type Proof;
+
+    fn encrypt(
+        &self,
+        x: &crate::secp256k1::KeyPair,
+        witness: &crate::secp256k1::KeyPair,
+    ) -> (Self::Ciphertext, Self::Proof);
+}
+
+/// Raises `t` to the power of the blinding factor `x` (line 11, Figure 6 and line 3, Figure 7).
+pub trait Pow<T> {
+    fn pow(&self, t: &T, x: &crate::secp256k1::KeyPair) -> T;
+}
+
+/// Verifies that a ciphertext encrypts the discrete log of a public key (line 8, Figure 6).
+pub trait Verify<C, P> {
+    fn verify(
+        &self,
+        ciphertext: &C,
+        encrypts: &crate::secp256k1::PublicKey,
+        proof: &P,
+    ) -> Result<(), InvalidProof>;
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("ciphertext does not encrypt the discrete log of the given public key")]
+pub struct InvalidProof;
+
+/// Recovers the discrete log encrypted in a ciphertext (line 5, Figure 7).
+pub trait Decrypt {
+    type Ciphertext;
+
+    fn decrypt(
+        &self,
+        x: &crate::secp256k1::KeyPair,
+        c: &Self::Ciphertext,
+    ) -> anyhow::Result<crate::secp256k1::SecretKey>;
+}
+
+impl Encrypt for KeyPair {
+    type Ciphertext = Ciphertext;
+    type Proof = Proof;
+
+    fn encrypt(
+        &self,
+        _x: &crate::secp256k1::KeyPair,
+        witness: &crate::secp256k1::KeyPair,
+    ) -> (Ciphertext, Proof) {
+        encrypt(self.public_key(), witness)
+    }
+}
+
+impl Verify<Ciphertext, Proof> for PublicKey {
+    fn verify(
+        &self,
+        ciphertext: &Ciphertext,
+        encrypts: &crate::secp256k1::PublicKey,
+        proof: &Proof,
+    ) -> Result<(), InvalidProof> {
+        if !verify(self, ciphertext, encrypts, proof) {
+            return Err(InvalidProof);
+        }
+
+        Ok(())
+    }
+}
+
+impl Pow<Ciphertext> for PublicKey {
+    fn pow(&self, t: &Ciphertext, x: &crate::secp256k1::KeyPair) -> Ciphertext {
+        multiply(t, x.secret_key())
+    }
+}
+
+impl Pow<crate::secp256k1::PublicKey> for PublicKey {
+    fn pow(
+        &self,
+        t: &crate::secp256k1::PublicKey,
+        x: &crate::secp256k1::KeyPair,
+    ) -> crate::secp256k1::PublicKey {
+        crate::secp256k1::randomize_point(t, x)
+    }
+}
+
+impl Decrypt for KeyPair {
+    type Ciphertext = Ciphertext;
+
+    fn decrypt(
+        &self,
+        _x: &crate::secp256k1::KeyPair,
+        c: &Ciphertext,
+    ) -> anyhow::Result<crate::secp256k1::SecretKey> {
+        if !is_ciphertext_under(&self.public_key.pk, c) {
+            anyhow::bail!("ciphertext is not encrypted under our key")
+        }
+        let scalar = decrypt(self, c.clone());
+
+        Ok(crate::secp256k1::SecretKey::try_from(scalar)?)
     }
 }
 
 pub fn encrypt(public_key: &PublicKey, message: &crate::secp256k1::KeyPair) -> (Ciphertext, Proof) {
+    let public_key = &public_key.pk;
     let r = BigInt::sample_below(&(&public_key.stilde * BigInt::from(2).pow(40)));
     let x = BigInt::from(message.secret_key().serialize().as_ref());
     let ciphertext = HSMCL::encrypt_predefined_randomness(&public_key, &x, &r);
-    let pk_untagged_bytes = &message.public_key().serialize()[1..];
-    let X = GE::from_bytes(pk_untagged_bytes).unwrap();
+    let X = to_curv_point(message.public_key());
 
     let proof = CLDLProofPublicSetup::prove(Witness { x: &x, r }, public_key, &ciphertext, &X);
 
@@ -43,17 +206,60 @@ pub fn verify(
     ciphertext: &Ciphertext,
     encrypts: &crate::secp256k1::PublicKey,
     proof: &Proof,
-    public_setup: &BigInt,
 ) -> bool {
-    let pk_untagged_bytes = &encrypts.serialize()[1..];
-    let encrypts = GE::from_bytes(pk_untagged_bytes).unwrap();
+    if !is_ciphertext_under(&pk.pk, ciphertext) {
+        return false;
+    }
+
     proof
-        .verify(pk, ciphertext, &encrypts, &public_setup)
+        .verify(
+            &pk.pk,
+            ciphertext,
+            &to_curv_point(encrypts),
+            &pk.public_setup,
+        )
         .is_ok()
 }
 
+/// The same point in the representation of `curv`, which the CL proofs are implemented with.
+fn to_curv_point(X: &crate::secp256k1::PublicKey) -> GE {
+    // `curv` expects the uncompressed encoding without its tag byte
+    GE::from_bytes(&X.serialize()[1..]).expect("a valid public key is a valid curv point")
+}
+
+/// Whether `form` is the reduced form of a class of discriminant `discriminant`, which is how
+/// class group elements are represented. `bincode` happily decodes any three integers.
+fn is_group_element(form: &BinaryQF, discriminant: &BigInt) -> bool {
+    let BinaryQF { a, b, c } = form;
+    let zero = BigInt::zero();
+
+    discriminant_of(form) == *discriminant
+        && *a > zero
+        && b.abs() <= *a
+        && a <= c
+        // the boundary cases have two representatives, the reduced one is the one with `b >= 0`
+        && !((b.abs() == *a || a == c) && *b < zero)
+}
+
+fn is_ciphertext_under(pk: &PK, ciphertext: &Ciphertext) -> bool {
+    is_group_element(&ciphertext.c1, &pk.delta_q) && is_group_element(&ciphertext.c2, &pk.delta_q)
+}
+
+/// What can be checked about a ciphertext without knowing the key it is encrypted under.
+fn is_well_formed(ciphertext: &Ciphertext) -> bool {
+    let discriminant = discriminant_of(&ciphertext.c1);
+
+    discriminant < BigInt::zero()
+        && is_group_element(&ciphertext.c1, &discriminant)
+        && is_group_element(&ciphertext.c2, &discriminant)
+}
+
+fn discriminant_of(BinaryQF { a, b, c }: &BinaryQF) -> BigInt {
+    &(b * b) - &(BigInt::from(4) * a * c)
+}
+
 pub fn decrypt(keypair: &KeyPair, ciphertext: Ciphertext) -> secp256k1::curve::Scalar {
-    let bytes = BigInt::to_vec(&keypair.0.decrypt(&ciphertext));
+    let bytes = BigInt::to_vec(&keypair.hsmcl.decrypt(&ciphertext));
 
     // Note, if this isn't true then the problem should be solved at a lower level :^)
     debug_assert!(
@@ -78,6 +284,81 @@ pub fn multiply(ciphertext: &Ciphertext, sk: &secp256k1::SecretKey) -> Ciphertex
     HSMCL::eval_scal(&ciphertext, &BigInt::from(&sk.serialize()[..]))
 }
 
+impl wire::Encode for Ciphertext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let bytes = bincode::serialize(self).expect("class group elements always serialize");
+        wire::write_var_bytes(buffer, &bytes);
+    }
+}
+
+impl wire::Decode for Ciphertext {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+        let ciphertext = bincode::deserialize::<Ciphertext>(bytes)
+            .map_err(|_| wire::DecodeError::InvalidCiphertext)?;
+
+        // whether it is encrypted under the right key is checked by `verify` and `Decrypt`
+        if !is_well_formed(&ciphertext) {
+            return Err(wire::DecodeError::InvalidCiphertext);
+        }
+
+        Ok(ciphertext)
+    }
+}
+
+impl wire::Encode for Proof {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let bytes = bincode::serialize(self).expect("class group proofs always serialize");
+        wire::write_var_bytes(buffer, &bytes);
+    }
+}
+
+impl wire::Decode for Proof {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+        let proof =
+            bincode::deserialize::<Proof>(bytes).map_err(|_| wire::DecodeError::InvalidProof)?;
+
+        // the fields of the proof are private to `class_group`, so all we can insist on here is a
+        // canonical encoding, the rest is up to `verify`
+        if bincode::serialize(&proof).ok().as_deref() != Some(bytes) {
+            return Err(wire::DecodeError::InvalidProof);
+        }
+
+        Ok(proof)
+    }
+}
+
+impl wire::Encode for KeyPair {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let bytes = bincode::serialize(&self.hsmcl).expect("HSM-CL keys always serialize");
+        wire::write_var_bytes(buffer, &bytes);
+        wire::write_var_bytes(buffer, &BigInt::to_vec(&self.public_key.public_setup));
+    }
+}
+
+impl wire::Decode for KeyPair {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let bytes = reader.read_var_bytes()?;
+        let hsmcl = bincode::deserialize::<HSMCL>(bytes)
+            .map_err(|_| wire::DecodeError::InvalidEncryptionKey)?;
+        // our own key, so the public setup stored with it is the one we generated it from
+        let public_key = PublicKey {
+            pk: hsmcl.pk.clone(),
+            public_setup: BigInt::from(reader.read_var_bytes()?),
+        };
+
+        Ok(KeyPair { hsmcl, public_key })
+    }
+}
+
+impl wire::Encode for PublicKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let pk = bincode::serialize(&self.pk).expect("HSM-CL keys always serialize");
+        wire::write_var_bytes(buffer, &pk);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -92,13 +373,7 @@ mod test {
 
         let (ciphertext, proof) = encrypt(public_key, &msg);
 
-        assert!(verify(
-            public_key,
-            &ciphertext,
-            msg.public_key(),
-            &proof,
-            &public_setup
-        ));
+        assert!(verify(public_key, &ciphertext, msg.public_key(), &proof));
 
         assert_eq!(
             decrypt(&kp, ciphertext.clone()),
@@ -116,13 +391,7 @@ mod test {
         );
 
         assert!(
-            !verify(
-                public_key,
-                &blinded_ciphertext,
-                msg.public_key(),
-                &proof,
-                &public_setup
-            ),
+            !verify(public_key, &blinded_ciphertext, msg.public_key(), &proof),
             "proof should not longer work on mutated ciphertext"
         );
 
@@ -135,4 +404,57 @@ mod test {
             "cipthertext multiplication produced same result as scalar multiplication"
         )
     }
+
+    #[test]
+    fn decode_rejects_ciphertext_that_is_not_reduced() {
+        let form = |a: i64, b: i64, c: i64| BinaryQF {
+            a: BigInt::from(a),
+            b: BigInt::from(b),
+            c: BigInt::from(c),
+        };
+        let roundtrip = |ciphertext: &Ciphertext| {
+            let mut buffer = Vec::new();
+            wire::Encode::encode(ciphertext, &mut buffer);
+            <Ciphertext as wire::Decode>::decode(&mut wire::Reader::new(&buffer))
+        };
+
+        // both of discriminant -59
+        let reduced = Ciphertext {
+            c1: form(3, 1, 5),
+            c2: form(1, 1, 15),
+        };
+        let not_reduced = Ciphertext {
+            c1: form(5, 1, 3),
+            c2: form(1, 1, 15),
+        };
+
+        assert!(roundtrip(&reduced).is_ok());
+        assert!(roundtrip(&not_reduced).is_err());
+    }
+
+    #[test]
+    fn public_key_is_used_with_the_local_public_setup() {
+        let kp = KeyPair::gen(&BigInt::from(b"A2L-PoC".as_ref()));
+        let msg = crate::secp256k1::KeyPair::random(&mut rand::thread_rng());
+        let (ciphertext, proof) = encrypt(kp.public_key(), &msg);
+
+        let mut buffer = Vec::new();
+        wire::Encode::encode(kp.public_key(), &mut buffer);
+        let decode = |public_setup: &[u8]| {
+            PublicKey::decode(&mut wire::Reader::new(&buffer), public_setup).unwrap()
+        };
+
+        assert!(verify(
+            &decode(b"A2L-PoC"),
+            &ciphertext,
+            msg.public_key(),
+            &proof
+        ));
+        assert!(!verify(
+            &decode(b"other setup"),
+            &ciphertext,
+            msg.public_key(),
+            &proof
+        ));
+    }
 }
@@ -0,0 +1,484 @@
+//! How the two parties of a joint output sign the transactions spending it, for either type of
+//! joint output.
+//!
+//! Both protocols follow the same steps: each party announces a nonce for the redeem signature,
+//! the party funding the joint output (`X_from`) hands out its share of the redeem signature
+//! encrypted under a puzzle `Y`, and the party redeeming (`X_to`) adds its own share. Whoever
+//! knows the solution to `Y` can then complete the redeem transaction, and the funding party
+//! learns the solution from it once it is published.
+//!
+//! With a P2WSH joint output ([`Ecdsa`]) the shares are two ECDSA signatures and there are no
+//! nonces to announce. With a P2TR joint output ([`Schnorr`]) the shares are MuSig2 partial
+//! signatures, which add up to a single signature under the output key.
+
+use crate::bitcoin::{self, OutputType, SigHash, Transaction, Transactions};
+use crate::secp256k1::musig::{self, PartialSignature, PublicNonce, SecretNonce};
+use crate::secp256k1::{
+    self, schnorr, schnorr_enc, AdaptorSignatureScheme, Ecdsa, KeyPair, PublicKey, Schnorr,
+};
+use crate::taproot::{self, FundOutput};
+use crate::{wire, Params, Protocol};
+use ::bitcoin::hashes::Hash;
+use anyhow::Context;
+use std::fmt::Debug;
+
+/// An adaptor signature scheme together with the joint output it is used with.
+pub trait JointOutputScheme: AdaptorSignatureScheme {
+    const OUTPUT_TYPE: OutputType;
+
+    /// What a party announces before signing the redeem transaction.
+    type RedeemNonce: Clone + Debug + wire::Encode + wire::Decode;
+    /// What a party keeps to itself between announcing its nonce and signing. It is never
+    /// written to a snapshot, see `restored_secret_redeem_nonce`.
+    type SecretRedeemNonce;
+    /// The share of the redeem signature of `X_from`, encrypted under `Y`.
+    type EncryptedRedeemShare: Clone + Debug + wire::Encode + wire::Decode;
+    /// The share of the redeem signature of `X_to`.
+    type RedeemShare: Clone + Debug + wire::Encode + wire::Decode;
+
+    /// Draws the nonce of `x` for signing the redeem transaction of `transactions`, which spends
+    /// the joint output of `X_from` and `X_to`.
+    fn redeem_nonce<R: rand::Rng>(
+        x: &KeyPair,
+        X_from: &PublicKey,
+        X_to: &PublicKey,
+        transactions: &Transactions,
+        rng: &mut R,
+    ) -> (Self::SecretRedeemNonce, Self::RedeemNonce);
+
+    /// The secret nonce of a party restored from a snapshot, if the scheme can do without the one
+    /// drawn before. `None` means the party has to start over instead of signing.
+    fn restored_secret_redeem_nonce() -> Option<Self::SecretRedeemNonce>;
+
+    fn encsign_redeem<R: rand::Rng>(
+        session: &RedeemSession<Self>,
+        x_from: &KeyPair,
+        nonce: Self::SecretRedeemNonce,
+        rng: &mut R,
+    ) -> anyhow::Result<Self::EncryptedRedeemShare>;
+
+    fn encverify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::EncryptedRedeemShare,
+    ) -> anyhow::Result<()>;
+
+    fn sign_redeem(
+        session: &RedeemSession<Self>,
+        x_to: &KeyPair,
+        nonce: Self::SecretRedeemNonce,
+    ) -> anyhow::Result<Self::RedeemShare>;
+
+    fn verify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::RedeemShare,
+    ) -> anyhow::Result<()>;
+
+    /// Decrypts the share of `X_from` with `y` and adds both shares to `transaction`, after
+    /// checking that they make a valid redeem signature.
+    fn complete_redeem_transaction(
+        session: &RedeemSession<Self>,
+        transaction: Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        share: &Self::RedeemShare,
+        y: &KeyPair,
+    ) -> anyhow::Result<Transaction>;
+
+    /// Recovers the discrete log of `Y` from the published `redeem_transaction`.
+    fn recover_from_redeem_transaction(
+        session: &RedeemSession<Self>,
+        redeem_transaction: &Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        share: &Self::RedeemShare,
+    ) -> anyhow::Result<KeyPair>;
+
+    /// Adds the refund signatures of both parties to `transaction`.
+    fn complete_refund_transaction(
+        transaction: Transaction,
+        from: (PublicKey, Self::Signature),
+        to: (PublicKey, Self::Signature),
+    ) -> anyhow::Result<Transaction>;
+}
+
+/// Everything both parties agreed on to sign the redeem transaction once their nonces are known.
+pub struct RedeemSession<S: JointOutputScheme + ?Sized> {
+    X_from: PublicKey,
+    X_to: PublicKey,
+    refund_locktime: u32,
+    redeem_tx_digest: SigHash,
+    Y: PublicKey,
+    nonce_from: S::RedeemNonce,
+    nonce_to: S::RedeemNonce,
+}
+
+impl<S: JointOutputScheme> RedeemSession<S> {
+    pub fn new(
+        X_from: PublicKey,
+        X_to: PublicKey,
+        transactions: &Transactions,
+        Y: PublicKey,
+        nonce_from: S::RedeemNonce,
+        nonce_to: S::RedeemNonce,
+    ) -> Self {
+        Self {
+            X_from,
+            X_to,
+            refund_locktime: transactions.refund.lock_time,
+            redeem_tx_digest: transactions.redeem_tx_digest,
+            Y,
+            nonce_from,
+            nonce_to,
+        }
+    }
+
+    pub fn redeem_tx_digest(&self) -> &SigHash {
+        &self.redeem_tx_digest
+    }
+
+    pub fn nonce_from(&self) -> &S::RedeemNonce {
+        &self.nonce_from
+    }
+
+    pub fn nonce_to(&self) -> &S::RedeemNonce {
+        &self.nonce_to
+    }
+}
+
+impl<S: JointOutputScheme> Clone for RedeemSession<S> {
+    fn clone(&self) -> Self {
+        Self {
+            X_from: self.X_from.clone(),
+            X_to: self.X_to.clone(),
+            refund_locktime: self.refund_locktime,
+            redeem_tx_digest: self.redeem_tx_digest,
+            Y: self.Y.clone(),
+            nonce_from: self.nonce_from.clone(),
+            nonce_to: self.nonce_to.clone(),
+        }
+    }
+}
+
+impl<S: JointOutputScheme> Debug for RedeemSession<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedeemSession")
+            .field("X_from", &self.X_from)
+            .field("X_to", &self.X_to)
+            .field("refund_locktime", &self.refund_locktime)
+            .field("redeem_tx_digest", &self.redeem_tx_digest)
+            .field("Y", &self.Y)
+            .field("nonce_from", &self.nonce_from)
+            .field("nonce_to", &self.nonce_to)
+            .finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "the secret nonce of the redeem signature is not kept across restarts, start a new session"
+)]
+pub struct SecretNonceLost;
+
+/// Returns the secret nonce a party drew, or the one of [`S::restored_secret_redeem_nonce`] if
+/// the party was restored from a snapshot since.
+///
+/// [`S::restored_secret_redeem_nonce`]: JointOutputScheme::restored_secret_redeem_nonce
+pub(crate) fn secret_redeem_nonce<S: JointOutputScheme>(
+    nonce: Option<S::SecretRedeemNonce>,
+) -> anyhow::Result<S::SecretRedeemNonce> {
+    Ok(nonce
+        .or_else(S::restored_secret_redeem_nonce)
+        .ok_or(SecretNonceLost)?)
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("params are for a {actual:?} joint output, expected {expected:?}")]
+pub struct OutputTypeMismatch {
+    expected: OutputType,
+    actual: OutputType,
+}
+
+/// Builds the transactions of `protocol` from `params`, after checking that they are meant for
+/// the joint output of `S` and that the partial fund transaction can pay for it.
+///
+/// Parties call this rather than `bitcoin::make_transactions`, because params that were not
+/// created through [`Params::new`], e.g. decoded from the wire, are not validated yet.
+pub fn make_transactions<S: JointOutputScheme>(
+    params: &Params,
+    protocol: Protocol,
+    X_from: &PublicKey,
+    X_to: &PublicKey,
+) -> anyhow::Result<Transactions> {
+    let actual = params.terms().output_type;
+    if actual != S::OUTPUT_TYPE {
+        anyhow::bail!(OutputTypeMismatch {
+            expected: S::OUTPUT_TYPE,
+            actual,
+        })
+    }
+    params.validate_partial_fund_transaction(protocol)?;
+
+    Ok(bitcoin::make_transactions(
+        params.partial_fund_transaction.clone(),
+        X_from,
+        X_to,
+        &params.joint_output(protocol),
+    ))
+}
+
+/// Two ECDSA signatures spending the redeem branch of a P2WSH joint output.
+impl JointOutputScheme for Ecdsa {
+    const OUTPUT_TYPE: OutputType = OutputType::P2wsh;
+
+    type RedeemNonce = ();
+    type SecretRedeemNonce = ();
+    type EncryptedRedeemShare = secp256k1::EncryptedSignature;
+    type RedeemShare = secp256k1::Signature;
+
+    fn redeem_nonce<R: rand::Rng>(
+        _: &KeyPair,
+        _: &PublicKey,
+        _: &PublicKey,
+        _: &Transactions,
+        _: &mut R,
+    ) -> ((), ()) {
+        ((), ())
+    }
+
+    fn restored_secret_redeem_nonce() -> Option<()> {
+        Some(())
+    }
+
+    fn encsign_redeem<R: rand::Rng>(
+        session: &RedeemSession<Self>,
+        x_from: &KeyPair,
+        (): (),
+        rng: &mut R,
+    ) -> anyhow::Result<Self::EncryptedRedeemShare> {
+        Ok(secp256k1::encsign(
+            session.redeem_tx_digest,
+            x_from,
+            &session.Y,
+            rng,
+        ))
+    }
+
+    fn encverify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::EncryptedRedeemShare,
+    ) -> anyhow::Result<()> {
+        Ok(secp256k1::encverify(
+            &session.X_from,
+            &session.Y,
+            &session.redeem_tx_digest.into_inner(),
+            share,
+        )?)
+    }
+
+    fn sign_redeem(
+        session: &RedeemSession<Self>,
+        x_to: &KeyPair,
+        (): (),
+    ) -> anyhow::Result<Self::RedeemShare> {
+        Ok(secp256k1::sign(session.redeem_tx_digest, x_to))
+    }
+
+    fn verify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::RedeemShare,
+    ) -> anyhow::Result<()> {
+        Ok(secp256k1::verify(
+            session.redeem_tx_digest,
+            share,
+            &session.X_to,
+        )?)
+    }
+
+    fn complete_redeem_transaction(
+        session: &RedeemSession<Self>,
+        transaction: Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        share: &Self::RedeemShare,
+        y: &KeyPair,
+    ) -> anyhow::Result<Transaction> {
+        let sig_from = secp256k1::decsig(y, encrypted_share);
+        secp256k1::verify(session.redeem_tx_digest, &sig_from, &session.X_from)
+            .context("failed to verify redeem signature after decryption")?;
+
+        bitcoin::complete_redeem_transaction(
+            transaction,
+            (session.X_from.clone(), sig_from),
+            (session.X_to.clone(), share.clone()),
+        )
+    }
+
+    fn recover_from_redeem_transaction(
+        session: &RedeemSession<Self>,
+        redeem_transaction: &Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        _: &Self::RedeemShare,
+    ) -> anyhow::Result<KeyPair> {
+        let sig_from = bitcoin::extract_signature_by_key(
+            redeem_transaction.clone(),
+            session.redeem_tx_digest,
+            &session.X_from,
+        )?;
+
+        Self::recover(&session.Y, encrypted_share, &sig_from)
+    }
+
+    fn complete_refund_transaction(
+        transaction: Transaction,
+        from: (PublicKey, Self::Signature),
+        to: (PublicKey, Self::Signature),
+    ) -> anyhow::Result<Transaction> {
+        bitcoin::complete_refund_transaction(transaction, from, to)
+    }
+}
+
+/// A MuSig2 signature spending the key path of a P2TR joint output, and two BIP340 signatures
+/// spending its refund leaf.
+impl JointOutputScheme for Schnorr {
+    const OUTPUT_TYPE: OutputType = OutputType::P2tr;
+
+    type RedeemNonce = PublicNonce;
+    type SecretRedeemNonce = SecretNonce;
+    /// A partial signature in a session whose aggregate nonce is offset by `Y`, which is what
+    /// encrypts it.
+    type EncryptedRedeemShare = PartialSignature;
+    type RedeemShare = PartialSignature;
+
+    fn redeem_nonce<R: rand::Rng>(
+        x: &KeyPair,
+        X_from: &PublicKey,
+        X_to: &PublicKey,
+        transactions: &Transactions,
+        rng: &mut R,
+    ) -> (SecretNonce, PublicNonce) {
+        let fund_output = FundOutput::new(X_from, X_to, transactions.refund.lock_time);
+
+        SecretNonce::new(
+            rng,
+            x,
+            fund_output.key_agg_context(),
+            &transactions.redeem_tx_digest.into_inner(),
+        )
+    }
+
+    /// The nonce announced before is part of the other party's session, which a fresh one would
+    /// not match.
+    fn restored_secret_redeem_nonce() -> Option<SecretNonce> {
+        None
+    }
+
+    fn encsign_redeem<R: rand::Rng>(
+        session: &RedeemSession<Self>,
+        x_from: &KeyPair,
+        nonce: SecretNonce,
+        _: &mut R,
+    ) -> anyhow::Result<Self::EncryptedRedeemShare> {
+        musig_session(session).partial_sign(nonce, x_from)
+    }
+
+    fn encverify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::EncryptedRedeemShare,
+    ) -> anyhow::Result<()> {
+        Ok(musig_session(session).verify_partial(share, &session.nonce_from, &session.X_from)?)
+    }
+
+    fn sign_redeem(
+        session: &RedeemSession<Self>,
+        x_to: &KeyPair,
+        nonce: SecretNonce,
+    ) -> anyhow::Result<Self::RedeemShare> {
+        musig_session(session).partial_sign(nonce, x_to)
+    }
+
+    fn verify_redeem(
+        session: &RedeemSession<Self>,
+        share: &Self::RedeemShare,
+    ) -> anyhow::Result<()> {
+        Ok(musig_session(session).verify_partial(share, &session.nonce_to, &session.X_to)?)
+    }
+
+    fn complete_redeem_transaction(
+        session: &RedeemSession<Self>,
+        transaction: Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        share: &Self::RedeemShare,
+        y: &KeyPair,
+    ) -> anyhow::Result<Transaction> {
+        let fund_output = FundOutput::new(&session.X_from, &session.X_to, session.refund_locktime);
+
+        let encrypted_signature =
+            musig_session(session).aggregate_encrypted(&[encrypted_share.clone(), share.clone()]);
+        let signature = schnorr_enc::decsig(y, &encrypted_signature);
+        schnorr::verify(
+            session.redeem_tx_digest,
+            &signature,
+            fund_output.output_key(),
+        )
+        .context("failed to verify redeem signature after decryption")?;
+
+        taproot::complete_redeem_transaction(transaction, &signature)
+    }
+
+    fn recover_from_redeem_transaction(
+        session: &RedeemSession<Self>,
+        redeem_transaction: &Transaction,
+        encrypted_share: &Self::EncryptedRedeemShare,
+        share: &Self::RedeemShare,
+    ) -> anyhow::Result<KeyPair> {
+        let signature = taproot::extract_redeem_signature(redeem_transaction)?;
+        let encrypted_signature =
+            musig_session(session).aggregate_encrypted(&[encrypted_share.clone(), share.clone()]);
+
+        Self::recover(&session.Y, &encrypted_signature, &signature)
+    }
+
+    fn complete_refund_transaction(
+        transaction: Transaction,
+        (_, sig_from): (PublicKey, Self::Signature),
+        (_, sig_to): (PublicKey, Self::Signature),
+    ) -> anyhow::Result<Transaction> {
+        taproot::complete_refund_transaction(transaction, &sig_from, &sig_to)
+    }
+}
+
+/// The MuSig2 session of the redeem signature, in which the partial signatures add up to a
+/// signature encrypted under `Y`.
+fn musig_session(session: &RedeemSession<Schnorr>) -> musig::Session {
+    let fund_output = FundOutput::new(&session.X_from, &session.X_to, session.refund_locktime);
+
+    musig::Session::new_encrypted(
+        fund_output.key_agg_context(),
+        &[session.nonce_from.clone(), session.nonce_to.clone()],
+        &session.redeem_tx_digest.into_inner(),
+        &session.Y,
+    )
+}
+
+impl<S: JointOutputScheme> wire::Encode for RedeemSession<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.X_from.encode(buffer);
+        self.X_to.encode(buffer);
+        self.refund_locktime.encode(buffer);
+        self.redeem_tx_digest.encode(buffer);
+        self.Y.encode(buffer);
+        self.nonce_from.encode(buffer);
+        self.nonce_to.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for RedeemSession<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(RedeemSession {
+            X_from: PublicKey::decode(reader)?,
+            X_to: PublicKey::decode(reader)?,
+            refund_locktime: u32::decode(reader)?,
+            redeem_tx_digest: SigHash::decode(reader)?,
+            Y: PublicKey::decode(reader)?,
+            nonce_from: S::RedeemNonce::decode(reader)?,
+            nonce_to: S::RedeemNonce::decode(reader)?,
+        })
+    }
+}
@@ -1,79 +1,238 @@
 #![allow(non_snake_case)]
 
 pub mod bitcoin;
+pub mod chain;
+pub mod client;
+pub mod config;
 mod dleq;
 pub mod dummy_hsm_cl;
+pub mod fee;
 pub mod hsm_cl;
+pub mod joint_output;
+pub mod mock_chain;
 pub mod puzzle_promise;
 pub mod puzzle_solver;
 pub mod secp256k1;
+pub mod storage;
+pub mod taproot;
+pub mod transcript;
+pub mod transport;
+pub mod tumbler_service;
+pub mod watcher;
+pub mod wire;
 
 #[derive(Default, Clone)]
 pub struct Input;
 
+/// The sub-protocol a set of [`Params`] is used in, which decides who funds the joint output and
+/// how much goes into it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// The tumbler funds a joint output with the receiver.
+    PuzzlePromise,
+    /// The sender funds a joint output with the tumbler.
+    PuzzleSolver,
+}
+
+/// The amounts and timing of a tumble.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Terms {
+    pub tumble_amount: u64,
+    pub tumbler_fee: u64,
+    /// Locktime of the refund transactions.
+    pub expiry: u32,
+    /// Fee rate of the redeem and refund transactions, in satoshi per virtual byte.
+    pub spend_transaction_fee_per_vbyte: u64,
+    /// Whether the redeem and refund transactions get an anchor output, through which either
+    /// party can bump the fee (CPFP).
+    pub anchor_outputs: bool,
+    /// The joint output, which decides the signature scheme both parties run the protocol with.
+    /// See [`joint_output::JointOutputScheme`].
+    pub output_type: bitcoin::OutputType,
+}
+
 #[derive(Clone, Debug)]
 pub struct Params {
     pub redeem_identity: bitcoin::Address,
     pub refund_identity: bitcoin::Address,
-    pub expiry: u32,
-
-    tumble_amount: u64,
-    tumbler_fee: u64,
-    spend_transaction_fee_per_wu: u64,
+    terms: Terms,
     /// A fully-funded transaction that is only missing the joint output.
     ///
     /// Fully-funded means we expect this transaction to have enough inputs to pay the joint output
     /// of value `amount` and in addition have one or more change outputs that already incorporate
     /// the fee the user is willing to pay.
     pub partial_fund_transaction: bitcoin::Transaction,
+    /// The outputs spent by the inputs of `partial_fund_transaction`, in order.
+    pub partial_fund_spent_outputs: Vec<bitcoin::TxOut>,
 }
 
 impl Params {
+    /// Creates the params of a run of `protocol`, checking that the partial fund transaction can
+    /// pay for the joint output.
     pub fn new(
+        protocol: Protocol,
         redeem_identity: bitcoin::Address,
         refund_identity: bitcoin::Address,
-        expiry: u32,
-        tumble_amount: u64,
-        tumbler_fee: u64,
-        spend_transaction_fee_per_wu: u64,
+        terms: Terms,
         partial_fund_transaction: bitcoin::Transaction,
-    ) -> Self {
-        Self {
+        partial_fund_spent_outputs: Vec<bitcoin::TxOut>,
+    ) -> Result<Self, bitcoin::InvalidPartialFundTransaction> {
+        let params = Self {
             redeem_identity,
             refund_identity,
-            expiry,
-            tumble_amount,
-            tumbler_fee,
-            spend_transaction_fee_per_wu,
+            terms,
             partial_fund_transaction,
+            partial_fund_spent_outputs,
+        };
+
+        params.validate_partial_fund_transaction(protocol)?;
+
+        Ok(params)
+    }
+
+    pub fn terms(&self) -> &Terms {
+        &self.terms
+    }
+
+    /// Checks that the partial fund transaction can be completed into the fund transaction of
+    /// `protocol`.
+    ///
+    /// Parties call this before building the protocol transactions, because params that were not
+    /// created through [`Params::new`], e.g. decoded from the wire, are not validated yet.
+    pub fn validate_partial_fund_transaction(
+        &self,
+        protocol: Protocol,
+    ) -> Result<(), bitcoin::InvalidPartialFundTransaction> {
+        bitcoin::validate_partial_fund_transaction(
+            &self.partial_fund_transaction,
+            &self.partial_fund_spent_outputs,
+            self.joint_output_value(protocol),
+        )
+    }
+
+    /// Returns the joint output of `protocol` and what its redeem and refund transactions pay.
+    pub fn joint_output(&self, protocol: Protocol) -> bitcoin::JointOutput {
+        bitcoin::JointOutput {
+            output_type: self.terms.output_type,
+            value: self.joint_output_value(protocol),
+            spend_value: self.joint_output_takeout(protocol),
+            refund_locktime: self.terms.expiry,
+            redeem_identity: self.redeem_identity.clone(),
+            refund_identity: self.refund_identity.clone(),
+            anchor_outputs: self.terms.anchor_outputs,
+        }
+    }
+
+    /// Returns how much the funding party has to put into the joint output of `protocol`.
+    pub fn joint_output_value(&self, protocol: Protocol) -> u64 {
+        match protocol {
+            Protocol::PuzzlePromise => self.tumbler_receiver_joint_output_value(),
+            Protocol::PuzzleSolver => self.sender_tumbler_joint_output_value(),
+        }
+    }
+
+    /// Returns how much the redeeming party is supposed to take out of the joint output of
+    /// `protocol`.
+    pub fn joint_output_takeout(&self, protocol: Protocol) -> u64 {
+        match protocol {
+            Protocol::PuzzlePromise => self.tumbler_receiver_joint_output_takeout(),
+            Protocol::PuzzleSolver => self.sender_tumbler_joint_output_takeout(),
         }
     }
 
     /// Returns how much the sender has to put into the joint output in the fund transaction.
     pub fn sender_tumbler_joint_output_value(&self) -> u64 {
         self.sender_tumbler_joint_output_takeout()
-            + bitcoin::MAX_SATISFACTION_WEIGHT * self.spend_transaction_fee_per_wu
+            + self.spend_transaction_fee()
+            + self.anchor_outputs_value()
     }
 
     /// Returns how much the tumbler is supposed to take out of the joint output funded by the sender.
     pub fn sender_tumbler_joint_output_takeout(&self) -> u64 {
-        self.tumble_amount + self.tumbler_fee
+        self.terms.tumble_amount + self.terms.tumbler_fee
     }
 
     /// Returns how much the tumbler has to put into the joint output in the fund transaction.
     pub fn tumbler_receiver_joint_output_value(&self) -> u64 {
         self.tumbler_receiver_joint_output_takeout()
-            + bitcoin::MAX_SATISFACTION_WEIGHT * self.spend_transaction_fee_per_wu
+            + self.spend_transaction_fee()
+            + self.anchor_outputs_value()
     }
 
     /// Returns how much the receiver is supposed to take out of the joint output funded by the tumbler.
     pub fn tumbler_receiver_joint_output_takeout(&self) -> u64 {
-        self.tumble_amount
+        self.terms.tumble_amount
+    }
+
+    /// Returns the fee set aside in a joint output for the transaction spending it.
+    pub fn spend_transaction_fee(&self) -> u64 {
+        fee::spend_transaction_fee(
+            self.terms.output_type,
+            self.terms.expiry,
+            &self.redeem_identity,
+            &self.refund_identity,
+            self.terms.spend_transaction_fee_per_vbyte,
+            self.terms.anchor_outputs,
+        )
+    }
+
+    /// Returns the value of the anchor output of the transaction spending a joint output.
+    pub fn anchor_outputs_value(&self) -> u64 {
+        if self.terms.anchor_outputs {
+            bitcoin::ANCHOR_VALUE
+        } else {
+            0
+        }
+    }
+}
+
+impl wire::Encode for Params {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.redeem_identity.encode(buffer);
+        self.refund_identity.encode(buffer);
+        self.terms.expiry.encode(buffer);
+        self.terms.tumble_amount.encode(buffer);
+        self.terms.tumbler_fee.encode(buffer);
+        self.terms.spend_transaction_fee_per_vbyte.encode(buffer);
+        self.partial_fund_transaction.encode(buffer);
+        self.partial_fund_spent_outputs.encode(buffer);
+        self.terms.anchor_outputs.encode(buffer);
+        self.terms.output_type.encode(buffer);
+    }
+}
+
+impl wire::Decode for Params {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let redeem_identity = bitcoin::Address::decode(reader)?;
+        let refund_identity = bitcoin::Address::decode(reader)?;
+        let expiry = u32::decode(reader)?;
+        let tumble_amount = u64::decode(reader)?;
+        let tumbler_fee = u64::decode(reader)?;
+        let spend_transaction_fee_per_vbyte = u64::decode(reader)?;
+        let partial_fund_transaction = bitcoin::Transaction::decode(reader)?;
+        let partial_fund_spent_outputs = Vec::decode(reader)?;
+        let anchor_outputs = bool::decode(reader)?;
+        let output_type = bitcoin::OutputType::decode(reader)?;
+
+        Ok(Params {
+            redeem_identity,
+            refund_identity,
+            terms: Terms {
+                tumble_amount,
+                tumbler_fee,
+                expiry,
+                spend_transaction_fee_per_vbyte,
+                anchor_outputs,
+                output_type,
+            },
+            partial_fund_transaction,
+            partial_fund_spent_outputs,
+        })
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct Lock {
-    pub c_alpha_prime: dummy_hsm_cl::Ciphertext,
+pub struct Lock<C> {
+    pub c_alpha_prime: C,
     pub A_prime: secp256k1::PublicKey,
 }
@@ -0,0 +1,502 @@
+//! An in-memory blockchain for exercising the protocols without a bitcoind.
+//!
+//! [`MockChain`] keeps a UTXO set, a mempool and the timestamps of its blocks. Transactions enter
+//! the mempool through [`MockChain::broadcast`], which enforces the rules the protocols rely on:
+//! every input must spend an unspent output and satisfy its script, no two transactions may spend
+//! the same output and a time-locked transaction is only accepted once it could be included in
+//! the next block. [`MockChain::mine`] confirms everything in the mempool.
+
+use crate::bitcoin::{self, OutPoint, Transaction, TxOut, Txid};
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+/// The seconds between two mined blocks, unless a block is mined with an explicit timestamp.
+pub const BLOCK_INTERVAL: u32 = 600;
+
+/// The lowest `nLockTime` that is interpreted as a UNIX timestamp instead of a block height.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+
+/// The number of blocks the median time past is computed over.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+const GENESIS_TIME: u32 = 1_231_006_505;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum Rejected {
+    #[error("transaction has no inputs")]
+    NoInputs,
+    #[error("output {0} does not exist or is already spent")]
+    MissingInput(OutPoint),
+    #[error("output {0} is already spent by transaction {1} in the mempool")]
+    DoubleSpend(OutPoint, Txid),
+    #[error("transaction is locked until {0}")]
+    NonFinal(u32),
+    #[error("transaction spends more than its inputs are worth")]
+    ValueOutOfRange,
+    #[error("transaction {0} is already known")]
+    AlreadyKnown(Txid),
+    #[error("input {0} is invalid: {1}")]
+    InvalidInput(usize, bitcoin::InvalidSpendTransaction),
+}
+
+pub struct MockChain {
+    utxos: HashMap<OutPoint, TxOut>,
+    /// The timestamps of all blocks, starting with the genesis block at height 0.
+    block_times: Vec<u32>,
+    /// Every confirmed transaction and the block height it was confirmed at.
+    confirmed: HashMap<Txid, (Transaction, u32)>,
+    /// The confirmed transaction that spent each output which is no longer unspent.
+    spenders: HashMap<OutPoint, Transaction>,
+    /// Transactions in the order they were accepted, so that a child always follows its parent.
+    mempool: Vec<Transaction>,
+    watchers: Vec<(OutPoint, mpsc::Sender<Transaction>)>,
+    fee_estimate_per_vbyte: Option<u64>,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockChain {
+    /// Creates a chain that only consists of the genesis block.
+    pub fn new() -> Self {
+        Self {
+            utxos: HashMap::new(),
+            block_times: vec![GENESIS_TIME],
+            confirmed: HashMap::new(),
+            spenders: HashMap::new(),
+            mempool: Vec::new(),
+            watchers: Vec::new(),
+            fee_estimate_per_vbyte: None,
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.block_times.len() as u32 - 1
+    }
+
+    /// Returns the median timestamp of the last 11 blocks, against which time-based locktimes are
+    /// checked (BIP113).
+    pub fn median_time_past(&self) -> u32 {
+        let start = self.block_times.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = self.block_times[start..].to_vec();
+        times.sort_unstable();
+
+        times[times.len() / 2]
+    }
+
+    /// Confirms `transaction` in a new block without looking at its inputs.
+    ///
+    /// This is how coins enter the chain, e.g. a fund transaction whose inputs belong to a wallet
+    /// that is not part of the simulation.
+    pub fn mine_external(&mut self, transaction: Transaction) {
+        self.mine_with(vec![transaction], self.next_block_time());
+    }
+
+    /// Validates `transaction` and adds it to the mempool.
+    ///
+    /// Watchers of the outputs it spends are notified right away, just like a node watching the
+    /// mempool would learn about a spend before it is confirmed.
+    pub fn broadcast(&mut self, transaction: Transaction) -> Result<Txid, Rejected> {
+        let txid = transaction.txid();
+
+        if self.confirmed.contains_key(&txid) || self.mempool.iter().any(|tx| tx.txid() == txid) {
+            return Err(Rejected::AlreadyKnown(txid));
+        }
+        if transaction.input.is_empty() {
+            return Err(Rejected::NoInputs);
+        }
+        if !self.is_final(&transaction) {
+            return Err(Rejected::NonFinal(transaction.lock_time));
+        }
+
+        let mut spent_outputs = Vec::with_capacity(transaction.input.len());
+        for input in transaction.input.iter() {
+            if let Some(spender) = self.mempool_spender_of(&input.previous_output) {
+                return Err(Rejected::DoubleSpend(input.previous_output, spender));
+            }
+
+            spent_outputs.push(
+                self.unspent_output(&input.previous_output)
+                    .ok_or(Rejected::MissingInput(input.previous_output))?,
+            );
+        }
+        // Taproot signatures commit to the outputs spent by all inputs
+        for index in 0..transaction.input.len() {
+            bitcoin::verify_input(&transaction, index, &spent_outputs)
+                .map_err(|e| Rejected::InvalidInput(index, e))?;
+        }
+        let input_value = spent_outputs.iter().map(|output| output.value).sum::<u64>();
+
+        let output_value = transaction
+            .output
+            .iter()
+            .map(|output| output.value)
+            .sum::<u64>();
+        if output_value > input_value {
+            return Err(Rejected::ValueOutOfRange);
+        }
+
+        self.notify_watchers(&transaction);
+        self.mempool.push(transaction);
+
+        Ok(txid)
+    }
+
+    /// Mines a block `BLOCK_INTERVAL` seconds after the current tip, confirming the whole mempool.
+    pub fn mine(&mut self) {
+        self.mine_at(self.next_block_time());
+    }
+
+    /// Mines a block with the given timestamp, confirming the whole mempool.
+    pub fn mine_at(&mut self, time: u32) {
+        let transactions = std::mem::take(&mut self.mempool);
+
+        self.mine_with(transactions, time);
+    }
+
+    /// Mines empty blocks until the tip is at `height`.
+    pub fn mine_until(&mut self, height: u32) {
+        while self.height() < height {
+            self.mine();
+        }
+    }
+
+    /// Returns the number of blocks that confirm `txid`, or `None` if it is not in the chain.
+    pub fn confirmations(&self, txid: &Txid) -> Option<u32> {
+        self.confirmed
+            .get(txid)
+            .map(|(_, height)| self.height() - height + 1)
+    }
+
+    /// Returns a confirmed transaction or one from the mempool.
+    pub fn transaction(&self, txid: &Txid) -> Option<Transaction> {
+        match self.confirmed.get(txid) {
+            Some((transaction, _)) => Some(transaction.clone()),
+            None => self.mempool.iter().find(|tx| tx.txid() == *txid).cloned(),
+        }
+    }
+
+    /// Sets the fee rate returned by [`MockChain::fee_estimate_per_vbyte`]. There is none by
+    /// default, like on a fresh regtest chain.
+    pub fn set_fee_estimate_per_vbyte(&mut self, fee_per_vbyte: u64) {
+        self.fee_estimate_per_vbyte = Some(fee_per_vbyte);
+    }
+
+    pub fn fee_estimate_per_vbyte(&self) -> Option<u64> {
+        self.fee_estimate_per_vbyte
+    }
+
+    pub fn mempool(&self) -> &[Transaction] {
+        &self.mempool
+    }
+
+    /// Returns the output if it exists and is neither spent in the chain nor in the mempool.
+    pub fn unspent(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        match self.mempool_spender_of(outpoint) {
+            Some(_) => None,
+            None => self.unspent_output(outpoint),
+        }
+    }
+
+    /// Returns the transaction spending `outpoint`, whether it is confirmed or in the mempool.
+    pub fn spending_transaction(&self, outpoint: &OutPoint) -> Option<Transaction> {
+        self.spenders.get(outpoint).cloned().or_else(|| {
+            self.mempool
+                .iter()
+                .find(|transaction| spends(transaction, outpoint))
+                .cloned()
+        })
+    }
+
+    /// Returns a channel on which every transaction spending `outpoint` is delivered once it
+    /// enters the mempool, or immediately if it already was spent.
+    pub fn watch(&mut self, outpoint: OutPoint) -> mpsc::Receiver<Transaction> {
+        let (sender, receiver) = mpsc::channel();
+
+        if let Some(spender) = self.spending_transaction(&outpoint) {
+            let _ = sender.send(spender);
+        }
+        self.watchers.push((outpoint, sender));
+
+        receiver
+    }
+
+    /// Mirrors `IsFinalTx`: a transaction may be included in the next block if its locktime is
+    /// already in the past or none of its inputs opt into locktime enforcement.
+    fn is_final(&self, transaction: &Transaction) -> bool {
+        let lock_time = transaction.lock_time;
+        let threshold = if lock_time < LOCKTIME_THRESHOLD {
+            self.height() + 1
+        } else {
+            self.median_time_past()
+        };
+
+        lock_time == 0
+            || lock_time < threshold
+            || transaction
+                .input
+                .iter()
+                .all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    /// Looks up an output of a confirmed transaction or of a transaction in the mempool, ignoring
+    /// whether the mempool spends it.
+    fn unspent_output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.utxos.get(outpoint).cloned().or_else(|| {
+            self.mempool
+                .iter()
+                .find(|tx| tx.txid() == outpoint.txid)
+                .and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
+        })
+    }
+
+    fn mempool_spender_of(&self, outpoint: &OutPoint) -> Option<Txid> {
+        self.mempool
+            .iter()
+            .find(|tx| spends(tx, outpoint))
+            .map(Transaction::txid)
+    }
+
+    fn next_block_time(&self) -> u32 {
+        self.block_times[self.block_times.len() - 1] + BLOCK_INTERVAL
+    }
+
+    fn mine_with(&mut self, transactions: Vec<Transaction>, time: u32) {
+        self.block_times.push(time);
+        let height = self.height();
+
+        for transaction in transactions {
+            let txid = transaction.txid();
+
+            for input in transaction.input.iter() {
+                self.utxos.remove(&input.previous_output);
+                self.spenders
+                    .insert(input.previous_output, transaction.clone());
+            }
+            for (vout, output) in transaction.output.iter().enumerate() {
+                self.utxos.insert(
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    output.clone(),
+                );
+            }
+
+            self.confirmed.insert(txid, (transaction, height));
+        }
+    }
+
+    fn notify_watchers(&mut self, transaction: &Transaction) {
+        // watchers whose receiver is gone are dropped
+        self.watchers.retain(|(outpoint, sender)| {
+            !spends(transaction, outpoint) || sender.send(transaction.clone()).is_ok()
+        });
+    }
+}
+
+fn spends(transaction: &Transaction, outpoint: &OutPoint) -> bool {
+    transaction
+        .input
+        .iter()
+        .any(|input| input.previous_output == *outpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::TxIn;
+    use crate::secp256k1::XCoor;
+    use ::bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1;
+    use ::bitcoin::blockdata::script::Builder;
+
+    fn anyone_can_spend(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Builder::new().push_opcode(OP_PUSHNUM_1).into_script(),
+        }
+    }
+
+    fn funded(chain: &mut MockChain, value: u64) -> OutPoint {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: chain.height(),
+            input: Vec::new(),
+            output: vec![anyone_can_spend(value)],
+        };
+        let txid = transaction.txid();
+        chain.mine_external(transaction);
+
+        OutPoint { txid, vout: 0 }
+    }
+
+    fn spend(outpoint: OutPoint, value: u64, lock_time: u32, sequence: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Default::default(),
+                sequence,
+                witness: Vec::new(),
+            }],
+            output: vec![anyone_can_spend(value)],
+        }
+    }
+
+    #[test]
+    fn rejects_double_spend() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+
+        let first = chain
+            .broadcast(spend(outpoint, 900, 0, SEQUENCE_FINAL))
+            .unwrap();
+        let second = chain.broadcast(spend(outpoint, 800, 0, SEQUENCE_FINAL));
+        assert_eq!(second, Err(Rejected::DoubleSpend(outpoint, first)));
+
+        chain.mine();
+        let third = chain.broadcast(spend(outpoint, 800, 0, SEQUENCE_FINAL));
+        assert_eq!(third, Err(Rejected::MissingInput(outpoint)));
+        assert_eq!(chain.confirmations(&first), Some(1));
+    }
+
+    #[test]
+    fn rejects_premature_height_locktime() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+        let refund = spend(outpoint, 900, 10, SEQUENCE_FINAL - 1);
+
+        chain.mine_until(9);
+        assert_eq!(chain.broadcast(refund.clone()), Err(Rejected::NonFinal(10)));
+
+        chain.mine();
+        assert!(chain.broadcast(refund).is_ok());
+    }
+
+    #[test]
+    fn ignores_locktime_of_final_inputs() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+
+        assert!(chain
+            .broadcast(spend(outpoint, 900, 10, SEQUENCE_FINAL))
+            .is_ok());
+    }
+
+    #[test]
+    fn checks_time_locktime_against_median_time_past() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+        let lock_time = GENESIS_TIME + 100 * BLOCK_INTERVAL;
+        let refund = spend(outpoint, 900, lock_time, SEQUENCE_FINAL - 1);
+
+        // the tip is past the locktime, but the median of the last 11 blocks is not
+        chain.mine_until(99);
+        chain.mine_at(lock_time + 1);
+        assert!(chain.median_time_past() < lock_time);
+        assert!(matches!(
+            chain.broadcast(refund.clone()),
+            Err(Rejected::NonFinal(_))
+        ));
+
+        chain.mine_until(106);
+        assert!(chain.median_time_past() > lock_time);
+        assert!(chain.broadcast(refund).is_ok());
+    }
+
+    #[test]
+    fn rejects_taproot_spend_without_valid_signature() {
+        let mut chain = MockChain::new();
+        let output_key = crate::secp256k1::KeyPair::random_from_thread_rng().to_pk();
+        let taproot_output = TxOut {
+            value: 1_000,
+            script_pubkey: Builder::new()
+                .push_int(1)
+                .push_slice(&output_key.x_coor())
+                .into_script(),
+        };
+        let transaction = Transaction {
+            version: 2,
+            lock_time: chain.height(),
+            input: Vec::new(),
+            output: vec![taproot_output],
+        };
+        let outpoint = OutPoint {
+            txid: transaction.txid(),
+            vout: 0,
+        };
+        chain.mine_external(transaction);
+
+        // the libbitcoinconsensus we link against would take this for an anyone-can-spend output
+        let mut spending = spend(outpoint, 900, 0, SEQUENCE_FINAL);
+        spending.input[0].witness = vec![vec![0x01; 64]];
+
+        assert!(matches!(
+            chain.broadcast(spending),
+            Err(Rejected::InvalidInput(0, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_spending_more_than_inputs() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+
+        assert_eq!(
+            chain.broadcast(spend(outpoint, 1_001, 0, SEQUENCE_FINAL)),
+            Err(Rejected::ValueOutOfRange)
+        );
+    }
+
+    #[test]
+    fn notifies_watchers_of_spends() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+        let spending = spend(outpoint, 900, 0, SEQUENCE_FINAL);
+
+        let watcher = chain.watch(outpoint);
+        assert!(watcher.try_recv().is_err());
+
+        chain.broadcast(spending.clone()).unwrap();
+        assert_eq!(watcher.try_recv().unwrap(), spending);
+
+        // a late watcher still learns about the spend, even once it is confirmed
+        assert_eq!(chain.watch(outpoint).try_recv().unwrap(), spending);
+        chain.mine();
+        assert_eq!(chain.watch(outpoint).try_recv().unwrap(), spending);
+    }
+
+    #[test]
+    fn accepts_child_of_unconfirmed_parent() {
+        let mut chain = MockChain::new();
+        let outpoint = funded(&mut chain, 1_000);
+
+        let parent = chain
+            .broadcast(spend(outpoint, 900, 0, SEQUENCE_FINAL))
+            .unwrap();
+        let child = chain
+            .broadcast(spend(
+                OutPoint {
+                    txid: parent,
+                    vout: 0,
+                },
+                800,
+                0,
+                SEQUENCE_FINAL,
+            ))
+            .unwrap();
+
+        assert_eq!(chain.transaction(&child).unwrap().txid(), child);
+
+        chain.mine();
+        assert_eq!(chain.confirmations(&parent), Some(1));
+        assert_eq!(chain.confirmations(&child), Some(1));
+        assert_eq!(chain.transaction(&child).unwrap().txid(), child);
+    }
+}
@@ -1,9 +1,10 @@
 use crate::bitcoin;
-use crate::Params;
-use crate::{dummy_hsm_cl as hsm_cl, secp256k1, Lock};
-use ::bitcoin::hashes::Hash;
+use crate::joint_output::{self, JointOutputScheme, RedeemSession};
+use crate::{hsm_cl, secp256k1, storage, wire, Lock};
+use crate::{Params, Protocol};
 use anyhow::Context;
 use rand::Rng;
+use std::marker::PhantomData;
 
 pub struct Tumbler0 {
     x_t: secp256k1::KeyPair,
@@ -13,67 +14,91 @@ pub struct Tumbler0 {
 
 pub struct Sender0;
 
-pub struct Receiver0 {
+pub struct Receiver0<S> {
     x_r: secp256k1::KeyPair,
     params: Params,
+    scheme: PhantomData<S>,
 }
 
 #[derive(Debug)]
-pub struct Sender1 {
-    l: Lock,
+pub struct Sender1<C> {
+    l: Lock<C>,
 }
 
 #[derive(Debug)]
-pub struct Tumbler1 {
+pub struct Tumbler1<S: JointOutputScheme> {
     x_t: secp256k1::KeyPair,
     a: secp256k1::KeyPair,
     signed_refund_transaction: bitcoin::Transaction,
     transactions: bitcoin::Transactions,
+    /// The outputs spent by the inputs of the fund transaction, in order.
+    fund_spent_outputs: Vec<bitcoin::TxOut>,
+    nonce_redeem_t: S::RedeemNonce,
+    sig_redeem_t: S::EncryptedRedeemShare,
 }
 
-pub struct Receiver1 {
+pub struct Receiver1<C, S: JointOutputScheme> {
     x_r: secp256k1::KeyPair,
     X_t: secp256k1::PublicKey,
-    c_alpha: hsm_cl::Ciphertext,
+    c_alpha: C,
     A: secp256k1::PublicKey,
     transactions: bitcoin::Transactions,
+    sig_refund_r: S::Signature,
+    /// `None` once restored from a snapshot, which never holds it.
+    secret_nonce_redeem_r: Option<S::SecretRedeemNonce>,
+    nonce_redeem_r: S::RedeemNonce,
 }
 
 #[derive(Debug)]
-pub struct Receiver2 {
+pub struct Receiver2<C, S: JointOutputScheme> {
     x_r: secp256k1::KeyPair,
-    X_t: secp256k1::PublicKey,
     beta: secp256k1::KeyPair,
-    c_alpha_prime: hsm_cl::Ciphertext,
+    c_alpha_prime: C,
     A_prime: secp256k1::PublicKey,
-    sig_redeem_r: secp256k1::Signature,
-    sig_redeem_t: secp256k1::EncryptedSignature,
+    redeem_session: RedeemSession<S>,
+    sig_redeem_r: S::RedeemShare,
+    sig_redeem_t: S::EncryptedRedeemShare,
     transactions: bitcoin::Transactions,
 }
 
-impl Receiver0 {
+impl<S: JointOutputScheme> Receiver0<S> {
     pub fn new(params: Params, rng: &mut impl Rng) -> Self {
         Self {
             x_r: secp256k1::KeyPair::random(rng),
             params,
+            scheme: PhantomData,
         }
     }
 
-    pub fn receive(self, Message0 { X_t, c_alpha, A }: Message0) -> anyhow::Result<Receiver1> {
-        let Receiver0 { x_r, params } = self;
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn receive<C, P>(
+        self,
+        Message0 {
+            X_t,
+            A,
+            c_alpha,
+            pi_alpha,
+        }: Message0<C, P>,
+        rng: &mut impl Rng,
+        HE: &impl hsm_cl::Verify<C, P>,
+    ) -> anyhow::Result<Receiver1<C, S>> {
+        let Receiver0 { x_r, params, .. } = self;
 
-        // TODO: Verify c_alpha with pi_alpha (line 8, Figure 6)
+        HE.verify(&c_alpha, &A, &pi_alpha)?;
 
-        let transactions = bitcoin::make_transactions(
-            params.partial_fund_transaction.clone(),
-            params.tumbler_receiver_joint_output_value(),
-            params.tumbler_receiver_joint_output_takeout(),
+        let transactions = joint_output::make_transactions::<S>(
+            &params,
+            Protocol::PuzzlePromise,
             &X_t,
             &x_r.to_pk(),
-            params.expiry,
-            &params.redeem_identity,
-            &params.refund_identity,
-        );
+        )?;
+
+        let sig_refund_r = S::sign(transactions.refund_tx_digest, &x_r, rng);
+        let (secret_nonce_redeem_r, nonce_redeem_r) =
+            S::redeem_nonce(&x_r, &X_t, &x_r.to_pk(), &transactions, rng);
 
         Ok(Receiver1 {
             x_r,
@@ -81,28 +106,33 @@ impl Receiver0 {
             c_alpha,
             A,
             transactions,
+            sig_refund_r,
+            secret_nonce_redeem_r: Some(secret_nonce_redeem_r),
+            nonce_redeem_r,
         })
     }
 }
 
-impl Receiver1 {
-    pub fn next_message(&self) -> Message1 {
-        let sig_refund_r = secp256k1::sign(self.transactions.refund_tx_digest, &self.x_r);
-
+impl<C, S: JointOutputScheme> Receiver1<C, S> {
+    pub fn next_message(&self) -> Message1<S> {
         Message1 {
             X_r: self.x_r.to_pk(),
-            sig_refund_r,
+            sig_refund_r: self.sig_refund_r.clone(),
+            nonce_redeem_r: self.nonce_redeem_r.clone(),
         }
     }
 
     pub fn receive<HE>(
         self,
-        Message2 { sig_redeem_t }: Message2,
+        Message2 {
+            nonce_redeem_t,
+            sig_redeem_t,
+        }: Message2<S>,
         rng: &mut impl Rng,
         HE: &HE,
-    ) -> anyhow::Result<Receiver2>
+    ) -> anyhow::Result<Receiver2<C, S>>
     where
-        HE: hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Pow<hsm_cl::Ciphertext>,
+        HE: hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Pow<C>,
     {
         let Self {
             x_r,
@@ -110,16 +140,25 @@ impl Receiver1 {
             A,
             c_alpha,
             transactions,
+            secret_nonce_redeem_r,
+            nonce_redeem_r,
+            ..
         } = self;
 
-        secp256k1::encverify(
-            &X_t,
-            &A,
-            &transactions.redeem_tx_digest.into_inner(),
-            &sig_redeem_t,
-        )?;
+        let redeem_session = RedeemSession::new(
+            X_t,
+            x_r.to_pk(),
+            &transactions,
+            A.clone(),
+            nonce_redeem_t,
+            nonce_redeem_r,
+        );
 
-        let sig_redeem_r = secp256k1::sign(transactions.redeem_tx_digest, &x_r);
+        S::encverify_redeem(&redeem_session, &sig_redeem_t)
+            .context("failed to verify tumbler encrypted redeem signature")?;
+
+        let secret_nonce_redeem_r = joint_output::secret_redeem_nonce::<S>(secret_nonce_redeem_r)?;
+        let sig_redeem_r = S::sign_redeem(&redeem_session, &x_r, secret_nonce_redeem_r)?;
 
         let beta = secp256k1::KeyPair::random(rng);
         let c_alpha_prime = HE.pow(&c_alpha, &beta);
@@ -127,10 +166,10 @@ impl Receiver1 {
 
         Ok(Receiver2 {
             x_r,
-            X_t,
             beta,
             c_alpha_prime,
             A_prime,
+            redeem_session,
             sig_redeem_r,
             sig_redeem_t,
             transactions,
@@ -146,65 +185,107 @@ impl Tumbler0 {
         Self { x_t, a, params }
     }
 
-    pub fn next_message(&self, HE: &impl hsm_cl::Encrypt) -> Message0 {
+    pub fn next_message<HE>(&self, HE: &HE) -> Message0<HE::Ciphertext, HE::Proof>
+    where
+        HE: hsm_cl::Encrypt,
+    {
         let X_t = self.x_t.to_pk();
         let A = self.a.to_pk();
-        let c_alpha = HE.encrypt(&self.x_t, self.a.as_ref());
-
-        // TODO: Compute pi_alpha (line 4, Figure 6)
+        let (c_alpha, pi_alpha) = HE.encrypt(&self.x_t, &self.a);
 
-        Message0 { X_t, A, c_alpha }
+        Message0 {
+            X_t,
+            A,
+            c_alpha,
+            pi_alpha,
+        }
     }
 
-    pub fn receive(self, Message1 { X_r, sig_refund_r }: Message1) -> anyhow::Result<Tumbler1> {
-        let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
-            self.params.tumbler_receiver_joint_output_value(),
-            self.params.tumbler_receiver_joint_output_takeout(),
-            &self.x_t.to_pk(),
+    pub fn receive<S: JointOutputScheme>(
+        self,
+        Message1 {
+            X_r,
+            sig_refund_r,
+            nonce_redeem_r,
+        }: Message1<S>,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Tumbler1<S>> {
+        let X_t = self.x_t.to_pk();
+        let transactions = joint_output::make_transactions::<S>(
+            &self.params,
+            Protocol::PuzzlePromise,
+            &X_t,
             &X_r,
-            self.params.expiry,
-            &self.params.redeem_identity,
-            &self.params.refund_identity,
-        );
+        )?;
 
         let signed_refund_transaction = {
-            secp256k1::verify(transactions.refund_tx_digest, &sig_refund_r, &X_r)
+            S::verify(transactions.refund_tx_digest, &sig_refund_r, &X_r)
                 .context("failed to verify receiver refund signature")?;
 
-            let sig_refund_t = secp256k1::sign(transactions.refund_tx_digest, &self.x_t);
+            let sig_refund_t = S::sign(transactions.refund_tx_digest, &self.x_t, rng);
 
-            bitcoin::complete_spend_transaction(
+            S::complete_refund_transaction(
                 transactions.refund.clone(),
-                (self.x_t.to_pk(), sig_refund_t),
-                (X_r, sig_refund_r),
+                (X_t.clone(), sig_refund_t),
+                (X_r.clone(), sig_refund_r),
             )?
         };
 
+        let (nonce_redeem_t, sig_redeem_t) = {
+            let (secret_nonce, nonce) = S::redeem_nonce(&self.x_t, &X_t, &X_r, &transactions, rng);
+            let redeem_session = RedeemSession::<S>::new(
+                X_t,
+                X_r,
+                &transactions,
+                self.a.to_pk(),
+                nonce.clone(),
+                nonce_redeem_r,
+            );
+
+            let sig_redeem_t = S::encsign_redeem(&redeem_session, &self.x_t, secret_nonce, rng)?;
+
+            (nonce, sig_redeem_t)
+        };
+
         Ok(Tumbler1 {
             x_t: self.x_t,
             signed_refund_transaction,
             a: self.a,
             transactions,
+            fund_spent_outputs: self.params.partial_fund_spent_outputs,
+            nonce_redeem_t,
+            sig_redeem_t,
         })
     }
 }
 
-impl Tumbler1 {
-    pub fn next_message(&self, rng: &mut impl Rng) -> Message2 {
-        let sig_redeem_t = secp256k1::encsign(
-            self.transactions.redeem_tx_digest,
-            &self.x_t,
-            &self.a.to_pk(),
-            rng,
-        );
-
-        Message2 { sig_redeem_t }
+impl<S: JointOutputScheme> Tumbler1<S> {
+    pub fn next_message(&self) -> Message2<S> {
+        Message2 {
+            nonce_redeem_t: self.nonce_redeem_t.clone(),
+            sig_redeem_t: self.sig_redeem_t.clone(),
+        }
     }
 
     pub fn unsigned_fund_transaction(&self) -> &bitcoin::Transaction {
         &self.transactions.fund
     }
+    /// The fund transaction as a PSBT for the tumbler's wallet to sign. See [`bitcoin::fund_psbt`].
+    pub fn unsigned_fund_psbt(&self) -> anyhow::Result<bitcoin::PartiallySignedTransaction> {
+        bitcoin::fund_psbt(
+            &self.transactions.fund,
+            &self.signed_refund_transaction,
+            &self.fund_spent_outputs,
+        )
+    }
+    /// Returns the fund transaction signed by the tumbler's wallet, after checking that the
+    /// refund transaction still spends it.
+    pub fn signed_fund_transaction(
+        &self,
+        psbt: bitcoin::PartiallySignedTransaction,
+    ) -> anyhow::Result<bitcoin::Transaction> {
+        bitcoin::extract_fund_transaction(psbt, self.transactions.fund.txid())
+    }
     pub fn signed_refund_transaction(&self) -> &bitcoin::Transaction {
         &self.signed_refund_transaction
     }
@@ -213,8 +294,8 @@ impl Tumbler1 {
     }
 }
 
-impl Receiver2 {
-    pub fn next_message(&self) -> Message3 {
+impl<C: Clone, S: JointOutputScheme> Receiver2<C, S> {
+    pub fn next_message(&self) -> Message3<C> {
         let l = Lock {
             c_alpha_prime: self.c_alpha_prime.clone(),
             A_prime: self.A_prime.clone(),
@@ -226,24 +307,21 @@ impl Receiver2 {
     pub fn x_r(&self) -> &secp256k1::KeyPair {
         &self.x_r
     }
-    pub fn X_t(&self) -> &secp256k1::PublicKey {
-        &self.X_t
+    pub fn redeem_session(&self) -> &RedeemSession<S> {
+        &self.redeem_session
     }
     pub fn unsigned_redeem_transaction(&self) -> &bitcoin::Transaction {
         &self.transactions.redeem
     }
-    pub fn sig_redeem_t(&self) -> &secp256k1::EncryptedSignature {
+    pub fn sig_redeem_t(&self) -> &S::EncryptedRedeemShare {
         &self.sig_redeem_t
     }
-    pub fn sig_redeem_r(&self) -> &secp256k1::Signature {
+    pub fn sig_redeem_r(&self) -> &S::RedeemShare {
         &self.sig_redeem_r
     }
     pub fn beta(&self) -> &secp256k1::KeyPair {
         &self.beta
     }
-    pub fn redeem_tx_digest(&self) -> &bitcoin::SigHash {
-        &self.transactions.redeem_tx_digest
-    }
 }
 
 impl Sender0 {
@@ -252,34 +330,291 @@ impl Sender0 {
         Self
     }
 
-    pub fn receive(self, message: Message3) -> Sender1 {
+    pub fn receive<C>(self, message: Message3<C>) -> Sender1<C> {
         Sender1 { l: message.l }
     }
 }
 
-impl Sender1 {
-    pub fn lock(&self) -> &Lock {
+impl<C> Sender1<C> {
+    pub fn lock(&self) -> &Lock<C> {
         &self.l
     }
 }
 
-pub struct Message0 {
+pub struct Message0<C, P> {
     X_t: secp256k1::PublicKey,
     A: secp256k1::PublicKey,
-    c_alpha: hsm_cl::Ciphertext,
+    c_alpha: C,
+    pi_alpha: P,
 }
 
-pub struct Message1 {
+pub struct Message1<S: JointOutputScheme> {
     X_r: secp256k1::PublicKey,
-    sig_refund_r: secp256k1::Signature,
+    sig_refund_r: S::Signature,
+    nonce_redeem_r: S::RedeemNonce,
 }
 
-pub struct Message2 {
-    sig_redeem_t: secp256k1::EncryptedSignature,
+pub struct Message2<S: JointOutputScheme> {
+    nonce_redeem_t: S::RedeemNonce,
+    sig_redeem_t: S::EncryptedRedeemShare,
+}
+
+pub struct Message3<C> {
+    l: Lock<C>,
+}
+
+impl wire::Encode for Tumbler0 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_t.encode(buffer);
+        self.a.encode(buffer);
+        self.params.encode(buffer);
+    }
+}
+
+impl wire::Decode for Tumbler0 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Tumbler0 {
+            x_t: secp256k1::KeyPair::decode(reader)?,
+            a: secp256k1::KeyPair::decode(reader)?,
+            params: Params::decode(reader)?,
+        })
+    }
+}
+
+impl storage::Snapshot for Tumbler0 {
+    const TAG: u8 = 0x20;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Tumbler1<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_t.encode(buffer);
+        self.a.encode(buffer);
+        self.signed_refund_transaction.encode(buffer);
+        self.transactions.encode(buffer);
+        self.fund_spent_outputs.encode(buffer);
+        self.nonce_redeem_t.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Tumbler1<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Tumbler1 {
+            x_t: secp256k1::KeyPair::decode(reader)?,
+            a: secp256k1::KeyPair::decode(reader)?,
+            signed_refund_transaction: bitcoin::Transaction::decode(reader)?,
+            transactions: bitcoin::Transactions::decode(reader)?,
+            fund_spent_outputs: Vec::decode(reader)?,
+            nonce_redeem_t: S::RedeemNonce::decode(reader)?,
+            sig_redeem_t: S::EncryptedRedeemShare::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> storage::Snapshot for Tumbler1<S> {
+    const TAG: u8 = 0x21;
+}
+
+impl wire::Encode for Sender0 {
+    fn encode(&self, _buffer: &mut Vec<u8>) {}
+}
+
+impl wire::Decode for Sender0 {
+    fn decode(_reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender0)
+    }
+}
+
+impl storage::Snapshot for Sender0 {
+    const TAG: u8 = 0x22;
+}
+
+impl<C: wire::Encode> wire::Encode for Sender1<C> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.l.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode> wire::Decode for Sender1<C> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender1 {
+            l: Lock::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode> storage::Snapshot for Sender1<C> {
+    const TAG: u8 = 0x23;
+}
+
+impl<S> wire::Encode for Receiver0<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_r.encode(buffer);
+        self.params.encode(buffer);
+    }
+}
+
+impl<S> wire::Decode for Receiver0<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Receiver0 {
+            x_r: secp256k1::KeyPair::decode(reader)?,
+            params: Params::decode(reader)?,
+            scheme: PhantomData,
+        })
+    }
+}
+
+impl<S> storage::Snapshot for Receiver0<S> {
+    const TAG: u8 = 0x24;
+}
+
+impl<C: wire::Encode, S: JointOutputScheme> wire::Encode for Receiver1<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_r.encode(buffer);
+        self.X_t.encode(buffer);
+        self.c_alpha.encode(buffer);
+        self.A.encode(buffer);
+        self.transactions.encode(buffer);
+        self.sig_refund_r.encode(buffer);
+        self.nonce_redeem_r.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, S: JointOutputScheme> wire::Decode for Receiver1<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Receiver1 {
+            x_r: secp256k1::KeyPair::decode(reader)?,
+            X_t: secp256k1::PublicKey::decode(reader)?,
+            c_alpha: C::decode(reader)?,
+            A: secp256k1::PublicKey::decode(reader)?,
+            transactions: bitcoin::Transactions::decode(reader)?,
+            sig_refund_r: S::Signature::decode(reader)?,
+            secret_nonce_redeem_r: None,
+            nonce_redeem_r: S::RedeemNonce::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S: JointOutputScheme> storage::Snapshot for Receiver1<C, S> {
+    const TAG: u8 = 0x25;
+}
+
+impl<C: wire::Encode, S: JointOutputScheme> wire::Encode for Receiver2<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_r.encode(buffer);
+        self.beta.encode(buffer);
+        self.c_alpha_prime.encode(buffer);
+        self.A_prime.encode(buffer);
+        self.redeem_session.encode(buffer);
+        self.sig_redeem_r.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+        self.transactions.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, S: JointOutputScheme> wire::Decode for Receiver2<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Receiver2 {
+            x_r: secp256k1::KeyPair::decode(reader)?,
+            beta: secp256k1::KeyPair::decode(reader)?,
+            c_alpha_prime: C::decode(reader)?,
+            A_prime: secp256k1::PublicKey::decode(reader)?,
+            redeem_session: RedeemSession::decode(reader)?,
+            sig_redeem_r: S::RedeemShare::decode(reader)?,
+            sig_redeem_t: S::EncryptedRedeemShare::decode(reader)?,
+            transactions: bitcoin::Transactions::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S: JointOutputScheme> storage::Snapshot for Receiver2<C, S> {
+    const TAG: u8 = 0x26;
+}
+
+impl<C: wire::Encode, P: wire::Encode> wire::Encode for Message0<C, P> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.X_t.encode(buffer);
+        self.A.encode(buffer);
+        self.c_alpha.encode(buffer);
+        self.pi_alpha.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, P: wire::Decode> wire::Decode for Message0<C, P> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message0 {
+            X_t: secp256k1::PublicKey::decode(reader)?,
+            A: secp256k1::PublicKey::decode(reader)?,
+            c_alpha: C::decode(reader)?,
+            pi_alpha: P::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, P: wire::Encode + wire::Decode> wire::WireMessage
+    for Message0<C, P>
+{
+    const TAG: u8 = 0x00;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Message1<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.X_r.encode(buffer);
+        self.sig_refund_r.encode(buffer);
+        self.nonce_redeem_r.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Message1<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message1 {
+            X_r: secp256k1::PublicKey::decode(reader)?,
+            sig_refund_r: S::Signature::decode(reader)?,
+            nonce_redeem_r: S::RedeemNonce::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> wire::WireMessage for Message1<S> {
+    const TAG: u8 = 0x01;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Message2<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.nonce_redeem_t.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Message2<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message2 {
+            nonce_redeem_t: S::RedeemNonce::decode(reader)?,
+            sig_redeem_t: S::EncryptedRedeemShare::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> wire::WireMessage for Message2<S> {
+    const TAG: u8 = 0x02;
+}
+
+impl<C: wire::Encode> wire::Encode for Message3<C> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.l.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode> wire::Decode for Message3<C> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message3 {
+            l: Lock::decode(reader)?,
+        })
+    }
 }
 
-pub struct Message3 {
-    l: Lock,
+impl<C: wire::Encode + wire::Decode> wire::WireMessage for Message3<C> {
+    const TAG: u8 = 0x03;
 }
 
 // #[cfg(test)]
@@ -1,5 +1,6 @@
-use crate::dummy_hsm_cl as hsm_cl;
+use crate::joint_output::JointOutputScheme;
 use crate::secp256k1;
+use crate::wire;
 
 mod receiver;
 mod sender;
@@ -13,20 +14,126 @@ pub struct Message0 {
     X_t: secp256k1::PublicKey,
 }
 
-pub struct Message1 {
+pub struct Message1<C, S: JointOutputScheme> {
     X_s: secp256k1::PublicKey,
-    c_alpha_prime_prime: hsm_cl::Ciphertext,
+    c_alpha_prime_prime: C,
+    nonce_redeem_s: S::RedeemNonce,
 }
 
-pub struct Message2 {
+/// Carries the tumbler's share of the redeem signature before the sender hands out its own, as
+/// the sender needs both to learn the solution from a MuSig2 redeem signature. The share is of
+/// no use to the sender otherwise: the redeem transaction pays the tumbler.
+pub struct Message2<S: JointOutputScheme> {
     A_prime_prime: secp256k1::PublicKey,
-    sig_refund_t: secp256k1::Signature,
+    sig_refund_t: S::Signature,
+    nonce_redeem_t: S::RedeemNonce,
+    sig_redeem_t: S::RedeemShare,
 }
 
-pub struct Message3 {
-    sig_redeem_s: secp256k1::EncryptedSignature,
+pub struct Message3<S: JointOutputScheme> {
+    sig_redeem_s: S::EncryptedRedeemShare,
 }
 
 pub struct Message4 {
     alpha_macron: secp256k1::SecretKey,
 }
+
+impl wire::Encode for Message0 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.X_t.encode(buffer);
+    }
+}
+
+impl wire::Decode for Message0 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message0 {
+            X_t: secp256k1::PublicKey::decode(reader)?,
+        })
+    }
+}
+
+impl wire::WireMessage for Message0 {
+    const TAG: u8 = 0x10;
+}
+
+impl<C: wire::Encode, S: JointOutputScheme> wire::Encode for Message1<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.X_s.encode(buffer);
+        self.c_alpha_prime_prime.encode(buffer);
+        self.nonce_redeem_s.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, S: JointOutputScheme> wire::Decode for Message1<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message1 {
+            X_s: secp256k1::PublicKey::decode(reader)?,
+            c_alpha_prime_prime: C::decode(reader)?,
+            nonce_redeem_s: S::RedeemNonce::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S: JointOutputScheme> wire::WireMessage for Message1<C, S> {
+    const TAG: u8 = 0x11;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Message2<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.A_prime_prime.encode(buffer);
+        self.sig_refund_t.encode(buffer);
+        self.nonce_redeem_t.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Message2<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message2 {
+            A_prime_prime: secp256k1::PublicKey::decode(reader)?,
+            sig_refund_t: S::Signature::decode(reader)?,
+            nonce_redeem_t: S::RedeemNonce::decode(reader)?,
+            sig_redeem_t: S::RedeemShare::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> wire::WireMessage for Message2<S> {
+    const TAG: u8 = 0x12;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Message3<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.sig_redeem_s.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Message3<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message3 {
+            sig_redeem_s: S::EncryptedRedeemShare::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> wire::WireMessage for Message3<S> {
+    const TAG: u8 = 0x13;
+}
+
+impl wire::Encode for Message4 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.alpha_macron.encode(buffer);
+    }
+}
+
+impl wire::Decode for Message4 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Message4 {
+            alpha_macron: secp256k1::SecretKey::decode(reader)?,
+        })
+    }
+}
+
+impl wire::WireMessage for Message4 {
+    const TAG: u8 = 0x14;
+}
@@ -1,73 +1,59 @@
 use crate::bitcoin;
+use crate::joint_output::{JointOutputScheme, RedeemSession};
 use crate::puzzle_solver::Message4;
 use crate::secp256k1;
+use crate::storage;
+use crate::wire;
 use anyhow::Context;
-use std::convert::TryFrom;
 
-pub struct Receiver0 {
-    X_r: secp256k1::PublicKey,
-    X_t: secp256k1::PublicKey,
+pub struct Receiver0<S: JointOutputScheme> {
+    redeem_session: RedeemSession<S>,
     unsigned_redeem_transaction: bitcoin::Transaction,
-    sig_redeem_t: secp256k1::EncryptedSignature,
-    sig_redeem_r: secp256k1::Signature,
+    sig_redeem_t: S::EncryptedRedeemShare,
+    sig_redeem_r: S::RedeemShare,
     beta: secp256k1::KeyPair,
-    redeem_tx_digest: bitcoin::SigHash,
 }
 
 pub struct Receiver1 {
     signed_redeem_transaction: bitcoin::Transaction,
 }
 
-impl Receiver0 {
+impl<S: JointOutputScheme> Receiver0<S> {
     pub fn new(
-        X_r: secp256k1::PublicKey,
-        X_t: secp256k1::PublicKey,
+        redeem_session: RedeemSession<S>,
         unsigned_redeem_transaction: bitcoin::Transaction,
-        sig_redeem_t: secp256k1::EncryptedSignature,
-        sig_redeem_r: secp256k1::Signature,
+        sig_redeem_t: S::EncryptedRedeemShare,
+        sig_redeem_r: S::RedeemShare,
         beta: secp256k1::KeyPair,
-        redeem_tx_digest: bitcoin::SigHash,
     ) -> Self {
         Self {
-            X_r,
-            X_t,
+            redeem_session,
             unsigned_redeem_transaction,
             sig_redeem_t,
             sig_redeem_r,
             beta,
-            redeem_tx_digest,
         }
     }
 
     pub fn receive(self, Message4 { alpha_macron }: Message4) -> anyhow::Result<Receiver1> {
         let Self {
-            X_r,
-            X_t,
+            redeem_session,
             unsigned_redeem_transaction,
             sig_redeem_t,
             sig_redeem_r,
             beta,
-            redeem_tx_digest,
         } = self;
 
-        let alpha = {
-            let alpha_macron: secp256k1::Scalar = alpha_macron.into();
-            // let beta: secp256k1::Scalar = beta.into_sk().into();
+        let alpha = secp256k1::derandomize(&alpha_macron, &beta);
 
-            // alpha_macron * beta.inv()
-            alpha_macron
-        };
-
-        let sig_redeem_t = secp256k1::decsig(&secp256k1::KeyPair::try_from(alpha)?, &sig_redeem_t);
-
-        secp256k1::verify(redeem_tx_digest, &sig_redeem_t, &X_t)
-            .context("failed to verify tumbler redeem signature after decryption")?;
-
-        let signed_redeem_transaction = bitcoin::complete_spend_transaction(
+        let signed_redeem_transaction = S::complete_redeem_transaction(
+            &redeem_session,
             unsigned_redeem_transaction,
-            (X_t, sig_redeem_t),
-            (X_r, sig_redeem_r),
-        )?;
+            &sig_redeem_t,
+            &sig_redeem_r,
+            &alpha,
+        )
+        .context("failed to complete redeem transaction with the decrypted tumbler signature")?;
 
         Ok(Receiver1 {
             signed_redeem_transaction,
@@ -80,3 +66,47 @@ impl Receiver1 {
         &self.signed_redeem_transaction
     }
 }
+
+impl<S: JointOutputScheme> wire::Encode for Receiver0<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.redeem_session.encode(buffer);
+        self.unsigned_redeem_transaction.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+        self.sig_redeem_r.encode(buffer);
+        self.beta.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Receiver0<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Receiver0 {
+            redeem_session: RedeemSession::decode(reader)?,
+            unsigned_redeem_transaction: bitcoin::Transaction::decode(reader)?,
+            sig_redeem_t: S::EncryptedRedeemShare::decode(reader)?,
+            sig_redeem_r: S::RedeemShare::decode(reader)?,
+            beta: secp256k1::KeyPair::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> storage::Snapshot for Receiver0<S> {
+    const TAG: u8 = 0x37;
+}
+
+impl wire::Encode for Receiver1 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.signed_redeem_transaction.encode(buffer);
+    }
+}
+
+impl wire::Decode for Receiver1 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Receiver1 {
+            signed_redeem_transaction: bitcoin::Transaction::decode(reader)?,
+        })
+    }
+}
+
+impl storage::Snapshot for Receiver1 {
+    const TAG: u8 = 0x38;
+}
@@ -1,37 +1,46 @@
 use crate::bitcoin;
-use crate::dummy_hsm_cl as hsm_cl;
+use crate::hsm_cl;
+use crate::joint_output::{self, JointOutputScheme, RedeemSession};
 use crate::puzzle_solver::{Message0, Message1, Message2, Message3, Message4};
 use crate::secp256k1;
+use crate::storage;
+use crate::wire;
 use crate::Lock;
-use crate::Params;
+use crate::{Params, Protocol};
 use anyhow::Context as _;
 use rand::Rng;
-use std::convert::TryInto;
+use std::marker::PhantomData;
 
-pub struct Sender0 {
+pub struct Sender0<C, S> {
     params: Params,
     x_s: secp256k1::KeyPair,
-    c_alpha_prime: hsm_cl::Ciphertext,
+    c_alpha_prime: C,
     A_prime: secp256k1::PublicKey,
+    scheme: PhantomData<S>,
 }
 
-pub struct Sender1 {
+pub struct Sender1<C, S: JointOutputScheme> {
     params: Params,
     x_s: secp256k1::KeyPair,
     X_t: secp256k1::PublicKey,
-    c_alpha_prime: hsm_cl::Ciphertext,
+    c_alpha_prime: C,
     A_prime: secp256k1::PublicKey,
     tau: secp256k1::KeyPair,
+    transactions: bitcoin::Transactions,
+    /// `None` once restored from a snapshot, which never holds it.
+    secret_nonce_redeem_s: Option<S::SecretRedeemNonce>,
+    nonce_redeem_s: S::RedeemNonce,
 }
 
-pub struct Sender2 {
+pub struct Sender2<S: JointOutputScheme> {
     unsigned_fund_transaction: bitcoin::Transaction,
+    /// The outputs spent by the inputs of the fund transaction, in order.
+    fund_spent_outputs: Vec<bitcoin::TxOut>,
     signed_refund_transaction: bitcoin::Transaction,
-    sig_redeem_s: secp256k1::EncryptedSignature,
-    A_prime_prime: secp256k1::PublicKey,
-    x_s: secp256k1::KeyPair,
+    redeem_session: RedeemSession<S>,
+    sig_redeem_s: S::EncryptedRedeemShare,
+    sig_redeem_t: S::RedeemShare,
     tau: secp256k1::KeyPair,
-    redeem_tx_digest: bitcoin::SigHash,
 }
 
 pub struct Sender3 {
@@ -42,13 +51,13 @@ pub struct Sender3 {
 #[error("(A')^tau != A''")]
 pub struct AptNotEqualApp;
 
-impl Sender0 {
+impl<C, S: JointOutputScheme> Sender0<C, S> {
     pub fn new(
         params: Params,
         Lock {
             c_alpha_prime,
             A_prime,
-        }: Lock,
+        }: Lock<C>,
         rng: &mut impl Rng,
     ) -> Self {
         Self {
@@ -56,28 +65,50 @@ impl Sender0 {
             x_s: secp256k1::KeyPair::random(rng),
             c_alpha_prime,
             A_prime,
+            scheme: PhantomData,
         }
     }
 
-    pub fn receive(self, Message0 { X_t }: Message0, rng: &mut impl Rng) -> Sender1 {
-        Sender1 {
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn receive(
+        self,
+        Message0 { X_t }: Message0,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Sender1<C, S>> {
+        let transactions = joint_output::make_transactions::<S>(
+            &self.params,
+            Protocol::PuzzleSolver,
+            &self.x_s.to_pk(),
+            &X_t,
+        )?;
+        let (secret_nonce_redeem_s, nonce_redeem_s) =
+            S::redeem_nonce(&self.x_s, &self.x_s.to_pk(), &X_t, &transactions, rng);
+
+        Ok(Sender1 {
             params: self.params,
             x_s: self.x_s,
             X_t,
             c_alpha_prime: self.c_alpha_prime,
             A_prime: self.A_prime,
             tau: secp256k1::KeyPair::random(rng),
-        }
+            transactions,
+            secret_nonce_redeem_s: Some(secret_nonce_redeem_s),
+            nonce_redeem_s,
+        })
     }
 }
 
-impl Sender1 {
-    pub fn next_message(&self, HE: &impl hsm_cl::Pow<hsm_cl::Ciphertext>) -> Message1 {
+impl<C, S: JointOutputScheme> Sender1<C, S> {
+    pub fn next_message(&self, HE: &impl hsm_cl::Pow<C>) -> Message1<C, S> {
         let c_alpha_prime_prime = HE.pow(&self.c_alpha_prime, &self.tau);
 
         Message1 {
             c_alpha_prime_prime,
             X_s: self.x_s.to_pk(),
+            nonce_redeem_s: self.nonce_redeem_s.clone(),
         }
     }
 
@@ -86,96 +117,106 @@ impl Sender1 {
         Message2 {
             A_prime_prime,
             sig_refund_t,
-        }: Message2,
+            nonce_redeem_t,
+            sig_redeem_t,
+        }: Message2<S>,
         rng: &mut impl Rng,
         HE: &impl hsm_cl::Pow<secp256k1::PublicKey>,
-    ) -> anyhow::Result<Sender2> {
+    ) -> anyhow::Result<Sender2<S>> {
         let A_prime_tau = HE.pow(&self.A_prime, &self.tau);
         if A_prime_tau != A_prime_prime {
             anyhow::bail!(AptNotEqualApp)
         }
 
-        let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
-            self.params.sender_tumbler_joint_output_value(),
-            self.params.sender_tumbler_joint_output_takeout(),
-            &self.x_s.to_pk(),
-            &self.X_t,
-            self.params.expiry,
-            &self.params.redeem_identity,
-            &self.params.refund_identity,
-        );
+        let Self {
+            params,
+            x_s,
+            X_t,
+            tau,
+            transactions,
+            secret_nonce_redeem_s,
+            nonce_redeem_s,
+            ..
+        } = self;
 
         let sig_refund_s = {
-            secp256k1::verify(transactions.refund_tx_digest, &sig_refund_t, &self.X_t)
+            S::verify(transactions.refund_tx_digest, &sig_refund_t, &X_t)
                 .context("failed to verify tumbler refund signature")?;
 
-            secp256k1::sign(transactions.refund_tx_digest, &self.x_s)
+            S::sign(transactions.refund_tx_digest, &x_s, rng)
         };
 
-        let sig_redeem_s = secp256k1::encsign(
-            transactions.redeem_tx_digest,
-            &self.x_s,
-            &A_prime_prime,
-            rng,
+        let redeem_session = RedeemSession::new(
+            x_s.to_pk(),
+            X_t.clone(),
+            &transactions,
+            A_prime_prime,
+            nonce_redeem_s,
+            nonce_redeem_t,
         );
+        S::verify_redeem(&redeem_session, &sig_redeem_t)
+            .context("failed to verify tumbler redeem signature")?;
+
+        let secret_nonce_redeem_s = joint_output::secret_redeem_nonce::<S>(secret_nonce_redeem_s)?;
+        let sig_redeem_s = S::encsign_redeem(&redeem_session, &x_s, secret_nonce_redeem_s, rng)?;
 
         Ok(Sender2 {
             unsigned_fund_transaction: transactions.fund,
-            signed_refund_transaction: bitcoin::complete_spend_transaction(
+            fund_spent_outputs: params.partial_fund_spent_outputs,
+            signed_refund_transaction: S::complete_refund_transaction(
                 transactions.refund,
-                (self.x_s.to_pk(), sig_refund_s),
-                (self.X_t.clone(), sig_refund_t),
+                (x_s.to_pk(), sig_refund_s),
+                (X_t, sig_refund_t),
             )?,
+            redeem_session,
             sig_redeem_s,
-            A_prime_prime,
-            x_s: self.x_s,
-            tau: self.tau,
-            redeem_tx_digest: transactions.redeem_tx_digest,
+            sig_redeem_t,
+            tau,
         })
     }
 }
 
-impl Sender2 {
-    pub fn next_message(&self) -> Message3 {
+impl<S: JointOutputScheme> Sender2<S> {
+    pub fn next_message(&self) -> Message3<S> {
         Message3 {
             sig_redeem_s: self.sig_redeem_s.clone(),
         }
     }
 
     pub fn receive(self, redeem_transaction: bitcoin::Transaction) -> anyhow::Result<Sender3> {
-        let Self {
-            sig_redeem_s: encrypted_signature,
-            A_prime_prime,
-            tau,
-            ..
-        } = self;
-
-        let decrypted_signature = bitcoin::extract_signature_by_key(
-            redeem_transaction,
-            self.redeem_tx_digest,
-            &self.x_s.to_pk(),
+        let gamma = S::recover_from_redeem_transaction(
+            &self.redeem_session,
+            &redeem_transaction,
+            &self.sig_redeem_s,
+            &self.sig_redeem_t,
         )?;
+        let alpha_macron = secp256k1::derandomize(gamma.secret_key(), &self.tau);
 
-        let gamma =
-            secp256k1::recover(&A_prime_prime, &encrypted_signature, &decrypted_signature)??;
-        let alpha_macron = {
-            let gamma: secp256k1::Scalar = gamma.into_sk().into();
-            // let tau: secp256k1::Scalar = tau.into_sk().into();
-
-            // gamma * tau.inv()
-            gamma
-        };
-
-        Ok(Sender3 {
-            alpha_macron: alpha_macron.try_into()?,
-        })
+        Ok(Sender3 { alpha_macron })
     }
 
     pub fn unsigned_fund_transaction(&self) -> bitcoin::Transaction {
         self.unsigned_fund_transaction.clone()
     }
 
+    /// The fund transaction as a PSBT for the sender's wallet to sign. See [`bitcoin::fund_psbt`].
+    pub fn unsigned_fund_psbt(&self) -> anyhow::Result<bitcoin::PartiallySignedTransaction> {
+        bitcoin::fund_psbt(
+            &self.unsigned_fund_transaction,
+            &self.signed_refund_transaction,
+            &self.fund_spent_outputs,
+        )
+    }
+
+    /// Returns the fund transaction signed by the sender's wallet, after checking that the refund
+    /// and redeem transactions still spend it.
+    pub fn signed_fund_transaction(
+        &self,
+        psbt: bitcoin::PartiallySignedTransaction,
+    ) -> anyhow::Result<bitcoin::Transaction> {
+        bitcoin::extract_fund_transaction(psbt, self.unsigned_fund_transaction.txid())
+    }
+
     pub fn signed_refund_transaction(&self) -> bitcoin::Transaction {
         self.signed_refund_transaction.clone()
     }
@@ -192,3 +233,109 @@ impl Sender3 {
         &self.alpha_macron
     }
 }
+
+impl<C: wire::Encode, S> wire::Encode for Sender0<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.params.encode(buffer);
+        self.x_s.encode(buffer);
+        self.c_alpha_prime.encode(buffer);
+        self.A_prime.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, S> wire::Decode for Sender0<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender0 {
+            params: Params::decode(reader)?,
+            x_s: secp256k1::KeyPair::decode(reader)?,
+            c_alpha_prime: C::decode(reader)?,
+            A_prime: secp256k1::PublicKey::decode(reader)?,
+            scheme: PhantomData,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S> storage::Snapshot for Sender0<C, S> {
+    const TAG: u8 = 0x33;
+}
+
+impl<C: wire::Encode, S: JointOutputScheme> wire::Encode for Sender1<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.params.encode(buffer);
+        self.x_s.encode(buffer);
+        self.X_t.encode(buffer);
+        self.c_alpha_prime.encode(buffer);
+        self.A_prime.encode(buffer);
+        self.tau.encode(buffer);
+        self.transactions.encode(buffer);
+        self.nonce_redeem_s.encode(buffer);
+    }
+}
+
+impl<C: wire::Decode, S: JointOutputScheme> wire::Decode for Sender1<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender1 {
+            params: Params::decode(reader)?,
+            x_s: secp256k1::KeyPair::decode(reader)?,
+            X_t: secp256k1::PublicKey::decode(reader)?,
+            c_alpha_prime: C::decode(reader)?,
+            A_prime: secp256k1::PublicKey::decode(reader)?,
+            tau: secp256k1::KeyPair::decode(reader)?,
+            transactions: bitcoin::Transactions::decode(reader)?,
+            secret_nonce_redeem_s: None,
+            nonce_redeem_s: S::RedeemNonce::decode(reader)?,
+        })
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S: JointOutputScheme> storage::Snapshot for Sender1<C, S> {
+    const TAG: u8 = 0x34;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Sender2<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.unsigned_fund_transaction.encode(buffer);
+        self.fund_spent_outputs.encode(buffer);
+        self.signed_refund_transaction.encode(buffer);
+        self.redeem_session.encode(buffer);
+        self.sig_redeem_s.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+        self.tau.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Sender2<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender2 {
+            unsigned_fund_transaction: bitcoin::Transaction::decode(reader)?,
+            fund_spent_outputs: Vec::decode(reader)?,
+            signed_refund_transaction: bitcoin::Transaction::decode(reader)?,
+            redeem_session: RedeemSession::decode(reader)?,
+            sig_redeem_s: S::EncryptedRedeemShare::decode(reader)?,
+            sig_redeem_t: S::RedeemShare::decode(reader)?,
+            tau: secp256k1::KeyPair::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> storage::Snapshot for Sender2<S> {
+    const TAG: u8 = 0x35;
+}
+
+impl wire::Encode for Sender3 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.alpha_macron.encode(buffer);
+    }
+}
+
+impl wire::Decode for Sender3 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Sender3 {
+            alpha_macron: secp256k1::KeyPair::decode(reader)?,
+        })
+    }
+}
+
+impl storage::Snapshot for Sender3 {
+    const TAG: u8 = 0x36;
+}
@@ -1,19 +1,24 @@
 use crate::bitcoin;
-use crate::dummy_hsm_cl as hsm_cl;
+use crate::hsm_cl;
+use crate::joint_output::{self, JointOutputScheme, RedeemSession};
 use crate::puzzle_solver::{Message0, Message1, Message2, Message3};
 use crate::secp256k1;
-use crate::Params;
+use crate::storage;
+use crate::wire;
+use crate::{Params, Protocol};
+use rand::Rng;
 
 pub struct Tumbler0 {
     x_t: secp256k1::KeyPair,
     params: Params,
 }
 
-pub struct Tumbler1 {
+pub struct Tumbler1<S: JointOutputScheme> {
     transactions: bitcoin::Transactions,
-    x_t: secp256k1::KeyPair,
-    X_s: secp256k1::PublicKey,
+    redeem_session: RedeemSession<S>,
     gamma: secp256k1::KeyPair,
+    sig_refund_t: S::Signature,
+    sig_redeem_t: S::RedeemShare,
 }
 
 pub struct Tumbler2 {
@@ -31,67 +36,78 @@ impl Tumbler0 {
         }
     }
 
-    pub fn receive(
+    pub fn receive<HE, S>(
         self,
         Message1 {
             X_s,
             c_alpha_prime_prime,
-        }: Message1,
-        HE: &impl hsm_cl::Decrypt,
-    ) -> Tumbler1 {
-        let gamma = HE.decrypt(&self.x_t, &c_alpha_prime_prime).into();
-
-        let transactions = bitcoin::make_transactions(
-            self.params.partial_fund_transaction.clone(),
-            self.params.sender_tumbler_joint_output_value(),
-            self.params.sender_tumbler_joint_output_takeout(),
-            &X_s,
-            &self.x_t.to_pk(),
-            self.params.expiry,
-            &self.params.redeem_identity,
-            &self.params.refund_identity,
+            nonce_redeem_s,
+        }: Message1<HE::Ciphertext, S>,
+        HE: &HE,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<Tumbler1<S>>
+    where
+        HE: hsm_cl::Decrypt,
+        S: JointOutputScheme,
+    {
+        let gamma: secp256k1::KeyPair = HE.decrypt(&self.x_t, &c_alpha_prime_prime)?.into();
+
+        let X_t = self.x_t.to_pk();
+        let transactions =
+            joint_output::make_transactions::<S>(&self.params, Protocol::PuzzleSolver, &X_s, &X_t)?;
+
+        let (secret_nonce_redeem_t, nonce_redeem_t) =
+            S::redeem_nonce(&self.x_t, &X_s, &X_t, &transactions, rng);
+        let redeem_session = RedeemSession::new(
+            X_s,
+            X_t,
+            &transactions,
+            gamma.to_pk(),
+            nonce_redeem_s,
+            nonce_redeem_t,
         );
 
-        Tumbler1 {
+        let sig_redeem_t = S::sign_redeem(&redeem_session, &self.x_t, secret_nonce_redeem_t)?;
+        let sig_refund_t = S::sign(transactions.refund_tx_digest, &self.x_t, rng);
+
+        Ok(Tumbler1 {
             transactions,
-            x_t: self.x_t,
-            X_s,
+            redeem_session,
             gamma,
-        }
+            sig_refund_t,
+            sig_redeem_t,
+        })
     }
 }
 
-impl Tumbler1 {
-    pub fn next_message(&self) -> Message2 {
-        let A_prime_prime = self.gamma.to_pk();
-        let sig_refund_t = secp256k1::sign(self.transactions.refund_tx_digest, &self.x_t);
-
+impl<S: JointOutputScheme> Tumbler1<S> {
+    pub fn next_message(&self) -> Message2<S> {
         Message2 {
-            A_prime_prime,
-            sig_refund_t,
+            A_prime_prime: self.gamma.to_pk(),
+            sig_refund_t: self.sig_refund_t.clone(),
+            nonce_redeem_t: self.redeem_session.nonce_to().clone(),
+            sig_redeem_t: self.sig_redeem_t.clone(),
         }
     }
 
-    pub fn receive(self, Message3 { sig_redeem_s }: Message3) -> anyhow::Result<Tumbler2> {
+    pub fn receive(self, Message3 { sig_redeem_s }: Message3<S>) -> anyhow::Result<Tumbler2> {
         let Self {
             transactions,
-            x_t,
-            X_s,
+            redeem_session,
             gamma,
+            sig_redeem_t,
+            ..
         } = self;
 
-        let signed_redeem_transaction = {
-            let sig_redeem_s = secp256k1::decsig(&gamma, &sig_redeem_s);
-            secp256k1::verify(transactions.redeem_tx_digest, &sig_redeem_s, &X_s)?;
+        S::encverify_redeem(&redeem_session, &sig_redeem_s)?;
 
-            let sig_redeem_t = secp256k1::sign(transactions.redeem_tx_digest, &x_t);
-
-            bitcoin::complete_spend_transaction(
-                transactions.redeem,
-                (X_s, sig_redeem_s),
-                (x_t.to_pk(), sig_redeem_t),
-            )?
-        };
+        let signed_redeem_transaction = S::complete_redeem_transaction(
+            &redeem_session,
+            transactions.redeem,
+            &sig_redeem_s,
+            &sig_redeem_t,
+            &gamma,
+        )?;
 
         Ok(Tumbler2 {
             signed_redeem_transaction,
@@ -104,3 +120,67 @@ impl Tumbler2 {
         &self.signed_redeem_transaction
     }
 }
+
+impl wire::Encode for Tumbler0 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.x_t.encode(buffer);
+        self.params.encode(buffer);
+    }
+}
+
+impl wire::Decode for Tumbler0 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Tumbler0 {
+            x_t: secp256k1::KeyPair::decode(reader)?,
+            params: Params::decode(reader)?,
+        })
+    }
+}
+
+impl storage::Snapshot for Tumbler0 {
+    const TAG: u8 = 0x30;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Tumbler1<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.transactions.encode(buffer);
+        self.redeem_session.encode(buffer);
+        self.gamma.encode(buffer);
+        self.sig_refund_t.encode(buffer);
+        self.sig_redeem_t.encode(buffer);
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Tumbler1<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Tumbler1 {
+            transactions: bitcoin::Transactions::decode(reader)?,
+            redeem_session: RedeemSession::decode(reader)?,
+            gamma: secp256k1::KeyPair::decode(reader)?,
+            sig_refund_t: S::Signature::decode(reader)?,
+            sig_redeem_t: S::RedeemShare::decode(reader)?,
+        })
+    }
+}
+
+impl<S: JointOutputScheme> storage::Snapshot for Tumbler1<S> {
+    const TAG: u8 = 0x31;
+}
+
+impl wire::Encode for Tumbler2 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.signed_redeem_transaction.encode(buffer);
+    }
+}
+
+impl wire::Decode for Tumbler2 {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Tumbler2 {
+            signed_redeem_transaction: bitcoin::Transaction::decode(reader)?,
+        })
+    }
+}
+
+impl storage::Snapshot for Tumbler2 {
+    const TAG: u8 = 0x32;
+}
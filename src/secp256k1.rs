@@ -1,10 +1,19 @@
+mod adaptor;
+mod blinding;
 mod constants;
 mod enc;
+pub mod group;
 mod keypair;
+pub mod musig;
+pub mod schnorr;
+pub mod schnorr_enc;
 
+pub use self::adaptor::{AdaptorSignatureScheme, Ecdsa, Schnorr};
+pub use self::blinding::{derandomize, randomize, randomize_point};
 pub use self::constants::G;
 pub use self::enc::{
-    decsig, encsign, encverify, recover, EncryptedSignature, InvalidEncryptedSignature,
+    decsig, encsign, encsign_with_aux, encverify, encverify_batch, recover, EncryptedSignature,
+    InvalidEncryptedSignature,
 };
 pub use self::keypair::{KeyPair, XCoor};
 pub use secp256k1::{curve::Affine, curve::Scalar, PublicKey, SecretKey, Signature};
@@ -0,0 +1,211 @@
+//! A common interface to the adaptor signature schemes, so that code can be written once for
+//! ECDSA (`enc`) and BIP340 Schnorr (`schnorr_enc`) and the scheme chosen per session.
+
+use crate::secp256k1::{self, schnorr, schnorr_enc, KeyPair, PublicKey, ToMessage};
+use crate::wire;
+use std::fmt::Debug;
+
+pub trait AdaptorSignatureScheme {
+    type Signature: Clone + Debug + wire::Encode + wire::Decode;
+    type EncryptedSignature: Clone + Debug + wire::Encode + wire::Decode;
+
+    fn sign<M: ToMessage, R: rand::Rng>(message: M, x: &KeyPair, rng: &mut R) -> Self::Signature;
+
+    fn verify<M: ToMessage>(
+        message: M,
+        signature: &Self::Signature,
+        X: &PublicKey,
+    ) -> anyhow::Result<()>;
+
+    /// Signs `message` with `x`, encrypted under `Y`.
+    fn encsign<M: ToMessage, R: rand::Rng>(
+        message: M,
+        x: &KeyPair,
+        Y: &PublicKey,
+        rng: &mut R,
+    ) -> Self::EncryptedSignature;
+
+    /// Checks that `encrypted_signature` decrypts with the discrete log of `Y` to a signature of
+    /// `message_hash` under `X`.
+    fn encverify(
+        X: &PublicKey,
+        Y: &PublicKey,
+        message_hash: &[u8; 32],
+        encrypted_signature: &Self::EncryptedSignature,
+    ) -> anyhow::Result<()>;
+
+    fn decsig(y: &KeyPair, encrypted_signature: &Self::EncryptedSignature) -> Self::Signature;
+
+    /// Recovers the discrete log of `Y` from a signature decrypted from `encrypted_signature`.
+    fn recover(
+        Y: &PublicKey,
+        encrypted_signature: &Self::EncryptedSignature,
+        signature: &Self::Signature,
+    ) -> anyhow::Result<KeyPair>;
+}
+
+/// ECDSA adaptor signatures, which come with a proof of discrete log equality.
+#[derive(Debug, Clone, Copy)]
+pub struct Ecdsa;
+
+/// BIP340 Schnorr adaptor signatures.
+#[derive(Debug, Clone, Copy)]
+pub struct Schnorr;
+
+impl AdaptorSignatureScheme for Ecdsa {
+    type Signature = secp256k1::Signature;
+    type EncryptedSignature = secp256k1::EncryptedSignature;
+
+    /// ECDSA signing derives its nonce deterministically (RFC6979) and ignores `rng`.
+    fn sign<M: ToMessage, R: rand::Rng>(message: M, x: &KeyPair, _: &mut R) -> Self::Signature {
+        secp256k1::sign(message, x)
+    }
+
+    fn verify<M: ToMessage>(
+        message: M,
+        signature: &Self::Signature,
+        X: &PublicKey,
+    ) -> anyhow::Result<()> {
+        Ok(secp256k1::verify(message, signature, X)?)
+    }
+
+    fn encsign<M: ToMessage, R: rand::Rng>(
+        message: M,
+        x: &KeyPair,
+        Y: &PublicKey,
+        rng: &mut R,
+    ) -> Self::EncryptedSignature {
+        secp256k1::encsign(message, x, Y, rng)
+    }
+
+    fn encverify(
+        X: &PublicKey,
+        Y: &PublicKey,
+        message_hash: &[u8; 32],
+        encrypted_signature: &Self::EncryptedSignature,
+    ) -> anyhow::Result<()> {
+        Ok(secp256k1::encverify(
+            X,
+            Y,
+            message_hash,
+            encrypted_signature,
+        )?)
+    }
+
+    fn decsig(y: &KeyPair, encrypted_signature: &Self::EncryptedSignature) -> Self::Signature {
+        secp256k1::decsig(y, encrypted_signature)
+    }
+
+    fn recover(
+        Y: &PublicKey,
+        encrypted_signature: &Self::EncryptedSignature,
+        signature: &Self::Signature,
+    ) -> anyhow::Result<KeyPair> {
+        Ok(secp256k1::recover(Y, encrypted_signature, signature)??)
+    }
+}
+
+impl AdaptorSignatureScheme for Schnorr {
+    type Signature = schnorr::Signature;
+    type EncryptedSignature = schnorr_enc::EncryptedSignature;
+
+    fn sign<M: ToMessage, R: rand::Rng>(message: M, x: &KeyPair, rng: &mut R) -> Self::Signature {
+        schnorr::sign(message, x, rng)
+    }
+
+    fn verify<M: ToMessage>(
+        message: M,
+        signature: &Self::Signature,
+        X: &PublicKey,
+    ) -> anyhow::Result<()> {
+        Ok(schnorr::verify(message, signature, X)?)
+    }
+
+    fn encsign<M: ToMessage, R: rand::Rng>(
+        message: M,
+        x: &KeyPair,
+        Y: &PublicKey,
+        rng: &mut R,
+    ) -> Self::EncryptedSignature {
+        schnorr_enc::encsign(message, x, Y, rng)
+    }
+
+    fn encverify(
+        X: &PublicKey,
+        Y: &PublicKey,
+        message_hash: &[u8; 32],
+        encrypted_signature: &Self::EncryptedSignature,
+    ) -> anyhow::Result<()> {
+        Ok(schnorr_enc::encverify(
+            X,
+            Y,
+            message_hash,
+            encrypted_signature,
+        )?)
+    }
+
+    fn decsig(y: &KeyPair, encrypted_signature: &Self::EncryptedSignature) -> Self::Signature {
+        schnorr_enc::decsig(y, encrypted_signature)
+    }
+
+    fn recover(
+        Y: &PublicKey,
+        encrypted_signature: &Self::EncryptedSignature,
+        signature: &Self::Signature,
+    ) -> anyhow::Result<KeyPair> {
+        Ok(schnorr_enc::recover(Y, encrypted_signature, signature)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// What the puzzle protocols do with an adaptor signature: the signer encrypts it under a
+    /// puzzle, the holder of the solution decrypts and publishes it, and the signer learns the
+    /// solution from the published signature.
+    fn solve_puzzle_through_signature<S: AdaptorSignatureScheme>() {
+        let mut rng = rand::thread_rng();
+        let x = KeyPair::random(&mut rng);
+        let y = KeyPair::random(&mut rng);
+        let message = [3u8; 32];
+
+        let encsig = S::encsign(message, &x, y.public_key(), &mut rng);
+        S::encverify(x.public_key(), y.public_key(), &message, &encsig).unwrap();
+
+        let sig = S::decsig(&y, &encsig);
+        S::verify(message, &sig, x.public_key()).unwrap();
+
+        assert_eq!(S::recover(y.public_key(), &encsig, &sig).unwrap(), y);
+    }
+
+    #[test]
+    fn ecdsa_solves_puzzle_through_signature() {
+        solve_puzzle_through_signature::<Ecdsa>()
+    }
+
+    #[test]
+    fn schnorr_solves_puzzle_through_signature() {
+        solve_puzzle_through_signature::<Schnorr>()
+    }
+
+    fn encverify_rejects_other_message<S: AdaptorSignatureScheme>() {
+        let mut rng = rand::thread_rng();
+        let x = KeyPair::random(&mut rng);
+        let y = KeyPair::random(&mut rng);
+
+        let encsig = S::encsign([3u8; 32], &x, y.public_key(), &mut rng);
+
+        S::encverify(x.public_key(), y.public_key(), &[4u8; 32], &encsig).unwrap_err();
+    }
+
+    #[test]
+    fn ecdsa_encverify_rejects_other_message() {
+        encverify_rejects_other_message::<Ecdsa>()
+    }
+
+    #[test]
+    fn schnorr_encverify_rejects_other_message() {
+        encverify_rejects_other_message::<Schnorr>()
+    }
+}
@@ -0,0 +1,78 @@
+use crate::secp256k1::{group, KeyPair, PublicKey, Scalar, SecretKey};
+use std::convert::TryFrom;
+
+/// Blinds the discrete log `x` by multiplying it with the blinding factor `r`.
+pub fn randomize(x: &SecretKey, r: &KeyPair) -> SecretKey {
+    let mut x = x.clone();
+    x.tweak_mul_assign(r.secret_key())
+        .expect("product of two non-zero scalars is non-zero");
+
+    x
+}
+
+/// Blinds the point `X` so that it matches `randomize` applied to its discrete log.
+pub fn randomize_point(X: &PublicKey, r: &KeyPair) -> PublicKey {
+    group::mul(X, &r.to_sk().into())
+        .expect("multiplying a valid point by a non-zero scalar never fails")
+}
+
+/// Removes the blinding factor `r` from the blinded discrete log `x_r`, i.e. computes `x_r * r^-1`.
+pub fn derandomize(x_r: &SecretKey, r: &KeyPair) -> KeyPair {
+    let x_r: Scalar = x_r.clone().into();
+    let r: Scalar = r.to_sk().into();
+
+    KeyPair::try_from(x_r * r.inv()).expect("product of two non-zero scalars is non-zero")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dummy_hsm_cl;
+    use crate::hsm_cl::{Decrypt, Encrypt, Pow};
+
+    #[test]
+    fn derandomize_undoes_randomize() {
+        let x = KeyPair::random_from_thread_rng();
+        let r = KeyPair::random_from_thread_rng();
+
+        let x_r = randomize(x.secret_key(), &r);
+
+        assert_ne!(&x_r, x.secret_key());
+        assert_eq!(derandomize(&x_r, &r), x);
+    }
+
+    #[test]
+    fn randomize_point_commutes_with_randomize() {
+        let x = KeyPair::random_from_thread_rng();
+        let r = KeyPair::random_from_thread_rng();
+
+        assert_eq!(
+            randomize_point(x.public_key(), &r),
+            PublicKey::from_secret_key(&randomize(x.secret_key(), &r))
+        );
+    }
+
+    #[test]
+    fn puzzle_solution_is_recovered_after_double_blinding() {
+        let (secretkey, publickey) = dummy_hsm_cl::keygen();
+        let x_t = KeyPair::random_from_thread_rng();
+        let a = KeyPair::random_from_thread_rng();
+        let beta = KeyPair::random_from_thread_rng();
+        let tau = KeyPair::random_from_thread_rng();
+
+        let (c_alpha, _) = secretkey.encrypt(&x_t, &a);
+        let c_alpha_prime = publickey.pow(&c_alpha, &beta);
+        let c_alpha_prime_prime = publickey.pow(&c_alpha_prime, &tau);
+        let A_prime_prime = publickey.pow(&publickey.pow(a.public_key(), &beta), &tau);
+
+        let gamma = KeyPair::from(secretkey.decrypt(&x_t, &c_alpha_prime_prime).unwrap());
+
+        assert_ne!(gamma, a, "Pow must not be the identity");
+        assert_eq!(gamma.to_pk(), A_prime_prime);
+
+        let alpha_macron = derandomize(gamma.secret_key(), &tau);
+        let alpha = derandomize(alpha_macron.secret_key(), &beta);
+
+        assert_eq!(alpha, a);
+    }
+}
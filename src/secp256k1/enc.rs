@@ -4,9 +4,12 @@ use crate::secp256k1::SecretKey;
 use crate::secp256k1::ToMessage;
 use crate::secp256k1::XCoor;
 use crate::secp256k1::G;
+use crate::secp256k1::{group, schnorr};
 use crate::secp256k1::{KeyPair, Scalar};
 use crate::secp256k1::{PublicKey, Signature};
-use std::convert::{TryFrom, TryInto};
+use crate::transcript::Transcript;
+use crate::wire;
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone)]
 pub struct EncryptedSignature {
@@ -16,6 +19,26 @@ pub struct EncryptedSignature {
     proof: dleq::Proof,
 }
 
+impl wire::Encode for EncryptedSignature {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.R.encode(buffer);
+        self.R_hat.encode(buffer);
+        self.s_hat.encode(buffer);
+        self.proof.encode(buffer);
+    }
+}
+
+impl wire::Decode for EncryptedSignature {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(EncryptedSignature {
+            R: PublicKey::decode(reader)?,
+            R_hat: PublicKey::decode(reader)?,
+            s_hat: SecretKey::decode(reader)?,
+            proof: dleq::Proof::decode(reader)?,
+        })
+    }
+}
+
 pub fn encsign<M, S: AsRef<SecretKey>, R: rand::Rng>(
     message: M,
     x: &S,
@@ -25,38 +48,69 @@ pub fn encsign<M, S: AsRef<SecretKey>, R: rand::Rng>(
 where
     M: ToMessage,
 {
-    let r = SecretKey::random(rng);
-
-    let R_hat = {
-        let mut R_hat = G.clone();
-        R_hat.tweak_mul_assign(&r).unwrap();
+    let mut aux = [0u8; 32];
+    rng.fill_bytes(&mut aux);
 
-        R_hat
-    };
+    encsign_with_aux(message, x, Y, &aux)
+}
 
-    let R = {
-        let mut R = Y.clone();
-        R.tweak_mul_assign(&r).unwrap();
+/// Signs `message` with `x`, encrypted under `Y`, using `aux` as auxiliary randomness.
+///
+/// The nonce is derived from `x`, `message`, `Y` and `aux` like a BIP340 nonce, so that a weak or
+/// repeated `aux` cannot leak `x` through `s_hat`.
+pub fn encsign_with_aux<M, S: AsRef<SecretKey>>(
+    message: M,
+    x: &S,
+    Y: &PublicKey,
+    aux: &[u8; 32],
+) -> EncryptedSignature
+where
+    M: ToMessage,
+{
+    let message = message.to_message();
+    let X = PublicKey::from_secret_key(x.as_ref());
+
+    let r = {
+        let aux_hash = schnorr::tagged_hash("ECDSAAdaptor/aux", &[aux]);
+        let mut t = x.as_ref().serialize();
+        for (t, aux_hash) in t.iter_mut().zip(aux_hash.iter()) {
+            *t ^= aux_hash;
+        }
 
-        R
+        let r = schnorr::scalar_from_hash(schnorr::tagged_hash(
+            "ECDSAAdaptor/nonce",
+            &[
+                &t,
+                &Y.serialize_compressed(),
+                &X.serialize_compressed(),
+                &message,
+            ],
+        ));
+        SecretKey::try_from(r).expect("nonce is zero with negligible probability")
     };
 
-    let proof = dleq::prove(rng, &*G, &R_hat, &Y, &R, r.clone().into());
-
-    let s_hat = {
-        let R_x = SecretKey::parse(&R.x_coor()).unwrap();
-
-        let mut s_hat = R_x;
-        s_hat.tweak_mul_assign(x.as_ref()).unwrap();
-        s_hat
-            .tweak_add_assign(&SecretKey::parse(&message.to_message()).unwrap())
-            .unwrap();
-
-        let r_inv = r.inv();
+    let R_hat = group::mul_base(&r.clone().into()).expect("nonce is non-zero");
+    let R = group::mul(Y, &r.clone().into()).expect("nonce is non-zero");
 
-        s_hat.tweak_mul_assign(&r_inv).unwrap();
+    let proof = dleq::prove_with_aux(
+        &mut proof_transcript(&X, &message),
+        &G,
+        &R_hat,
+        Y,
+        &R,
+        r.clone().into(),
+        aux,
+    );
 
-        s_hat
+    let s_hat = {
+        // reduced like in `verification_scalars`, so that any message hash can be signed
+        let R_x = schnorr::scalar_from_hash(R.x_coor());
+        let message_hash = schnorr::scalar_from_hash(message);
+        let x: Scalar = x.as_ref().clone().into();
+        let r: Scalar = r.into();
+
+        SecretKey::try_from((R_x * x + message_hash) * r.inv())
+            .expect("s_hat is zero with negligible probability")
     };
 
     EncryptedSignature {
@@ -67,10 +121,30 @@ where
     }
 }
 
-#[derive(Debug, Clone, thiserror::Error)]
-#[error("invalid encrypted signature")]
-pub struct InvalidEncryptedSignature;
+/// The transcript of the proof that `R_hat` and `R` have the same discrete log. It binds the proof
+/// to the key and message of the encrypted signature, so it cannot be reused for another one.
+fn proof_transcript(X: &PublicKey, message: &[u8; 32]) -> Transcript {
+    let mut transcript = Transcript::new("A2L/ECDSAAdaptor");
+    transcript.append_point("X", X);
+    transcript.append("message", message);
+
+    transcript
+}
 
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum InvalidEncryptedSignature {
+    #[error("R and R_hat do not share their discrete log")]
+    Proof(#[from] dleq::DiscreteLogNotEqual),
+    #[error("x-coordinate of R is zero modulo the group order")]
+    ZeroNonce,
+    #[error("s_hat is zero")]
+    ZeroSHat,
+    #[error("R_hat does not match the message and public key")]
+    Mismatch,
+}
+
+/// Checks an encrypted signature received from a possibly malicious party, so it fails with an
+/// error rather than panicking on any input.
 pub fn encverify(
     X: &PublicKey,
     Y: &PublicKey,
@@ -81,42 +155,99 @@ pub fn encverify(
         s_hat,
         proof,
     }: &EncryptedSignature,
-) -> anyhow::Result<()> {
-    //TODO: check that s_hat is not 0 -- it will cause a panic
-    dleq::verify(&G, R_hat, Y, R, proof)?;
+) -> Result<(), InvalidEncryptedSignature> {
+    dleq::verify(
+        &mut proof_transcript(X, message_hash),
+        &G,
+        R_hat,
+        Y,
+        R,
+        proof,
+    )?;
 
-    //TODO: Don't panic on something that can be provided by a malicious party
-    // ::parse(0) panics
-    let R_x = SecretKey::parse(&R.x_coor()).unwrap();
+    // R_hat = u0 * G + u1 * X
+    let (u0, u1) = verification_scalars(R, s_hat, message_hash)?;
+    let R_hat_candidate =
+        group::double_mul(X, &u1, &u0).ok_or(InvalidEncryptedSignature::Mismatch)?;
 
-    let message_hash = SecretKey::parse(message_hash).unwrap();
+    if &R_hat_candidate != R_hat {
+        return Err(InvalidEncryptedSignature::Mismatch);
+    }
 
-    let s_hat_inv = s_hat.inv();
+    Ok(())
+}
 
-    let U0 = {
-        let mut u0 = message_hash;
-        u0.tweak_mul_assign(&s_hat_inv).unwrap();
+/// Checks many encrypted signatures, given as `(X, Y, message_hash, encrypted_signature)`, at
+/// once, which is faster than calling [`encverify`] on each of them.
+///
+/// Both the proofs and the equations `R_hat = u0 * G + u1 * X` are combined with random weights
+/// drawn from `rng` into multi-scalar multiplications. The error does not say which encrypted
+/// signature is invalid; [`encverify`] does.
+pub fn encverify_batch<R: rand::Rng>(
+    rng: &mut R,
+    items: &[(&PublicKey, &PublicKey, &[u8; 32], &EncryptedSignature)],
+) -> Result<(), InvalidEncryptedSignature> {
+    let proofs = items
+        .iter()
+        .map(|(X, Y, message_hash, encsig)| dleq::BatchItem {
+            transcript: proof_transcript(X, message_hash),
+            G: &G,
+            Gx: &encsig.R_hat,
+            H: Y,
+            Hx: &encsig.R,
+            proof: &encsig.proof,
+        })
+        .collect();
+    dleq::verify_batch(rng, proofs)?;
+
+    // sum of z(u0 * G + u1 * X - R_hat) for random z
+    let mut g = Scalar::from_int(0);
+    let mut terms = Vec::with_capacity(2 * items.len());
+    for (
+        X,
+        _,
+        message_hash,
+        EncryptedSignature {
+            R, R_hat, s_hat, ..
+        },
+    ) in items
+    {
+        let (u0, u1) = verification_scalars(R, s_hat, message_hash)?;
+        let z = schnorr::scalar_from_hash(rng.gen());
+
+        g += z.clone() * u0;
+        terms.push((z.clone() * u1, (*X).clone()));
+        terms.push((-z, R_hat.clone()));
+    }
 
-        let mut U0 = G.clone();
-        U0.tweak_mul_assign(&u0).unwrap();
-        U0
-    };
+    match group::multi_mul(&terms, &g) {
+        None => Ok(()),
+        Some(_) => Err(InvalidEncryptedSignature::Mismatch),
+    }
+}
 
-    let U1 = {
-        let mut u1 = R_x;
-        u1.tweak_mul_assign(&s_hat_inv).unwrap();
-        let mut U1 = X.clone();
-        U1.tweak_mul_assign(&u1).unwrap();
-        U1
-    };
+/// The scalars `u0 = m * s_hat^-1` and `u1 = R_x * s_hat^-1` for which `R_hat = u0 * G + u1 * X`
+/// holds if the encrypted signature is valid.
+fn verification_scalars(
+    R: &PublicKey,
+    s_hat: &SecretKey,
+    message_hash: &[u8; 32],
+) -> Result<(Scalar, Scalar), InvalidEncryptedSignature> {
+    let R_x = schnorr::scalar_from_hash(R.x_coor());
+    if R_x.is_zero() {
+        return Err(InvalidEncryptedSignature::ZeroNonce);
+    }
 
-    let R_hat_candidate = PublicKey::combine(&[U0, U1]).unwrap();
+    // like ECDSA verification, the message hash is reduced rather than rejected
+    let message_hash = schnorr::scalar_from_hash(*message_hash);
 
-    if &R_hat_candidate != R_hat {
-        return Err(InvalidEncryptedSignature.into());
+    let s_hat: Scalar = s_hat.clone().into();
+    if s_hat.is_zero() {
+        return Err(InvalidEncryptedSignature::ZeroSHat);
     }
+    let s_hat_inv = s_hat.inv();
 
-    Ok(())
+    Ok((message_hash * s_hat_inv.clone(), R_x * s_hat_inv))
 }
 
 pub fn decsig<S: AsRef<SecretKey>>(
@@ -131,11 +262,9 @@ pub fn decsig<S: AsRef<SecretKey>>(
         s
     };
 
-    let R_x = R.x_coor();
-
     Signature {
         s: s.into(),
-        r: SecretKey::parse(&R_x).unwrap().into(),
+        r: schnorr::scalar_from_hash(R.x_coor()),
     }
 }
 
@@ -155,12 +284,9 @@ pub fn recover(
         s_hat * s_inv
     };
 
-    let Gy_macron: Affine = {
-        let mut Gy_macron = G.clone();
-        Gy_macron.tweak_mul_assign(&y_macron.clone().try_into()?)?;
-
-        Gy_macron.into()
-    };
+    let Gy_macron: Affine = group::mul_base(&y_macron)
+        .ok_or(::secp256k1::Error::InvalidSecretKey)?
+        .into();
     let Y: Affine = Y.clone().into();
 
     let keypair = if Gy_macron == Y {
@@ -214,6 +340,25 @@ mod test {
         ))
     }
 
+    #[test]
+    fn ecdsa_encsign_and_decsig_message_above_group_order() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+
+        let message = [0xff; 32];
+
+        let encsig = encsign(message, &x, &y.to_pk(), &mut rand::thread_rng());
+        encverify(&x.to_pk(), &y.to_pk(), &message, &encsig).unwrap();
+
+        let sig = decsig(&y, &encsig);
+
+        assert!(::secp256k1::verify(
+            &Message::parse(&message),
+            &sig,
+            &x.to_pk()
+        ))
+    }
+
     #[test]
     fn recover_key_from_decrypted_signature() {
         let x = KeyPair::random_from_thread_rng();
@@ -228,4 +373,179 @@ mod test {
 
         assert_eq!(y, y_tag);
     }
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    fn keypair(hex: &str) -> KeyPair {
+        KeyPair::from(SecretKey::parse(&bytes32(hex)).unwrap())
+    }
+
+    /// Fixed vectors: secret key, encryption secret, message, auxiliary randomness, the encoded
+    /// encrypted signature `R | R_hat | s_hat | proof` and the decrypted signature `r | s`.
+    #[test]
+    fn test_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "02A4F928091C63142DF6BE987274F74C550C06BA284EFDB20E38C9F99943FE4C5F02F587681C21C643EC1A1F1CD68CF2B2401DF0B135F5596968DC94F7E6858D828A84EBFA513D6ADA1D46179A6E967F4961F6A7E61E6681457873F2464446B9CAAE039892352AD0B2ED577A4230B9A5CF7A4711A3E589B673DF4A07ACEB10E0A79898026E33C5EE24FF56A11EAAC5D24B1B2A8E56F5FEBC36A1E95EC87A9B502F38AB5D365A32AF584BF0216F0B35DA989D476D332C61D4A01E244AC5580C597E454522",
+                "A4F928091C63142DF6BE987274F74C550C06BA284EFDB20E38C9F99943FE4C5F1A959876A5E22B9F746B1EE2EAE641E06487FAD2E14D0DE4E3FD4140DAF1F556",
+            ),
+            // only the auxiliary randomness differs from the previous vector
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "02A59EBA8A42A7E41189605DF0BA9CB9211EA92488E84F708A2C1522883AD1677D027AB4B8F5CA612C29A4D95E37C27F1ADCEDBA991FC4DC722E153C83407955C15B0FCC21896FACDD50E8DE3D297BFAB0FCFDDA9A5584C69418338FBA97845F36B802E1C7BDA1D3740CF3FDE73672CDA045C6ABACDCB6EB9BC1D5090D791591117888037583D82CE4073008D78A40A269CE8176A94DE5EC0814DF9BC32EE5FC6ABCF0C1129CAFE964B9038FFB6C8E372372768DEB30EAE7002C5269330B8C670D4C5B68",
+                "A59EBA8A42A7E41189605DF0BA9CB9211EA92488E84F708A2C1522883AD1677D365C06B51655C5DCFB5FA5D518CBBCFF24E84B0C0A6970DD97139EA0DDB77E65",
+            ),
+            (
+                "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+                "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+                "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0300206372667F176A1A9119B94E20C6A34BC48EBC46A3BD697EE3BF277AB0C62003B6D354D315004758E2DCEE7F5D13D121EFB3AA0DF7BE40C9791731305949E2632CB1D0B314BB999C927188182A500001C22E4D23E9A00D60A93BC79FF2C0275002D24EF66185B3CABC163F2E24F355E5BA3C3F5C27A2B6A19350E4E5F1D903DB52031A60B81505EC7F8B7037E212F32831B1228F571AF71B293E91F2022B4B2686CAF240F8F910688B3F6CB2974CBFD6B0AE959FCA1B06B82B7014138F81909F6B51",
+                "00206372667F176A1A9119B94E20C6A34BC48EBC46A3BD697EE3BF277AB0C620B10B9B54FD56F46A44FD8C50FF95A9526F65EBEABC759C368D7A68169769DB4A",
+            ),
+            // the encryption secret is -1, so R is -R_hat
+            (
+                "0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364140",
+                "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "02BAF7247964C78AD26CC2BC473B966F0F775379C58081A522B4C46335FEC5CDA903BAF7247964C78AD26CC2BC473B966F0F775379C58081A522B4C46335FEC5CDA9E1B645732D9B75E8A650FB8BE1E81BF76DDF5FDD0EEF5AE03FEC81C2B4FD3EC802F408854B77D69538364472F0D3C52BA054A33741675D6172593295580C8CCCF103F408854B77D69538364472F0D3C52BA054A33741675D6172593295580C8CCCF1D4219EFC7E47E5F2ECCD9B7670E25274674FC87FEFDD051F3D25AA80CBF678EF",
+                "BAF7247964C78AD26CC2BC473B966F0F775379C58081A522B4C46335FEC5CDA91E49BA8CD2648A1759AF04741E17E4074CCF7D09A059455B7FE5DCCA1B390279",
+            ),
+        ];
+
+        for (x, y, message, aux, expected_encsig, expected_sig) in vectors.iter() {
+            let x = keypair(x);
+            let y = keypair(y);
+            let message = bytes32(message);
+
+            let encsig = encsign_with_aux(message, &x, &y.to_pk(), &bytes32(aux));
+            let mut encoded = Vec::new();
+            wire::Encode::encode(&encsig, &mut encoded);
+            assert_eq!(hex::encode_upper(encoded), *expected_encsig);
+            encverify(&x.to_pk(), &y.to_pk(), &message, &encsig).unwrap();
+
+            let sig = decsig(&y, &encsig);
+            assert_eq!(hex::encode_upper(&sig.serialize()[..]), *expected_sig);
+
+            assert_eq!(recover(&y.to_pk(), &encsig, &sig).unwrap().unwrap(), y);
+        }
+    }
+
+    fn random_encrypted_signatures(
+        n: usize,
+    ) -> Vec<(PublicKey, PublicKey, [u8; 32], EncryptedSignature)> {
+        (0..n)
+            .map(|i| {
+                let x = KeyPair::random_from_thread_rng();
+                let y = KeyPair::random_from_thread_rng();
+                let message = [i as u8 + 1; 32];
+
+                let encsig = encsign(message, &x, &y.to_pk(), &mut rand::thread_rng());
+
+                (x.to_pk(), y.to_pk(), message, encsig)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encverify_batch_accepts_valid_encrypted_signatures() {
+        let encsigs = random_encrypted_signatures(5);
+        let items = encsigs
+            .iter()
+            .map(|(X, Y, message, encsig)| (X, Y, message, encsig))
+            .collect::<Vec<_>>();
+
+        encverify_batch(&mut rand::thread_rng(), &items).unwrap();
+    }
+
+    #[test]
+    fn encverify_batch_rejects_batch_with_one_tampered_s_hat() {
+        let mut encsigs = random_encrypted_signatures(5);
+        let x = KeyPair::random_from_thread_rng();
+        encsigs[2].3.s_hat.tweak_add_assign(x.as_ref()).unwrap();
+
+        let items = encsigs
+            .iter()
+            .map(|(X, Y, message, encsig)| (X, Y, message, encsig))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            encverify_batch(&mut rand::thread_rng(), &items),
+            Err(InvalidEncryptedSignature::Mismatch)
+        );
+    }
+
+    #[test]
+    fn encverify_rejects_tampered_s_hat() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let mut encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+        encsig.s_hat.tweak_add_assign(x.as_ref()).unwrap();
+
+        assert_eq!(
+            encverify(&x.to_pk(), &y.to_pk(), message, &encsig),
+            Err(InvalidEncryptedSignature::Mismatch)
+        );
+    }
+
+    fn arbitrary_encrypted_signature(
+    ) -> impl proptest::strategy::Strategy<Value = EncryptedSignature> {
+        use proptest::strategy::Strategy;
+
+        (
+            dleq::strategy::point(),
+            dleq::strategy::point(),
+            dleq::strategy::secret_key(),
+            dleq::strategy::proof(),
+        )
+            .prop_map(|(R, R_hat, s_hat, proof)| EncryptedSignature {
+                R,
+                R_hat,
+                s_hat,
+                proof,
+            })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn encverify_does_not_panic(
+            X in dleq::strategy::point(),
+            Y in dleq::strategy::point(),
+            message_hash in proptest::prelude::any::<[u8; 32]>(),
+            encsig in arbitrary_encrypted_signature(),
+        ) {
+            let _ = encverify(&X, &Y, &message_hash, &encsig);
+        }
+
+        #[test]
+        fn encverify_does_not_panic_on_valid_proof(
+            x in dleq::strategy::secret_key(),
+            y in dleq::strategy::secret_key(),
+            message_hash in proptest::prelude::any::<[u8; 32]>(),
+            s_hat in dleq::strategy::secret_key(),
+        ) {
+            // only s_hat is adversarial, so verification gets past the proof
+            let x = KeyPair::from(x);
+            let y = KeyPair::from(y);
+
+            let mut encsig = encsign(message_hash, &x, &y.to_pk(), &mut rand::thread_rng());
+            encsig.s_hat = s_hat;
+
+            let _ = encverify(&x.to_pk(), &y.to_pk(), &message_hash, &encsig);
+        }
+    }
 }
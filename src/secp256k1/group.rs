@@ -0,0 +1,286 @@
+//! Point arithmetic on secp256k1.
+//!
+//! Every group operation of the crate goes through this module. Points are kept in the Jacobian
+//! coordinates of libsecp256k1 while they are computed on, and only converted to a `PublicKey`
+//! once at the end. Products are computed as one multi-scalar multiplication wherever possible:
+//! `aA + gG` uses the precomputed generator tables of libsecp256k1, and longer sums use Straus'
+//! method, in which all terms share one run of doublings.
+
+use crate::secp256k1::{PublicKey, Scalar, G};
+use secp256k1::curve::{Affine, Field, Jacobian, ECMULT_CONTEXT, ECMULT_GEN_CONTEXT};
+
+const WINDOW: usize = 5;
+const TABLE_SIZE: usize = 1 << (WINDOW - 2);
+
+/// Computes `gG`, which is `None` if `g` is zero.
+pub fn mul_base(g: &Scalar) -> Option<PublicKey> {
+    let mut result = Jacobian::default();
+    ECMULT_GEN_CONTEXT.ecmult_gen(&mut result, g);
+
+    to_public_key(&result)
+}
+
+/// Computes `aA`, which is `None` if `a` is zero.
+pub fn mul(A: &PublicKey, a: &Scalar) -> Option<PublicKey> {
+    if A == &*G {
+        return mul_base(a);
+    }
+
+    double_mul(A, a, &Scalar::from_int(0))
+}
+
+/// Computes `aA + gG`, which is `None` if it is the point at infinity.
+pub fn double_mul(A: &PublicKey, a: &Scalar, g: &Scalar) -> Option<PublicKey> {
+    let A: Affine = A.clone().into();
+    let mut result = Jacobian::default();
+    ECMULT_CONTEXT.ecmult(&mut result, &Jacobian::from_ge(&A), a, g);
+
+    to_public_key(&result)
+}
+
+/// Computes `a_1 P_1 + ... + a_n P_n + g G`, which is `None` if it is the point at infinity.
+///
+/// Terms with a zero scalar are skipped and terms on the generator are folded into `g`, so that a
+/// single remaining term is handled by [`double_mul`].
+pub fn multi_mul(terms: &[(Scalar, PublicKey)], g: &Scalar) -> Option<PublicKey> {
+    let mut g = g.clone();
+    let mut points = Vec::with_capacity(terms.len() + 1);
+    for (scalar, point) in terms {
+        if scalar.is_zero() {
+            continue;
+        }
+
+        if point == &*G {
+            g += scalar.clone();
+        } else {
+            points.push((scalar.clone(), point.clone()));
+        }
+    }
+
+    match points.len() {
+        0 => mul_base(&g),
+        1 => double_mul(&points[0].1, &points[0].0, &g),
+        _ => {
+            if !g.is_zero() {
+                points.push((g, G.clone()));
+            }
+
+            straus(&points)
+        }
+    }
+}
+
+/// Adds up `points`, which is `None` if the sum is the point at infinity.
+pub fn sum(points: &[PublicKey]) -> Option<PublicKey> {
+    let mut result = Jacobian::default();
+    result.set_infinity();
+
+    for point in points {
+        let point: Affine = point.clone().into();
+        result = result.add_ge_var(&point, None);
+    }
+
+    to_public_key(&result)
+}
+
+pub fn negate(X: &PublicKey) -> PublicKey {
+    let X: Affine = X.clone().into();
+
+    to_public_key(&Jacobian::from_ge(&X.neg())).expect("negation of a valid point is valid")
+}
+
+/// The point with x-coordinate `x` and an even y-coordinate, if there is one.
+pub fn lift_x(x: &[u8; 32]) -> Option<PublicKey> {
+    let mut compressed = [0x02; 33];
+    compressed[1..].copy_from_slice(x);
+
+    PublicKey::parse_compressed(&compressed).ok()
+}
+
+pub fn has_even_y(X: &PublicKey) -> bool {
+    X.serialize_compressed()[0] == 0x02
+}
+
+fn to_public_key(point: &Jacobian) -> Option<PublicKey> {
+    if point.is_infinity() {
+        return None;
+    }
+
+    let mut point = Affine::from_gej(point);
+    point.x.normalize_var();
+    point.y.normalize_var();
+
+    let mut serialized = [0x04; 65];
+    serialized[1..33].copy_from_slice(&point.x.b32());
+    serialized[33..].copy_from_slice(&point.y.b32());
+
+    Some(PublicKey::parse(&serialized).expect("a point on the curve"))
+}
+
+/// Straus' method with a width-`WINDOW` NAF of every scalar: the doublings are shared by all terms,
+/// so each additional term only costs its additions.
+fn straus(terms: &[(Scalar, PublicKey)]) -> Option<PublicKey> {
+    let wnafs = terms
+        .iter()
+        .map(|(scalar, _)| wnaf(scalar))
+        .collect::<Vec<_>>();
+
+    let top = wnafs
+        .iter()
+        .filter_map(|wnaf| wnaf.iter().rposition(|digit| *digit != 0))
+        .max()?;
+
+    let tables = to_affine(
+        &terms
+            .iter()
+            .flat_map(|(_, point)| odd_multiples(point).to_vec())
+            .collect::<Vec<_>>(),
+    );
+
+    let mut sum = Jacobian::default();
+    sum.set_infinity();
+    for bit in (0..=top).rev() {
+        sum = sum.double_var(None);
+
+        for (table, wnaf) in tables.chunks(TABLE_SIZE).zip(wnafs.iter()) {
+            let digit = wnaf[bit];
+            if digit > 0 {
+                sum = sum.add_ge_var(&table[(digit as usize - 1) / 2], None);
+            } else if digit < 0 {
+                sum = sum.add_ge_var(&table[(-digit as usize - 1) / 2].neg(), None);
+            }
+        }
+    }
+
+    to_public_key(&sum)
+}
+
+/// `P, 3P, 5P, ..., (2^(WINDOW - 1) - 1)P`
+fn odd_multiples(point: &PublicKey) -> [Jacobian; TABLE_SIZE] {
+    let point: Affine = point.clone().into();
+    let point = Jacobian::from_ge(&point);
+    let double = point.double_var(None);
+
+    let mut table: [Jacobian; TABLE_SIZE] = Default::default();
+    table[0] = point;
+    for i in 1..TABLE_SIZE {
+        table[i] = table[i - 1].add_var(&double, None);
+    }
+
+    table
+}
+
+/// Converts `points`, none of which is the point at infinity, to affine coordinates with a single
+/// field inversion.
+fn to_affine(points: &[Jacobian]) -> Vec<Affine> {
+    // prefixes[i] = z_0 * ... * z_i
+    let mut prefixes = Vec::with_capacity(points.len());
+    let mut prefix = Field::from_int(1);
+    for point in points {
+        prefix *= &point.z;
+        prefixes.push(prefix.clone());
+    }
+
+    // (z_0 * ... * z_i)^-1, from the last point to the first
+    let mut inverse = prefix.inv();
+    let mut affine = vec![Affine::default(); points.len()];
+    for i in (0..points.len()).rev() {
+        let z_inv = match i {
+            0 => inverse.clone(),
+            _ => &inverse * &prefixes[i - 1],
+        };
+        affine[i].set_gej_zinv(&points[i], &z_inv);
+
+        inverse *= &points[i].z;
+    }
+
+    affine
+}
+
+/// The width-`WINDOW` NAF of `scalar`: digits that are zero or odd and below `2^(WINDOW - 1)` in
+/// absolute value, with at most one non-zero digit in every `WINDOW` consecutive ones.
+fn wnaf(scalar: &Scalar) -> [i32; 256] {
+    let mut wnaf = [0i32; 256];
+
+    // a scalar above n/2 is negated, so that it fits into 255 bits and carries never overflow
+    let (scalar, sign) = if scalar.bits(255, 1) == 1 {
+        (-scalar.clone(), -1)
+    } else {
+        (scalar.clone(), 1)
+    };
+
+    let mut bit = 0;
+    let mut carry = 0;
+    while bit < 256 {
+        if scalar.bits(bit, 1) as i32 == carry {
+            bit += 1;
+            continue;
+        }
+
+        let now = WINDOW.min(256 - bit);
+        let mut word = scalar.bits_var(bit, now) as i32 + carry;
+
+        carry = (word >> (WINDOW - 1)) & 1;
+        word -= carry << WINDOW;
+
+        wnaf[bit] = sign * word;
+        bit += now;
+    }
+
+    wnaf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secp256k1::KeyPair;
+
+    fn random_scalar() -> Scalar {
+        KeyPair::random_from_thread_rng().as_ref().clone().into()
+    }
+
+    #[test]
+    fn multi_mul_equals_sum_of_products() {
+        for n in 0..6 {
+            let terms = (0..n)
+                .map(|_| (random_scalar(), KeyPair::random_from_thread_rng().to_pk()))
+                .collect::<Vec<_>>();
+            let g = random_scalar();
+
+            let products = terms
+                .iter()
+                .map(|(scalar, point)| mul(point, scalar).unwrap())
+                .chain(mul_base(&g))
+                .collect::<Vec<_>>();
+
+            assert_eq!(multi_mul(&terms, &g), sum(&products));
+        }
+    }
+
+    #[test]
+    fn multi_mul_handles_edge_case_scalars() {
+        let X = KeyPair::random_from_thread_rng().to_pk();
+        let Y = KeyPair::random_from_thread_rng().to_pk();
+        let minus_one = -Scalar::from_int(1);
+
+        // -X + X + 0X + Y - Y - G + G
+        let terms = vec![
+            (minus_one.clone(), X.clone()),
+            (Scalar::from_int(1), X.clone()),
+            (Scalar::from_int(0), X),
+            (Scalar::from_int(1), Y.clone()),
+            (minus_one.clone(), Y),
+            (minus_one, G.clone()),
+        ];
+
+        assert_eq!(multi_mul(&terms, &Scalar::from_int(1)), None);
+    }
+
+    #[test]
+    fn mul_by_generator_equals_mul_base() {
+        let x = random_scalar();
+
+        assert_eq!(mul(&G, &x), double_mul(&G, &x, &Scalar::from_int(0)));
+        assert_eq!(mul_base(&Scalar::from_int(0)), None);
+    }
+}
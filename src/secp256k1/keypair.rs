@@ -44,6 +44,18 @@ impl KeyPair {
     }
 }
 
+impl crate::wire::Encode for KeyPair {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.sk.encode(buffer);
+    }
+}
+
+impl crate::wire::Decode for KeyPair {
+    fn decode(reader: &mut crate::wire::Reader<'_>) -> Result<Self, crate::wire::DecodeError> {
+        Ok(KeyPair::from(SecretKey::decode(reader)?))
+    }
+}
+
 impl From<SecretKey> for KeyPair {
     fn from(secret_key: SecretKey) -> Self {
         Self {
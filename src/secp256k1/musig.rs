@@ -0,0 +1,720 @@
+//! Two-round MuSig2 multi-signatures as specified in BIP327.
+//!
+//! The signers aggregate their keys into a single x-only key with `KeyAggContext`, exchange a
+//! `PublicNonce` each, and then each of them produces a `PartialSignature` within a `Session`.
+//! The partial signatures add up to a BIP340 signature under the aggregate key, which cannot be
+//! told apart from a single-signer one.
+//!
+//! A session can also be encrypted under a point `Y`, in which case the partial signatures add up
+//! to a `schnorr_enc` adaptor signature under the aggregate key. This is how both parties of a
+//! joint output sign a redeem transaction that only the holder of the discrete log of `Y` can
+//! complete, revealing it to the others.
+
+use crate::secp256k1::group::{double_mul, has_even_y, mul, multi_mul, negate, sum};
+use crate::secp256k1::schnorr::{
+    self, challenge, scalar_from_bytes, scalar_from_hash, tagged_hash,
+};
+use crate::secp256k1::schnorr_enc;
+use crate::secp256k1::{KeyPair, PublicKey, Scalar, SecretKey, XCoor, G};
+use crate::wire;
+use std::convert::TryFrom;
+
+/// The aggregate of a list of public keys, optionally tweaked.
+#[derive(Debug, Clone)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    /// Hash of all keys, committing every key coefficient to the whole list.
+    list_hash: [u8; 32],
+    /// The first key of the list that differs from the first one, whose coefficient is 1.
+    second_key: Option<PublicKey>,
+    Q: PublicKey,
+    /// The product of all sign flips of the aggregate key done while tweaking, 1 or -1.
+    gacc: Scalar,
+    /// The accumulated tweak.
+    tacc: Scalar,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("tweaked aggregate key is the point at infinity")]
+pub struct InvalidTweak;
+
+impl KeyAggContext {
+    /// Aggregates `keys`. The order of the keys matters and must be the same for all signers.
+    pub fn new(keys: &[PublicKey]) -> Self {
+        let serialized_keys = keys
+            .iter()
+            .map(|key| key.serialize_compressed().to_vec())
+            .collect::<Vec<_>>()
+            .concat();
+        let list_hash = tagged_hash("KeyAgg list", &[&serialized_keys]);
+        let second_key = keys.iter().find(|key| *key != &keys[0]).cloned();
+
+        let mut context = Self {
+            keys: keys.to_vec(),
+            list_hash,
+            second_key,
+            Q: G.clone(),
+            gacc: Scalar::from_int(1),
+            tacc: Scalar::from_int(0),
+        };
+
+        let weighted_keys = keys
+            .iter()
+            .map(|key| (context.coefficient(key), key.clone()))
+            .collect::<Vec<_>>();
+        context.Q = multi_mul(&weighted_keys, &Scalar::from_int(0))
+            .expect("aggregate key is the point at infinity with negligible probability");
+
+        context
+    }
+
+    /// The aggregate key, of which only the x-coordinate is used by BIP340.
+    pub fn aggregate_key(&self) -> &PublicKey {
+        &self.Q
+    }
+
+    /// Adds `tweak * G` to the x-only aggregate key, as Taproot does to commit to a script tree.
+    pub fn with_xonly_tweak(self, tweak: &Scalar) -> Result<Self, InvalidTweak> {
+        let g = parity(&self.Q);
+
+        // Q' = gQ + tG
+        let Q = double_mul(&self.Q, &g, tweak).ok_or(InvalidTweak)?;
+
+        Ok(Self {
+            Q,
+            gacc: g.clone() * self.gacc,
+            tacc: tweak.clone() + g * self.tacc,
+            ..self
+        })
+    }
+
+    fn coefficient(&self, X: &PublicKey) -> Scalar {
+        if Some(X) == self.second_key.as_ref() {
+            return Scalar::from_int(1);
+        }
+
+        scalar_from_hash(tagged_hash(
+            "KeyAgg coefficient",
+            &[&self.list_hash, &X.serialize_compressed()],
+        ))
+    }
+
+    fn contains(&self, X: &PublicKey) -> bool {
+        self.keys.contains(X)
+    }
+}
+
+/// The two secret nonces of a signer. It is consumed when signing, so it cannot be used twice.
+///
+/// Signing twice with the same secret nonce in sessions with different nonces of the other
+/// signers reveals the secret key, so it has no wire encoding either: a party restored from a
+/// snapshot cannot sign with the nonce it announced before.
+pub struct SecretNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    X: PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicNonce {
+    R1: PublicKey,
+    R2: PublicKey,
+}
+
+impl wire::Encode for PublicNonce {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.R1.encode(buffer);
+        self.R2.encode(buffer);
+    }
+}
+
+impl wire::Decode for PublicNonce {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(PublicNonce {
+            R1: PublicKey::decode(reader)?,
+            R2: PublicKey::decode(reader)?,
+        })
+    }
+}
+
+impl SecretNonce {
+    /// Draws the nonces of `x` for signing `message` under `context`.
+    ///
+    /// Besides the randomness of `rng`, the nonces are derived from the secret key, the aggregate
+    /// key and the message, so that a broken `rng` does not make them repeat across sessions.
+    pub fn new<R: rand::Rng>(
+        rng: &mut R,
+        x: &KeyPair,
+        context: &KeyAggContext,
+        message: &[u8; 32],
+    ) -> (SecretNonce, PublicNonce) {
+        let mut rand = [0u8; 32];
+        rng.fill_bytes(&mut rand);
+
+        let aux_hash = tagged_hash("MuSig/aux", &[&rand]);
+        let mut rand = x.secret_key().serialize();
+        for (rand, aux_hash) in rand.iter_mut().zip(aux_hash.iter()) {
+            *rand ^= aux_hash;
+        }
+
+        let X = x.to_pk().serialize_compressed();
+        let Q = context.Q.x_coor();
+
+        let k = |i: u8| {
+            let k = scalar_from_hash(tagged_hash(
+                "MuSig/nonce",
+                &[
+                    &rand,
+                    &[X.len() as u8],
+                    &X,
+                    &[Q.len() as u8],
+                    &Q,
+                    &[1],
+                    &(message.len() as u64).to_be_bytes(),
+                    message,
+                    &0u32.to_be_bytes(),
+                    &[i],
+                ],
+            ));
+
+            SecretKey::try_from(k).expect("nonce is zero with negligible probability")
+        };
+        let (k1, k2) = (k(0), k(1));
+
+        let public_nonce = PublicNonce {
+            R1: PublicKey::from_secret_key(&k1),
+            R2: PublicKey::from_secret_key(&k2),
+        };
+        let secret_nonce = SecretNonce {
+            k1,
+            k2,
+            X: x.to_pk(),
+        };
+
+        (secret_nonce, public_nonce)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialSignature(Scalar);
+
+impl wire::Encode for PartialSignature {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.0.encode(buffer);
+    }
+}
+
+impl wire::Decode for PartialSignature {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let s = reader.read_array_32()?;
+
+        scalar_from_bytes(&s)
+            .map(PartialSignature)
+            .ok_or(wire::DecodeError::InvalidScalar)
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("key is not part of the aggregate key")]
+pub struct KeyNotAggregated;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("secret nonce was drawn for a different key")]
+pub struct NonceKeyMismatch;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("invalid partial signature")]
+pub struct InvalidPartialSignature;
+
+/// Signing `message` under `context` once all public nonces are known.
+pub struct Session {
+    context: KeyAggContext,
+    message: [u8; 32],
+    /// The coefficient of the second nonce of every signer.
+    b: Scalar,
+    R: PublicKey,
+    e: Scalar,
+}
+
+impl Session {
+    pub fn new(context: &KeyAggContext, nonces: &[PublicNonce], message: &[u8; 32]) -> Self {
+        Self::with_nonce_offset(context, nonces, message, None)
+    }
+
+    /// Like [`Session::new`], but the aggregate nonce is offset by `Y`, so that the partial
+    /// signatures add up to a signature encrypted under `Y`, see [`Session::aggregate_encrypted`].
+    pub fn new_encrypted(
+        context: &KeyAggContext,
+        nonces: &[PublicNonce],
+        message: &[u8; 32],
+        Y: &PublicKey,
+    ) -> Self {
+        Self::with_nonce_offset(context, nonces, message, Some(Y))
+    }
+
+    fn with_nonce_offset(
+        context: &KeyAggContext,
+        nonces: &[PublicNonce],
+        message: &[u8; 32],
+        Y: Option<&PublicKey>,
+    ) -> Self {
+        let R1 = sum(&nonces
+            .iter()
+            .map(|nonce| nonce.R1.clone())
+            .collect::<Vec<_>>());
+        let R2 = sum(&nonces
+            .iter()
+            .map(|nonce| nonce.R2.clone())
+            .collect::<Vec<_>>());
+
+        let b = scalar_from_hash(tagged_hash(
+            "MuSig/noncecoef",
+            &[
+                &serialize_nonce_point(&R1),
+                &serialize_nonce_point(&R2),
+                &context.Q.x_coor(),
+                message,
+            ],
+        ));
+
+        // R = R1 + bR2, replaced by G if it is the point at infinity
+        let R2b = R2.and_then(|R2| mul(&R2, &b));
+        let R = sum(&R1.into_iter().chain(R2b).collect::<Vec<_>>()).unwrap_or_else(|| G.clone());
+
+        // the nonce of the decrypted signature, R_hat + Y, like that of `schnorr_enc`. `b` commits
+        // to the nonces, so nobody can make it the point at infinity by choosing theirs.
+        let R = match Y {
+            Some(Y) => sum(&[R, Y.clone()]).expect(
+                "aggregate nonce is the negated encryption key with negligible probability",
+            ),
+            None => R,
+        };
+
+        let e = challenge(&R.x_coor(), &context.Q, message);
+
+        Self {
+            context: context.clone(),
+            message: *message,
+            b,
+            R,
+            e,
+        }
+    }
+
+    pub fn message(&self) -> &[u8; 32] {
+        &self.message
+    }
+
+    pub fn partial_sign(
+        &self,
+        SecretNonce { k1, k2, X }: SecretNonce,
+        x: &KeyPair,
+    ) -> anyhow::Result<PartialSignature> {
+        if &X != x.public_key() {
+            anyhow::bail!(NonceKeyMismatch)
+        }
+        if !self.context.contains(&X) {
+            anyhow::bail!(KeyNotAggregated)
+        }
+
+        let (k1, k2): (Scalar, Scalar) = (k1.into(), k2.into());
+        let (k1, k2) = if has_even_y(&self.R) {
+            (k1, k2)
+        } else {
+            (-k1, -k2)
+        };
+
+        let d: Scalar = x.to_sk().into();
+        let d = parity(&self.context.Q) * self.context.gacc.clone() * d;
+        let a = self.context.coefficient(&X);
+
+        // s = k1 + b * k2 + e * a * d
+        let s = k1 + self.b.clone() * k2 + self.e.clone() * a * d;
+
+        Ok(PartialSignature(s))
+    }
+
+    /// Checks the partial signature of `X` against the public nonce it announced.
+    pub fn verify_partial(
+        &self,
+        PartialSignature(s): &PartialSignature,
+        PublicNonce { R1, R2 }: &PublicNonce,
+        X: &PublicKey,
+    ) -> Result<(), InvalidPartialSignature> {
+        if !self.context.contains(X) {
+            return Err(InvalidPartialSignature);
+        }
+
+        // the effective nonce R1 + bR2, negated along with the aggregate nonce
+        let R_e = {
+            let R2b = mul(R2, &self.b).ok_or(InvalidPartialSignature)?;
+            let R_e = sum(&[R1.clone(), R2b]).ok_or(InvalidPartialSignature)?;
+
+            if has_even_y(&self.R) {
+                R_e
+            } else {
+                negate(&R_e)
+            }
+        };
+
+        // sG - e * a * g * gacc * X must equal the effective nonce
+        let g = parity(&self.context.Q) * self.context.gacc.clone();
+        let e_a_g = self.e.clone() * self.context.coefficient(X) * g;
+        let R_e_candidate = double_mul(X, &-e_a_g, s).ok_or(InvalidPartialSignature)?;
+
+        if R_e_candidate != R_e {
+            return Err(InvalidPartialSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Adds up the partial signatures of all signers into a BIP340 signature under the aggregate
+    /// key. The result is only valid if every partial signature is.
+    pub fn aggregate(&self, partial_signatures: &[PartialSignature]) -> schnorr::Signature {
+        schnorr::Signature {
+            R_x: self.R.x_coor(),
+            s: self.sum(partial_signatures),
+        }
+    }
+
+    /// Adds up the partial signatures of all signers of a session created with
+    /// [`Session::new_encrypted`] into an adaptor signature under the aggregate key. The result
+    /// is only valid if every partial signature is.
+    pub fn aggregate_encrypted(
+        &self,
+        partial_signatures: &[PartialSignature],
+    ) -> schnorr_enc::EncryptedSignature {
+        schnorr_enc::EncryptedSignature::new(self.R.clone(), self.sum(partial_signatures))
+    }
+
+    fn sum(&self, partial_signatures: &[PartialSignature]) -> Scalar {
+        let s = partial_signatures
+            .iter()
+            .fold(Scalar::from_int(0), |s, PartialSignature(s_i)| {
+                s + s_i.clone()
+            });
+
+        let g = parity(&self.context.Q);
+
+        s + self.e.clone() * g * self.context.tacc.clone()
+    }
+}
+
+/// 1 if `X` has an even y-coordinate, -1 otherwise.
+fn parity(X: &PublicKey) -> Scalar {
+    if has_even_y(X) {
+        Scalar::from_int(1)
+    } else {
+        -Scalar::from_int(1)
+    }
+}
+
+/// A sum of nonces, which can be the point at infinity, encoded as 33 zero bytes.
+fn serialize_nonce_point(R: &Option<PublicKey>) -> [u8; 33] {
+    match R {
+        Some(R) => R.serialize_compressed(),
+        None => [0u8; 33],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MESSAGE: [u8; 32] = [42u8; 32];
+
+    /// Runs both rounds of MuSig2 for `x_1` and `x_2`, checking the partial signatures.
+    fn sign_2_of_2(context: &KeyAggContext, x_1: &KeyPair, x_2: &KeyPair) -> schnorr::Signature {
+        let mut rng = rand::thread_rng();
+
+        let (secnonce_1, pubnonce_1) = SecretNonce::new(&mut rng, x_1, context, &MESSAGE);
+        let (secnonce_2, pubnonce_2) = SecretNonce::new(&mut rng, x_2, context, &MESSAGE);
+
+        let session = Session::new(context, &[pubnonce_1.clone(), pubnonce_2.clone()], &MESSAGE);
+        let partial_1 = session.partial_sign(secnonce_1, x_1).unwrap();
+        let partial_2 = session.partial_sign(secnonce_2, x_2).unwrap();
+
+        session
+            .verify_partial(&partial_1, &pubnonce_1, x_1.public_key())
+            .unwrap();
+        session
+            .verify_partial(&partial_2, &pubnonce_2, x_2.public_key())
+            .unwrap();
+
+        session.aggregate(&[partial_1, partial_2])
+    }
+
+    #[test]
+    fn aggregate_signature_verifies_under_aggregate_key() {
+        for _ in 0..8 {
+            let x_1 = KeyPair::random_from_thread_rng();
+            let x_2 = KeyPair::random_from_thread_rng();
+            let context = KeyAggContext::new(&[x_1.to_pk(), x_2.to_pk()]);
+
+            let signature = sign_2_of_2(&context, &x_1, &x_2);
+
+            schnorr::verify(MESSAGE, &signature, context.aggregate_key()).unwrap();
+        }
+    }
+
+    #[test]
+    fn aggregate_signature_verifies_under_tweaked_aggregate_key() {
+        for _ in 0..8 {
+            let x_1 = KeyPair::random_from_thread_rng();
+            let x_2 = KeyPair::random_from_thread_rng();
+            let tweak = KeyPair::random_from_thread_rng().to_sk().into();
+            let context = KeyAggContext::new(&[x_1.to_pk(), x_2.to_pk()])
+                .with_xonly_tweak(&tweak)
+                .unwrap();
+
+            let signature = sign_2_of_2(&context, &x_1, &x_2);
+
+            schnorr::verify(MESSAGE, &signature, context.aggregate_key()).unwrap();
+        }
+    }
+
+    #[test]
+    fn aggregate_key_depends_on_key_order() {
+        let X_1 = KeyPair::random_from_thread_rng().to_pk();
+        let X_2 = KeyPair::random_from_thread_rng().to_pk();
+
+        assert_ne!(
+            KeyAggContext::new(&[X_1.clone(), X_2.clone()]).aggregate_key(),
+            KeyAggContext::new(&[X_2, X_1]).aggregate_key()
+        );
+    }
+
+    #[test]
+    fn partial_signature_of_other_signer_is_rejected() {
+        let mut rng = rand::thread_rng();
+        let x_1 = KeyPair::random_from_thread_rng();
+        let x_2 = KeyPair::random_from_thread_rng();
+        let context = KeyAggContext::new(&[x_1.to_pk(), x_2.to_pk()]);
+
+        let (secnonce_1, pubnonce_1) = SecretNonce::new(&mut rng, &x_1, &context, &MESSAGE);
+        let (_, pubnonce_2) = SecretNonce::new(&mut rng, &x_2, &context, &MESSAGE);
+
+        let session = Session::new(&context, &[pubnonce_1.clone(), pubnonce_2], &MESSAGE);
+        let partial_1 = session.partial_sign(secnonce_1, &x_1).unwrap();
+
+        assert_eq!(
+            session.verify_partial(&partial_1, &pubnonce_1, x_2.public_key()),
+            Err(InvalidPartialSignature)
+        );
+    }
+
+    #[test]
+    fn encrypted_session_aggregates_to_adaptor_signature_under_aggregate_key() {
+        let mut rng = rand::thread_rng();
+        let x_1 = KeyPair::random_from_thread_rng();
+        let x_2 = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let tweak = KeyPair::random_from_thread_rng().to_sk().into();
+        let context = KeyAggContext::new(&[x_1.to_pk(), x_2.to_pk()])
+            .with_xonly_tweak(&tweak)
+            .unwrap();
+
+        let (secnonce_1, pubnonce_1) = SecretNonce::new(&mut rng, &x_1, &context, &MESSAGE);
+        let (secnonce_2, pubnonce_2) = SecretNonce::new(&mut rng, &x_2, &context, &MESSAGE);
+
+        let session = Session::new_encrypted(
+            &context,
+            &[pubnonce_1.clone(), pubnonce_2],
+            &MESSAGE,
+            y.public_key(),
+        );
+        let partial_1 = session.partial_sign(secnonce_1, &x_1).unwrap();
+        let partial_2 = session.partial_sign(secnonce_2, &x_2).unwrap();
+        session
+            .verify_partial(&partial_1, &pubnonce_1, x_1.public_key())
+            .unwrap();
+
+        let encsig = session.aggregate_encrypted(&[partial_1, partial_2]);
+        schnorr_enc::encverify(context.aggregate_key(), y.public_key(), &MESSAGE, &encsig).unwrap();
+
+        let signature = schnorr_enc::decsig(&y, &encsig);
+        schnorr::verify(MESSAGE, &signature, context.aggregate_key()).unwrap();
+        assert_eq!(
+            schnorr_enc::recover(y.public_key(), &encsig, &signature).unwrap(),
+            y
+        );
+    }
+
+    #[test]
+    fn cannot_sign_with_key_outside_of_aggregate() {
+        let mut rng = rand::thread_rng();
+        let x_1 = KeyPair::random_from_thread_rng();
+        let x_2 = KeyPair::random_from_thread_rng();
+        let x_3 = KeyPair::random_from_thread_rng();
+        let context = KeyAggContext::new(&[x_1.to_pk(), x_2.to_pk()]);
+
+        let (secnonce_3, pubnonce_3) = SecretNonce::new(&mut rng, &x_3, &context, &MESSAGE);
+        let session = Session::new(&context, &[pubnonce_3], &MESSAGE);
+
+        session.partial_sign(secnonce_3, &x_3).unwrap_err();
+    }
+
+    fn public_key(hex: &str) -> PublicKey {
+        let mut bytes = [0u8; 33];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+
+        PublicKey::parse_compressed(&bytes).unwrap()
+    }
+
+    fn secret_key(hex: &str) -> SecretKey {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+
+        SecretKey::parse(&bytes).unwrap()
+    }
+
+    fn public_nonce(hex: &str) -> PublicNonce {
+        PublicNonce {
+            R1: public_key(&hex[..66]),
+            R2: public_key(&hex[66..]),
+        }
+    }
+
+    /// The valid cases of `key_agg_vectors.json` of BIP327.
+    #[test]
+    fn bip327_key_aggregation_vectors() {
+        let keys = [
+            public_key("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            public_key("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            public_key("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+        ];
+        let test_cases: &[(&[usize], &str)] = &[
+            (
+                &[0, 1, 2],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ];
+
+        for (key_indices, expected) in test_cases {
+            let keys = key_indices
+                .iter()
+                .map(|i| keys[*i].clone())
+                .collect::<Vec<_>>();
+
+            let context = KeyAggContext::new(&keys);
+
+            assert_eq!(
+                hex::encode_upper(context.aggregate_key().x_coor()),
+                *expected
+            );
+        }
+    }
+
+    /// Signer 0 of `sign_verify_vectors.json` and `tweak_vectors.json` of BIP327.
+    struct SignVector {
+        x: KeyPair,
+        keys: [PublicKey; 3],
+        nonces: [PublicNonce; 3],
+        message: [u8; 32],
+    }
+
+    impl SignVector {
+        fn new(third_key: &str) -> Self {
+            let x = KeyPair::from(secret_key(
+                "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671",
+            ));
+            let mut message = [0u8; 32];
+            message.copy_from_slice(
+                &hex::decode("F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF")
+                    .unwrap(),
+            );
+
+            Self {
+                keys: [x.to_pk(), public_key("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"), public_key(third_key)],
+                x,
+                nonces: [
+                    public_nonce("0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480"),
+                    public_nonce("0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798"),
+                    public_nonce("032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046"),
+                ],
+                message,
+            }
+        }
+
+        fn secret_nonce(&self) -> SecretNonce {
+            SecretNonce {
+                k1: secret_key("508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61"),
+                k2: secret_key("FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7"),
+                X: self.x.to_pk(),
+            }
+        }
+
+        /// Signs as signer 0 with the keys and nonces in the order of `indices`.
+        fn sign(&self, indices: &[usize], tweak: Option<&str>) -> String {
+            let keys = indices
+                .iter()
+                .map(|i| self.keys[*i].clone())
+                .collect::<Vec<_>>();
+            let nonces = indices
+                .iter()
+                .map(|i| self.nonces[*i].clone())
+                .collect::<Vec<_>>();
+
+            let mut context = KeyAggContext::new(&keys);
+            if let Some(tweak) = tweak {
+                context = context.with_xonly_tweak(&secret_key(tweak).into()).unwrap();
+            }
+            let session = Session::new(&context, &nonces, &self.message);
+
+            let partial_signature = session.partial_sign(self.secret_nonce(), &self.x).unwrap();
+            session
+                .verify_partial(&partial_signature, &self.nonces[0], &self.keys[0])
+                .unwrap();
+
+            hex::encode_upper(partial_signature.0.b32())
+        }
+    }
+
+    /// The valid cases of `sign_verify_vectors.json` of BIP327 with a 32-byte message.
+    #[test]
+    fn bip327_sign_vectors() {
+        let vector =
+            SignVector::new("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661");
+
+        assert_eq!(
+            vector.sign(&[0, 1, 2], None),
+            "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB"
+        );
+        assert_eq!(
+            vector.sign(&[1, 0, 2], None),
+            "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52"
+        );
+        assert_eq!(
+            vector.sign(&[1, 2, 0], None),
+            "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900"
+        );
+    }
+
+    /// The valid case of `tweak_vectors.json` of BIP327 with a single x-only tweak.
+    #[test]
+    fn bip327_tweak_vector() {
+        let vector =
+            SignVector::new("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659");
+
+        assert_eq!(
+            vector.sign(
+                &[1, 2, 0],
+                Some("E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB")
+            ),
+            "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91"
+        );
+    }
+}
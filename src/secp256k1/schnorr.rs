@@ -0,0 +1,297 @@
+//! BIP340 Schnorr signatures over x-only public keys.
+//!
+//! A public key is identified by its x-coordinate only; of the two points with that
+//! x-coordinate, the one with an even y-coordinate is meant. Signing with a key whose point has
+//! an odd y-coordinate therefore signs with its negation.
+
+use crate::secp256k1::group::{double_mul, has_even_y, lift_x};
+use crate::secp256k1::{PublicKey, Scalar, SecretKey, ToMessage, XCoor};
+use crate::wire;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    /// The x-coordinate of the nonce point `R`, whose y-coordinate is even.
+    pub R_x: [u8; 32],
+    pub s: Scalar,
+}
+
+impl Signature {
+    pub fn serialize(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.R_x);
+        bytes[32..].copy_from_slice(&self.s.b32());
+
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8; 64]) -> Result<Self, InvalidSignature> {
+        let mut R_x = [0u8; 32];
+        R_x.copy_from_slice(&bytes[..32]);
+
+        let mut s_bytes = [0u8; 32];
+        s_bytes.copy_from_slice(&bytes[32..]);
+        let s = scalar_from_bytes(&s_bytes).ok_or(InvalidSignature)?;
+
+        Ok(Self { R_x, s })
+    }
+}
+
+impl wire::Encode for Signature {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.serialize());
+    }
+}
+
+impl wire::Decode for Signature {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let R_x = reader.read_array_32()?;
+        let s = reader.read_array_32()?;
+
+        let s = scalar_from_bytes(&s).ok_or(wire::DecodeError::InvalidScalar)?;
+
+        Ok(Self { R_x, s })
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("invalid schnorr signature")]
+pub struct InvalidSignature;
+
+/// Signs `message` with `x`, mixing fresh auxiliary randomness into the nonce.
+pub fn sign<M: ToMessage, S: AsRef<SecretKey>, R: rand::Rng>(
+    message: M,
+    x: &S,
+    rng: &mut R,
+) -> Signature {
+    let mut aux = [0u8; 32];
+    rng.fill_bytes(&mut aux);
+
+    sign_with_aux(message, x, &aux)
+}
+
+/// Signs `message` with `x` as specified in BIP340, using `aux` as auxiliary randomness.
+pub fn sign_with_aux<M: ToMessage, S: AsRef<SecretKey>>(
+    message: M,
+    x: &S,
+    aux: &[u8; 32],
+) -> Signature {
+    let message = message.to_message();
+    let X = PublicKey::from_secret_key(x.as_ref());
+    let d = even_y_secret(x.as_ref(), &X);
+
+    let k = {
+        let aux_hash = tagged_hash("BIP0340/aux", &[aux]);
+        let mut t = d.b32();
+        for (t, aux_hash) in t.iter_mut().zip(aux_hash.iter()) {
+            *t ^= aux_hash;
+        }
+
+        let k = scalar_from_hash(tagged_hash("BIP0340/nonce", &[&t, &X.x_coor(), &message]));
+        SecretKey::try_from(k).expect("nonce is zero with negligible probability")
+    };
+
+    let R = PublicKey::from_secret_key(&k);
+    let k = even_y_secret(&k, &R);
+
+    let e = challenge(&R.x_coor(), &X, &message);
+
+    Signature {
+        R_x: R.x_coor(),
+        s: k + e * d,
+    }
+}
+
+pub fn verify<M: ToMessage>(
+    message: M,
+    Signature { R_x, s }: &Signature,
+    X: &PublicKey,
+) -> Result<(), InvalidSignature> {
+    let X = lift_x(&X.x_coor()).ok_or(InvalidSignature)?;
+    let e = challenge(R_x, &X, &message.to_message());
+
+    // R = sG - eX
+    let R = double_mul(&X, &-e, s).ok_or(InvalidSignature)?;
+
+    if !has_even_y(&R) || &R.x_coor() != R_x {
+        return Err(InvalidSignature);
+    }
+
+    Ok(())
+}
+
+/// The challenge `e = H(R_x | X_x | m)` of a signature.
+pub fn challenge(R_x: &[u8; 32], X: &PublicKey, message: &[u8; 32]) -> Scalar {
+    scalar_from_hash(tagged_hash(
+        "BIP0340/challenge",
+        &[R_x, &X.x_coor(), message],
+    ))
+}
+
+/// `SHA256(SHA256(tag) | SHA256(tag) | data)`, a hash whose output is specific to `tag`.
+pub fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = Sha256::digest(tag.as_bytes());
+
+    let mut hasher = Sha256::default();
+    hasher.input(tag);
+    hasher.input(tag);
+    for data in data {
+        hasher.input(data);
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Interprets a hash as an integer modulo the group order.
+pub fn scalar_from_hash(hash: [u8; 32]) -> Scalar {
+    let mut scalar = Scalar::default();
+    let _overflowed = scalar.set_b32(&hash);
+
+    scalar
+}
+
+/// Parses a scalar that is expected to be in canonical form, i.e. below the group order.
+pub(crate) fn scalar_from_bytes(bytes: &[u8; 32]) -> Option<Scalar> {
+    let mut scalar = Scalar::default();
+    let overflowed = scalar.set_b32(bytes);
+
+    if bool::from(overflowed) {
+        return None;
+    }
+
+    Some(scalar)
+}
+
+/// The secret key of the point with `X`'s x-coordinate and an even y-coordinate.
+pub(crate) fn even_y_secret(x: &SecretKey, X: &PublicKey) -> Scalar {
+    let x: Scalar = x.clone().into();
+
+    if has_even_y(X) {
+        x
+    } else {
+        -x
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secp256k1::KeyPair;
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    fn bytes64(hex: &str) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let x = KeyPair::random_from_thread_rng();
+        let message = *b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let signature = sign(message, &x, &mut rand::thread_rng());
+
+        verify(message, &signature, &x.to_pk()).unwrap();
+    }
+
+    #[test]
+    fn signature_does_not_verify_for_other_message() {
+        let x = KeyPair::random_from_thread_rng();
+
+        let signature = sign(
+            *b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm",
+            &x,
+            &mut rand::thread_rng(),
+        );
+
+        verify(*b"nnnnnnnnnnnnnnnnnnnnnnnnnnnnnnnn", &signature, &x.to_pk()).unwrap_err();
+    }
+
+    #[test]
+    fn signature_roundtrips_through_bytes() {
+        let x = KeyPair::random_from_thread_rng();
+        let signature = sign([7u8; 32], &x, &mut rand::thread_rng());
+
+        assert_eq!(Signature::parse(&signature.serialize()), Ok(signature));
+    }
+
+    /// Signing vectors 0 to 3 of BIP340.
+    #[test]
+    fn bip340_signing_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+            ),
+            (
+                "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+                "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+                "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+            ),
+            (
+                "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+                "DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+                "C87AA53824B4D7AE2EB035A2B5BBBCCC080E76CDC6D1692C4B0B62D798E6D906",
+                "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+                "5831AAEED7B44BB74E5EAB94BA9D4294C49BCF2A60728D8B4C200F50DD313C1BAB745879A5AD954A72C45A91C3A51D3C7ADEA98D82F8481E0E1E03674A6F3FB7",
+            ),
+            (
+                "0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+                "25D1DFF95105F5253C4022F628A996AD3A0D95FBF21D468A1B33F8C160D8F517",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "7EB0509757E246F19449885651611CB965ECC1A187DD51B64FDA1EDC9637D5EC97582B9CB13DB3933705B32BA982AF5AF25FD78881EBB32771FC5922EFC66EA3",
+            ),
+        ];
+
+        for (secret_key, public_key, aux, message, signature) in vectors.iter() {
+            let x = KeyPair::from(SecretKey::parse(&bytes32(secret_key)).unwrap());
+            assert_eq!(x.to_pk().x_coor(), bytes32(public_key));
+
+            let signature = Signature::parse(&bytes64(signature)).unwrap();
+            let message = bytes32(message);
+
+            assert_eq!(sign_with_aux(message, &x, &bytes32(aux)), signature);
+            verify(message, &signature, &x.to_pk()).unwrap();
+        }
+    }
+
+    /// Verification vectors 5 to 7 of BIP340, which must fail.
+    #[test]
+    fn bip340_invalid_signatures() {
+        let message = bytes32("243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89");
+        let X = lift_x(&bytes32(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        ))
+        .unwrap();
+
+        let signatures = [
+            // R has an odd y-coordinate
+            "FFF97BD5755EEEA420453A14355235D382F6472F8568A18B2F057A14602975563CC27944640AC607CD107AE10923D9EF7A73C643E166BE5EBEAFA34B1AC553E2",
+            // negated message
+            "1FA62E331EDBC21C394792D2AB1100A7B432B013DF3F6FF4F99FCB33E0E1515F28890B3EDB6E7189B630448B515CE4F8622A954CFE545735AAEA5134FCCDB2BD",
+            // negated s
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769961764B3AA9B2FFCB6EF947B6887A226E8D7C93E00C5ED0C1834FF0D0C2E6DA6",
+        ];
+
+        for signature in signatures.iter() {
+            let signature = Signature::parse(&bytes64(signature)).unwrap();
+
+            verify(message, &signature, &X).unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Adaptor signatures for BIP340 Schnorr signatures.
+//!
+//! Unlike their ECDSA counterpart in `enc`, these need no proof of discrete log equality: the
+//! encrypted signature is checked with a linear equation in the nonce and the encryption key.
+//! Decrypting with `y` yields a plain BIP340 signature, from which `y` can be recovered given the
+//! encrypted signature.
+
+use crate::secp256k1::group::{double_mul, has_even_y, lift_x, negate, sum};
+use crate::secp256k1::schnorr::{
+    self, challenge, even_y_secret, scalar_from_bytes, scalar_from_hash, tagged_hash,
+};
+use crate::secp256k1::{KeyPair, PublicKey, Scalar, SecretKey, ToMessage, XCoor};
+use crate::wire;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedSignature {
+    /// The nonce of the decrypted signature, `R_hat + Y`. Its y-coordinate decides whether `y` is
+    /// added or subtracted when decrypting.
+    R: PublicKey,
+    s_hat: Scalar,
+}
+
+impl EncryptedSignature {
+    /// The encrypted signature `(R, s_hat)` produced by a group of signers, see
+    /// `musig::Session::aggregate_encrypted`.
+    pub(crate) fn new(R: PublicKey, s_hat: Scalar) -> Self {
+        Self { R, s_hat }
+    }
+}
+
+impl wire::Encode for EncryptedSignature {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.R.encode(buffer);
+        self.s_hat.encode(buffer);
+    }
+}
+
+impl wire::Decode for EncryptedSignature {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let R = PublicKey::decode(reader)?;
+        let s_hat = reader.read_array_32()?;
+        let s_hat = scalar_from_bytes(&s_hat).ok_or(wire::DecodeError::InvalidScalar)?;
+
+        Ok(EncryptedSignature { R, s_hat })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("invalid encrypted schnorr signature")]
+pub struct InvalidEncryptedSignature;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("signature was not decrypted from the encrypted signature with the given key")]
+pub struct KeyMismatch;
+
+/// Signs `message` with `x`, encrypted under `Y`.
+pub fn encsign<M: ToMessage, S: AsRef<SecretKey>, R: rand::Rng>(
+    message: M,
+    x: &S,
+    Y: &PublicKey,
+    rng: &mut R,
+) -> EncryptedSignature {
+    let mut aux = [0u8; 32];
+    rng.fill_bytes(&mut aux);
+
+    encsign_with_aux(message, x, Y, &aux)
+}
+
+/// Signs `message` with `x`, encrypted under `Y`, using `aux` as auxiliary randomness.
+///
+/// The nonce is derived as in BIP340, additionally committing to `Y`, so that encrypting the same
+/// message under different keys never reuses a nonce.
+pub fn encsign_with_aux<M: ToMessage, S: AsRef<SecretKey>>(
+    message: M,
+    x: &S,
+    Y: &PublicKey,
+    aux: &[u8; 32],
+) -> EncryptedSignature {
+    let message = message.to_message();
+    let X = PublicKey::from_secret_key(x.as_ref());
+    let d = even_y_secret(x.as_ref(), &X);
+
+    let k = {
+        let aux_hash = tagged_hash("SchnorrAdaptor/aux", &[aux]);
+        let mut t = d.b32();
+        for (t, aux_hash) in t.iter_mut().zip(aux_hash.iter()) {
+            *t ^= aux_hash;
+        }
+
+        let k = scalar_from_hash(tagged_hash(
+            "SchnorrAdaptor/nonce",
+            &[&t, &Y.serialize_compressed(), &X.x_coor(), &message],
+        ));
+        SecretKey::try_from(k).expect("nonce is zero with negligible probability")
+    };
+
+    let R_hat = PublicKey::from_secret_key(&k);
+    let R = sum(&[R_hat, Y.clone()])
+        .expect("nonce is the negated encryption key with negligible probability");
+
+    // the decrypted signature has nonce R, or -R = -R_hat - Y if R has an odd y-coordinate
+    let k: Scalar = k.into();
+    let k = if has_even_y(&R) { k } else { -k };
+
+    let e = challenge(&R.x_coor(), &X, &message);
+
+    EncryptedSignature {
+        s_hat: k + e * d,
+        R,
+    }
+}
+
+/// Checks that `encrypted_signature` decrypts with the discrete log of `Y` to a signature of
+/// `message_hash` under `X`.
+///
+/// Every input may come from a malicious party, so this returns an error instead of panicking on
+/// any of them.
+pub fn encverify(
+    X: &PublicKey,
+    Y: &PublicKey,
+    message_hash: &[u8; 32],
+    EncryptedSignature { R, s_hat }: &EncryptedSignature,
+) -> Result<(), InvalidEncryptedSignature> {
+    let X = lift_x(&X.x_coor()).ok_or(InvalidEncryptedSignature)?;
+    let e = challenge(&R.x_coor(), &X, message_hash);
+
+    // s_hat * G - e * X
+    let R_hat_candidate = double_mul(&X, &-e, s_hat).ok_or(InvalidEncryptedSignature)?;
+
+    let R_hat = sum(&[R.clone(), negate(Y)]).ok_or(InvalidEncryptedSignature)?;
+    let R_hat = if has_even_y(R) { R_hat } else { negate(&R_hat) };
+
+    if R_hat_candidate != R_hat {
+        return Err(InvalidEncryptedSignature);
+    }
+
+    Ok(())
+}
+
+pub fn decsig<S: AsRef<SecretKey>>(
+    y: &S,
+    EncryptedSignature { R, s_hat }: &EncryptedSignature,
+) -> schnorr::Signature {
+    let y: Scalar = y.as_ref().clone().into();
+    let y = if has_even_y(R) { y } else { -y };
+
+    schnorr::Signature {
+        R_x: R.x_coor(),
+        s: s_hat.clone() + y,
+    }
+}
+
+/// Recovers the decryption key `y` of `Y` from `signature`, which was decrypted from
+/// `encrypted_signature`.
+pub fn recover(
+    Y: &PublicKey,
+    EncryptedSignature { R, s_hat }: &EncryptedSignature,
+    signature: &schnorr::Signature,
+) -> Result<KeyPair, KeyMismatch> {
+    if signature.R_x != R.x_coor() {
+        return Err(KeyMismatch);
+    }
+
+    let y = signature.s.clone() + -s_hat.clone();
+    let y = if has_even_y(R) { y } else { -y };
+
+    let y = KeyPair::try_from(y).map_err(|_| KeyMismatch)?;
+    if y.public_key() != Y {
+        return Err(KeyMismatch);
+    }
+
+    Ok(y)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex).unwrap());
+        bytes
+    }
+
+    fn keypair(hex: &str) -> KeyPair {
+        KeyPair::from(SecretKey::parse(&bytes32(hex)).unwrap())
+    }
+
+    #[test]
+    fn encsign_and_encverify() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+
+        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+
+        encverify(&x.to_pk(), &y.to_pk(), message, &encsig).unwrap();
+    }
+
+    #[test]
+    fn encverify_fails_for_other_encryption_key() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let other = KeyPair::random_from_thread_rng();
+        let message = b"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+
+        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+
+        assert_eq!(
+            encverify(&x.to_pk(), &other.to_pk(), message, &encsig),
+            Err(InvalidEncryptedSignature)
+        );
+    }
+
+    #[test]
+    fn schnorr_encsign_and_decsig() {
+        for _ in 0..8 {
+            let x = KeyPair::random_from_thread_rng();
+            let y = KeyPair::random_from_thread_rng();
+            let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+            let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+            let sig = decsig(&y, &encsig);
+
+            schnorr::verify(*message, &sig, &x.to_pk()).unwrap();
+        }
+    }
+
+    #[test]
+    fn recover_key_from_decrypted_signature() {
+        for _ in 0..8 {
+            let x = KeyPair::random_from_thread_rng();
+            let y = KeyPair::random_from_thread_rng();
+            let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+            let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+            let sig = decsig(&y, &encsig);
+
+            assert_eq!(recover(&y.to_pk(), &encsig, &sig), Ok(y));
+        }
+    }
+
+    #[test]
+    fn recover_fails_for_unrelated_signature() {
+        let x = KeyPair::random_from_thread_rng();
+        let y = KeyPair::random_from_thread_rng();
+        let message = b"mmmmmmmmmmmmmmmmmmmmmmmmmmmmmmmm";
+
+        let encsig = encsign(*message, &x, &y.to_pk(), &mut rand::thread_rng());
+        let sig = schnorr::sign(*message, &x, &mut rand::thread_rng());
+
+        assert_eq!(recover(&y.to_pk(), &encsig, &sig), Err(KeyMismatch));
+    }
+
+    /// Fixed vectors: secret key, encryption secret, message, auxiliary randomness, the encrypted
+    /// signature `R | s_hat` and the decrypted BIP340 signature.
+    #[test]
+    fn test_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "02D242193E3DA9ABBEE03E0E6F7633909E002E2421BED11993306F52EA25436AB5E9DB26AA60E6C7819F21D053B367BD21A24D6258C8537E9ABA6988005D42B01E",
+                "D242193E3DA9ABBEE03E0E6F7633909E002E2421BED11993306F52EA25436AB5E9DB26AA60E6C7819F21D053B367BD21A24D6258C8537E9ABA6988005D42B023",
+            ),
+            // the nonce of the decrypted signature is -R, so decrypting subtracts y
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0398C746B09C4D39270212954ABCD1E6805E49686FA49F9CBE40E2CEF8DAD6EB2BBFC68D3FBA1F45604801AEF99290FA2297D1832806CB2BAA111D3FBBA8906834",
+                "98C746B09C4D39270212954ABCD1E6805E49686FA49F9CBE40E2CEF8DAD6EB2BBFC68D3FBA1F45604801AEF99290FA2297D1832806CB2BAA111D3FBBA890682F",
+            ),
+            (
+                "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+                "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+                "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "02DBA5BF88311C282B639858F5096B93706157F8A33977E4838CC0A5BC00E50E053A8302B2D8836E0F991E4958513EFD364974E4110E7687B17AE67F0D3369E746",
+                "DBA5BF88311C282B639858F5096B93706157F8A33977E4838CC0A5BC00E50E050392DD54F9EC30445DE4ABE3D21B1A08B7C85532E995B3E9BD1FDF269E488BCE",
+            ),
+            (
+                "0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364140",
+                "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+                "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+                "02D17E42686AF96F65E0F89F122B2115961DD31165476E2BD30E748D2A7DC021AA89620BFF8B554C2FB40EF62B9BA4F29C5D2611BB0E6FAF6AE84FFE664DB708DC",
+                "D17E42686AF96F65E0F89F122B2115961DD31165476E2BD30E748D2A7DC021AA89620BFF8B554C2FB40EF62B9BA4F29C5D2611BB0E6FAF6AE84FFE664DB708DB",
+            ),
+        ];
+
+        for (x, y, message, aux, expected_encsig, expected_sig) in vectors.iter() {
+            let x = keypair(x);
+            let y = keypair(y);
+            let message = bytes32(message);
+
+            let encsig = encsign_with_aux(message, &x, &y.to_pk(), &bytes32(aux));
+            let encoded = [
+                encsig.R.serialize_compressed().to_vec(),
+                encsig.s_hat.b32().to_vec(),
+            ]
+            .concat();
+            assert_eq!(hex::encode_upper(encoded), *expected_encsig);
+            encverify(&x.to_pk(), &y.to_pk(), &message, &encsig).unwrap();
+
+            let sig = decsig(&y, &encsig);
+            assert_eq!(hex::encode_upper(&sig.serialize()[..]), *expected_sig);
+            schnorr::verify(message, &sig, &x.to_pk()).unwrap();
+
+            assert_eq!(recover(&y.to_pk(), &encsig, &sig), Ok(y));
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Persistence of protocol states so that a party can resume after a crash.
+
+use crate::wire;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A protocol state that can be written to and restored from a [`Storage`].
+pub trait Snapshot: wire::Encode + wire::Decode {
+    /// Identifies the state type, unique across all parties and both sub-protocols.
+    const TAG: u8;
+
+    fn snapshot(&self) -> Vec<u8> {
+        wire::to_framed_bytes(Self::TAG, self)
+    }
+
+    fn restore(bytes: &[u8]) -> Result<Self, wire::DecodeError> {
+        wire::from_framed_bytes(Self::TAG, bytes)
+    }
+}
+
+/// A key-value store for snapshots, keyed by an identifier chosen by the caller.
+pub trait Storage {
+    fn put(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn delete(&mut self, key: &str) -> anyhow::Result<()>;
+    /// Lists the keys of all stored values, in no particular order.
+    fn keys(&self) -> anyhow::Result<Vec<String>>;
+
+    fn save<S: Snapshot>(&mut self, key: &str, state: &S) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        self.put(key, &state.snapshot())
+    }
+
+    fn load<S: Snapshot>(&self, key: &str) -> anyhow::Result<Option<S>>
+    where
+        Self: Sized,
+    {
+        match self.get(key)? {
+            Some(bytes) => Ok(Some(S::restore(&bytes).with_context(|| {
+                format!("failed to restore state stored under {}", key)
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct MemoryStorage {
+    entries: HashMap<String, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn put(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.entries.insert(key.to_owned(), value.to_vec());
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn delete(&mut self, key: &str) -> anyhow::Result<()> {
+        self.entries.remove(key);
+
+        Ok(())
+    }
+
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+}
+
+/// Stores each snapshot in its own file inside a directory.
+///
+/// Snapshots are written to a temporary file, flushed to disk and then renamed over the previous
+/// one, and the rename is flushed as well. A crash during `put` therefore leaves either the old or
+/// the new snapshot in place. The snapshots hold secret keys, so only the current user can read
+/// them.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("storage key {0:?} must be non-empty and only contain [A-Za-z0-9_-]")]
+pub struct InvalidKey(String);
+
+impl FileStorage {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create storage directory {}", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let is_valid = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid {
+            bail!(InvalidKey(key.to_owned()))
+        }
+
+        Ok(self.dir.join(format!("{}.state", key)))
+    }
+}
+
+impl Storage for FileStorage {
+    fn put(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        let tmp_path = path.with_extension("state.tmp");
+
+        // a leftover from a crash may have been created with other permissions
+        remove_if_exists(&tmp_path)
+            .with_context(|| format!("failed to remove {}", tmp_path.display()))?;
+        write_private_file(&tmp_path, value)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed to move snapshot to {}", path.display()))?;
+        sync_dir(&self.dir)
+            .with_context(|| format!("failed to sync directory {}", self.dir.display()))?;
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+
+        remove_if_exists(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+        sync_dir(&self.dir)
+            .with_context(|| format!("failed to sync directory {}", self.dir.display()))?;
+
+        Ok(())
+    }
+
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to list {}", self.dir.display()))?;
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("failed to list {}", self.dir.display()))?;
+
+            // leftover temporary files end in `.state.tmp` and are skipped
+            if let Some(key) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".state"))
+            {
+                keys.push(key.to_owned());
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Creates a file at `path` that only the current user can read and write, and returns once
+/// `bytes` are on disk. Fails if the file already exists.
+pub(crate) fn write_private_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Flushes the entries of `dir`, so that files created in or renamed into it survive a crash.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    // directories cannot be opened as files on other platforms
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secp256k1;
+
+    impl Snapshot for secp256k1::KeyPair {
+        const TAG: u8 = 0xff;
+    }
+
+    #[test]
+    fn file_storage_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+
+        assert!(storage
+            .load::<secp256k1::KeyPair>("session-1")
+            .unwrap()
+            .is_none());
+
+        storage.save("session-1", &x).unwrap();
+        let storage = FileStorage::open(dir.path()).unwrap();
+
+        assert_eq!(storage.load("session-1").unwrap(), Some(x));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_storage_is_only_readable_by_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+
+        storage.put("session-1", b"secret").unwrap();
+        storage.put("session-1", b"updated secret").unwrap();
+
+        let mode = fs::metadata(dir.path().join("session-1.state"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            storage.get("session-1").unwrap().unwrap(),
+            b"updated secret"
+        );
+    }
+
+    #[test]
+    fn file_storage_lists_only_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+
+        storage.put("session-1", b"").unwrap();
+        storage.put("session-2", b"").unwrap();
+        storage.delete("session-2").unwrap();
+        fs::write(dir.path().join("session-3.state.tmp"), b"").unwrap();
+        fs::write(dir.path().join("tumbler.key"), b"").unwrap();
+
+        assert_eq!(storage.keys().unwrap(), vec!["session-1".to_owned()]);
+    }
+
+    #[test]
+    fn file_storage_rejects_path_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::open(dir.path()).unwrap();
+
+        assert!(storage.put("../escape", b"").is_err());
+    }
+}
@@ -0,0 +1,974 @@
+//! A Taproot (P2TR) alternative to the P2WSH joint output.
+//!
+//! The output key is a MuSig2 aggregate of `X_from` and `X_to`, tweaked with a single script leaf
+//! holding the time-locked refund. The redeem transaction spends through the key path with one
+//! aggregate signature, so on chain it looks like any single-sig Taproot spend. Only a refund
+//! reveals the script, and with it that both keys were involved.
+
+use crate::bitcoin::{
+    anchor_output, insert_joint_output, make_spend_output, EmptyWitnessStack, JointOutput,
+    NoInputs, OutPoint, SigHash, TooManyInputs, Transaction, Transactions, TxIn, TxOut,
+    JOINT_OUTPUT_INDEX, SEQUENCE_ENABLE_LOCKTIME, SEQUENCE_FINAL,
+};
+use crate::secp256k1::group::{double_mul, has_even_y, lift_x};
+use crate::secp256k1::musig::KeyAggContext;
+use crate::secp256k1::schnorr::{self, scalar_from_bytes, tagged_hash};
+use crate::secp256k1::{KeyPair, PublicKey, Scalar, SecretKey, XCoor};
+use anyhow::bail;
+use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY, OP_CLTV, OP_DROP};
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::Hash;
+use fehler::throws;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+/// Version of the only script leaf, which is executed as Tapscript.
+const LEAF_VERSION_TAPSCRIPT: u8 = 0xc0;
+/// Size of the control block of the refund leaf: the leaf version and the parity of the output
+/// key, followed by the internal key. The leaf is the only one, so there is no merkle path.
+pub const CONTROL_BLOCK_SIZE: usize = 33;
+/// The lowest locktime that is interpreted as a UNIX timestamp instead of a block height.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// The first byte of the annex, the optional last witness element of BIP341.
+const ANNEX_TAG: u8 = 0x50;
+
+/// The joint output and the ways of spending it.
+#[derive(Debug, Clone)]
+pub struct FundOutput {
+    X_from: PublicKey,
+    X_to: PublicKey,
+    refund_locktime: u32,
+    /// The untweaked aggregate of `X_from` and `X_to`.
+    internal_key: PublicKey,
+    /// The aggregate of `X_from` and `X_to`, tweaked to commit to `refund_script`. Both parties
+    /// sign the redeem transaction under it.
+    key_agg_context: KeyAggContext,
+    refund_script: Script,
+}
+
+impl FundOutput {
+    pub fn new(X_from: &PublicKey, X_to: &PublicKey, refund_locktime: u32) -> Self {
+        let key_agg_context = KeyAggContext::new(&[X_from.clone(), X_to.clone()]);
+        let internal_key = key_agg_context.aggregate_key().clone();
+        let refund_script = refund_script(X_from, X_to, refund_locktime);
+
+        // with a single leaf, the merkle root is the hash of that leaf
+        let tweak = tap_tweak(&internal_key, Some(&leaf_hash(&refund_script)))
+            .expect("tweak exceeds the group order with negligible probability");
+        let key_agg_context = key_agg_context
+            .with_xonly_tweak(&tweak)
+            .expect("output key is the point at infinity with negligible probability");
+
+        Self {
+            X_from: X_from.clone(),
+            X_to: X_to.clone(),
+            refund_locktime,
+            internal_key,
+            key_agg_context,
+            refund_script,
+        }
+    }
+
+    /// The context in which both parties sign the redeem transaction with MuSig2.
+    pub fn key_agg_context(&self) -> &KeyAggContext {
+        &self.key_agg_context
+    }
+
+    pub fn output_key(&self) -> &PublicKey {
+        self.key_agg_context.aggregate_key()
+    }
+
+    /// `OP_1 <output key>`, a version 1 witness program.
+    pub fn script_pubkey(&self) -> Script {
+        Builder::new()
+            .push_int(1)
+            .push_slice(&self.output_key().x_coor())
+            .into_script()
+    }
+
+    pub fn refund_script(&self) -> &Script {
+        &self.refund_script
+    }
+
+    /// Proves that `refund_script` is committed to by the output key.
+    pub fn control_block(&self) -> Vec<u8> {
+        let parity = if has_even_y(self.output_key()) { 0 } else { 1 };
+
+        let mut control_block = vec![LEAF_VERSION_TAPSCRIPT | parity];
+        control_block.extend_from_slice(&self.internal_key.x_coor());
+
+        control_block
+    }
+
+    /// Checks the witness of the only input of `spend_transaction` against this output, which is
+    /// `fund_transaction`'s joint output.
+    ///
+    /// As with `bitcoin::verify_spend_transaction`, whether the transaction is final at the
+    /// current height is up to the chain.
+    #[throws(anyhow::Error)]
+    pub fn verify_spend_transaction(
+        &self,
+        spend_transaction: &Transaction,
+        fund_transaction: &Transaction,
+    ) {
+        let input = match spend_transaction.input.as_slice() {
+            [input] => input,
+            [] => bail!(NoInputs),
+            inputs => bail!(TooManyInputs(inputs.len())),
+        };
+        let fund_output = fund_transaction
+            .output
+            .get(input.previous_output.vout as usize)
+            .filter(|output| {
+                input.previous_output.txid == fund_transaction.txid()
+                    && output.script_pubkey == self.script_pubkey()
+            })
+            .ok_or(NotSpendingFundOutput)?;
+
+        verify_input(spend_transaction, 0, &[fund_output.clone()])?;
+    }
+}
+
+/// Whether `script_pubkey` is a version 1 witness program, i.e. that of a Taproot output.
+pub fn is_v1_witness_program(script_pubkey: &Script) -> bool {
+    let bytes = script_pubkey.as_bytes();
+
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20
+}
+
+/// Checks the witness of input `index` of `transaction`, which spends a Taproot output.
+///
+/// The libbitcoinconsensus we link against predates Taproot and accepts any spend of a version 1
+/// witness program. This applies the rules of BIP341 and BIP342 to the spends we make: key path
+/// spends, and script path spends of a refund leaf as built by `FundOutput`. Any other script is
+/// rejected, even if it would be valid on chain. `spent_outputs` are the outputs spent by all
+/// inputs, in order, which the signatures commit to.
+#[throws(anyhow::Error)]
+pub fn verify_input(transaction: &Transaction, index: usize, spent_outputs: &[TxOut]) {
+    if spent_outputs.len() != transaction.input.len() {
+        bail!(MissingSpentOutputs(transaction.input.len()))
+    }
+    let input = transaction
+        .input
+        .get(index)
+        .ok_or(MissingSpentOutputs(transaction.input.len()))?;
+    let output_key = Some(&spent_outputs[index].script_pubkey)
+        .filter(|script_pubkey| is_v1_witness_program(script_pubkey))
+        .ok_or(NotATaprootOutput)?
+        .as_bytes()[2..]
+        .to_vec();
+
+    if input.witness.len() >= 2
+        && input.witness.last().and_then(|annex| annex.first()) == Some(&ANNEX_TAG)
+    {
+        bail!(UnsupportedAnnex)
+    }
+
+    match input.witness.as_slice() {
+        [] => bail!(EmptyWitnessStack),
+        [signature] => {
+            let (signature, sighash_type) = parse_signature(signature)?;
+            let digest = signature_hash(transaction, index, spent_outputs, None, sighash_type);
+            let output_key = lift_x(&to_array_32(&output_key)).ok_or(InvalidOutputKey)?;
+
+            schnorr::verify(digest, &signature, &output_key)?;
+        }
+        [stack @ .., script, control_block] => {
+            let script = Script::from(script.clone());
+            let leaf_hash = leaf_hash(&script);
+            verify_commitment(&output_key, &leaf_hash, control_block)?;
+
+            let (X_from, X_to, refund_locktime) =
+                parse_refund_script(&script).ok_or(UnsupportedScript)?;
+
+            // `X_from`'s `OP_CHECKSIGVERIFY` consumes the signature on top of the stack first
+            let (sig_to, sig_from) = match stack {
+                [sig_to, sig_from] => (sig_to, sig_from),
+                _ => bail!(UnknownWitness),
+            };
+            for (signature, X) in &[(sig_from, X_from), (sig_to, X_to)] {
+                let (signature, sighash_type) = parse_signature(signature)?;
+                let digest = signature_hash(
+                    transaction,
+                    index,
+                    spent_outputs,
+                    Some(leaf_hash),
+                    sighash_type,
+                );
+
+                schnorr::verify(digest, &signature, X)?;
+            }
+
+            // what `OP_CHECKLOCKTIMEVERIFY` checks against the spending transaction
+            if (transaction.lock_time < LOCKTIME_THRESHOLD)
+                != (refund_locktime < LOCKTIME_THRESHOLD)
+                || transaction.lock_time < refund_locktime
+                || input.sequence == SEQUENCE_FINAL
+            {
+                bail!(RefundLocktimeNotSatisfied)
+            }
+        }
+    }
+}
+
+/// Checks that `control_block` proves the leaf with `leaf_hash` to be committed to by
+/// `output_key`.
+#[throws(anyhow::Error)]
+fn verify_commitment(output_key: &[u8], leaf_hash: &[u8; 32], control_block: &[u8]) {
+    if control_block.len() < CONTROL_BLOCK_SIZE
+        || (control_block.len() - CONTROL_BLOCK_SIZE) % 32 != 0
+    {
+        bail!(InvalidControlBlock)
+    }
+    if control_block[0] & 0xfe != LEAF_VERSION_TAPSCRIPT {
+        bail!(UnsupportedScript)
+    }
+
+    let internal_key =
+        lift_x(&to_array_32(&control_block[1..CONTROL_BLOCK_SIZE])).ok_or(InvalidControlBlock)?;
+    let merkle_root =
+        control_block[CONTROL_BLOCK_SIZE..]
+            .chunks(32)
+            .fold(*leaf_hash, |node, sibling| {
+                // the children of a branch are hashed in lexicographic order
+                if &node[..] < sibling {
+                    tagged_hash("TapBranch", &[&node, sibling])
+                } else {
+                    tagged_hash("TapBranch", &[sibling, &node])
+                }
+            });
+
+    let tweak = tap_tweak(&internal_key, Some(&merkle_root)).ok_or(InvalidControlBlock)?;
+    let tweaked_key =
+        double_mul(&internal_key, &Scalar::from_int(1), &tweak).ok_or(InvalidControlBlock)?;
+    let parity = if has_even_y(&tweaked_key) { 0 } else { 1 };
+
+    if tweaked_key.x_coor()[..] != *output_key || control_block[0] & 1 != parity {
+        bail!(InvalidControlBlock)
+    }
+}
+
+/// The tweak of `internal_key` committing to the script tree with `merkle_root`, or to no script
+/// tree at all. `None` if it exceeds the group order.
+fn tap_tweak(internal_key: &PublicKey, merkle_root: Option<&[u8; 32]>) -> Option<Scalar> {
+    let tweak = match merkle_root {
+        Some(merkle_root) => tagged_hash("TapTweak", &[&internal_key.x_coor(), merkle_root]),
+        None => tagged_hash("TapTweak", &[&internal_key.x_coor()]),
+    };
+
+    scalar_from_bytes(&tweak)
+}
+
+fn to_array_32(bytes: &[u8]) -> [u8; 32] {
+    let mut array = [0u8; 32];
+    array.copy_from_slice(bytes);
+
+    array
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("spend transaction does not spend the taproot joint output of the fund transaction")]
+pub struct NotSpendingFundOutput;
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness spends neither the key path nor the refund leaf")]
+pub struct UnknownWitness;
+
+#[derive(thiserror::Error, Debug)]
+#[error("refund transaction does not satisfy the locktime of the refund leaf")]
+pub struct RefundLocktimeNotSatisfied;
+
+#[derive(thiserror::Error, Debug)]
+#[error("spend transaction does not carry the refund leaf and its control block")]
+pub struct MissingRefundLeaf;
+
+#[derive(thiserror::Error, Debug)]
+#[error("expected the {0} outputs spent by the transaction")]
+pub struct MissingSpentOutputs(usize);
+
+#[derive(thiserror::Error, Debug)]
+#[error("spent output is not a taproot output")]
+pub struct NotATaprootOutput;
+
+#[derive(thiserror::Error, Debug)]
+#[error("output key is not a valid x-only public key")]
+pub struct InvalidOutputKey;
+
+#[derive(thiserror::Error, Debug)]
+#[error("control block does not commit to the script through the output key")]
+pub struct InvalidControlBlock;
+
+#[derive(thiserror::Error, Debug)]
+#[error("script is not a refund leaf")]
+pub struct UnsupportedScript;
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness carries an annex")]
+pub struct UnsupportedAnnex;
+
+/// `<refund_locktime> OP_CHECKLOCKTIMEVERIFY OP_DROP <X_from> OP_CHECKSIGVERIFY <X_to> OP_CHECKSIG`
+fn refund_script(X_from: &PublicKey, X_to: &PublicKey, refund_locktime: u32) -> Script {
+    Builder::new()
+        .push_int(refund_locktime as i64)
+        .push_opcode(OP_CLTV)
+        .push_opcode(OP_DROP)
+        .push_slice(&X_from.x_coor())
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_slice(&X_to.x_coor())
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
+/// A refund leaf with the given refund locktime, locked to placeholder keys.
+///
+/// Keys are x-only, so its length is that of every refund leaf with the same refund locktime.
+pub fn placeholder_refund_script(refund_locktime: u32) -> Script {
+    let placeholder_key =
+        |byte| KeyPair::from(SecretKey::parse(&[byte; 32]).expect("a valid secret key")).to_pk();
+
+    refund_script(&placeholder_key(1), &placeholder_key(2), refund_locktime)
+}
+
+/// Reads the keys and the locktime of a refund leaf, if `script` is one.
+fn parse_refund_script(script: &Script) -> Option<(PublicKey, PublicKey, u32)> {
+    let bytes = script.as_bytes();
+
+    // the locktime is followed by `OP_CLTV OP_DROP <X_from> OP_CHECKSIGVERIFY <X_to> OP_CHECKSIG`
+    let (locktime, rest) = bytes.split_at(bytes.len().checked_sub(70)?);
+    let X_from = lift_x(&to_array_32(&rest[3..35]))?;
+    let X_to = lift_x(&to_array_32(&rest[37..69]))?;
+    let refund_locktime = parse_script_number(locktime)?;
+
+    // rebuilding the script checks the opcodes and that the locktime is minimally encoded
+    if refund_script(&X_from, &X_to, refund_locktime) != *script {
+        return None;
+    }
+
+    Some((X_from, X_to, refund_locktime))
+}
+
+/// Reads a non-negative number pushed as `Builder::push_int` does.
+fn parse_script_number(push: &[u8]) -> Option<u32> {
+    match push {
+        [0x00] => Some(0),
+        // OP_1 to OP_16
+        [opcode @ 0x51..=0x60] => Some(u32::from(opcode - 0x50)),
+        [length, number @ ..] if usize::from(*length) == number.len() && number.len() <= 5 => {
+            // little-endian, with the sign in the most significant bit
+            let value = number
+                .iter()
+                .rev()
+                .fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+            if number.last()? & 0x80 != 0 {
+                return None;
+            }
+
+            u32::try_from(value).ok()
+        }
+        _ => None,
+    }
+}
+
+fn leaf_hash(script: &Script) -> [u8; 32] {
+    tagged_hash("TapLeaf", &[&[LEAF_VERSION_TAPSCRIPT], &serialize(script)])
+}
+
+/// Builds the fund transaction with a Taproot joint output, and the redeem and refund transactions
+/// spending it, like `bitcoin::make_transactions` does for the P2WSH joint output.
+///
+/// The digests are those of BIP341 with `SIGHASH_DEFAULT`: the redeem digest is signed under the
+/// output key with MuSig2 and the refund digest by `X_fund_from` and `X_fund_to` individually.
+pub fn make_transactions(
+    partial_fund_transaction: Transaction,
+    X_fund_from: &PublicKey,
+    X_fund_to: &PublicKey,
+    joint_output: &JointOutput,
+) -> Transactions {
+    let JointOutput {
+        output_type: _,
+        value: fund_amount,
+        spend_value: spend_amount,
+        refund_locktime,
+        redeem_identity: X_redeem,
+        refund_identity: X_refund,
+        anchor_outputs,
+    } = joint_output.clone();
+    let fund_output = FundOutput::new(X_fund_from, X_fund_to, refund_locktime);
+    let joint_output = TxOut {
+        value: fund_amount,
+        script_pubkey: fund_output.script_pubkey(),
+    };
+
+    let fund_transaction = insert_joint_output(partial_fund_transaction, joint_output.clone());
+
+    let input = TxIn {
+        previous_output: OutPoint {
+            txid: fund_transaction.txid(),
+            vout: JOINT_OUTPUT_INDEX,
+        },
+        script_sig: Script::new(),
+        sequence: SEQUENCE_FINAL,
+        witness: Vec::new(),
+    };
+
+    let anchors = if anchor_outputs {
+        vec![anchor_output()]
+    } else {
+        Vec::new()
+    };
+
+    let (redeem_transaction, redeem_tx_digest) = {
+        let mut output = vec![make_spend_output(spend_amount, &X_redeem)];
+        output.extend(anchors.iter().cloned());
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input.clone()],
+            output,
+        };
+
+        let digest = signature_hash(
+            &transaction,
+            0,
+            &[joint_output.clone()],
+            None,
+            SighashType::Default,
+        );
+
+        (transaction, digest)
+    };
+
+    let (refund_transaction, refund_tx_digest) = {
+        let mut output = vec![make_spend_output(spend_amount, &X_refund)];
+        output.extend(anchors);
+        let input = TxIn {
+            sequence: SEQUENCE_ENABLE_LOCKTIME,
+            // the signatures are added in front of the leaf when completing the refund
+            witness: vec![
+                fund_output.refund_script().to_bytes(),
+                fund_output.control_block(),
+            ],
+            ..input
+        };
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: refund_locktime,
+            input: vec![input],
+            output,
+        };
+
+        let digest = signature_hash(
+            &transaction,
+            0,
+            &[joint_output],
+            Some(leaf_hash(fund_output.refund_script())),
+            SighashType::Default,
+        );
+
+        (transaction, digest)
+    };
+
+    Transactions {
+        fund: fund_transaction,
+        redeem: redeem_transaction,
+        redeem_tx_digest,
+        refund: refund_transaction,
+        refund_tx_digest,
+    }
+}
+
+/// Adds the aggregate signature spending the joint output through the key path.
+#[throws(anyhow::Error)]
+pub fn complete_redeem_transaction(
+    mut transaction: Transaction,
+    signature: &schnorr::Signature,
+) -> Transaction {
+    let input = match transaction.input.as_mut_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        inputs => bail!(TooManyInputs(inputs.len())),
+    };
+
+    // a 64-byte signature implies SIGHASH_DEFAULT
+    input.witness = vec![signature.serialize().to_vec()];
+
+    transaction
+}
+
+/// Adds the witness spending the joint output through the time-locked refund leaf.
+#[throws(anyhow::Error)]
+pub fn complete_refund_transaction(
+    mut transaction: Transaction,
+    sig_from: &schnorr::Signature,
+    sig_to: &schnorr::Signature,
+) -> Transaction {
+    let input = match transaction.input.as_mut_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        inputs => bail!(TooManyInputs(inputs.len())),
+    };
+    let (script, control_block) = match input.witness.as_slice() {
+        [script, control_block] => (script.clone(), control_block.clone()),
+        _ => bail!(MissingRefundLeaf),
+    };
+
+    // `X_from`'s `OP_CHECKSIGVERIFY` consumes the signature on top of the stack first
+    input.witness = vec![
+        sig_to.serialize().to_vec(),
+        sig_from.serialize().to_vec(),
+        script,
+        control_block,
+    ];
+
+    transaction
+}
+
+/// Reads the aggregate signature from the witness of a redeem transaction spending the joint
+/// output through the key path, from which the party that encrypted its part of it can recover
+/// the decryption key.
+#[throws(anyhow::Error)]
+pub fn extract_redeem_signature(redeem_transaction: &Transaction) -> schnorr::Signature {
+    let input = match redeem_transaction.input.as_slice() {
+        [input] => input,
+        [] => bail!(NoInputs),
+        inputs => bail!(TooManyInputs(inputs.len())),
+    };
+
+    match input.witness.as_slice() {
+        [signature] => match parse_signature(signature)? {
+            (signature, SighashType::Default) => signature,
+            (_, SighashType::All) => bail!(UnsupportedSighashType),
+        },
+        [] => bail!(EmptyWitnessStack),
+        _ => bail!(NotAKeyPathSpend),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("witness does not spend the key path")]
+pub struct NotAKeyPathSpend;
+
+#[derive(thiserror::Error, Debug)]
+#[error("expected a 64-byte signature or a 65-byte one with SIGHASH_ALL")]
+pub struct UnsupportedSighashType;
+
+/// The sighash types we check signatures with, both of which commit to all inputs and outputs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SighashType {
+    /// Implied by a 64-byte signature.
+    Default = 0x00,
+    All = 0x01,
+}
+
+#[throws(anyhow::Error)]
+fn parse_signature(bytes: &[u8]) -> (schnorr::Signature, SighashType) {
+    let sighash_type = match bytes.len() {
+        64 => SighashType::Default,
+        65 if bytes[64] == SighashType::All as u8 => SighashType::All,
+        _ => bail!(UnsupportedSighashType),
+    };
+
+    let mut signature = [0u8; 64];
+    signature.copy_from_slice(&bytes[..64]);
+
+    (schnorr::Signature::parse(&signature)?, sighash_type)
+}
+
+/// The BIP341 signature hash of input `index` of `transaction` with a sighash type that commits
+/// to all inputs and outputs.
+///
+/// `spent_outputs` are the outputs spent by all inputs, in order. `leaf_hash` is that of the
+/// script being executed for a script path spend and `None` for a key path spend.
+fn signature_hash(
+    transaction: &Transaction,
+    index: usize,
+    spent_outputs: &[TxOut],
+    leaf_hash: Option<[u8; 32]>,
+    sighash_type: SighashType,
+) -> SigHash {
+    let sha256 = |items: &mut dyn Iterator<Item = Vec<u8>>| {
+        let mut hasher = Sha256::default();
+        for item in items {
+            hasher.input(&item);
+        }
+        hasher.result()
+    };
+
+    let sha_prevouts = sha256(
+        &mut transaction
+            .input
+            .iter()
+            .map(|input| serialize(&input.previous_output)),
+    );
+    let sha_amounts = sha256(
+        &mut spent_outputs
+            .iter()
+            .map(|output| output.value.to_le_bytes().to_vec()),
+    );
+    let sha_script_pubkeys = sha256(
+        &mut spent_outputs
+            .iter()
+            .map(|output| serialize(&output.script_pubkey)),
+    );
+    let sha_sequences = sha256(
+        &mut transaction
+            .input
+            .iter()
+            .map(|input| input.sequence.to_le_bytes().to_vec()),
+    );
+    let sha_outputs = sha256(&mut transaction.output.iter().map(serialize));
+
+    // epoch
+    let mut message = vec![0x00, sighash_type as u8];
+    message.extend_from_slice(&transaction.version.to_le_bytes());
+    message.extend_from_slice(&transaction.lock_time.to_le_bytes());
+    message.extend_from_slice(&sha_prevouts);
+    message.extend_from_slice(&sha_amounts);
+    message.extend_from_slice(&sha_script_pubkeys);
+    message.extend_from_slice(&sha_sequences);
+    message.extend_from_slice(&sha_outputs);
+    // spend type: whether this is a script path spend, without annex
+    message.push(if leaf_hash.is_some() { 2 } else { 0 });
+    message.extend_from_slice(&(index as u32).to_le_bytes());
+    if let Some(leaf_hash) = leaf_hash {
+        message.extend_from_slice(&leaf_hash);
+        // key version
+        message.push(0x00);
+        // position of the last executed OP_CODESEPARATOR, none
+        message.extend_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+    }
+
+    SigHash::from_inner(tagged_hash("TapSighash", &[&message]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::{Address, OutputType};
+    use crate::secp256k1::musig::{SecretNonce, Session};
+    use crate::secp256k1::{schnorr_enc, KeyPair, ToMessage};
+    use bitcoin::consensus::encode::deserialize;
+    use rand::thread_rng;
+
+    const REFUND_LOCKTIME: u32 = 500;
+
+    fn random_p2wpkh() -> Address {
+        Address::p2wpkh(
+            &::bitcoin::PublicKey::from_slice(
+                &KeyPair::random(&mut thread_rng())
+                    .to_pk()
+                    .serialize_compressed(),
+            )
+            .unwrap(),
+            ::bitcoin::Network::Regtest,
+        )
+    }
+
+    fn transactions(x_from: &KeyPair, x_to: &KeyPair) -> Transactions {
+        make_transactions(
+            Transaction {
+                lock_time: 0,
+                version: 2,
+                input: Vec::new(),
+                output: Vec::new(),
+            },
+            x_from.public_key(),
+            x_to.public_key(),
+            &JointOutput {
+                output_type: OutputType::P2tr,
+                value: 10_000,
+                spend_value: 9_000,
+                refund_locktime: REFUND_LOCKTIME,
+                redeem_identity: random_p2wpkh(),
+                refund_identity: random_p2wpkh(),
+                anchor_outputs: false,
+            },
+        )
+    }
+
+    #[test]
+    fn redeem_through_key_path_with_musig2() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let transactions = transactions(&x_from, &x_to);
+
+        let context = fund_output.key_agg_context();
+        let message = transactions.redeem_tx_digest.to_message();
+        let (secnonce_from, pubnonce_from) = SecretNonce::new(&mut rng, &x_from, context, &message);
+        let (secnonce_to, pubnonce_to) = SecretNonce::new(&mut rng, &x_to, context, &message);
+
+        let session = Session::new(context, &[pubnonce_from, pubnonce_to], &message);
+        let signature = session.aggregate(&[
+            session.partial_sign(secnonce_from, &x_from).unwrap(),
+            session.partial_sign(secnonce_to, &x_to).unwrap(),
+        ]);
+
+        let redeem = complete_redeem_transaction(transactions.redeem, &signature).unwrap();
+
+        assert_eq!(redeem.input[0].witness.len(), 1);
+        fund_output
+            .verify_spend_transaction(&redeem, &transactions.fund)
+            .unwrap();
+    }
+
+    #[test]
+    fn refund_through_script_path() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let transactions = transactions(&x_from, &x_to);
+
+        let sig_from = schnorr::sign(transactions.refund_tx_digest, &x_from, &mut rng);
+        let sig_to = schnorr::sign(transactions.refund_tx_digest, &x_to, &mut rng);
+
+        let refund = complete_refund_transaction(transactions.refund, &sig_from, &sig_to).unwrap();
+
+        assert_eq!(refund.lock_time, REFUND_LOCKTIME);
+        assert_ne!(refund.input[0].sequence, SEQUENCE_FINAL);
+        fund_output
+            .verify_spend_transaction(&refund, &transactions.fund)
+            .unwrap();
+    }
+
+    #[test]
+    fn refund_with_swapped_signatures_is_invalid() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let transactions = transactions(&x_from, &x_to);
+
+        let sig_from = schnorr::sign(transactions.refund_tx_digest, &x_from, &mut rng);
+        let sig_to = schnorr::sign(transactions.refund_tx_digest, &x_to, &mut rng);
+
+        let refund = complete_refund_transaction(transactions.refund, &sig_to, &sig_from).unwrap();
+
+        fund_output
+            .verify_spend_transaction(&refund, &transactions.fund)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn redeem_signed_by_one_party_is_invalid() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let transactions = transactions(&x_from, &x_to);
+
+        let signature = schnorr::sign(transactions.redeem_tx_digest, &x_from, &mut rng);
+        let redeem = complete_redeem_transaction(transactions.redeem, &signature).unwrap();
+
+        fund_output
+            .verify_spend_transaction(&redeem, &transactions.fund)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn joint_output_is_a_version_1_witness_program() {
+        let x_from = KeyPair::random(&mut thread_rng());
+        let x_to = KeyPair::random(&mut thread_rng());
+
+        let transactions = transactions(&x_from, &x_to);
+        let script_pubkey = transactions.fund.output[JOINT_OUTPUT_INDEX as usize]
+            .script_pubkey
+            .as_bytes();
+
+        assert_eq!(script_pubkey.len(), 34);
+        assert_eq!(&script_pubkey[..2], &[0x51, 0x20]);
+    }
+
+    #[test]
+    fn redeem_completed_from_musig2_adaptor_signature_reveals_decryption_key() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let y = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let transactions = transactions(&x_from, &x_to);
+
+        let context = fund_output.key_agg_context();
+        let message = transactions.redeem_tx_digest.to_message();
+        let (secnonce_from, pubnonce_from) = SecretNonce::new(&mut rng, &x_from, context, &message);
+        let (secnonce_to, pubnonce_to) = SecretNonce::new(&mut rng, &x_to, context, &message);
+
+        let session = Session::new_encrypted(
+            context,
+            &[pubnonce_from, pubnonce_to],
+            &message,
+            y.public_key(),
+        );
+        let encrypted_signature = session.aggregate_encrypted(&[
+            session.partial_sign(secnonce_from, &x_from).unwrap(),
+            session.partial_sign(secnonce_to, &x_to).unwrap(),
+        ]);
+
+        let signature = schnorr_enc::decsig(&y, &encrypted_signature);
+        let redeem = complete_redeem_transaction(transactions.redeem, &signature).unwrap();
+        fund_output
+            .verify_spend_transaction(&redeem, &transactions.fund)
+            .unwrap();
+
+        let published = extract_redeem_signature(&redeem).unwrap();
+        assert_eq!(
+            schnorr_enc::recover(y.public_key(), &encrypted_signature, &published).unwrap(),
+            y
+        );
+    }
+
+    #[test]
+    fn refund_before_refund_locktime_is_invalid() {
+        let mut rng = thread_rng();
+        let x_from = KeyPair::random(&mut rng);
+        let x_to = KeyPair::random(&mut rng);
+        let fund_output = FundOutput::new(x_from.public_key(), x_to.public_key(), REFUND_LOCKTIME);
+        let mut transactions = transactions(&x_from, &x_to);
+
+        transactions.refund.lock_time = REFUND_LOCKTIME - 1;
+        let spent_outputs = [transactions.fund.output[JOINT_OUTPUT_INDEX as usize].clone()];
+        let digest = signature_hash(
+            &transactions.refund,
+            0,
+            &spent_outputs,
+            Some(leaf_hash(fund_output.refund_script())),
+            SighashType::Default,
+        );
+        let sig_from = schnorr::sign(digest, &x_from, &mut rng);
+        let sig_to = schnorr::sign(digest, &x_to, &mut rng);
+
+        let refund = complete_refund_transaction(transactions.refund, &sig_from, &sig_to).unwrap();
+
+        fund_output
+            .verify_spend_transaction(&refund, &transactions.fund)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn placeholder_refund_script_has_the_length_of_any_refund_leaf() {
+        let x_from = KeyPair::random(&mut thread_rng());
+        let x_to = KeyPair::random(&mut thread_rng());
+
+        for locktime in &[0, 16, 500, 700_000, LOCKTIME_THRESHOLD + 1] {
+            let script = refund_script(x_from.public_key(), x_to.public_key(), *locktime);
+
+            assert_eq!(script.len(), placeholder_refund_script(*locktime).len());
+            let (X_from, X_to, refund_locktime) = parse_refund_script(&script).unwrap();
+            assert_eq!(X_from.x_coor(), x_from.public_key().x_coor());
+            assert_eq!(X_to.x_coor(), x_to.public_key().x_coor());
+            assert_eq!(refund_locktime, *locktime);
+        }
+    }
+
+    // The `scriptPubKey` vectors of BIP341 with a single leaf or none at all
+    #[test]
+    fn bip341_script_pubkey_vectors() {
+        let internal_key = lift_x(&hex_32(
+            "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        ))
+        .unwrap();
+        let tweak = tap_tweak(&internal_key, None).unwrap();
+        assert_eq!(
+            tweak.b32(),
+            hex_32("b86e7be8f39bab32a6f2c0443abbc210f0edac0e2c53d501b36b64437d9c6c70")
+        );
+        assert_eq!(
+            double_mul(&internal_key, &Scalar::from_int(1), &tweak)
+                .unwrap()
+                .x_coor(),
+            hex_32("53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343")
+        );
+
+        let internal_key = lift_x(&hex_32(
+            "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+        ))
+        .unwrap();
+        let script = Script::from(
+            hex::decode("20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac")
+                .unwrap(),
+        );
+        let leaf_hash = leaf_hash(&script);
+        assert_eq!(
+            leaf_hash,
+            hex_32("5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21")
+        );
+        let tweak = tap_tweak(&internal_key, Some(&leaf_hash)).unwrap();
+        assert_eq!(
+            tweak.b32(),
+            hex_32("cbd8679ba636c1110ea247542cfbd964131a6be84f873f7f3b62a777528ed001")
+        );
+        let output_key = hex_32("147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3");
+        assert_eq!(
+            double_mul(&internal_key, &Scalar::from_int(1), &tweak)
+                .unwrap()
+                .x_coor(),
+            output_key
+        );
+        verify_commitment(
+            &output_key,
+            &leaf_hash,
+            &hex::decode("c1187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27")
+                .unwrap(),
+        )
+        .unwrap();
+    }
+
+    // The taproot signature hash vectors of Bitcoin Core's `script_assets_test` that use a sighash
+    // type we support, as collected by rust-bitcoin
+    #[test]
+    fn bip341_signature_hash_vectors() {
+        let vectors = [
+            (
+                "020000000164eb050a5e3da0c2a65e4786f26d753b7bc69691fabccafb11f7acef36641f1846010000003101b2b404392a22000000000017a9147f2bde86fe78bf68a0544a4f290e12f0b7e0a08c87580200000000000017a91425d11723074ecfb96a0a83c3956bfaf362ae0c908758020000000000001600147e20f938993641de67bb0cdd71682aa34c4d29ad5802000000000000160014c64984dc8761acfa99418bd6bedc79b9287d652d72000000",
+                "01365724000000000023542156b39dab4f8f3508e0432cfb41fab110170acaa2d4c42539cb90a4dc7c093bc500",
+                0,
+                None,
+                SighashType::Default,
+                "33ca0ebfb4a945eeee9569fc0f5040221275f88690b7f8592ada88ce3bdf6703",
+            ),
+            (
+                "0200000002fff49be59befe7566050737910f6ccdc5e749c7f8860ddc140386463d88c5ad0f3000000002cf68eb4a3d67f9d4c079249f7e4f27b8854815cb1ed13842d4fbf395f9e217fd605ee24090100000065235d9203f458520000000000160014b6d48333bb13b4c644e57c43a9a26df3a44b785e58020000000000001976a914eea9461a9e1e3f765d3af3e726162e0229fe3eb688ac58020000000000001976a9143a8869c9f2b5ea1d4ff3aeeb6a8fb2fffb1ad5fe88ac0ad7125c",
+                "02591f220000000000225120f25ad35583ea31998d968871d7de1abd2a52f6fe4178b54ea158274806ff4ece48fb310000000000225120f25ad35583ea31998d968871d7de1abd2a52f6fe4178b54ea158274806ff4ece",
+                1,
+                None,
+                SighashType::All,
+                "626ab955d58c9a8a600a0c580549d06dc7da4e802eb2a531f62a588e430967a8",
+            ),
+            (
+                "020000000189fc651483f9296b906455dd939813bf086b1bbe7c77635e157c8e14ae29062195010000004445b5c7044561320000000000160014331414dbdada7fb578f700f38fb69995fc9b5ab958020000000000001976a914268db0a8104cc6d8afd91233cc8b3d1ace8ac3ef88ac580200000000000017a914ec00dcb368d6a693e11986d265f659d2f59e8be2875802000000000000160014c715799a49a0bae3956df9c17cb4440a673ac0df6f010000",
+                "011bec34000000000022512028055142ea437db73382e991861446040b61dd2185c4891d7daf6893d79f7182",
+                0,
+                Some("20cc4e1107aea1d170c5ff5b6817e1303010049724fb3caa7941792ea9d29b3e2bacab"),
+                SighashType::All,
+                "d66de5274a60400c7b08c86ba6b7f198f40660079edf53aca89d2a9501317f2e",
+            ),
+        ];
+
+        for (transaction, spent_outputs, index, script, sighash_type, expected) in &vectors {
+            let transaction: Transaction = deserialize(&hex::decode(transaction).unwrap()).unwrap();
+            let spent_outputs: Vec<TxOut> =
+                deserialize(&hex::decode(spent_outputs).unwrap()).unwrap();
+            let leaf_hash =
+                script.map(|script| leaf_hash(&Script::from(hex::decode(script).unwrap())));
+
+            let digest = signature_hash(
+                &transaction,
+                *index,
+                &spent_outputs,
+                leaf_hash,
+                *sighash_type,
+            );
+
+            assert_eq!(digest.into_inner(), hex_32(expected));
+        }
+    }
+
+    fn hex_32(hex: &str) -> [u8; 32] {
+        to_array_32(&hex::decode(hex).unwrap())
+    }
+}
@@ -0,0 +1,274 @@
+//! Request/response transports between the tumbler and its clients.
+//!
+//! Transports move opaque byte strings; encoding requests and responses is left to the
+//! [`Handler`] and its clients.
+
+use crate::wire;
+use anyhow::{bail, Context};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Upper bound on the size of a single request or response.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// The client side of a transport.
+pub trait Transport {
+    fn send(&self, request: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// The server side of a transport.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, peer: Peer, request: &[u8]) -> Vec<u8>;
+}
+
+/// Where a request came from, as far as the transport can tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// A client in the same process, connected through a [`ChannelTransport`].
+    Local,
+    Remote(IpAddr),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Local => write!(f, "local peer"),
+            Peer::Remote(ip) => write!(f, "peer {}", ip),
+        }
+    }
+}
+
+impl wire::Encode for Peer {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Peer::Local => buffer.push(0),
+            Peer::Remote(IpAddr::V4(ip)) => {
+                buffer.push(1);
+                buffer.extend_from_slice(&ip.octets());
+            }
+            Peer::Remote(IpAddr::V6(ip)) => {
+                buffer.push(2);
+                buffer.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+}
+
+impl wire::Decode for Peer {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let peer = match reader.read_u8()? {
+            0 => Peer::Local,
+            1 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(reader.read_slice(4)?);
+
+                Peer::Remote(octets.into())
+            }
+            2 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(reader.read_slice(16)?);
+
+                Peer::Remote(octets.into())
+            }
+            variant => return Err(wire::DecodeError::UnknownVariant(variant)),
+        };
+
+        Ok(peer)
+    }
+}
+
+type Envelope = (Vec<u8>, mpsc::Sender<Vec<u8>>);
+
+/// An in-process transport backed by a channel.
+///
+/// Every request is handled on its own thread so that concurrent clients do not wait on each
+/// other.
+pub struct ChannelTransport {
+    sender: Mutex<mpsc::Sender<Envelope>>,
+    timeout: Duration,
+}
+
+impl Clone for ChannelTransport {
+    fn clone(&self) -> Self {
+        Self {
+            sender: Mutex::new(self.sender.lock().expect("poisoned lock").clone()),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("handler has shut down")]
+pub struct Disconnected;
+
+#[derive(thiserror::Error, Debug)]
+#[error("no response within {0:?}")]
+pub struct Timeout(pub Duration);
+
+impl ChannelTransport {
+    pub fn spawn<H: Handler>(handler: Arc<H>, timeout: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<Envelope>();
+
+        thread::spawn(move || {
+            for (request, reply) in receiver {
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    let _ = reply.send(handler.handle(Peer::Local, &request));
+                });
+            }
+        });
+
+        Self {
+            sender: Mutex::new(sender),
+            timeout,
+        }
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (reply, response) = mpsc::channel();
+
+        self.sender
+            .lock()
+            .expect("poisoned lock")
+            .send((request.to_vec(), reply))
+            .map_err(|_| Disconnected)?;
+
+        match response.recv_timeout(self.timeout) {
+            Ok(response) => Ok(response),
+            Err(mpsc::RecvTimeoutError::Timeout) => bail!(Timeout(self.timeout)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!(Disconnected),
+        }
+    }
+}
+
+/// Accepts connections on `listener` and serves length-prefixed requests on each of them until
+/// the client hangs up.
+pub fn serve_tcp<H: Handler>(handler: Arc<H>, listener: TcpListener) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let handler = handler.clone();
+
+            thread::spawn(move || {
+                let _ = serve_connection(&*handler, stream);
+            });
+        }
+    })
+}
+
+fn serve_connection<H: Handler>(handler: &H, mut stream: TcpStream) -> anyhow::Result<()> {
+    // the port changes with every connection, so peers are told apart by their address only
+    let peer = Peer::Remote(stream.peer_addr()?.ip());
+
+    while let Some(request) = read_frame(&mut stream)? {
+        write_frame(&mut stream, &handler.handle(peer, &request))?;
+    }
+
+    Ok(())
+}
+
+/// A TCP transport which opens a new connection for every request.
+#[derive(Clone, Debug)]
+pub struct TcpTransport {
+    addr: SocketAddr,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn new(addr: SocketAddr, timeout: Duration) -> Self {
+        Self { addr, timeout }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, self.timeout)
+            .with_context(|| format!("failed to connect to {}", self.addr))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        write_frame(&mut stream, request)?;
+
+        match read_frame(&mut stream)? {
+            Some(response) => Ok(response),
+            None => bail!(Disconnected),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("frame of {0} bytes exceeds the maximum frame size")]
+pub struct FrameTooLarge(usize);
+
+fn write_frame(stream: &mut impl Write, bytes: &[u8]) -> anyhow::Result<()> {
+    if bytes.len() > MAX_FRAME_SIZE {
+        bail!(FrameTooLarge(bytes.len()))
+    }
+
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Reads a frame, returning `None` if the peer closed the connection before sending one.
+fn read_frame(stream: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        bail!(FrameTooLarge(len))
+    }
+
+    let mut bytes = vec![0u8; len];
+    stream.read_exact(&mut bytes)?;
+
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle(&self, _: Peer, request: &[u8]) -> Vec<u8> {
+            request.iter().rev().cloned().collect()
+        }
+    }
+
+    #[test]
+    fn channel_transport_roundtrip() {
+        let transport = ChannelTransport::spawn(Arc::new(Echo), Duration::from_secs(5));
+
+        assert_eq!(transport.send(b"abc").unwrap(), b"cba".to_vec());
+    }
+
+    #[test]
+    fn tcp_transport_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = serve_tcp(Arc::new(Echo), listener);
+
+        let transport = TcpTransport::new(addr, Duration::from_secs(5));
+
+        assert_eq!(transport.send(b"abc").unwrap(), b"cba".to_vec());
+        assert_eq!(transport.send(b"").unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,1001 @@
+//! A tumbler that runs many puzzle promise and puzzle solver sessions at once.
+//!
+//! Clients address a session by a [`SessionId`] of their choosing. Each request must match the
+//! state the session is currently in, so that a session can only advance one step at a time and
+//! in protocol order. Sessions which are not completed within the configured timeout are dropped.
+//!
+//! A client only brings its own part of the [`Params`] of a session. The amounts, the expiry and
+//! the tumbler's addresses and coins come from the [`ServiceConfig`] and the [`Wallet`], and the
+//! completed params are sent back with the tumbler's first message. The joint output, and with it
+//! the signature scheme of every session, is that of the [`JointOutputScheme`] the service runs
+//! with.
+//!
+//! Anyone can start a puzzle promise session, so the coins the tumbler offers in it are only
+//! reserved once the receiver's first message has been verified. Until then, sessions may be
+//! offered the same coins, and all but the first to reach that point are aborted.
+//!
+//! Every step of a session is written to a [`Storage`] before its response goes out, and the
+//! service resumes the sessions it finds there when it starts, so a restarted tumbler picks up
+//! where it stopped.
+
+use crate::bitcoin::{Address, Transaction, TxOut};
+use crate::chain::{self, BitcoindRpc, ChainSource};
+use crate::joint_output::JointOutputScheme;
+use crate::storage::{Snapshot, Storage};
+use crate::transport::{Handler, Peer};
+use crate::wire::{self, WireMessage};
+use crate::{hsm_cl, puzzle_promise, puzzle_solver, Params, Protocol, Terms};
+use anyhow::bail;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(pub [u8; 16]);
+
+impl SessionId {
+    pub fn random<R: rand::Rng>(rng: &mut R) -> Self {
+        Self(rng.gen())
+    }
+
+    fn storage_key(&self) -> String {
+        format!("session-{}", self)
+    }
+
+    fn from_storage_key(key: &str) -> Option<Self> {
+        let bytes = hex::decode(key.strip_prefix("session-")?).ok()?;
+        if bytes.len() != 16 {
+            return None;
+        }
+
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&bytes);
+
+        Some(SessionId(id))
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl wire::Encode for SessionId {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.0);
+    }
+}
+
+impl wire::Decode for SessionId {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let mut id = [0u8; 16];
+        id.copy_from_slice(reader.read_slice(16)?);
+
+        Ok(SessionId(id))
+    }
+}
+
+/// The tumbler's side of every session, which clients have no say in.
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    /// Where the tumbler is paid to when it redeems from a sender.
+    pub payout_address: Address,
+    /// Where the tumbler's coins go back to if a receiver does not redeem.
+    pub refund_address: Address,
+    pub tumble_amount: u64,
+    pub tumbler_fee: u64,
+    pub spend_transaction_fee_per_vbyte: u64,
+    pub anchor_outputs: bool,
+    /// Blocks from the start of a puzzle promise session until the tumbler can take its coins
+    /// back.
+    pub promise_expiry_blocks: u32,
+    /// Blocks from the start of a puzzle solver session until the sender can take its coins back.
+    ///
+    /// Has to be shorter than `promise_expiry_blocks`: a receiver can only redeem after the
+    /// tumbler redeemed from the sender and needs time to do so before the tumbler can refund.
+    pub solver_expiry_blocks: u32,
+    pub session_timeout: Duration,
+    /// How many sessions a single peer may have open at once. Starting a puzzle promise session
+    /// costs the tumbler an HSM-CL encryption and proof, so this bounds the work a peer can cause.
+    pub max_open_sessions_per_peer: usize,
+}
+
+/// Funds the tumbler's joint outputs with receivers.
+pub trait Wallet {
+    /// Returns the height of the current tip, from which the expiry of new sessions is counted.
+    fn height(&self) -> anyhow::Result<u32>;
+    /// Returns a transaction with inputs worth at least `amount` plus fees and a change output, as
+    /// [`Params`] expects its partial fund transaction, together with the outputs it spends.
+    ///
+    /// The inputs are not reserved, so later calls may return some of them again.
+    fn partial_fund_transaction(&self, amount: u64) -> anyhow::Result<(Transaction, Vec<TxOut>)>;
+    /// Reserves the coins spent by `transaction` for it, failing if any of them is already
+    /// reserved.
+    fn reserve_inputs(&self, transaction: &Transaction) -> anyhow::Result<()>;
+}
+
+impl Wallet for BitcoindRpc {
+    fn height(&self) -> anyhow::Result<u32> {
+        ChainSource::height(self)
+    }
+
+    fn partial_fund_transaction(&self, amount: u64) -> anyhow::Result<(Transaction, Vec<TxOut>)> {
+        let transaction = self.fund_transaction(amount)?;
+        let spent_outputs = chain::spent_outputs(self, &transaction)?;
+
+        Ok((transaction, spent_outputs))
+    }
+
+    fn reserve_inputs(&self, transaction: &Transaction) -> anyhow::Result<()> {
+        self.lock_unspents(transaction)
+    }
+}
+
+/// The sender's part of the params of a puzzle solver session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderContribution {
+    pub refund_identity: Address,
+    pub partial_fund_transaction: Transaction,
+    pub partial_fund_spent_outputs: Vec<TxOut>,
+}
+
+pub enum Request<C, S: JointOutputScheme> {
+    StartPromise {
+        session: SessionId,
+        /// The receiver's part of the params.
+        redeem_identity: Address,
+    },
+    PromiseMessage1 {
+        session: SessionId,
+        message: puzzle_promise::Message1<S>,
+    },
+    StartSolver {
+        session: SessionId,
+        sender: SenderContribution,
+    },
+    SolverMessage1 {
+        session: SessionId,
+        message: puzzle_solver::Message1<C, S>,
+    },
+    SolverMessage3 {
+        session: SessionId,
+        message: puzzle_solver::Message3<S>,
+    },
+}
+
+pub enum Response<C, P, S: JointOutputScheme> {
+    /// The params completed by the tumbler and its first message.
+    PromiseMessage0(Params, puzzle_promise::Message0<C, P>),
+    PromiseMessage2(puzzle_promise::Message2<S>),
+    /// The params completed by the tumbler and its first message.
+    SolverMessage0(Params, puzzle_solver::Message0),
+    SolverMessage2(puzzle_solver::Message2<S>),
+    SolverDone,
+    /// The session is processing another request; the client should retry later.
+    Busy,
+    Rejected(String),
+}
+
+impl<C, S: JointOutputScheme> Request<C, S> {
+    pub fn session(&self) -> SessionId {
+        match self {
+            Request::StartPromise { session, .. }
+            | Request::PromiseMessage1 { session, .. }
+            | Request::StartSolver { session, .. }
+            | Request::SolverMessage1 { session, .. }
+            | Request::SolverMessage3 { session, .. } => *session,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("session {0} already exists")]
+    AlreadyExists(SessionId),
+    #[error("unknown or expired session {0}")]
+    Unknown(SessionId),
+    #[error("session {0} is busy processing another request")]
+    Busy(SessionId),
+    #[error("session {0} is in state {1} which does not accept this request")]
+    OutOfOrder(SessionId, &'static str),
+    #[error("{0} has too many open sessions")]
+    TooManySessions(Peer),
+}
+
+enum Session<S: JointOutputScheme> {
+    Promise0(puzzle_promise::Tumbler0),
+    Promise1(puzzle_promise::Tumbler1<S>),
+    Solver0(puzzle_solver::Tumbler0),
+    Solver1(puzzle_solver::Tumbler1<S>),
+    Solver2(puzzle_solver::Tumbler2),
+}
+
+impl<S: JointOutputScheme> Session<S> {
+    fn name(&self) -> &'static str {
+        match self {
+            Session::Promise0(_) => "puzzle_promise::Tumbler0",
+            Session::Promise1(_) => "puzzle_promise::Tumbler1",
+            Session::Solver0(_) => "puzzle_solver::Tumbler0",
+            Session::Solver1(_) => "puzzle_solver::Tumbler1",
+            Session::Solver2(_) => "puzzle_solver::Tumbler2",
+        }
+    }
+
+    /// Completed sessions hold what the tumbler needs on-chain and are never expired.
+    fn is_completed(&self) -> bool {
+        match self {
+            Session::Promise1(_) | Session::Solver2(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What the service keeps in storage about a session.
+struct Record<S: JointOutputScheme> {
+    session: Session<S>,
+    peer: Peer,
+    last_exchange: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+struct Entry<S: JointOutputScheme> {
+    /// `None` while a request for this session is being processed.
+    session: Option<Session<S>>,
+    peer: Peer,
+    last_active: Instant,
+    /// The request that last advanced this session and the response sent for it, so that a
+    /// retransmitted request is answered without being processed twice.
+    last_exchange: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Runs every session with the joint output of `S`.
+pub struct TumblerService<HE, W, St, S: JointOutputScheme> {
+    HE: HE,
+    wallet: W,
+    config: ServiceConfig,
+    sessions: Mutex<HashMap<SessionId, Entry<S>>>,
+    storage: Mutex<St>,
+    scheme: PhantomData<S>,
+}
+
+impl<HE, W, St: Storage, S: JointOutputScheme> TumblerService<HE, W, St, S> {
+    /// Creates a service that keeps its sessions in `storage`, resuming the ones already in there.
+    ///
+    /// Resumed sessions count as active from now on, so that their clients get a full timeout to
+    /// come back.
+    pub fn new(HE: HE, wallet: W, storage: St, config: ServiceConfig) -> anyhow::Result<Self> {
+        let mut sessions = HashMap::new();
+
+        for key in storage.keys()? {
+            let id = match SessionId::from_storage_key(&key) {
+                Some(id) => id,
+                None => continue,
+            };
+            let record = match storage.load::<Record<S>>(&key)? {
+                Some(record) => record,
+                None => continue,
+            };
+
+            sessions.insert(
+                id,
+                Entry {
+                    session: Some(record.session),
+                    peer: record.peer,
+                    last_active: Instant::now(),
+                    last_exchange: record.last_exchange,
+                },
+            );
+        }
+
+        Ok(Self {
+            HE,
+            wallet,
+            config,
+            sessions: Mutex::new(sessions),
+            storage: Mutex::new(storage),
+            scheme: PhantomData,
+        })
+    }
+
+    /// Drops every session that is neither completed nor busy and has been idle for longer than
+    /// the session timeout.
+    pub fn expire_idle_sessions(&self) -> Vec<SessionId> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+        let timeout = self.config.session_timeout;
+
+        let expired = sessions
+            .iter()
+            .filter(|(_, entry)| match &entry.session {
+                Some(session) => !session.is_completed() && entry.last_active.elapsed() > timeout,
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired.iter() {
+            sessions.remove(id);
+            self.forget(*id);
+        }
+
+        expired
+    }
+
+    /// Lists the sessions whose tumbler state can be taken out with `take_completed_promise` or
+    /// `take_completed_solver`.
+    pub fn completed_sessions(&self) -> Vec<SessionId> {
+        let sessions = self.sessions.lock().expect("poisoned lock");
+
+        sessions
+            .iter()
+            .filter(|(_, entry)| match &entry.session {
+                Some(session) => session.is_completed(),
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Takes a completed puzzle promise session out of the service.
+    ///
+    /// The tumbler state stays in storage under `promise-<id>`.
+    pub fn take_completed_promise(
+        &self,
+        id: &SessionId,
+    ) -> anyhow::Result<Option<puzzle_promise::Tumbler1<S>>> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        match sessions.get(id).and_then(|entry| entry.session.as_ref()) {
+            Some(Session::Promise1(tumbler)) => {
+                self.hand_over(*id, &format!("promise-{}", id), tumbler)?
+            }
+            _ => return Ok(None),
+        }
+
+        match sessions.remove(id).and_then(|entry| entry.session) {
+            Some(Session::Promise1(tumbler)) => Ok(Some(tumbler)),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    /// Takes a completed puzzle solver session out of the service.
+    ///
+    /// The tumbler state stays in storage under `solver-<id>`.
+    pub fn take_completed_solver(
+        &self,
+        id: &SessionId,
+    ) -> anyhow::Result<Option<puzzle_solver::Tumbler2>> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        match sessions.get(id).and_then(|entry| entry.session.as_ref()) {
+            Some(Session::Solver2(tumbler)) => {
+                self.hand_over(*id, &format!("solver-{}", id), tumbler)?
+            }
+            _ => return Ok(None),
+        }
+
+        match sessions.remove(id).and_then(|entry| entry.session) {
+            Some(Session::Solver2(tumbler)) => Ok(Some(tumbler)),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    /// Moves the state of a completed session from the session's record to `key`.
+    fn hand_over<T: Snapshot>(&self, id: SessionId, key: &str, state: &T) -> anyhow::Result<()> {
+        let mut storage = self.storage.lock().expect("poisoned lock");
+
+        storage.save(key, state)?;
+        // a record left behind is handed over again after a restart, which writes the same state
+        let _ = storage.delete(&id.storage_key());
+
+        Ok(())
+    }
+
+    /// Claims `id` for a new session of `peer`, leaving a busy marker until the session is put in
+    /// place.
+    ///
+    /// Starting a session is only worth the work once it is known that it can be stored.
+    fn reserve(&self, id: SessionId, peer: Peer) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        match sessions.get(&id) {
+            Some(Entry { session: None, .. }) => return Err(SessionError::Busy(id)),
+            Some(_) => return Err(SessionError::AlreadyExists(id)),
+            None => {}
+        }
+
+        let open = sessions
+            .values()
+            .filter(|entry| entry.peer == peer)
+            .filter(|entry| match &entry.session {
+                Some(session) => !session.is_completed(),
+                None => true,
+            })
+            .count();
+        if open >= self.config.max_open_sessions_per_peer {
+            return Err(SessionError::TooManySessions(peer));
+        }
+
+        sessions.insert(
+            id,
+            Entry {
+                session: None,
+                peer,
+                last_active: Instant::now(),
+                last_exchange: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Checks out a session for processing, leaving a busy marker in its place.
+    fn take(&self, id: SessionId) -> Result<Session<S>, SessionError> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        let entry = sessions.get_mut(&id).ok_or(SessionError::Unknown(id))?;
+        let session = entry.session.take().ok_or(SessionError::Busy(id))?;
+
+        Ok(session)
+    }
+
+    /// Persists the new state of a session and checks it back in.
+    ///
+    /// A session whose state cannot be persisted is aborted, as its client must not be told about
+    /// a step the tumbler could forget.
+    fn put(&self, id: SessionId, session: Session<S>) -> anyhow::Result<()> {
+        let (session, result) = self.persist(id, session);
+        if let Err(e) = result {
+            self.abort(id);
+            return Err(e.context(format!("failed to persist session {}", id)));
+        }
+
+        self.check_in(id, session);
+
+        Ok(())
+    }
+
+    /// Writes a checked out session to storage along with what the service knows about it,
+    /// handing the session back whether or not that worked.
+    fn persist(&self, id: SessionId, session: Session<S>) -> (Session<S>, anyhow::Result<()>) {
+        let (peer, last_exchange) = match self.sessions.lock().expect("poisoned lock").get(&id) {
+            Some(entry) => (entry.peer, entry.last_exchange.clone()),
+            None => return (session, Ok(())),
+        };
+
+        let record = Record {
+            session,
+            peer,
+            last_exchange,
+        };
+        let result = self
+            .storage
+            .lock()
+            .expect("poisoned lock")
+            .save(&id.storage_key(), &record);
+
+        (record.session, result)
+    }
+
+    /// Checks a session back in after processing.
+    fn check_in(&self, id: SessionId, session: Session<S>) {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        // only `abort` removes a checked out session, and it is only called instead of `put`
+        if let Some(entry) = sessions.get_mut(&id) {
+            entry.session = Some(session);
+            entry.last_active = Instant::now();
+        }
+    }
+
+    /// Returns the response previously sent for `request` if it is a retransmission of the
+    /// request that last advanced the session.
+    fn replay(&self, id: SessionId, request: &[u8]) -> Option<Vec<u8>> {
+        let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+        let entry = sessions.get_mut(&id)?;
+        match &entry.last_exchange {
+            Some((last_request, response)) if last_request.as_slice() == request => {
+                entry.last_active = Instant::now();
+                Some(response.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn remember(&self, id: SessionId, request: &[u8], response: &[u8]) {
+        let session = {
+            let mut sessions = self.sessions.lock().expect("poisoned lock");
+
+            let entry = match sessions.get_mut(&id) {
+                Some(entry) => entry,
+                None => return,
+            };
+            entry.last_exchange = Some((request.to_vec(), response.to_vec()));
+
+            // a session that is being processed again is persisted once it is put back
+            match entry.session.take() {
+                Some(session) => session,
+                None => return,
+            }
+        };
+
+        // without the exchange in storage, a retransmission after a restart is only rejected as
+        // out of order
+        let (session, _) = self.persist(id, session);
+        self.check_in(id, session);
+    }
+
+    /// Drops a checked out session whose last request failed.
+    fn abort(&self, id: SessionId) {
+        self.sessions.lock().expect("poisoned lock").remove(&id);
+        self.forget(id);
+    }
+
+    /// Deletes the record of a session that was dropped.
+    fn forget(&self, id: SessionId) {
+        // a record left behind is resumed after a restart and then expires again
+        let _ = self
+            .storage
+            .lock()
+            .expect("poisoned lock")
+            .delete(&id.storage_key());
+    }
+
+    fn out_of_order(&self, id: SessionId, session: Session<S>) -> SessionError {
+        let name = session.name();
+        self.check_in(id, session);
+
+        SessionError::OutOfOrder(id, name)
+    }
+}
+
+impl<HE, W, St, S, C> TumblerService<HE, W, St, S>
+where
+    HE: hsm_cl::Encrypt<Ciphertext = C> + hsm_cl::Decrypt<Ciphertext = C>,
+    W: Wallet,
+    St: Storage,
+    S: JointOutputScheme,
+{
+    /// Advances the session addressed by `request` by one step.
+    ///
+    /// A session whose incoming message fails verification is aborted.
+    pub fn process(
+        &self,
+        peer: Peer,
+        request: Request<C, S>,
+    ) -> anyhow::Result<Response<C, HE::Proof, S>> {
+        self.expire_idle_sessions();
+
+        let mut rng = rand::thread_rng();
+
+        let response = match request {
+            Request::StartPromise {
+                session: id,
+                redeem_identity,
+            } => {
+                self.reserve(id, peer)?;
+
+                let params = match self.promise_params(redeem_identity) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        self.abort(id);
+                        return Err(e);
+                    }
+                };
+                let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+                let message = tumbler.next_message(&self.HE);
+                self.put(id, Session::Promise0(tumbler))?;
+
+                Response::PromiseMessage0(params, message)
+            }
+            Request::PromiseMessage1 {
+                session: id,
+                message,
+            } => {
+                let tumbler = match self.take(id)? {
+                    Session::Promise0(tumbler) => tumbler,
+                    session => bail!(self.out_of_order(id, session)),
+                };
+
+                let tumbler = match tumbler.receive(message, &mut rng) {
+                    Ok(tumbler) => tumbler,
+                    Err(e) => {
+                        self.abort(id);
+                        return Err(e);
+                    }
+                };
+                // another session may have been offered the same coins and reserved them first
+                if let Err(e) = self
+                    .wallet
+                    .reserve_inputs(tumbler.unsigned_fund_transaction())
+                {
+                    self.abort(id);
+                    return Err(e.context(format!("failed to reserve the coins of session {}", id)));
+                }
+                let message = tumbler.next_message();
+                self.put(id, Session::Promise1(tumbler))?;
+
+                Response::PromiseMessage2(message)
+            }
+            Request::StartSolver {
+                session: id,
+                sender,
+            } => {
+                self.reserve(id, peer)?;
+
+                let params = match self.solver_params(sender) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        self.abort(id);
+                        return Err(e);
+                    }
+                };
+                let x_t = crate::secp256k1::KeyPair::random(&mut rng);
+                let tumbler = puzzle_solver::Tumbler0::new(params.clone(), x_t);
+                let message = tumbler.next_message();
+                self.put(id, Session::Solver0(tumbler))?;
+
+                Response::SolverMessage0(params, message)
+            }
+            Request::SolverMessage1 {
+                session: id,
+                message,
+            } => {
+                let tumbler = match self.take(id)? {
+                    Session::Solver0(tumbler) => tumbler,
+                    session => bail!(self.out_of_order(id, session)),
+                };
+
+                let tumbler = match tumbler.receive(message, &self.HE, &mut rng) {
+                    Ok(tumbler) => tumbler,
+                    Err(e) => {
+                        self.abort(id);
+                        return Err(e);
+                    }
+                };
+                let message = tumbler.next_message();
+                self.put(id, Session::Solver1(tumbler))?;
+
+                Response::SolverMessage2(message)
+            }
+            Request::SolverMessage3 {
+                session: id,
+                message,
+            } => {
+                let tumbler = match self.take(id)? {
+                    Session::Solver1(tumbler) => tumbler,
+                    session => bail!(self.out_of_order(id, session)),
+                };
+
+                let tumbler = match tumbler.receive(message) {
+                    Ok(tumbler) => tumbler,
+                    Err(e) => {
+                        self.abort(id);
+                        return Err(e);
+                    }
+                };
+                self.put(id, Session::Solver2(tumbler))?;
+
+                Response::SolverDone
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Completes the params of a puzzle promise session with a receiver paid to
+    /// `redeem_identity`, funding the joint output from coins of the wallet that are not reserved
+    /// yet.
+    fn promise_params(&self, redeem_identity: Address) -> anyhow::Result<Params> {
+        let terms = self.terms(Protocol::PuzzlePromise)?;
+        let refund_identity = self.config.refund_address.clone();
+
+        // the joint output does not depend on the transaction funding it
+        let value = Params {
+            redeem_identity: redeem_identity.clone(),
+            refund_identity: refund_identity.clone(),
+            terms,
+            partial_fund_transaction: Transaction {
+                version: 2,
+                lock_time: 0,
+                input: Vec::new(),
+                output: Vec::new(),
+            },
+            partial_fund_spent_outputs: Vec::new(),
+        }
+        .joint_output_value(Protocol::PuzzlePromise);
+        let (partial_fund_transaction, partial_fund_spent_outputs) =
+            self.wallet.partial_fund_transaction(value)?;
+
+        Ok(Params::new(
+            Protocol::PuzzlePromise,
+            redeem_identity,
+            refund_identity,
+            terms,
+            partial_fund_transaction,
+            partial_fund_spent_outputs,
+        )?)
+    }
+
+    /// Completes the params of a puzzle solver session with the sender's part.
+    fn solver_params(&self, sender: SenderContribution) -> anyhow::Result<Params> {
+        Ok(Params::new(
+            Protocol::PuzzleSolver,
+            self.config.payout_address.clone(),
+            sender.refund_identity,
+            self.terms(Protocol::PuzzleSolver)?,
+            sender.partial_fund_transaction,
+            sender.partial_fund_spent_outputs,
+        )?)
+    }
+
+    /// The terms of a session of `protocol` starting now.
+    fn terms(&self, protocol: Protocol) -> anyhow::Result<Terms> {
+        let expiry_blocks = match protocol {
+            Protocol::PuzzlePromise => self.config.promise_expiry_blocks,
+            Protocol::PuzzleSolver => self.config.solver_expiry_blocks,
+        };
+
+        Ok(Terms {
+            tumble_amount: self.config.tumble_amount,
+            tumbler_fee: self.config.tumbler_fee,
+            expiry: self.wallet.height()? + expiry_blocks,
+            spend_transaction_fee_per_vbyte: self.config.spend_transaction_fee_per_vbyte,
+            anchor_outputs: self.config.anchor_outputs,
+            output_type: S::OUTPUT_TYPE,
+        })
+    }
+}
+
+impl<HE, W, St, S, C> Handler for TumblerService<HE, W, St, S>
+where
+    HE: hsm_cl::Encrypt<Ciphertext = C> + hsm_cl::Decrypt<Ciphertext = C> + Send + Sync + 'static,
+    HE::Proof: wire::Encode + wire::Decode,
+    W: Wallet + Send + Sync + 'static,
+    St: Storage + Send + 'static,
+    S: JointOutputScheme + Send + Sync + 'static,
+    C: wire::Encode + wire::Decode,
+{
+    fn handle(&self, peer: Peer, bytes: &[u8]) -> Vec<u8> {
+        let request = match Request::<C, S>::from_bytes(bytes) {
+            Ok(request) => request,
+            Err(e) => return Response::<C, HE::Proof, S>::Rejected(e.to_string()).to_bytes(),
+        };
+        let id = request.session();
+
+        if let Some(response) = self.replay(id, bytes) {
+            return response;
+        }
+
+        match self.process(peer, request) {
+            Ok(response) => {
+                let response = response.to_bytes();
+                self.remember(id, bytes, &response);
+
+                response
+            }
+            Err(e) => match e.downcast_ref::<SessionError>() {
+                Some(SessionError::Busy(_)) => Response::<C, HE::Proof, S>::Busy.to_bytes(),
+                _ => Response::<C, HE::Proof, S>::Rejected(format!("{:#}", e)).to_bytes(),
+            },
+        }
+    }
+}
+
+impl<C: wire::Encode, S: JointOutputScheme> wire::Encode for Request<C, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Request::StartPromise {
+                session,
+                redeem_identity,
+            } => {
+                buffer.push(0);
+                session.encode(buffer);
+                redeem_identity.encode(buffer);
+            }
+            Request::PromiseMessage1 { session, message } => {
+                buffer.push(1);
+                session.encode(buffer);
+                message.encode(buffer);
+            }
+            Request::StartSolver { session, sender } => {
+                buffer.push(2);
+                session.encode(buffer);
+                sender.refund_identity.encode(buffer);
+                sender.partial_fund_transaction.encode(buffer);
+                sender.partial_fund_spent_outputs.encode(buffer);
+            }
+            Request::SolverMessage1 { session, message } => {
+                buffer.push(3);
+                session.encode(buffer);
+                message.encode(buffer);
+            }
+            Request::SolverMessage3 { session, message } => {
+                buffer.push(4);
+                session.encode(buffer);
+                message.encode(buffer);
+            }
+        }
+    }
+}
+
+impl<C: wire::Decode, S: JointOutputScheme> wire::Decode for Request<C, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let request = match reader.read_u8()? {
+            0 => Request::StartPromise {
+                session: SessionId::decode(reader)?,
+                redeem_identity: Address::decode(reader)?,
+            },
+            1 => Request::PromiseMessage1 {
+                session: SessionId::decode(reader)?,
+                message: puzzle_promise::Message1::decode(reader)?,
+            },
+            2 => Request::StartSolver {
+                session: SessionId::decode(reader)?,
+                sender: SenderContribution {
+                    refund_identity: Address::decode(reader)?,
+                    partial_fund_transaction: Transaction::decode(reader)?,
+                    partial_fund_spent_outputs: Vec::decode(reader)?,
+                },
+            },
+            3 => Request::SolverMessage1 {
+                session: SessionId::decode(reader)?,
+                message: puzzle_solver::Message1::decode(reader)?,
+            },
+            4 => Request::SolverMessage3 {
+                session: SessionId::decode(reader)?,
+                message: puzzle_solver::Message3::decode(reader)?,
+            },
+            variant => return Err(wire::DecodeError::UnknownVariant(variant)),
+        };
+
+        Ok(request)
+    }
+}
+
+impl<C: wire::Encode + wire::Decode, S: JointOutputScheme> wire::WireMessage for Request<C, S> {
+    const TAG: u8 = 0x60;
+}
+
+impl<C: wire::Encode, P: wire::Encode, S: JointOutputScheme> wire::Encode for Response<C, P, S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Response::PromiseMessage0(params, message) => {
+                buffer.push(0);
+                params.encode(buffer);
+                message.encode(buffer);
+            }
+            Response::PromiseMessage2(message) => {
+                buffer.push(1);
+                message.encode(buffer);
+            }
+            Response::SolverMessage0(params, message) => {
+                buffer.push(2);
+                params.encode(buffer);
+                message.encode(buffer);
+            }
+            Response::SolverMessage2(message) => {
+                buffer.push(3);
+                message.encode(buffer);
+            }
+            Response::SolverDone => buffer.push(4),
+            Response::Busy => buffer.push(6),
+            Response::Rejected(reason) => {
+                buffer.push(5);
+                wire::write_var_bytes(buffer, reason.as_bytes());
+            }
+        }
+    }
+}
+
+impl<C: wire::Decode, P: wire::Decode, S: JointOutputScheme> wire::Decode for Response<C, P, S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let response = match reader.read_u8()? {
+            0 => Response::PromiseMessage0(
+                Params::decode(reader)?,
+                puzzle_promise::Message0::decode(reader)?,
+            ),
+            1 => Response::PromiseMessage2(puzzle_promise::Message2::decode(reader)?),
+            2 => Response::SolverMessage0(
+                Params::decode(reader)?,
+                puzzle_solver::Message0::decode(reader)?,
+            ),
+            3 => Response::SolverMessage2(puzzle_solver::Message2::decode(reader)?),
+            4 => Response::SolverDone,
+            5 => Response::Rejected(String::from_utf8_lossy(reader.read_var_bytes()?).into_owned()),
+            6 => Response::Busy,
+            variant => return Err(wire::DecodeError::UnknownVariant(variant)),
+        };
+
+        Ok(response)
+    }
+}
+
+impl<C, P, S> wire::WireMessage for Response<C, P, S>
+where
+    C: wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+    S: JointOutputScheme,
+{
+    const TAG: u8 = 0x61;
+}
+
+impl<S: JointOutputScheme> wire::Encode for Session<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Session::Promise0(tumbler) => {
+                buffer.push(0);
+                tumbler.encode(buffer);
+            }
+            Session::Promise1(tumbler) => {
+                buffer.push(1);
+                tumbler.encode(buffer);
+            }
+            Session::Solver0(tumbler) => {
+                buffer.push(2);
+                tumbler.encode(buffer);
+            }
+            Session::Solver1(tumbler) => {
+                buffer.push(3);
+                tumbler.encode(buffer);
+            }
+            Session::Solver2(tumbler) => {
+                buffer.push(4);
+                tumbler.encode(buffer);
+            }
+        }
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Session<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        let session = match reader.read_u8()? {
+            0 => Session::Promise0(puzzle_promise::Tumbler0::decode(reader)?),
+            1 => Session::Promise1(puzzle_promise::Tumbler1::decode(reader)?),
+            2 => Session::Solver0(puzzle_solver::Tumbler0::decode(reader)?),
+            3 => Session::Solver1(puzzle_solver::Tumbler1::decode(reader)?),
+            4 => Session::Solver2(puzzle_solver::Tumbler2::decode(reader)?),
+            variant => return Err(wire::DecodeError::UnknownVariant(variant)),
+        };
+
+        Ok(session)
+    }
+}
+
+impl<S: JointOutputScheme> wire::Encode for Record<S> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.session.encode(buffer);
+        self.peer.encode(buffer);
+        match &self.last_exchange {
+            None => buffer.push(0),
+            Some((request, response)) => {
+                buffer.push(1);
+                wire::write_var_bytes(buffer, request);
+                wire::write_var_bytes(buffer, response);
+            }
+        }
+    }
+}
+
+impl<S: JointOutputScheme> wire::Decode for Record<S> {
+    fn decode(reader: &mut wire::Reader<'_>) -> Result<Self, wire::DecodeError> {
+        Ok(Record {
+            session: Session::decode(reader)?,
+            peer: Peer::decode(reader)?,
+            last_exchange: match reader.read_u8()? {
+                0 => None,
+                1 => Some((
+                    reader.read_var_bytes()?.to_vec(),
+                    reader.read_var_bytes()?.to_vec(),
+                )),
+                variant => return Err(wire::DecodeError::UnknownVariant(variant)),
+            },
+        })
+    }
+}
+
+impl<S: JointOutputScheme> Snapshot for Record<S> {
+    const TAG: u8 = 0x40;
+}
@@ -0,0 +1,117 @@
+//! Completes the sender's side of the puzzle solver protocol by watching the chain.
+//!
+//! Once the sender has funded the joint output and handed out `Message3`, one of two things
+//! happens: either the tumbler redeems, revealing the signature the sender needs to learn the
+//! puzzle solution, or the refund locktime passes and the sender takes its coins back.
+//!
+//! A broadcast refund can still lose against the redeem until it confirms, so the watcher keeps
+//! looking at the fund output until either the redeem shows up or the refund is in a block.
+
+use crate::bitcoin::{Transaction, Txid};
+use crate::chain::{Broadcaster, ChainSource};
+use crate::joint_output::JointOutputScheme;
+use crate::puzzle_solver;
+use std::thread;
+use std::time::Duration;
+
+pub enum Outcome {
+    /// The tumbler redeemed and the sender learned the solution to the puzzle.
+    Solved(puzzle_solver::Sender3),
+    /// The sender's refund transaction is confirmed.
+    Refunded(Txid),
+}
+
+pub enum Poll<S: JointOutputScheme> {
+    Pending(SenderWatcher<S>),
+    Ready(Outcome),
+    /// Looking at the chain or broadcasting the refund failed, e.g. because bitcoind was not
+    /// reachable. Nothing was lost, the watcher can be polled again.
+    Retry(SenderWatcher<S>, anyhow::Error),
+}
+
+pub struct SenderWatcher<S: JointOutputScheme> {
+    sender: puzzle_solver::Sender2<S>,
+}
+
+impl<S: JointOutputScheme> SenderWatcher<S> {
+    pub fn new(sender: puzzle_solver::Sender2<S>) -> Self {
+        Self { sender }
+    }
+
+    /// Looks at the chain once, broadcasting the refund if the tumbler did not redeem in time.
+    ///
+    /// Only fails if the fund output was spent by a transaction the sender cannot learn the
+    /// solution from, which no amount of retrying fixes.
+    pub fn poll<Ch: ChainSource + Broadcaster>(self, chain: &Ch) -> anyhow::Result<Poll<S>> {
+        let refund = self.sender.signed_refund_transaction();
+        let fund_outpoint = refund.input[0].previous_output;
+
+        let spend = match chain.spending_transaction(&fund_outpoint) {
+            Ok(spend) => spend,
+            Err(e) => return Ok(Poll::Retry(self, e)),
+        };
+        if let Some(spend) = spend {
+            return self.settle(chain, &refund, spend);
+        }
+
+        // the refund can be included in the block after its locktime
+        let height = match chain.height() {
+            Ok(height) => height,
+            Err(e) => return Ok(Poll::Retry(self, e)),
+        };
+        if height < refund.lock_time {
+            return Ok(Poll::Pending(self));
+        }
+
+        if let Err(e) = chain.broadcast(&refund) {
+            // the redeem may have been published since we looked, in which case it is the reason
+            // the refund was rejected
+            return match chain.spending_transaction(&fund_outpoint) {
+                Ok(Some(spend)) => self.settle(chain, &refund, spend),
+                _ => Ok(Poll::Retry(self, e)),
+            };
+        }
+
+        Ok(Poll::Pending(self))
+    }
+
+    /// Handles a transaction spending the fund output, which is either our refund or the redeem.
+    fn settle<Ch: ChainSource>(
+        self,
+        chain: &Ch,
+        refund: &Transaction,
+        spend: Transaction,
+    ) -> anyhow::Result<Poll<S>> {
+        if spend.txid() != refund.txid() {
+            let sender = self.sender.receive(spend)?;
+
+            return Ok(Poll::Ready(Outcome::Solved(sender)));
+        }
+
+        match chain.confirmations(&refund.txid()) {
+            Ok(Some(confirmations)) if confirmations > 0 => {
+                Ok(Poll::Ready(Outcome::Refunded(refund.txid())))
+            }
+            Ok(_) => Ok(Poll::Pending(self)),
+            Err(e) => Ok(Poll::Retry(self, e)),
+        }
+    }
+
+    /// Polls the chain every `interval` until the protocol is settled one way or the other.
+    ///
+    /// Failures to reach the chain are retried at the next interval.
+    pub fn run<Ch: ChainSource + Broadcaster>(
+        mut self,
+        chain: &Ch,
+        interval: Duration,
+    ) -> anyhow::Result<Outcome> {
+        loop {
+            match self.poll(chain)? {
+                Poll::Pending(watcher) | Poll::Retry(watcher, _) => self = watcher,
+                Poll::Ready(outcome) => return Ok(outcome),
+            }
+
+            thread::sleep(interval);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Canonical binary encoding of the protocol messages.
+//!
+//! Every message is framed as `version || tag || payload`. Payloads are the concatenation of
+//! their fields in declaration order, using fixed-size encodings for curve types and a
+//! big-endian `u32` length prefix for variable-size ones.
+
+use crate::secp256k1;
+use std::convert::TryInto;
+
+/// Bumped whenever the encoding of a message or snapshot changes.
+///
+/// - 2: `Params` carries the spent outputs of the partial fund transaction.
+/// - 3: `Params` says whether the redeem and refund transactions get anchor outputs.
+/// - 4: DLEQ proofs carry their commitments instead of the challenge.
+/// - 5: The tumbler and sender states holding the fund transaction carry the outputs it spends.
+/// - 6: Session start requests carry only the client's part of the params, and the tumbler's first
+///   response carries the completed params.
+/// - 7: `Params` carry the type of the joint output, and the messages and states of both
+///   protocols carry the nonces and shares of the redeem signature of the chosen scheme.
+/// - 8: Snapshots of parties waiting to sign the redeem transaction leave out their secret nonce.
+pub const VERSION: u8 = 8;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum DecodeError {
+    #[error("unsupported wire format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("expected message tag {expected}, got {actual}")]
+    UnexpectedTag { expected: u8, actual: u8 },
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("{0} trailing bytes after message")]
+    TrailingBytes(usize),
+    #[error("invalid secp256k1 point")]
+    InvalidPoint,
+    #[error("invalid secp256k1 scalar")]
+    InvalidScalar,
+    #[error("invalid ciphertext")]
+    InvalidCiphertext,
+    #[error("invalid proof")]
+    InvalidProof,
+    #[error("invalid encryption key")]
+    InvalidEncryptionKey,
+    #[error("invalid bitcoin transaction")]
+    InvalidTransaction,
+    #[error("invalid bitcoin transaction output")]
+    InvalidOutput,
+    #[error("invalid bitcoin address")]
+    InvalidAddress,
+    #[error("unknown variant {0}")]
+    UnknownVariant(u8),
+}
+
+pub trait Encode {
+    fn encode(&self, buffer: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError>;
+}
+
+/// A protocol message that can be sent over the wire on its own.
+pub trait WireMessage: Encode + Decode {
+    /// Identifies the message type, unique across both sub-protocols.
+    const TAG: u8;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        to_framed_bytes(Self::TAG, self)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        from_framed_bytes(Self::TAG, bytes)
+    }
+}
+
+/// Encodes `value` as `version || tag || payload`.
+pub fn to_framed_bytes<T: Encode>(tag: u8, value: &T) -> Vec<u8> {
+    let mut buffer = vec![VERSION, tag];
+    value.encode(&mut buffer);
+
+    buffer
+}
+
+/// Decodes a `version || tag || payload` frame, checking the version and tag and that the payload
+/// is consumed entirely.
+pub fn from_framed_bytes<T: Decode>(tag: u8, bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut reader = Reader::new(bytes);
+
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let actual = reader.read_u8()?;
+    if actual != tag {
+        return Err(DecodeError::UnexpectedTag {
+            expected: tag,
+            actual,
+        });
+    }
+
+    let value = T::decode(&mut reader)?;
+    reader.finish()?;
+
+    Ok(value)
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.read_slice(4)?;
+
+        Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.read_slice(8)?;
+
+        Ok(u64::from_be_bytes(bytes.try_into().expect("8 bytes")))
+    }
+
+    pub fn read_array_32(&mut self) -> Result<[u8; 32], DecodeError> {
+        let bytes = self.read_slice(32)?;
+
+        Ok(bytes.try_into().expect("32 bytes"))
+    }
+
+    pub fn read_array_33(&mut self) -> Result<[u8; 33], DecodeError> {
+        let bytes = self.read_slice(33)?;
+        let mut array = [0u8; 33];
+        array.copy_from_slice(bytes);
+
+        Ok(array)
+    }
+
+    /// Reads a `u32` length prefix followed by that many bytes.
+    pub fn read_var_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.read_u32()? as usize;
+
+        self.read_slice(len)
+    }
+
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+
+        Ok(head)
+    }
+
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if !self.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes(self.bytes.len()));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn write_var_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+/// Nothing, for the fields a signature scheme does not need, e.g. the redeem nonces of ECDSA.
+impl Encode for () {
+    fn encode(&self, _buffer: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode(_reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match reader.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(DecodeError::UnknownVariant(other)),
+        }
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.read_u32()
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.read_u64()
+    }
+}
+
+/// Sequences are prefixed with their number of elements as a `u32`.
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (self.len() as u32).encode(buffer);
+        for element in self {
+            element.encode(buffer);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let len = reader.read_u32()?;
+
+        // not preallocated, the length is untrusted
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+impl Encode for secp256k1::PublicKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.serialize_compressed());
+    }
+}
+
+impl Decode for secp256k1::PublicKey {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let bytes = reader.read_array_33()?;
+
+        secp256k1::PublicKey::parse_compressed(&bytes).map_err(|_| DecodeError::InvalidPoint)
+    }
+}
+
+impl Encode for secp256k1::Scalar {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.b32());
+    }
+}
+
+/// Decodes a scalar, rejecting encodings that are not reduced modulo the curve order.
+///
+/// Zero is accepted because some proof scalars may legitimately be zero.
+impl Decode for secp256k1::Scalar {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let bytes = reader.read_array_32()?;
+
+        let mut scalar = secp256k1::Scalar::default();
+        let overflow: bool = scalar.set_b32(&bytes).into();
+        if overflow {
+            return Err(DecodeError::InvalidScalar);
+        }
+
+        Ok(scalar)
+    }
+}
+
+impl Encode for secp256k1::SecretKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.serialize());
+    }
+}
+
+impl Decode for secp256k1::SecretKey {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let bytes = reader.read_array_32()?;
+
+        secp256k1::SecretKey::parse(&bytes).map_err(|_| DecodeError::InvalidScalar)
+    }
+}
+
+impl Encode for secp256k1::Signature {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.serialize());
+    }
+}
+
+impl Decode for secp256k1::Signature {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let r = secp256k1::Scalar::decode(reader)?;
+        let s = secp256k1::Scalar::decode(reader)?;
+
+        if r.is_zero() || s.is_zero() {
+            return Err(DecodeError::InvalidScalar);
+        }
+
+        Ok(secp256k1::Signature { r, s })
+    }
+}
+
+impl<C: Encode> Encode for crate::Lock<C> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.c_alpha_prime.encode(buffer);
+        self.A_prime.encode(buffer);
+    }
+}
+
+impl<C: Decode> Decode for crate::Lock<C> {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(crate::Lock {
+            c_alpha_prime: C::decode(reader)?,
+            A_prime: secp256k1::PublicKey::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip<T: Encode + Decode>(value: &T) -> T {
+        let mut buffer = Vec::new();
+        value.encode(&mut buffer);
+
+        let mut reader = Reader::new(&buffer);
+        let decoded = T::decode(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        decoded
+    }
+
+    #[test]
+    fn curve_types_roundtrip() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let signature = secp256k1::sign([1u8; 32], &x);
+
+        assert_eq!(&roundtrip(x.public_key()), x.public_key());
+        assert_eq!(&roundtrip(x.secret_key()), x.secret_key());
+        assert_eq!(roundtrip(&signature), signature);
+    }
+
+    #[test]
+    fn sequences_roundtrip() {
+        assert_eq!(roundtrip(&vec![1u32, 2, 3]), vec![1, 2, 3]);
+        assert_eq!(roundtrip(&Vec::<u64>::new()), Vec::<u64>::new());
+        assert_eq!(roundtrip(&vec![true, false]), vec![true, false]);
+
+        // a length prefix longer than the input must not be trusted
+        assert_eq!(
+            Vec::<u32>::decode(&mut Reader::new(&[0xff, 0xff, 0xff, 0xff])),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn rejects_point_not_on_curve() {
+        let mut bytes = [0xffu8; 33];
+        bytes[0] = 0x02;
+
+        let mut reader = Reader::new(&bytes);
+
+        assert_eq!(
+            secp256k1::PublicKey::decode(&mut reader),
+            Err(DecodeError::InvalidPoint)
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_and_zero_scalars() {
+        let overflowing = [0xffu8; 32];
+        let zero = [0u8; 32];
+
+        assert_eq!(
+            secp256k1::Scalar::decode(&mut Reader::new(&overflowing)),
+            Err(DecodeError::InvalidScalar)
+        );
+        assert_eq!(
+            secp256k1::SecretKey::decode(&mut Reader::new(&zero)),
+            Err(DecodeError::InvalidScalar)
+        );
+        assert_eq!(
+            secp256k1::Signature::decode(&mut Reader::new(&[zero, zero].concat())),
+            Err(DecodeError::InvalidScalar)
+        );
+    }
+
+    impl WireMessage for secp256k1::PublicKey {
+        const TAG: u8 = 0xff;
+    }
+
+    #[test]
+    fn message_framing_is_checked() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let bytes = x.public_key().to_bytes();
+
+        assert_eq!(&bytes[..2], &[VERSION, 0xff]);
+        assert_eq!(
+            &secp256k1::PublicKey::from_bytes(&bytes).unwrap(),
+            x.public_key()
+        );
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = VERSION + 1;
+        assert_eq!(
+            secp256k1::PublicKey::from_bytes(&wrong_version),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut wrong_tag = bytes.clone();
+        wrong_tag[1] = 0x00;
+        assert_eq!(
+            secp256k1::PublicKey::from_bytes(&wrong_tag),
+            Err(DecodeError::UnexpectedTag {
+                expected: 0xff,
+                actual: 0x00
+            })
+        );
+
+        let mut trailing = bytes;
+        trailing.push(0x00);
+        assert_eq!(
+            secp256k1::PublicKey::from_bytes(&trailing),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let x = secp256k1::KeyPair::random_from_thread_rng();
+        let mut buffer = Vec::new();
+        x.public_key().encode(&mut buffer);
+
+        assert_eq!(
+            secp256k1::PublicKey::decode(&mut Reader::new(&buffer[..32])),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+}
@@ -1,93 +1,374 @@
+use a2l_poc::bitcoin::{InvalidPartialFundTransaction, OutputType};
+use a2l_poc::joint_output::{JointOutputScheme, OutputTypeMismatch, SecretNonceLost};
+use a2l_poc::mock_chain::{MockChain, Rejected};
 use a2l_poc::puzzle_promise;
 use a2l_poc::puzzle_solver;
-use a2l_poc::{dummy_hsm_cl as hsm_cl, Params};
+use a2l_poc::secp256k1::{self, Ecdsa, Schnorr};
+use a2l_poc::storage::{FileStorage, Snapshot, Storage};
+use a2l_poc::wire::{self, WireMessage};
+use a2l_poc::{dummy_hsm_cl, hsm_cl, Protocol, Terms};
+use common::{make_params, make_params_with_terms, random_p2wpkh, terms, SOLVER_EXPIRY};
+use std::path::Path;
+
+mod common;
 
 #[test]
 fn dry_happy_path() {
-    let mut blockchain = Blockchain::default();
-    let amount = 10_000_000;
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    assert_dry_happy_path::<Ecdsa, _, _, _, _>(&secretkey, &publickey);
+}
+
+#[test]
+fn dry_happy_path_hsm_cl() {
+    let (secretkey, publickey) = hsm_cl::keygen(b"A2L-PoC");
+
+    assert_dry_happy_path::<Ecdsa, _, _, _, _>(&secretkey, &publickey);
+}
 
-    run_a2l_happy_path(amount, 0, 0, &mut blockchain);
+#[test]
+fn dry_happy_path_p2tr() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    assert_dry_happy_path::<Schnorr, _, _, _, _>(&secretkey, &publickey);
+}
+
+#[test]
+fn dry_happy_path_p2tr_hsm_cl() {
+    let (secretkey, publickey) = hsm_cl::keygen(b"A2L-PoC");
 
-    assert!(blockchain.sender_fund.is_some());
-    assert!(blockchain.tumbler_redeem.is_some());
-    assert!(blockchain.tumbler_fund.is_some());
-    assert!(blockchain.receiver_redeem.is_some());
+    assert_dry_happy_path::<Schnorr, _, _, _, _>(&secretkey, &publickey);
 }
 
 #[test]
 fn happy_path_fees() {
-    let mut blockchain = Blockchain::default();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
 
-    // global parameters
-    let tumble_amount = 10_000_000;
-    let tumbler_fee = 10_000;
-    let spend_transaction_fee_per_wu = 15;
+    assert_happy_path_fees::<Ecdsa, _, _, _, _>(&secretkey, &publickey);
+}
 
-    run_a2l_happy_path(
-        tumble_amount,
-        tumbler_fee,
-        spend_transaction_fee_per_wu,
-        &mut blockchain,
+#[test]
+fn happy_path_fees_hsm_cl() {
+    let (secretkey, publickey) = hsm_cl::keygen(b"A2L-PoC");
+
+    assert_happy_path_fees::<Ecdsa, _, _, _, _>(&secretkey, &publickey);
+}
+
+#[test]
+fn happy_path_fees_p2tr() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    assert_happy_path_fees::<Schnorr, _, _, _, _>(&secretkey, &publickey);
+}
+
+#[test]
+fn parties_reject_params_for_other_output_type() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let params = make_params_with_terms(
+        Protocol::PuzzlePromise,
+        Terms {
+            output_type: OutputType::P2tr,
+            ..terms(Protocol::PuzzlePromise)
+        },
     );
 
-    let (sender_fund, tumbler_redeem, tumbler_fund, receiver_redeem) = (
-        blockchain.sender_fund.unwrap(),
-        blockchain.tumbler_redeem.unwrap(),
-        blockchain.tumbler_fund.unwrap(),
-        blockchain.receiver_redeem.unwrap(),
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+
+    let message = tumbler.next_message(&secretkey);
+    let error = receiver
+        .receive(message, &mut rng, &publickey)
+        .err()
+        .expect("receiver to reject the output type");
+
+    assert!(error.downcast_ref::<OutputTypeMismatch>().is_some());
+}
+
+#[test]
+fn p2tr_parties_restored_before_signing_refuse_to_sign() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let p2tr_params = |protocol| {
+        make_params_with_terms(
+            protocol,
+            Terms {
+                output_type: OutputType::P2tr,
+                ..terms(protocol)
+            },
+        )
+    };
+
+    // puzzle promise protocol
+    let params = p2tr_params(Protocol::PuzzlePromise);
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Schnorr>::new(params, &mut rng);
+    let sender = puzzle_promise::Sender0::new();
+
+    let message = tumbler.next_message(&secretkey);
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let message = receiver.next_message();
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let error = restored_copy(dir, "promise-receiver", &receiver)
+        .receive(tumbler.next_message(), &mut rng, &publickey)
+        .err()
+        .expect("restored receiver to refuse to sign");
+    assert!(error.downcast_ref::<SecretNonceLost>().is_some());
+    let message = tumbler.next_message();
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let message = receiver.next_message();
+    let sender = sender.receive(message);
+
+    // puzzle solver protocol
+    let params = p2tr_params(Protocol::PuzzleSolver);
+    let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
+    let sender = puzzle_solver::Sender0::<_, Schnorr>::new(params, sender.lock().clone(), &mut rng);
+
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let message = sender.next_message(&publickey);
+    let tumbler = tumbler.receive(message, &secretkey, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let error = restored_copy(dir, "solver-sender", &sender)
+        .receive(message, &mut rng, &publickey)
+        .err()
+        .expect("restored sender to refuse to sign");
+    assert!(error.downcast_ref::<SecretNonceLost>().is_some());
+}
+
+#[test]
+fn dry_happy_path_resumes_after_crash_at_every_step() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let params = make_params(Protocol::PuzzlePromise);
+
+    // puzzle promise protocol
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let tumbler = crash_and_resume(dir, "promise-tumbler", tumbler);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+    let receiver = crash_and_resume(dir, "promise-receiver", receiver);
+    let sender = crash_and_resume(dir, "promise-sender", puzzle_promise::Sender0::new());
+
+    let message = tumbler.next_message(&secretkey);
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let receiver = crash_and_resume(dir, "promise-receiver", receiver);
+    let message = receiver.next_message();
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let tumbler = crash_and_resume(dir, "promise-tumbler", tumbler);
+    let message = tumbler.next_message();
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let receiver = crash_and_resume(dir, "promise-receiver", receiver);
+    let message = receiver.next_message();
+    let sender = sender.receive(message);
+    let sender = crash_and_resume(dir, "promise-sender", sender);
+
+    // puzzle solver protocol
+    let params = make_params(Protocol::PuzzleSolver);
+
+    let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
+    let tumbler = crash_and_resume(dir, "solver-tumbler", tumbler);
+    let sender = puzzle_solver::Sender0::<_, Ecdsa>::new(params, sender.lock().clone(), &mut rng);
+    let sender = crash_and_resume(dir, "solver-sender", sender);
+    let receiver = puzzle_solver::Receiver0::new(
+        receiver.redeem_session().clone(),
+        receiver.unsigned_redeem_transaction().clone(),
+        receiver.sig_redeem_t().clone(),
+        receiver.sig_redeem_r().clone(),
+        receiver.beta().clone(),
     );
+    let receiver = crash_and_resume(dir, "solver-receiver", receiver);
+
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let sender = crash_and_resume(dir, "solver-sender", sender);
+    let message = sender.next_message(&publickey);
+    let tumbler = tumbler.receive(message, &secretkey, &mut rng).unwrap();
+    let tumbler = crash_and_resume(dir, "solver-tumbler", tumbler);
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng, &publickey).unwrap();
+    let sender = crash_and_resume(dir, "solver-sender", sender);
+    let message = sender.next_message();
+    let tumbler = tumbler.receive(message).unwrap();
+    let tumbler = crash_and_resume(dir, "solver-tumbler", tumbler);
+
+    let sender = sender
+        .receive(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+    let sender = crash_and_resume(dir, "solver-sender", sender);
+    let message = sender.next_message();
+    let receiver = receiver.receive(message).unwrap();
+    let receiver = crash_and_resume(dir, "solver-receiver", receiver);
 
     assert_eq!(
-        sender_fund.output[0].value,
-        tumble_amount
-            + tumbler_fee
-            + a2l_poc::bitcoin::MAX_SATISFACTION_WEIGHT * spend_transaction_fee_per_wu
+        receiver.signed_redeem_transaction().output[0].value,
+        10_000_000
     );
-    assert_eq!(tumbler_redeem.output[0].value, tumble_amount + tumbler_fee);
-    assert_eq!(
-        tumbler_fund.output[0].value,
-        tumble_amount + a2l_poc::bitcoin::MAX_SATISFACTION_WEIGHT * spend_transaction_fee_per_wu
+}
+
+#[test]
+fn receiver_rejects_c_alpha_encrypted_under_other_key() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, _) = hsm_cl::keygen(b"A2L-PoC");
+    let (_, other_publickey) = hsm_cl::keygen(b"A2L-PoC");
+
+    let params = make_params_with_terms(
+        Protocol::PuzzlePromise,
+        Terms {
+            tumbler_fee: 0,
+            spend_transaction_fee_per_vbyte: 0,
+            ..terms(Protocol::PuzzlePromise)
+        },
     );
-    assert_eq!(receiver_redeem.output[0].value, tumble_amount);
+
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+
+    let message = tumbler.next_message(&secretkey);
+    let error = receiver
+        .receive(message, &mut rng, &other_publickey)
+        .err()
+        .expect("receiver to reject pi_alpha");
+
+    assert!(error.downcast_ref::<hsm_cl::InvalidProof>().is_some());
 }
 
-fn run_a2l_happy_path(
-    tumble_amount: u64,
-    tumbler_fee: u64,
-    spend_transaction_fee_per_wu: u64,
-    blockchain: &mut Blockchain,
-) {
+#[test]
+fn parties_reject_partial_fund_transaction_not_covering_joint_output() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    // params decoded from the wire skip the checks of `Params::new`, so the parties check again
+    let mut params = make_params(Protocol::PuzzlePromise);
+    params.partial_fund_spent_outputs[0].value = 10_000_000;
+
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+
+    let message = tumbler.next_message(&secretkey);
+    let error = receiver
+        .receive(message, &mut rng, &publickey)
+        .err()
+        .expect("receiver to reject the partial fund transaction");
+
+    assert!(matches!(
+        error.downcast_ref::<InvalidPartialFundTransaction>(),
+        Some(InvalidPartialFundTransaction::InsufficientFunds { .. })
+    ));
+}
+
+#[test]
+fn fund_transaction_roundtrips_through_psbt() {
     let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let params = make_params(Protocol::PuzzlePromise);
+    let spent_outputs = params.partial_fund_spent_outputs.clone();
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+
+    let message = tumbler.next_message(&secretkey);
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let tumbler = tumbler.receive(receiver.next_message(), &mut rng).unwrap();
 
-    let (secretkey, publickey) = hsm_cl::keygen();
+    // signers cannot sign segwit inputs or check the fee without the outputs they spend
+    let error = a2l_poc::bitcoin::fund_psbt(
+        tumbler.unsigned_fund_transaction(),
+        tumbler.signed_refund_transaction(),
+        &[],
+    )
+    .unwrap_err();
+    assert!(error
+        .downcast_ref::<a2l_poc::bitcoin::SpentOutputsMismatch>()
+        .is_some());
+
+    let psbt = tumbler.unsigned_fund_psbt().unwrap();
+    let psbt =
+        a2l_poc::bitcoin::psbt_from_base64(&a2l_poc::bitcoin::psbt_to_base64(&psbt)).unwrap();
+
+    let joint_output = tumbler.signed_refund_transaction().input[0]
+        .previous_output
+        .vout as usize;
+    let witness_script = psbt.outputs[joint_output].witness_script.clone().unwrap();
+    assert_eq!(
+        tumbler.unsigned_fund_transaction().output[joint_output].script_pubkey,
+        witness_script.to_v0_p2wsh()
+    );
+    assert_eq!(
+        psbt.inputs[0].witness_utxo.as_ref(),
+        Some(&spent_outputs[0])
+    );
+
+    let error = tumbler.signed_fund_transaction(psbt.clone()).unwrap_err();
+    assert!(error
+        .downcast_ref::<a2l_poc::bitcoin::InputNotFinalized>()
+        .is_some());
 
-    let params = make_params(tumble_amount, tumbler_fee, spend_transaction_fee_per_wu);
+    // what a wallet signing a P2WPKH input adds
+    let mut psbt = psbt;
+    psbt.inputs[0].final_script_witness = Some(vec![vec![0x30; 72], vec![0x02; 33]]);
+
+    let mut tampered = psbt.clone();
+    tampered.global.unsigned_tx.output[joint_output].value -= 1;
+    let error = tumbler.signed_fund_transaction(tampered).unwrap_err();
+    assert!(error
+        .downcast_ref::<a2l_poc::bitcoin::FundTxidMismatch>()
+        .is_some());
+
+    let fund_transaction = tumbler.signed_fund_transaction(psbt).unwrap();
+    assert_eq!(
+        fund_transaction.txid(),
+        tumbler.signed_refund_transaction().input[0]
+            .previous_output
+            .txid
+    );
+}
+
+#[test]
+fn dry_happy_path_with_anchor_outputs() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let mut chain = MockChain::new();
+    let anchored_params = |protocol| {
+        make_params_with_terms(
+            protocol,
+            Terms {
+                anchor_outputs: true,
+                ..terms(protocol)
+            },
+        )
+    };
 
     // puzzle promise protocol
+    let params = anchored_params(Protocol::PuzzlePromise);
     let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
-    let receiver = puzzle_promise::Receiver0::new(params, &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
     let sender = puzzle_promise::Sender0::new();
 
     let message = tumbler.next_message(&secretkey);
-    let receiver = receiver.receive(message).unwrap();
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
     let message = receiver.next_message();
-    let tumbler = tumbler.receive(message).unwrap();
-    let message = tumbler.next_message(&mut rng);
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let message = tumbler.next_message();
     let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
     let message = receiver.next_message();
     let sender = sender.receive(message);
 
-    let params = make_params(tumble_amount, tumbler_fee, spend_transaction_fee_per_wu);
-
-    blockchain.tumbler_fund = Some(tumbler.unsigned_fund_transaction().clone());
+    let tumbler_fund = tumbler.unsigned_fund_transaction().clone();
+    chain.mine_external(tumbler_fund.clone());
 
     // puzzle solver protocol
+    let params = anchored_params(Protocol::PuzzleSolver);
     let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
-    let sender = puzzle_solver::Sender0::new(params, sender.lock().clone(), &mut rng);
+    let sender =
+        puzzle_solver::Sender0::<_, Ecdsa>::new(params.clone(), sender.lock().clone(), &mut rng);
     let receiver = puzzle_solver::Receiver0::new(
-        receiver.x_r().to_pk(),
-        receiver.X_t().clone(),
+        receiver.redeem_session().clone(),
         receiver.unsigned_redeem_transaction().clone(),
         receiver.sig_redeem_t().clone(),
         receiver.sig_redeem_r().clone(),
@@ -95,69 +376,344 @@ fn run_a2l_happy_path(
     );
 
     let message = tumbler.next_message();
-    let sender = sender.receive(message, &mut rng);
+    let sender = sender.receive(message, &mut rng).unwrap();
     let message = sender.next_message(&publickey);
-    let tumbler = tumbler.receive(message, &secretkey);
+    let tumbler = tumbler.receive(message, &secretkey, &mut rng).unwrap();
     let message = tumbler.next_message();
     let sender = sender.receive(message, &mut rng, &publickey).unwrap();
     let message = sender.next_message();
     let tumbler = tumbler.receive(message).unwrap();
 
-    blockchain.sender_fund = Some(sender.unsigned_fund_transaction().clone());
-    blockchain.tumbler_redeem = Some(tumbler.signed_redeem_transaction().clone());
+    let sender_fund = sender.unsigned_fund_transaction();
+    chain.mine_external(sender_fund.clone());
 
-    let sender = sender
-        .receive(blockchain.tumbler_redeem.clone().unwrap())
-        .unwrap();
-    let message = sender.next_message();
-    let receiver = receiver.receive(message).unwrap();
+    // the tumbler's redeem is stuck in the mempool, so it pays for a child spending the anchor
+    let tumbler_redeem = tumbler.signed_redeem_transaction().clone();
+    chain.broadcast(tumbler_redeem.clone()).unwrap();
+    let wallet_input = (
+        bitcoin::OutPoint {
+            txid: Default::default(),
+            vout: 1,
+        },
+        bitcoin::TxOut {
+            value: 100_000,
+            script_pubkey: random_p2wpkh().script_pubkey(),
+        },
+    );
+    let child = a2l_poc::fee::cpfp_child(
+        &tumbler_redeem,
+        params.sender_tumbler_joint_output_value(),
+        &[wallet_input.clone()],
+        &random_p2wpkh(),
+        100,
+    )
+    .unwrap()
+    .extract_tx();
+    let anchor = &tumbler_redeem.output[child.input[0].previous_output.vout as usize];
+    a2l_poc::bitcoin::verify_input(&child, 0, &[anchor.clone(), wallet_input.1]).unwrap();
+    chain.mine();
+
+    let sender = sender.receive(tumbler_redeem.clone()).unwrap();
+    let receiver = receiver.receive(sender.next_message()).unwrap();
+    let receiver_redeem = receiver.signed_redeem_transaction().clone();
+    chain.broadcast(receiver_redeem.clone()).unwrap();
+    chain.mine();
+
+    a2l_poc::bitcoin::verify_spend_transaction(&tumbler_redeem, &sender_fund).unwrap();
+    a2l_poc::bitcoin::verify_spend_transaction(&receiver_redeem, &tumbler_fund).unwrap();
+    assert_eq!(receiver_redeem.output.len(), 2);
+    assert_eq!(receiver_redeem.output[0].value, 10_000_000);
+}
+
+fn assert_dry_happy_path<S, SK, PK, C, P>(secretkey: &SK, publickey: &PK)
+where
+    S: JointOutputScheme,
+    SK: hsm_cl::Encrypt<Ciphertext = C, Proof = P> + hsm_cl::Decrypt<Ciphertext = C>,
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Verify<C, P>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+{
+    let mut chain = MockChain::new();
+    let amount = 10_000_000;
+
+    let transactions =
+        run_a2l_happy_path::<S, _, _, _, _>(amount, 0, 0, &mut chain, secretkey, publickey);
+
+    assert_eq!(
+        chain.confirmations(&transactions.tumbler_redeem.txid()),
+        Some(2)
+    );
+    assert_eq!(
+        chain.confirmations(&transactions.receiver_redeem.txid()),
+        Some(1)
+    );
+
+    transactions.assert_spends_are_valid();
+}
+
+#[test]
+fn refund_loses_the_race_against_confirmed_redeem() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let mut chain = MockChain::new();
+
+    let transactions = run_a2l_happy_path::<Ecdsa, _, _, _, _>(
+        10_000_000, 10_000, 15, &mut chain, &secretkey, &publickey,
+    );
+    chain.mine_until(SOLVER_EXPIRY);
+
+    let fund_outpoint = transactions.sender_refund.input[0].previous_output;
+    assert_eq!(
+        chain.broadcast(transactions.sender_refund),
+        Err(Rejected::MissingInput(fund_outpoint))
+    );
+}
+
+#[test]
+fn redeem_loses_the_race_against_refund_after_expiry() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let mut chain = MockChain::new();
+
+    // run the protocol on a throwaway chain, so that nothing is published on `chain` yet
+    let transactions = run_a2l_happy_path::<Ecdsa, _, _, _, _>(
+        10_000_000,
+        10_000,
+        15,
+        &mut MockChain::new(),
+        &secretkey,
+        &publickey,
+    );
+    chain.mine_external(transactions.sender_fund.clone());
+
+    let fund_outpoint = transactions.sender_refund.input[0].previous_output;
+    assert_eq!(
+        chain.broadcast(transactions.sender_refund.clone()),
+        Err(Rejected::NonFinal(SOLVER_EXPIRY))
+    );
+
+    chain.mine_until(SOLVER_EXPIRY);
+    let refund = chain.broadcast(transactions.sender_refund).unwrap();
+
+    assert_eq!(
+        chain.broadcast(transactions.tumbler_redeem),
+        Err(Rejected::DoubleSpend(fund_outpoint, refund))
+    );
+}
+
+#[test]
+fn tampered_spend_transaction_fails_script_verification() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let transactions = run_a2l_happy_path::<Ecdsa, _, _, _, _>(
+        10_000_000,
+        10_000,
+        15,
+        &mut MockChain::new(),
+        &secretkey,
+        &publickey,
+    );
+
+    let mut receiver_redeem = transactions.receiver_redeem;
+    receiver_redeem.output[0].value -= 1;
 
-    blockchain.receiver_redeem = Some(receiver.signed_redeem_transaction().clone());
+    let error =
+        a2l_poc::bitcoin::verify_spend_transaction(&receiver_redeem, &transactions.tumbler_fund)
+            .unwrap_err();
+
+    assert!(error
+        .downcast_ref::<a2l_poc::bitcoin::InvalidSpendTransaction>()
+        .is_some());
 }
 
-fn make_params(tumble_amount: u64, tumbler_fee: u64, spend_transaction_fee_per_wu: u64) -> Params {
-    Params {
-        redeem_identity: random_p2wpkh(),
-        refund_identity: random_p2wpkh(),
-        expiry: 0,
+fn assert_happy_path_fees<S, SK, PK, C, P>(secretkey: &SK, publickey: &PK)
+where
+    S: JointOutputScheme,
+    SK: hsm_cl::Encrypt<Ciphertext = C, Proof = P> + hsm_cl::Decrypt<Ciphertext = C>,
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Verify<C, P>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+{
+    let mut chain = MockChain::new();
+
+    // global parameters
+    let tumble_amount = 10_000_000;
+    let tumbler_fee = 10_000;
+    let spend_transaction_fee_per_vbyte = 15;
+
+    let transactions = run_a2l_happy_path::<S, _, _, _, _>(
         tumble_amount,
         tumbler_fee,
-        spend_transaction_fee_per_wu,
-        partial_fund_transaction: bitcoin::Transaction {
-            lock_time: 0,
-            version: 2,
-            input: Vec::new(),
-            output: vec![bitcoin::TxOut {
-                value: 150_000,
-                script_pubkey: Default::default(),
-            }],
-        },
-        fund_transaction_fee: 20_500,
+        spend_transaction_fee_per_vbyte,
+        &mut chain,
+        secretkey,
+        publickey,
+    );
+
+    transactions.assert_spends_are_valid();
+
+    let Transactions {
+        sender_fund,
+        tumbler_redeem,
+        sender_refund,
+        tumbler_fund,
+        receiver_redeem,
+        tumbler_refund,
+    } = transactions;
+
+    assert_eq!(tumbler_redeem.output[0].value, tumble_amount + tumbler_fee);
+    assert_eq!(receiver_redeem.output[0].value, tumble_amount);
+
+    // the joint outputs pay for the weight of the signed spend transactions, which can only be
+    // lighter than estimated by a few weight units
+    for (spend, fund) in vec![
+        (&tumbler_redeem, &sender_fund),
+        (&sender_refund, &sender_fund),
+        (&receiver_redeem, &tumbler_fund),
+        (&tumbler_refund, &tumbler_fund),
+    ] {
+        let fee = fund.output[0].value - spend.output[0].value;
+        let vsize = a2l_poc::fee::vsize(spend.get_weight() as u64);
+
+        assert!(fee >= vsize * spend_transaction_fee_per_vbyte);
+        assert!(fee <= (vsize + 3) * spend_transaction_fee_per_vbyte);
     }
 }
 
-fn random_p2wpkh() -> ::bitcoin::Address {
-    ::bitcoin::Address::p2wpkh(
-        &::bitcoin::PublicKey::from_private_key(
-            &::bitcoin::secp256k1::Secp256k1::signing_only(),
-            &::bitcoin::PrivateKey {
-                compressed: true,
-                network: ::bitcoin::Network::Regtest,
-                key: ::bitcoin::secp256k1::SecretKey::new(
-                    &mut ::bitcoin::secp256k1::rand::thread_rng(),
-                ),
-            },
-        ),
-        ::bitcoin::Network::Regtest,
-    )
+fn run_a2l_happy_path<S, SK, PK, C, P>(
+    tumble_amount: u64,
+    tumbler_fee: u64,
+    spend_transaction_fee_per_vbyte: u64,
+    chain: &mut MockChain,
+    secretkey: &SK,
+    publickey: &PK,
+) -> Transactions
+where
+    S: JointOutputScheme,
+    SK: hsm_cl::Encrypt<Ciphertext = C, Proof = P> + hsm_cl::Decrypt<Ciphertext = C>,
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Verify<C, P>,
+    C: Clone + wire::Encode + wire::Decode,
+    P: wire::Encode + wire::Decode,
+{
+    let mut rng = rand::thread_rng();
+
+    let terms = |protocol| Terms {
+        tumble_amount,
+        tumbler_fee,
+        spend_transaction_fee_per_vbyte,
+        output_type: S::OUTPUT_TYPE,
+        ..common::terms(protocol)
+    };
+    let params = make_params_with_terms(Protocol::PuzzlePromise, terms(Protocol::PuzzlePromise));
+
+    // puzzle promise protocol
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<S>::new(params, &mut rng);
+    let sender = puzzle_promise::Sender0::new();
+
+    let message = roundtrip(tumbler.next_message(secretkey));
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
+    let message = roundtrip(receiver.next_message());
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let message = roundtrip(tumbler.next_message());
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
+    let message = roundtrip(receiver.next_message());
+    let sender = sender.receive(message);
+
+    let params = make_params_with_terms(Protocol::PuzzleSolver, terms(Protocol::PuzzleSolver));
+
+    let tumbler_fund = tumbler.unsigned_fund_transaction().clone();
+    let tumbler_refund = tumbler.signed_refund_transaction().clone();
+    chain.mine_external(tumbler_fund.clone());
+
+    // puzzle solver protocol
+    let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
+    let sender = puzzle_solver::Sender0::<_, S>::new(params, sender.lock().clone(), &mut rng);
+    let receiver = puzzle_solver::Receiver0::new(
+        receiver.redeem_session().clone(),
+        receiver.unsigned_redeem_transaction().clone(),
+        receiver.sig_redeem_t().clone(),
+        receiver.sig_redeem_r().clone(),
+        receiver.beta().clone(),
+    );
+
+    let message = roundtrip(tumbler.next_message());
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let message = roundtrip(sender.next_message(publickey));
+    let tumbler = tumbler.receive(message, secretkey, &mut rng).unwrap();
+    let message = roundtrip(tumbler.next_message());
+    let sender = sender.receive(message, &mut rng, publickey).unwrap();
+    let message = roundtrip(sender.next_message());
+    let tumbler = tumbler.receive(message).unwrap();
+
+    let sender_fund = sender.unsigned_fund_transaction();
+    let sender_refund = sender.signed_refund_transaction();
+    chain.mine_external(sender_fund.clone());
+
+    // the sender learns the tumbler's redeem transaction from the chain
+    let spends = chain.watch(sender_refund.input[0].previous_output);
+    let tumbler_redeem = tumbler.signed_redeem_transaction().clone();
+    chain.broadcast(tumbler_redeem.clone()).unwrap();
+    chain.mine();
+
+    let sender = sender.receive(spends.try_recv().unwrap()).unwrap();
+    let message = roundtrip(sender.next_message());
+    let receiver = receiver.receive(message).unwrap();
+
+    let receiver_redeem = receiver.signed_redeem_transaction().clone();
+    chain.broadcast(receiver_redeem.clone()).unwrap();
+    chain.mine();
+
+    Transactions {
+        sender_fund,
+        tumbler_redeem,
+        sender_refund,
+        tumbler_fund,
+        receiver_redeem,
+        tumbler_refund,
+    }
+}
+
+/// Persists `state`, drops it and restores it from a freshly opened storage, as if the process
+/// had crashed right after reaching `state`.
+fn crash_and_resume<S: Snapshot>(dir: &Path, key: &str, state: S) -> S {
+    FileStorage::open(dir).unwrap().save(key, &state).unwrap();
+    drop(state);
+
+    FileStorage::open(dir).unwrap().load(key).unwrap().unwrap()
 }
 
-#[derive(Default)]
-struct Blockchain {
-    pub sender_fund: Option<bitcoin::Transaction>,
-    pub tumbler_redeem: Option<bitcoin::Transaction>,
-    pub sender_refund: Option<bitcoin::Transaction>,
-    pub tumbler_fund: Option<bitcoin::Transaction>,
-    pub receiver_redeem: Option<bitcoin::Transaction>,
-    pub tumbler_refund: Option<bitcoin::Transaction>,
+/// Restores a copy of `state` from storage, as a process restarted right after reaching `state`
+/// would, while `state` itself carries on.
+fn restored_copy<S: Snapshot>(dir: &Path, key: &str, state: &S) -> S {
+    FileStorage::open(dir).unwrap().save(key, state).unwrap();
+
+    FileStorage::open(dir).unwrap().load(key).unwrap().unwrap()
+}
+
+fn roundtrip<M: WireMessage>(message: M) -> M {
+    M::from_bytes(&message.to_bytes()).unwrap()
+}
+
+/// Everything the parties produced in a run of the protocols.
+struct Transactions {
+    sender_fund: bitcoin::Transaction,
+    tumbler_redeem: bitcoin::Transaction,
+    sender_refund: bitcoin::Transaction,
+    tumbler_fund: bitcoin::Transaction,
+    receiver_redeem: bitcoin::Transaction,
+    tumbler_refund: bitcoin::Transaction,
+}
+
+impl Transactions {
+    /// Checks every redeem and refund transaction against the fund output it spends, as a node
+    /// would.
+    fn assert_spends_are_valid(&self) {
+        for (spend, fund) in vec![
+            (&self.tumbler_redeem, &self.sender_fund),
+            (&self.sender_refund, &self.sender_fund),
+            (&self.receiver_redeem, &self.tumbler_fund),
+            (&self.tumbler_refund, &self.tumbler_fund),
+        ] {
+            a2l_poc::bitcoin::verify_spend_transaction(spend, fund).unwrap();
+        }
+    }
 }
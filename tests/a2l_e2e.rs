@@ -1,70 +1,34 @@
+use a2l_poc::chain::{self, BitcoindRpc, Broadcaster, ChainSource};
 use a2l_poc::puzzle_promise;
 use a2l_poc::puzzle_solver;
-use a2l_poc::{dummy_hsm_cl as hsm_cl, Params};
+use a2l_poc::secp256k1::{self, Ecdsa};
+use a2l_poc::watcher::{Outcome, Poll, SenderWatcher};
+use a2l_poc::{bitcoin, dummy_hsm_cl, fee, hsm_cl, Params, Protocol, Terms};
 use anyhow::Context;
-use bitcoin::consensus::deserialize;
-use bitcoin::consensus::encode::serialize_hex;
-use bitcoin::hashes::hex::FromHex;
 use rand::SeedableRng;
-use serde::*;
+use std::time::Duration;
 use testcontainers::{clients, images::coblox_bitcoincore::BitcoinCore, Docker};
-use ureq::SerdeValue;
 
-#[derive(Deserialize)]
-pub struct JsonRpcResponse<T> {
-    result: Option<T>,
-    error: Option<JsonRpcError>,
-}
-
-#[derive(Debug, Deserialize, thiserror::Error)]
-#[error("{message}")]
-pub struct JsonRpcError {
-    code: i32,
-    message: String,
-}
+#[test]
+fn a2l_happy_path() -> anyhow::Result<()> {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
 
-fn rpc_command<'a, T>(url: &str, body: SerdeValue) -> anyhow::Result<T>
-where
-    T: Deserialize<'a>,
-{
-    let json = ureq::post(url).send_json(body).into_json()?;
-    match JsonRpcResponse::<T>::deserialize(json)? {
-        JsonRpcResponse {
-            result: Some(t), ..
-        } => Ok(t),
-        JsonRpcResponse { error: Some(e), .. } => Err(e.into()),
-        _ => Err(anyhow::anyhow!("invalid jsonrpc")),
-    }
+    run_a2l_happy_path(&secretkey, &publickey)
 }
 
-fn make_fund_transaction(sats: u64, url: &str) -> anyhow::Result<bitcoin::Transaction> {
-    #[derive(Deserialize)]
-    struct Response {
-        hex: String,
-        changepos: i8,
-    }
-
-    let dummy_address = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x";
-    let btc = sats as f64 / 100_000_000.0;
-
-    let transaction_hex = rpc_command::<String>(
-        url,
-        ureq::json!({"jsonrpc": "1.0", "method": "createrawtransaction", "params": [[], { dummy_address:btc }] }),
-    )?;
-    let res = rpc_command::<Response>(
-        url,
-        ureq::json!({"jsonrpc": "1.0", "method": "fundrawtransaction", "params": [transaction_hex] }),
-    )?;
+#[test]
+fn a2l_happy_path_hsm_cl() -> anyhow::Result<()> {
+    let (secretkey, publickey) = hsm_cl::keygen(b"A2L-PoC");
 
-    let mut transaction = deserialize::<bitcoin::Transaction>(&Vec::<u8>::from_hex(&res.hex)?)?;
-    Ok(bitcoin::Transaction {
-        output: vec![transaction.output.remove(res.changepos as usize)],
-        ..transaction
-    })
+    run_a2l_happy_path(&secretkey, &publickey)
 }
 
-#[test]
-fn a2l_happy_path() -> anyhow::Result<()> {
+fn run_a2l_happy_path<SK, PK, C, P>(secretkey: &SK, publickey: &PK) -> anyhow::Result<()>
+where
+    SK: hsm_cl::Encrypt<Ciphertext = C, Proof = P> + hsm_cl::Decrypt<Ciphertext = C>,
+    PK: hsm_cl::Pow<C> + hsm_cl::Pow<secp256k1::PublicKey> + hsm_cl::Verify<C, P>,
+    C: Clone,
+{
     let client = clients::Cli::default();
     let container = client.run(BitcoinCore::default().with_tag("0.19.1"));
     let port = container.get_host_port(18443);
@@ -77,205 +41,161 @@ fn a2l_happy_path() -> anyhow::Result<()> {
         port.unwrap()
     );
 
-    let address = rpc_command::<String>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
-    let _ = rpc_command::<Vec<String>>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [101, address] }),
-    )?;
-
-    let tumbler_wallet = "tumbler";
-    let receiver_wallet = "receiver";
-    let sender_wallet = "sender";
-
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "createwallet", "params": [tumbler_wallet] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "createwallet", "params": [receiver_wallet] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "createwallet", "params": [sender_wallet] }),
-    )?;
+    let node = BitcoindRpc::new(url.as_str(), 0);
+    let miner = node.new_address()?;
+    node.generate(101, &miner)?;
 
-    let address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [100, address] }),
-    )?;
+    let wallet = |name: &str| -> anyhow::Result<BitcoindRpc> {
+        node.create_wallet(name)?;
 
-    let address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, sender_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [100, address] }),
-    )?;
+        Ok(BitcoindRpc::new(format!("{}/wallet/{}", url, name), 0))
+    };
+    let tumbler_wallet = wallet("tumbler")?;
+    let receiver_wallet = wallet("receiver")?;
+    let sender_wallet = wallet("sender")?;
 
-    let redeem_address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, receiver_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
-    let refund_address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
+    node.generate(100, &tumbler_wallet.new_address()?)?;
+    node.generate(100, &sender_wallet.new_address()?)?;
 
     let amount = 10_000_000;
+    let fee_per_vbyte = 10;
+
+    let redeem_identity = receiver_wallet.new_address()?;
+    let refund_identity = tumbler_wallet.new_address()?;
+    let partial_fund_transaction = tumbler_wallet.fund_transaction(
+        amount
+            + fee::spend_transaction_fee(
+                bitcoin::OutputType::P2wsh,
+                1_000,
+                &redeem_identity,
+                &refund_identity,
+                fee_per_vbyte,
+                false,
+            ),
+    )?;
     let params = Params::new(
-        redeem_address.parse()?,
-        refund_address.parse()?,
-        0,
-        amount,
-        10_000,
-        10,
-        make_fund_transaction(
-            amount + a2l_poc::bitcoin::MAX_SATISFACTION_WEIGHT * 10,
-            &format!("{}/wallet/{}", url, tumbler_wallet),
-        )?,
-    );
+        Protocol::PuzzlePromise,
+        redeem_identity,
+        refund_identity,
+        Terms {
+            tumble_amount: amount,
+            tumbler_fee: 10_000,
+            expiry: 1_000,
+            spend_transaction_fee_per_vbyte: fee_per_vbyte,
+            anchor_outputs: false,
+            output_type: bitcoin::OutputType::P2wsh,
+        },
+        partial_fund_transaction.clone(),
+        chain::spent_outputs(&node, &partial_fund_transaction)?,
+    )?;
 
     let mut rng = rand::rngs::StdRng::seed_from_u64(123456);
-    let (secretkey, publickey) = hsm_cl::keygen();
 
     // puzzle promise protocol
     let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
-    let receiver = puzzle_promise::Receiver0::new(params, &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
     let sender = puzzle_promise::Sender0::new();
 
-    let message = tumbler.next_message(&secretkey);
-    let receiver = receiver.receive(message).unwrap();
+    let message = tumbler.next_message(secretkey);
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
     let message = receiver.next_message();
-    let tumbler = tumbler.receive(message).unwrap();
-    let message = tumbler.next_message(&mut rng);
-    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
     let message = receiver.next_message();
     let sender = sender.receive(message);
 
-    let res = rpc_command::<SerdeValue>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "signrawtransactionwithwallet", "params": [serialize_hex(dbg!(tumbler.unsigned_fund_transaction()))] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "sendrawtransaction", "params": [ res["hex"].as_str().unwrap() ] }),
-    )?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [1, "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"] }),
-    )?;
-
-    dbg!("puzzle promised");
+    let psbt = tumbler.unsigned_fund_psbt()?;
+    let fund_transaction = tumbler.signed_fund_transaction(tumbler_wallet.sign_psbt(&psbt)?)?;
+    node.broadcast(&fund_transaction)
+        .context("failed to broadcast fund transaction for tumbler")?;
+    node.generate(1, &miner)?;
 
     // puzzle solver protocol
-    let redeem_address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
-    )?;
-    let refund_address = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, sender_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "getnewaddress", "params": ["", "bech32"] }),
+    let redeem_identity = tumbler_wallet.new_address()?;
+    let refund_identity = sender_wallet.new_address()?;
+    let partial_fund_transaction = sender_wallet.fund_transaction(
+        amount
+            + 10_000
+            + fee::spend_transaction_fee(
+                bitcoin::OutputType::P2wsh,
+                1_000,
+                &redeem_identity,
+                &refund_identity,
+                fee_per_vbyte,
+                false,
+            ),
     )?;
-
     let params = Params::new(
-        redeem_address.parse()?,
-        refund_address.parse()?,
-        0,
-        amount,
-        10_000,
-        10,
-        make_fund_transaction(
-            amount + 10_000 + a2l_poc::bitcoin::MAX_SATISFACTION_WEIGHT * 10,
-            &format!("{}/wallet/{}", url, sender_wallet),
-        )?,
-    );
+        Protocol::PuzzleSolver,
+        redeem_identity,
+        refund_identity,
+        Terms {
+            tumble_amount: amount,
+            tumbler_fee: 10_000,
+            expiry: 1_000,
+            spend_transaction_fee_per_vbyte: fee_per_vbyte,
+            anchor_outputs: false,
+            output_type: bitcoin::OutputType::P2wsh,
+        },
+        partial_fund_transaction.clone(),
+        chain::spent_outputs(&node, &partial_fund_transaction)?,
+    )?;
 
     let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
-    let sender = puzzle_solver::Sender0::new(params, sender.lock().clone(), &mut rng);
+    let sender = puzzle_solver::Sender0::<_, Ecdsa>::new(params, sender.lock().clone(), &mut rng);
     let receiver = puzzle_solver::Receiver0::new(
-        receiver.x_r().to_pk(),
-        receiver.X_t().clone(),
+        receiver.redeem_session().clone(),
         receiver.unsigned_redeem_transaction().clone(),
         receiver.sig_redeem_t().clone(),
         receiver.sig_redeem_r().clone(),
         receiver.beta().clone(),
-        receiver.redeem_tx_digest().clone(),
     );
 
     let message = tumbler.next_message();
-    let sender = sender.receive(message, &mut rng);
-    let message = sender.next_message(&publickey);
-    let tumbler = tumbler.receive(message, &secretkey);
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let message = sender.next_message(publickey);
+    let tumbler = tumbler.receive(message, secretkey, &mut rng).unwrap();
     let message = tumbler.next_message();
-    let sender = sender.receive(message, &mut rng, &publickey).unwrap();
+    let sender = sender.receive(message, &mut rng, publickey).unwrap();
     let message = sender.next_message();
     let tumbler = tumbler.receive(message).unwrap();
 
-    let res = rpc_command::<SignRawTransactionWithWalletResponse>(
-        &format!("{}/wallet/{}", url, sender_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "signrawtransactionwithwallet", "params": [serialize_hex(dbg!(&sender.unsigned_fund_transaction()))] }),
-    )?;
-
-    dbg!(&res.hex);
-
-    let _ = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, sender_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "sendrawtransaction", "params": [ res.hex ] }),
-    )
-    .context("failed to broadcast fund transaction for sender")?;
-
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [1, "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"] }),
-    )?;
-
-    let signed_redeem_transaction = tumbler.signed_redeem_transaction();
-    let signed_redeem_transaction = serialize_hex(signed_redeem_transaction);
-
-    dbg!(&signed_redeem_transaction);
-
-    let _ = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, tumbler_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "sendrawtransaction", "params": [signed_redeem_transaction] }),
-    )
-    .context("failed to broadcast redeem transaction for tumbler")?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [1, "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"] }),
-    )?;
-
-    let sender = sender
-        .receive(tumbler.signed_redeem_transaction().clone())
-        .unwrap();
+    let psbt = sender.unsigned_fund_psbt()?;
+    let fund_transaction = sender.signed_fund_transaction(sender_wallet.sign_psbt(&psbt)?)?;
+    node.broadcast(&fund_transaction)
+        .context("failed to broadcast fund transaction for sender")?;
+    node.generate(1, &miner)?;
+
+    // the sender learns the solution from the redeem transaction once the tumbler publishes it
+    let chain = BitcoindRpc::new(url.as_str(), node.height()?);
+    let watcher = match SenderWatcher::new(sender).poll(&chain)? {
+        Poll::Pending(watcher) => watcher,
+        Poll::Ready(_) => anyhow::bail!("fund transaction spent before the tumbler redeemed"),
+        Poll::Retry(_, e) => return Err(e),
+    };
+
+    node.broadcast(tumbler.signed_redeem_transaction())
+        .context("failed to broadcast redeem transaction for tumbler")?;
+    node.generate(1, &miner)?;
+
+    let sender = match watcher.run(&chain, Duration::from_millis(100))? {
+        Outcome::Solved(sender) => sender,
+        Outcome::Refunded(_) => anyhow::bail!("sender refunded instead of learning the solution"),
+    };
     let message = sender.next_message();
     let receiver = receiver.receive(message).unwrap();
 
-    let signed_redeem_transaction = serialize_hex(receiver.signed_redeem_transaction());
+    let txid = node
+        .broadcast(receiver.signed_redeem_transaction())
+        .context("failed to broadcast redeem transaction for receiver")?;
+    node.generate(1, &miner)?;
 
-    dbg!(&signed_redeem_transaction);
-
-    let _ = rpc_command::<String>(
-        &format!("{}/wallet/{}", url, receiver_wallet),
-        ureq::json!({"jsonrpc": "1.0", "method": "sendrawtransaction", "params": [signed_redeem_transaction] }),
-    ).context("failed to broadcast redeem transaction for receiver")?;
-    let _ = rpc_command::<SerdeValue>(
-        &url,
-        ureq::json!({"jsonrpc": "1.0", "method": "generatetoaddress", "params": [1, "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x"] }),
-    )?;
+    let utxo = node
+        .utxo(&bitcoin::OutPoint { txid, vout: 0 })?
+        .context("redeem output of the receiver not found")?;
+    assert_eq!(utxo.output.value, amount);
+    assert_eq!(utxo.confirmations, 1);
 
     Ok(())
 }
-
-#[derive(Deserialize)]
-struct SignRawTransactionWithWalletResponse {
-    hex: String,
-}
@@ -0,0 +1,226 @@
+use a2l_poc::client::{ReceiverClient, RetryPolicy, SenderClient};
+use a2l_poc::joint_output::JointOutputScheme;
+use a2l_poc::secp256k1::{Ecdsa, Schnorr};
+use a2l_poc::storage::MemoryStorage;
+use a2l_poc::transport::{ChannelTransport, Transport};
+use a2l_poc::tumbler_service::{ServiceConfig, TumblerService};
+use a2l_poc::{dummy_hsm_cl, puzzle_promise, puzzle_solver, Protocol, Terms};
+use common::{random_p2wpkh, sender_contribution, service_config, terms, TestWallet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn clients_complete_a_tumble_against_in_process_tumbler() {
+    assert_tumble_against_in_process_tumbler::<Ecdsa>();
+}
+
+#[test]
+fn clients_complete_a_p2tr_tumble_against_in_process_tumbler() {
+    assert_tumble_against_in_process_tumbler::<Schnorr>();
+}
+
+fn assert_tumble_against_in_process_tumbler<S>()
+where
+    S: JointOutputScheme + Send + Sync + 'static,
+{
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let service = Arc::new(
+        TumblerService::<_, _, _, S>::new(
+            secretkey,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service.clone(), Duration::from_secs(10));
+
+    let receiver_client = ReceiverClient::new(transport.clone(), dummy_hsm_cl::PublicKey);
+    let sender_client = SenderClient::new(transport, publickey);
+
+    assert_tumble(&receiver_client, &sender_client, &service);
+}
+
+#[test]
+fn clients_retry_when_responses_are_lost() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            secretkey,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service.clone(), Duration::from_secs(10));
+    let retry_policy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+    };
+
+    let receiver_client = ReceiverClient::new(LossyTransport::new(transport.clone()), publickey)
+        .with_retry_policy(retry_policy);
+    let sender_client = SenderClient::new(LossyTransport::new(transport), dummy_hsm_cl::PublicKey)
+        .with_retry_policy(retry_policy);
+
+    assert_tumble(&receiver_client, &sender_client, &service);
+}
+
+#[test]
+fn client_gives_up_after_max_attempts() {
+    let retry_policy = RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: Duration::from_millis(1),
+    };
+    let receiver_client =
+        ReceiverClient::new(Unreachable, dummy_hsm_cl::PublicKey).with_retry_policy(retry_policy);
+
+    let result = receiver_client
+        .request_promise::<dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof, Ecdsa>(
+            random_p2wpkh(),
+            &terms(Protocol::PuzzlePromise),
+        );
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn clients_reject_unexpected_terms() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            secretkey,
+            TestWallet,
+            MemoryStorage::default(),
+            ServiceConfig {
+                tumbler_fee: 20_000,
+                ..service_config()
+            },
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service, Duration::from_secs(10));
+    let receiver_client = ReceiverClient::new(transport, publickey);
+
+    let result = receiver_client
+        .request_promise::<dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof, Ecdsa>(
+            random_p2wpkh(),
+            &terms(Protocol::PuzzlePromise),
+        );
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn clients_reject_tumbler_running_other_output_type() {
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            secretkey,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service, Duration::from_secs(10));
+    let receiver_client = ReceiverClient::new(transport, publickey);
+
+    let result = receiver_client
+        .request_promise::<dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof, Schnorr>(
+            random_p2wpkh(),
+            &Terms {
+                output_type: Schnorr::OUTPUT_TYPE,
+                ..terms(Protocol::PuzzlePromise)
+            },
+        );
+
+    assert!(result.is_err());
+}
+
+fn assert_tumble<T: Transport, S: JointOutputScheme>(
+    receiver_client: &ReceiverClient<T, dummy_hsm_cl::PublicKey>,
+    sender_client: &SenderClient<T, dummy_hsm_cl::PublicKey>,
+    service: &TumblerService<dummy_hsm_cl::SecretKey, TestWallet, MemoryStorage, S>,
+) {
+    let terms = |protocol| Terms {
+        output_type: S::OUTPUT_TYPE,
+        ..terms(protocol)
+    };
+
+    let receiver = receiver_client
+        .request_promise::<_, _, S>(random_p2wpkh(), &terms(Protocol::PuzzlePromise))
+        .unwrap();
+    let sender = puzzle_promise::Sender0::new().receive(receiver.next_message());
+
+    let sender = sender_client
+        .solve::<_, _, S>(
+            sender_contribution(),
+            &terms(Protocol::PuzzleSolver),
+            sender.lock().clone(),
+        )
+        .unwrap();
+
+    // the tumbler publishes the redeem transaction, from which the sender learns the solution
+    let redeem_transaction = service
+        .completed_sessions()
+        .into_iter()
+        .filter_map(|id| service.take_completed_solver(&id).unwrap())
+        .map(|tumbler| tumbler.signed_redeem_transaction().clone())
+        .next()
+        .unwrap();
+    let sender = sender.receive(redeem_transaction).unwrap();
+
+    let receiver = puzzle_solver::Receiver0::new(
+        receiver.redeem_session().clone(),
+        receiver.unsigned_redeem_transaction().clone(),
+        receiver.sig_redeem_t().clone(),
+        receiver.sig_redeem_r().clone(),
+        receiver.beta().clone(),
+    );
+    let receiver = receiver.receive(sender.next_message()).unwrap();
+
+    assert_eq!(
+        receiver.signed_redeem_transaction().output[0].value,
+        10_000_000
+    );
+}
+
+/// Delivers every request but loses every other response.
+struct LossyTransport<T> {
+    inner: T,
+    sent: AtomicUsize,
+}
+
+impl<T> LossyTransport<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            sent: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: Transport> Transport for LossyTransport<T> {
+    fn send(&self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let response = self.inner.send(request)?;
+
+        if self.sent.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+            anyhow::bail!("response lost")
+        }
+
+        Ok(response)
+    }
+}
+
+struct Unreachable;
+
+impl Transport for Unreachable {
+    fn send(&self, _: &[u8]) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("connection refused")
+    }
+}
@@ -0,0 +1,205 @@
+//! Params, addresses and a tumbler wallet shared by the integration tests.
+
+// every test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use a2l_poc::bitcoin::OutputType;
+use a2l_poc::tumbler_service::{SenderContribution, ServiceConfig, Wallet};
+use a2l_poc::{Params, Protocol, Terms};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Expiry of the sender's joint output with the tumbler.
+pub const SOLVER_EXPIRY: u32 = 1_000;
+/// Expiry of the tumbler's joint output with the receiver.
+///
+/// The receiver can only redeem once the tumbler has redeemed from the sender, which the tumbler
+/// may put off until just before `SOLVER_EXPIRY`. The receiver then needs time to redeem before
+/// the tumbler can take its coins back.
+pub const PROMISE_EXPIRY: u32 = SOLVER_EXPIRY + 144;
+
+pub fn expiry(protocol: Protocol) -> u32 {
+    match protocol {
+        Protocol::PuzzlePromise => PROMISE_EXPIRY,
+        Protocol::PuzzleSolver => SOLVER_EXPIRY,
+    }
+}
+
+pub fn terms(protocol: Protocol) -> Terms {
+    Terms {
+        tumble_amount: 10_000_000,
+        tumbler_fee: 10_000,
+        expiry: expiry(protocol),
+        spend_transaction_fee_per_vbyte: 15,
+        anchor_outputs: false,
+        output_type: OutputType::P2wsh,
+    }
+}
+
+pub fn make_params(protocol: Protocol) -> Params {
+    make_params_with_terms(protocol, terms(protocol))
+}
+
+pub fn make_params_with_terms(protocol: Protocol, terms: Terms) -> Params {
+    let (partial_fund_transaction, partial_fund_spent_outputs) = partial_fund_transaction();
+
+    Params::new(
+        protocol,
+        random_p2wpkh(),
+        random_p2wpkh(),
+        terms,
+        partial_fund_transaction,
+        partial_fund_spent_outputs,
+    )
+    .unwrap()
+}
+
+/// A partial fund transaction spending a made-up 0.2 BTC coin, and the output it spends.
+pub fn partial_fund_transaction() -> (bitcoin::Transaction, Vec<bitcoin::TxOut>) {
+    let transaction = bitcoin::Transaction {
+        lock_time: 0,
+        version: 2,
+        // a wallet coin, only the txid of the fund transaction depends on it
+        input: vec![bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint {
+                txid: Default::default(),
+                vout: 0,
+            },
+            script_sig: Default::default(),
+            sequence: 0xFFFF_FFFF,
+            witness: Vec::new(),
+        }],
+        output: vec![bitcoin::TxOut {
+            value: 150_000,
+            script_pubkey: Default::default(),
+        }],
+    };
+    let spent_outputs = vec![bitcoin::TxOut {
+        value: 20_000_000,
+        script_pubkey: random_p2wpkh().script_pubkey(),
+    }];
+
+    (transaction, spent_outputs)
+}
+
+/// The sender's part of params like the ones of [`make_params`].
+pub fn sender_contribution() -> SenderContribution {
+    let (partial_fund_transaction, partial_fund_spent_outputs) = partial_fund_transaction();
+
+    SenderContribution {
+        refund_identity: random_p2wpkh(),
+        partial_fund_transaction,
+        partial_fund_spent_outputs,
+    }
+}
+
+/// A tumbler offering the [`terms`] on a chain at height 0.
+pub fn service_config() -> ServiceConfig {
+    let terms = terms(Protocol::PuzzlePromise);
+
+    ServiceConfig {
+        payout_address: random_p2wpkh(),
+        refund_address: random_p2wpkh(),
+        tumble_amount: terms.tumble_amount,
+        tumbler_fee: terms.tumbler_fee,
+        spend_transaction_fee_per_vbyte: terms.spend_transaction_fee_per_vbyte,
+        anchor_outputs: terms.anchor_outputs,
+        promise_expiry_blocks: PROMISE_EXPIRY,
+        solver_expiry_blocks: SOLVER_EXPIRY,
+        session_timeout: Duration::from_secs(60),
+        max_open_sessions_per_peer: 16,
+    }
+}
+
+/// Funds every session with [`partial_fund_transaction`] and never sees a block.
+pub struct TestWallet;
+
+impl Wallet for TestWallet {
+    fn height(&self) -> anyhow::Result<u32> {
+        Ok(0)
+    }
+
+    fn partial_fund_transaction(
+        &self,
+        _: u64,
+    ) -> anyhow::Result<(bitcoin::Transaction, Vec<bitcoin::TxOut>)> {
+        Ok(partial_fund_transaction())
+    }
+
+    fn reserve_inputs(&self, _: &bitcoin::Transaction) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Funds each session with one of `coins` coins like the one of [`partial_fund_transaction`],
+/// which stay reserved once a session has reserved them.
+pub struct LimitedWallet {
+    pub coins: u32,
+    pub reserved: Arc<Mutex<HashSet<bitcoin::OutPoint>>>,
+}
+
+impl LimitedWallet {
+    pub fn new(coins: u32) -> Self {
+        Self {
+            coins,
+            reserved: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+}
+
+impl Wallet for LimitedWallet {
+    fn height(&self) -> anyhow::Result<u32> {
+        Ok(0)
+    }
+
+    fn partial_fund_transaction(
+        &self,
+        _: u64,
+    ) -> anyhow::Result<(bitcoin::Transaction, Vec<bitcoin::TxOut>)> {
+        let reserved = self.reserved.lock().unwrap();
+        let (mut transaction, spent_outputs) = partial_fund_transaction();
+
+        let coin = (0..self.coins)
+            .map(|vout| bitcoin::OutPoint {
+                txid: Default::default(),
+                vout,
+            })
+            .find(|coin| !reserved.contains(coin))
+            .ok_or_else(|| anyhow::anyhow!("all coins are reserved"))?;
+        transaction.input[0].previous_output = coin;
+
+        Ok((transaction, spent_outputs))
+    }
+
+    fn reserve_inputs(&self, transaction: &bitcoin::Transaction) -> anyhow::Result<()> {
+        let mut reserved = self.reserved.lock().unwrap();
+
+        if transaction
+            .input
+            .iter()
+            .any(|input| reserved.contains(&input.previous_output))
+        {
+            anyhow::bail!("coin is already reserved")
+        }
+        reserved.extend(transaction.input.iter().map(|input| input.previous_output));
+
+        Ok(())
+    }
+}
+
+pub fn random_p2wpkh() -> ::bitcoin::Address {
+    ::bitcoin::Address::p2wpkh(
+        &::bitcoin::PublicKey::from_private_key(
+            &::bitcoin::secp256k1::Secp256k1::signing_only(),
+            &::bitcoin::PrivateKey {
+                compressed: true,
+                network: ::bitcoin::Network::Regtest,
+                key: ::bitcoin::secp256k1::SecretKey::new(
+                    &mut ::bitcoin::secp256k1::rand::thread_rng(),
+                ),
+            },
+        ),
+        ::bitcoin::Network::Regtest,
+    )
+}
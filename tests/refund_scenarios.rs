@@ -0,0 +1,264 @@
+//! Every party that funds a joint output must be able to get its coins back with its signed
+//! refund transaction once `expiry` has passed, no matter at which point the other parties stop
+//! cooperating.
+
+use a2l_poc::mock_chain::{MockChain, Rejected};
+use a2l_poc::secp256k1::Ecdsa;
+use a2l_poc::{dummy_hsm_cl, puzzle_promise, puzzle_solver, Lock, Params, Protocol};
+use common::make_params;
+
+mod common;
+
+#[test]
+fn sender_is_refunded_if_sender_never_sends_message3() {
+    let mut chain = MockChain::new();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let promise = run_puzzle_promise(&mut chain, &secretkey, &publickey);
+    let params = make_params(Protocol::PuzzleSolver);
+    let (sender, _tumbler) = run_puzzle_solver_until_message3(
+        params.clone(),
+        &promise,
+        &mut chain,
+        &secretkey,
+        &publickey,
+    );
+
+    // the sender funds, but aborts before handing out the signature the tumbler needs to redeem
+    chain.mine_external(sender.unsigned_fund_transaction());
+
+    assert_refunded(&mut chain, sender.signed_refund_transaction(), &params);
+    assert_refunded(&mut chain, promise.tumbler_refund, &promise.params);
+}
+
+#[test]
+fn sender_is_refunded_if_tumbler_never_redeems() {
+    let mut chain = MockChain::new();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let promise = run_puzzle_promise(&mut chain, &secretkey, &publickey);
+    let params = make_params(Protocol::PuzzleSolver);
+    let (sender, tumbler) = run_puzzle_solver_until_message3(
+        params.clone(),
+        &promise,
+        &mut chain,
+        &secretkey,
+        &publickey,
+    );
+    chain.mine_external(sender.unsigned_fund_transaction());
+    let tumbler = tumbler.receive(sender.next_message()).unwrap();
+
+    assert_refunded(&mut chain, sender.signed_refund_transaction(), &params);
+    assert_refunded(&mut chain, promise.tumbler_refund, &promise.params);
+
+    // too late, the sender already took the coins back
+    let fund_outpoint = tumbler.signed_redeem_transaction().input[0].previous_output;
+    assert_eq!(
+        chain.broadcast(tumbler.signed_redeem_transaction().clone()),
+        Err(Rejected::MissingInput(fund_outpoint))
+    );
+}
+
+#[test]
+fn tumbler_is_refunded_if_receiver_never_gets_message4() {
+    let mut chain = MockChain::new();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let promise = run_puzzle_promise(&mut chain, &secretkey, &publickey);
+    let params = make_params(Protocol::PuzzleSolver);
+    let (sender, tumbler) =
+        run_puzzle_solver_until_message3(params, &promise, &mut chain, &secretkey, &publickey);
+    chain.mine_external(sender.unsigned_fund_transaction());
+    let tumbler = tumbler.receive(sender.next_message()).unwrap();
+
+    chain
+        .broadcast(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+    chain.mine();
+
+    // the sender learns the solution, but never passes it on to the receiver
+    let _sender = sender
+        .receive(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+
+    assert_refunded(&mut chain, promise.tumbler_refund, &promise.params);
+}
+
+#[test]
+fn sender_is_refunded_if_tumbler_never_funds() {
+    let mut chain = MockChain::new();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    // the tumbler completes the puzzle promise protocol, but keeps its fund transaction to itself
+    let promise = run_puzzle_promise(&mut MockChain::new(), &secretkey, &publickey);
+    let params = make_params(Protocol::PuzzleSolver);
+    let (sender, _tumbler) = run_puzzle_solver_until_message3(
+        params.clone(),
+        &promise,
+        &mut chain,
+        &secretkey,
+        &publickey,
+    );
+    chain.mine_external(sender.unsigned_fund_transaction());
+
+    // without the tumbler's joint output on chain, the sender has no reason to pay the tumbler
+    assert_eq!(chain.unspent(&promise.fund_outpoint()), None);
+    assert_refunded(&mut chain, sender.signed_refund_transaction(), &params);
+}
+
+#[test]
+fn receiver_redeems_before_tumbler_refund_if_tumbler_redeems_at_the_last_moment() {
+    let mut chain = MockChain::new();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+
+    let promise = run_puzzle_promise(&mut chain, &secretkey, &publickey);
+    let fund_outpoint = promise.fund_outpoint();
+    let params = make_params(Protocol::PuzzleSolver);
+    let (sender, tumbler) = run_puzzle_solver_until_message3(
+        params.clone(),
+        &promise,
+        &mut chain,
+        &secretkey,
+        &publickey,
+    );
+    chain.mine_external(sender.unsigned_fund_transaction());
+    let tumbler = tumbler.receive(sender.next_message()).unwrap();
+
+    // the tumbler redeems in the last block before the sender's refund becomes valid
+    let solver_expiry = params.terms().expiry;
+    chain.mine_until(solver_expiry - 1);
+    chain
+        .broadcast(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+    chain.mine();
+
+    let sender = sender
+        .receive(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+    let receiver = promise.receiver.receive(sender.next_message()).unwrap();
+
+    // the tumbler's refund is still locked, so the receiver's redeem gets in first
+    let promise_expiry = promise.params.terms().expiry;
+    assert!(chain.height() < promise_expiry);
+    assert_eq!(
+        chain.broadcast(promise.tumbler_refund.clone()),
+        Err(Rejected::NonFinal(promise_expiry))
+    );
+    let txid = chain
+        .broadcast(receiver.signed_redeem_transaction().clone())
+        .unwrap();
+    chain.mine();
+    assert_eq!(chain.confirmations(&txid), Some(1));
+
+    chain.mine_until(promise_expiry);
+    assert_eq!(
+        chain.broadcast(promise.tumbler_refund),
+        Err(Rejected::MissingInput(fund_outpoint))
+    );
+}
+
+/// What is left of a completed puzzle promise protocol.
+struct Promise {
+    params: Params,
+    tumbler_refund: bitcoin::Transaction,
+    lock: Lock<dummy_hsm_cl::Ciphertext>,
+    x_t: a2l_poc::secp256k1::KeyPair,
+    receiver: puzzle_solver::Receiver0<Ecdsa>,
+}
+
+impl Promise {
+    fn fund_outpoint(&self) -> bitcoin::OutPoint {
+        self.tumbler_refund.input[0].previous_output
+    }
+}
+
+/// Runs the puzzle promise protocol and publishes the tumbler's fund transaction on `chain`.
+fn run_puzzle_promise(
+    chain: &mut MockChain,
+    secretkey: &dummy_hsm_cl::SecretKey,
+    publickey: &dummy_hsm_cl::PublicKey,
+) -> Promise {
+    let mut rng = rand::thread_rng();
+    let params = make_params(Protocol::PuzzlePromise);
+
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params.clone(), &mut rng);
+    let sender = puzzle_promise::Sender0::new();
+
+    let message = tumbler.next_message(secretkey);
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
+    let message = receiver.next_message();
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let receiver = receiver.receive(message, &mut rng, publickey).unwrap();
+    let message = receiver.next_message();
+    let sender = sender.receive(message);
+
+    chain.mine_external(tumbler.unsigned_fund_transaction().clone());
+
+    Promise {
+        params,
+        tumbler_refund: tumbler.signed_refund_transaction().clone(),
+        lock: sender.lock().clone(),
+        x_t: tumbler.x_t().clone(),
+        receiver: puzzle_solver::Receiver0::new(
+            receiver.redeem_session().clone(),
+            receiver.unsigned_redeem_transaction().clone(),
+            receiver.sig_redeem_t().clone(),
+            receiver.sig_redeem_r().clone(),
+            receiver.beta().clone(),
+        ),
+    }
+}
+
+/// Runs the puzzle solver protocol up to the point where the sender has everything it needs to
+/// fund and refund, but has not sent `Message3` yet.
+fn run_puzzle_solver_until_message3(
+    params: Params,
+    promise: &Promise,
+    chain: &mut MockChain,
+    secretkey: &dummy_hsm_cl::SecretKey,
+    publickey: &dummy_hsm_cl::PublicKey,
+) -> (
+    puzzle_solver::Sender2<Ecdsa>,
+    puzzle_solver::Tumbler1<Ecdsa>,
+) {
+    let mut rng = rand::thread_rng();
+
+    let tumbler = puzzle_solver::Tumbler0::new(params.clone(), promise.x_t.clone());
+    let sender = puzzle_solver::Sender0::<_, Ecdsa>::new(params, promise.lock.clone(), &mut rng);
+
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let message = sender.next_message(publickey);
+    let tumbler = tumbler.receive(message, secretkey, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng, publickey).unwrap();
+
+    // nothing is published before the sender decides to fund
+    assert!(chain.mempool().is_empty());
+
+    (sender, tumbler)
+}
+
+/// Asserts that `refund` is rejected until the expiry in `params` and then pays the refund
+/// identity.
+fn assert_refunded(chain: &mut MockChain, refund: bitcoin::Transaction, params: &Params) {
+    let expiry = params.terms().expiry;
+    if chain.height() < expiry {
+        assert_eq!(
+            chain.broadcast(refund.clone()),
+            Err(Rejected::NonFinal(expiry))
+        );
+        chain.mine_until(expiry);
+    }
+
+    let txid = chain.broadcast(refund.clone()).unwrap();
+    chain.mine();
+
+    assert_eq!(chain.confirmations(&txid), Some(1));
+    assert_eq!(
+        refund.output[0].script_pubkey,
+        params.refund_identity.script_pubkey()
+    );
+}
@@ -0,0 +1,478 @@
+use a2l_poc::secp256k1::Ecdsa;
+use a2l_poc::storage::{FileStorage, MemoryStorage, Storage};
+use a2l_poc::transport::{self, ChannelTransport, Handler, Peer, TcpTransport, Transport};
+use a2l_poc::tumbler_service::{Request, Response, ServiceConfig, SessionId, TumblerService};
+use a2l_poc::wire::WireMessage;
+use a2l_poc::{dummy_hsm_cl, puzzle_promise, puzzle_solver};
+use common::{
+    random_p2wpkh, sender_contribution, service_config, LimitedWallet, TestWallet, PROMISE_EXPIRY,
+};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+type DummyRequest = Request<dummy_hsm_cl::Ciphertext, Ecdsa>;
+type DummyResponse = Response<dummy_hsm_cl::Ciphertext, dummy_hsm_cl::Proof, Ecdsa>;
+
+#[test]
+fn concurrent_sessions_over_channel_transport() {
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            dummy_hsm_cl::keygen().0,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service.clone(), Duration::from_secs(10));
+
+    let sessions = (0..8)
+        .map(|_| {
+            let transport = transport.clone();
+            thread::spawn(move || run_promise_and_solver(&transport))
+        })
+        .collect::<Vec<_>>();
+
+    for session in sessions {
+        let (promise, solver) = session.join().unwrap();
+
+        assert!(service.take_completed_promise(&promise).unwrap().is_some());
+        assert!(service.take_completed_solver(&solver).unwrap().is_some());
+    }
+}
+
+#[test]
+fn concurrent_sessions_over_tcp_transport() {
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            dummy_hsm_cl::keygen().0,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _server = transport::serve_tcp(service.clone(), listener);
+
+    let sessions = (0..4)
+        .map(|_| {
+            thread::spawn(move || {
+                run_promise_and_solver(&TcpTransport::new(addr, Duration::from_secs(10)))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for session in sessions {
+        let (promise, solver) = session.join().unwrap();
+
+        assert!(service.take_completed_promise(&promise).unwrap().is_some());
+        assert!(service.take_completed_solver(&solver).unwrap().is_some());
+    }
+}
+
+#[test]
+fn rejects_requests_out_of_order() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        secretkey,
+        TestWallet,
+        MemoryStorage::default(),
+        service_config(),
+    )
+    .unwrap();
+    let id = SessionId::random(&mut rng);
+    let redeem_identity = random_p2wpkh();
+
+    let (params, message) = match service
+        .process(
+            Peer::Local,
+            Request::StartPromise {
+                session: id,
+                redeem_identity: redeem_identity.clone(),
+            },
+        )
+        .unwrap()
+    {
+        Response::PromiseMessage0(params, message) => (params, message),
+        _ => panic!("expected puzzle promise message 0"),
+    };
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng)
+        .receive(message, &mut rng, &publickey)
+        .unwrap();
+
+    assert!(service
+        .process(
+            Peer::Local,
+            Request::StartPromise {
+                session: id,
+                redeem_identity,
+            },
+        )
+        .is_err());
+    let solver = SessionId::random(&mut rng);
+    service
+        .process(
+            Peer::Local,
+            Request::StartSolver {
+                session: solver,
+                sender: sender_contribution(),
+            },
+        )
+        .unwrap();
+    assert!(service
+        .process(
+            Peer::Local,
+            Request::PromiseMessage1 {
+                session: solver,
+                message: receiver.next_message(),
+            },
+        )
+        .is_err());
+    assert!(service
+        .process(
+            Peer::Local,
+            Request::PromiseMessage1 {
+                session: SessionId::random(&mut rng),
+                message: receiver.next_message(),
+            },
+        )
+        .is_err());
+
+    // the session survives rejected requests
+    assert!(service
+        .process(
+            Peer::Local,
+            Request::PromiseMessage1 {
+                session: id,
+                message: receiver.next_message(),
+            },
+        )
+        .is_ok());
+    assert!(service.take_completed_promise(&id).unwrap().is_some());
+}
+
+#[test]
+fn resumes_sessions_after_a_restart() {
+    let mut rng = rand::thread_rng();
+    let dir = tempfile::tempdir().unwrap();
+    let publickey = dummy_hsm_cl::PublicKey;
+    let id = SessionId::random(&mut rng);
+    let request = DummyRequest::StartPromise {
+        session: id,
+        redeem_identity: random_p2wpkh(),
+    }
+    .to_bytes();
+
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        dummy_hsm_cl::SecretKey,
+        TestWallet,
+        FileStorage::open(dir.path()).unwrap(),
+        service_config(),
+    )
+    .unwrap();
+    let response = service.handle(Peer::Local, &request);
+    drop(service);
+
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        dummy_hsm_cl::SecretKey,
+        TestWallet,
+        FileStorage::open(dir.path()).unwrap(),
+        service_config(),
+    )
+    .unwrap();
+
+    // the response to a retransmitted request survives the restart as well
+    assert_eq!(service.handle(Peer::Local, &request), response);
+
+    let (params, message) = match DummyResponse::from_bytes(&response).unwrap() {
+        Response::PromiseMessage0(params, message) => (params, message),
+        _ => panic!("expected puzzle promise message 0"),
+    };
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng)
+        .receive(message, &mut rng, &publickey)
+        .unwrap();
+    service
+        .process(
+            Peer::Local,
+            Request::PromiseMessage1 {
+                session: id,
+                message: receiver.next_message(),
+            },
+        )
+        .unwrap();
+
+    assert!(service.take_completed_promise(&id).unwrap().is_some());
+    assert_eq!(
+        FileStorage::open(dir.path()).unwrap().keys().unwrap(),
+        vec![format!("promise-{}", id)]
+    );
+}
+
+#[test]
+fn completes_params_with_its_own_terms_and_addresses() {
+    let mut rng = rand::thread_rng();
+    let config = service_config();
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        dummy_hsm_cl::keygen().0,
+        TestWallet,
+        MemoryStorage::default(),
+        config.clone(),
+    )
+    .unwrap();
+    let redeem_identity = random_p2wpkh();
+
+    let params = match service
+        .process(
+            Peer::Local,
+            Request::StartPromise {
+                session: SessionId::random(&mut rng),
+                redeem_identity: redeem_identity.clone(),
+            },
+        )
+        .unwrap()
+    {
+        Response::PromiseMessage0(params, _) => params,
+        _ => panic!("expected puzzle promise message 0"),
+    };
+
+    assert_eq!(params.redeem_identity, redeem_identity);
+    assert_eq!(params.refund_identity, config.refund_address);
+    assert_eq!(params.terms().tumble_amount, config.tumble_amount);
+    assert_eq!(params.terms().expiry, PROMISE_EXPIRY);
+}
+
+#[test]
+fn limits_open_sessions_per_peer() {
+    let mut rng = rand::thread_rng();
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        dummy_hsm_cl::keygen().0,
+        TestWallet,
+        MemoryStorage::default(),
+        ServiceConfig {
+            max_open_sessions_per_peer: 2,
+            ..service_config()
+        },
+    )
+    .unwrap();
+    let mut start = |peer| {
+        service.process(
+            peer,
+            Request::StartPromise {
+                session: SessionId::random(&mut rng),
+                redeem_identity: random_p2wpkh(),
+            },
+        )
+    };
+
+    assert!(start(Peer::Local).is_ok());
+    assert!(start(Peer::Local).is_ok());
+    assert!(start(Peer::Local).is_err());
+    assert!(start(Peer::Remote([127, 0, 0, 2].into())).is_ok());
+}
+
+#[test]
+fn opening_sessions_does_not_reserve_coins() {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let wallet = LimitedWallet::new(1);
+    let reserved = wallet.reserved.clone();
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        secretkey,
+        wallet,
+        MemoryStorage::default(),
+        service_config(),
+    )
+    .unwrap();
+    let mut start = |peer| {
+        let id = SessionId::random(&mut rng);
+        let response = service
+            .process(
+                peer,
+                Request::StartPromise {
+                    session: id,
+                    redeem_identity: random_p2wpkh(),
+                },
+            )
+            .unwrap();
+
+        match response {
+            Response::PromiseMessage0(params, message) => (id, params, message),
+            _ => panic!("expected puzzle promise message 0"),
+        }
+    };
+
+    // far more sessions than the wallet has coins, all of them offered the same one
+    let sessions = (0..32u8)
+        .map(|i| start(Peer::Remote([127, 0, 0, i].into())))
+        .collect::<Vec<_>>();
+    assert!(reserved.lock().unwrap().is_empty());
+
+    let mut finish = |(id, params, message)| {
+        let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng)
+            .receive(message, &mut rng, &publickey)
+            .unwrap();
+
+        service.process(
+            Peer::Local,
+            Request::PromiseMessage1 {
+                session: id,
+                message: receiver.next_message(),
+            },
+        )
+    };
+    let mut sessions = sessions.into_iter();
+    let (first, second) = (sessions.next().unwrap(), sessions.next().unwrap());
+    let (first_id, second_id) = (first.0, second.0);
+
+    assert!(finish(first).is_ok());
+    assert_eq!(reserved.lock().unwrap().len(), 1);
+    assert!(finish(second).is_err());
+    assert!(service.take_completed_promise(&first_id).unwrap().is_some());
+    assert!(service
+        .take_completed_promise(&second_id)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn rejected_requests_are_reported_over_the_transport() {
+    let service = Arc::new(
+        TumblerService::<_, _, _, Ecdsa>::new(
+            dummy_hsm_cl::keygen().0,
+            TestWallet,
+            MemoryStorage::default(),
+            service_config(),
+        )
+        .unwrap(),
+    );
+    let transport = ChannelTransport::spawn(service, Duration::from_secs(10));
+
+    let response = transport.send(b"garbage").unwrap();
+
+    match DummyResponse::from_bytes(&response).unwrap() {
+        Response::Rejected(_) => {}
+        _ => panic!("expected rejection"),
+    }
+}
+
+#[test]
+fn idle_sessions_expire() {
+    let mut rng = rand::thread_rng();
+    let service = TumblerService::<_, _, _, Ecdsa>::new(
+        dummy_hsm_cl::keygen().0,
+        TestWallet,
+        MemoryStorage::default(),
+        ServiceConfig {
+            session_timeout: Duration::from_secs(0),
+            ..service_config()
+        },
+    )
+    .unwrap();
+    let id = SessionId::random(&mut rng);
+
+    service
+        .process(
+            Peer::Local,
+            Request::StartSolver {
+                session: id,
+                sender: sender_contribution(),
+            },
+        )
+        .unwrap();
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(service.expire_idle_sessions(), vec![id]);
+}
+
+/// Runs a full puzzle promise session followed by a puzzle solver session against the tumbler
+/// behind `transport`, returning the ids of both sessions.
+fn run_promise_and_solver(transport: &impl Transport) -> (SessionId, SessionId) {
+    let mut rng = rand::thread_rng();
+    let publickey = dummy_hsm_cl::PublicKey;
+
+    // puzzle promise protocol
+    let promise = SessionId::random(&mut rng);
+    let sender = puzzle_promise::Sender0::new();
+
+    let (params, message) = match send(
+        transport,
+        Request::StartPromise {
+            session: promise,
+            redeem_identity: random_p2wpkh(),
+        },
+    ) {
+        Response::PromiseMessage0(params, message) => (params, message),
+        _ => panic!("expected puzzle promise message 0"),
+    };
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng)
+        .receive(message, &mut rng, &publickey)
+        .unwrap();
+    let message = match send(
+        transport,
+        Request::PromiseMessage1 {
+            session: promise,
+            message: receiver.next_message(),
+        },
+    ) {
+        Response::PromiseMessage2(message) => message,
+        _ => panic!("expected puzzle promise message 2"),
+    };
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let sender = sender.receive(receiver.next_message());
+
+    // puzzle solver protocol
+    let solver = SessionId::random(&mut rng);
+
+    let (params, message) = match send(
+        transport,
+        Request::StartSolver {
+            session: solver,
+            sender: sender_contribution(),
+        },
+    ) {
+        Response::SolverMessage0(params, message) => (params, message),
+        _ => panic!("expected puzzle solver message 0"),
+    };
+    let sender = puzzle_solver::Sender0::<_, Ecdsa>::new(params, sender.lock().clone(), &mut rng)
+        .receive(message, &mut rng)
+        .unwrap();
+    let message = match send(
+        transport,
+        Request::SolverMessage1 {
+            session: solver,
+            message: sender.next_message(&publickey),
+        },
+    ) {
+        Response::SolverMessage2(message) => message,
+        _ => panic!("expected puzzle solver message 2"),
+    };
+    let sender = sender.receive(message, &mut rng, &publickey).unwrap();
+    match send(
+        transport,
+        Request::SolverMessage3 {
+            session: solver,
+            message: sender.next_message(),
+        },
+    ) {
+        Response::SolverDone => {}
+        _ => panic!("expected puzzle solver to complete"),
+    }
+
+    (promise, solver)
+}
+
+fn send(transport: &impl Transport, request: DummyRequest) -> DummyResponse {
+    let response = transport.send(&request.to_bytes()).unwrap();
+
+    match DummyResponse::from_bytes(&response).unwrap() {
+        Response::Rejected(reason) => panic!("tumbler rejected request: {}", reason),
+        response => response,
+    }
+}
@@ -0,0 +1,226 @@
+use a2l_poc::bitcoin::{OutPoint, Transaction, Txid};
+use a2l_poc::chain::{Broadcaster, ChainSource, Utxo};
+use a2l_poc::mock_chain::MockChain;
+use a2l_poc::secp256k1::Ecdsa;
+use a2l_poc::watcher::{Outcome, Poll, SenderWatcher};
+use a2l_poc::{dummy_hsm_cl, puzzle_promise, puzzle_solver, Protocol};
+use common::{make_params, SOLVER_EXPIRY};
+use std::cell::Cell;
+use std::sync::Mutex;
+
+mod common;
+
+#[test]
+fn watcher_learns_solution_from_tumbler_redeem() {
+    let chain = Mutex::new(MockChain::new());
+    let (sender, tumbler, receiver) = run_until_sender_funds(&chain);
+
+    let watcher = match SenderWatcher::new(sender).poll(&chain).unwrap() {
+        Poll::Pending(watcher) => watcher,
+        _ => panic!("nothing happened on chain yet"),
+    };
+
+    chain
+        .lock()
+        .unwrap()
+        .broadcast(tumbler.signed_redeem_transaction().clone())
+        .unwrap();
+
+    let sender = match watcher.poll(&chain).unwrap() {
+        Poll::Ready(Outcome::Solved(sender)) => sender,
+        _ => panic!("expected the watcher to learn the solution"),
+    };
+    let receiver = receiver.receive(sender.next_message()).unwrap();
+
+    chain
+        .lock()
+        .unwrap()
+        .broadcast(receiver.signed_redeem_transaction().clone())
+        .unwrap();
+}
+
+#[test]
+fn watcher_fails_on_spend_with_empty_signature() {
+    let chain = Mutex::new(MockChain::new());
+    let (sender, tumbler, _receiver) = run_until_sender_funds(&chain);
+
+    // not a valid spend, but the watcher may learn about any transaction spending the fund output
+    let mut spend = tumbler.signed_redeem_transaction().clone();
+    spend.input[0].witness[1] = Vec::new();
+    chain.lock().unwrap().mine_external(spend);
+
+    assert!(SenderWatcher::new(sender).poll(&chain).is_err());
+}
+
+#[test]
+fn watcher_refunds_after_expiry() {
+    let chain = Mutex::new(MockChain::new());
+    let (sender, _tumbler, _receiver) = run_until_sender_funds(&chain);
+    let refund_txid = sender.signed_refund_transaction().txid();
+
+    chain.lock().unwrap().mine_until(SOLVER_EXPIRY - 1);
+    let watcher = match SenderWatcher::new(sender).poll(&chain).unwrap() {
+        Poll::Pending(watcher) => watcher,
+        _ => panic!("refund is not valid yet"),
+    };
+    assert!(chain.lock().unwrap().mempool().is_empty());
+
+    chain.lock().unwrap().mine();
+    let watcher = match watcher.poll(&chain).unwrap() {
+        Poll::Pending(watcher) => watcher,
+        _ => panic!("refund is not confirmed yet"),
+    };
+    assert_eq!(chain.lock().unwrap().mempool()[0].txid(), refund_txid);
+
+    // polling again must not trip over the refund already being in the mempool
+    let watcher = match watcher.poll(&chain).unwrap() {
+        Poll::Pending(watcher) => watcher,
+        _ => panic!("refund is not confirmed yet"),
+    };
+
+    chain.lock().unwrap().mine();
+    match watcher.poll(&chain).unwrap() {
+        Poll::Ready(Outcome::Refunded(txid)) => assert_eq!(txid, refund_txid),
+        _ => panic!("expected the watcher to report the confirmed refund"),
+    }
+}
+
+#[test]
+fn watcher_retries_while_the_chain_is_unreachable() {
+    let chain = Mutex::new(MockChain::new());
+    let (sender, _tumbler, _receiver) = run_until_sender_funds(&chain);
+    let refund_txid = sender.signed_refund_transaction().txid();
+    chain.lock().unwrap().mine_until(SOLVER_EXPIRY);
+
+    let flaky = Flaky {
+        chain,
+        online: Cell::new(false),
+    };
+    let watcher = match SenderWatcher::new(sender).poll(&flaky).unwrap() {
+        Poll::Retry(watcher, _) => watcher,
+        _ => panic!("expected the watcher to retry"),
+    };
+
+    flaky.online.set(true);
+    let watcher = match watcher.poll(&flaky).unwrap() {
+        Poll::Pending(watcher) => watcher,
+        _ => panic!("refund is not confirmed yet"),
+    };
+    flaky.chain.lock().unwrap().mine();
+
+    match watcher.poll(&flaky).unwrap() {
+        Poll::Ready(Outcome::Refunded(txid)) => assert_eq!(txid, refund_txid),
+        _ => panic!("expected the watcher to report the confirmed refund"),
+    }
+}
+
+/// A chain that can be taken offline, like a bitcoind that is restarting.
+struct Flaky {
+    chain: Mutex<MockChain>,
+    online: Cell<bool>,
+}
+
+impl Flaky {
+    fn check_online(&self) -> anyhow::Result<()> {
+        if !self.online.get() {
+            anyhow::bail!("chain is offline")
+        }
+
+        Ok(())
+    }
+}
+
+impl ChainSource for Flaky {
+    fn height(&self) -> anyhow::Result<u32> {
+        self.check_online()?;
+        self.chain.height()
+    }
+
+    fn transaction(&self, txid: &Txid) -> anyhow::Result<Option<Transaction>> {
+        self.check_online()?;
+        self.chain.transaction(txid)
+    }
+
+    fn confirmations(&self, txid: &Txid) -> anyhow::Result<Option<u32>> {
+        self.check_online()?;
+        self.chain.confirmations(txid)
+    }
+
+    fn utxo(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Utxo>> {
+        self.check_online()?;
+        self.chain.utxo(outpoint)
+    }
+
+    fn spending_transaction(&self, outpoint: &OutPoint) -> anyhow::Result<Option<Transaction>> {
+        self.check_online()?;
+        self.chain.spending_transaction(outpoint)
+    }
+
+    fn fee_estimate_per_vbyte(&self, target_blocks: u32) -> anyhow::Result<Option<u64>> {
+        self.check_online()?;
+        self.chain.fee_estimate_per_vbyte(target_blocks)
+    }
+}
+
+impl Broadcaster for Flaky {
+    fn broadcast(&self, transaction: &Transaction) -> anyhow::Result<Txid> {
+        self.check_online()?;
+        self.chain.broadcast(transaction)
+    }
+}
+
+/// Runs both protocols up to the point where the sender has funded and handed out `Message3`, so
+/// the tumbler is able to redeem.
+fn run_until_sender_funds(
+    chain: &Mutex<MockChain>,
+) -> (
+    puzzle_solver::Sender2<Ecdsa>,
+    puzzle_solver::Tumbler2,
+    puzzle_solver::Receiver0<Ecdsa>,
+) {
+    let mut rng = rand::thread_rng();
+    let (secretkey, publickey) = dummy_hsm_cl::keygen();
+    let mut chain = chain.lock().unwrap();
+
+    // puzzle promise protocol
+    let params = make_params(Protocol::PuzzlePromise);
+    let tumbler = puzzle_promise::Tumbler0::new(params.clone(), &mut rng);
+    let receiver = puzzle_promise::Receiver0::<Ecdsa>::new(params, &mut rng);
+    let sender = puzzle_promise::Sender0::new();
+
+    let message = tumbler.next_message(&secretkey);
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let message = receiver.next_message();
+    let tumbler = tumbler.receive(message, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let receiver = receiver.receive(message, &mut rng, &publickey).unwrap();
+    let message = receiver.next_message();
+    let sender = sender.receive(message);
+
+    chain.mine_external(tumbler.unsigned_fund_transaction().clone());
+
+    // puzzle solver protocol
+    let params = make_params(Protocol::PuzzleSolver);
+    let tumbler = puzzle_solver::Tumbler0::new(params.clone(), tumbler.x_t().clone());
+    let sender = puzzle_solver::Sender0::<_, Ecdsa>::new(params, sender.lock().clone(), &mut rng);
+    let receiver = puzzle_solver::Receiver0::new(
+        receiver.redeem_session().clone(),
+        receiver.unsigned_redeem_transaction().clone(),
+        receiver.sig_redeem_t().clone(),
+        receiver.sig_redeem_r().clone(),
+        receiver.beta().clone(),
+    );
+
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng).unwrap();
+    let message = sender.next_message(&publickey);
+    let tumbler = tumbler.receive(message, &secretkey, &mut rng).unwrap();
+    let message = tumbler.next_message();
+    let sender = sender.receive(message, &mut rng, &publickey).unwrap();
+    let message = sender.next_message();
+    let tumbler = tumbler.receive(message).unwrap();
+
+    chain.mine_external(sender.unsigned_fund_transaction());
+
+    (sender, tumbler, receiver)
+}